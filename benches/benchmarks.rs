@@ -1,9 +1,10 @@
 use std::io;
 #[macro_use]
 extern crate criterion;
-use criterion::Criterion;
+use criterion::{Benchmark, Criterion, Throughput};
 extern crate huffman;
 use huffman::coding;
+use huffman::header::Header;
 
 
 struct EmptyWriter;
@@ -23,22 +24,26 @@ fn wrap_byte(b: u8) -> Result<u8, ()> {
     Ok(b)
 }
 
-fn build_freqs(bytes: &Vec<u8>) -> coding::Frequencies {
+fn build_freqs(bytes: &[u8]) -> coding::Frequencies {
     let iter1 = bytes.iter().map(|b| wrap_byte(*b));
     coding::Frequencies::count_bytes(iter1).unwrap()
 }
 
+fn count_freqs(bytes: &[u8]) -> coding::Frequencies {
+    coding::Frequencies::count_reader(&mut &bytes[..]).unwrap()
+}
+
 fn build_tree(freqs: &coding::Frequencies) -> coding::HuffTree {
     coding::HuffTree::from_freqs(freqs)
 }
 
-fn encode(bytes: &Vec<u8>, tree: &coding::HuffTree) {
-    let mut encoder = coding::HuffWriter::from_tree(tree);
+fn encode<S: coding::Scratch>(bytes: &[u8], tree: &coding::HuffTree) {
+    let mut encoder = coding::HuffWriter::<S>::with_scratch(tree);
     let mut writer = EmptyWriter;
     for byte in bytes {
         encoder.write_byte(*byte, &mut writer).unwrap();
     }
-    encoder.end_transmission(&mut writer).unwrap();
+    encoder.finish(&mut writer).unwrap();
 }
 
 fn encoding_benchmark(c: &mut Criterion) {
@@ -49,21 +54,68 @@ fn encoding_benchmark(c: &mut Criterion) {
         }
     }
     let bytes1 = bytes.clone();
+    let bytes2 = bytes.clone();
+    let bytes3 = bytes.clone();
     let freqs = build_freqs(&bytes);
     let tree = build_tree(&freqs);
+    let tree1 = build_tree(&freqs);
     c.bench_function("building freqs", move |b| b.iter(|| {
         build_freqs(&bytes);
     }));
+    c.bench_function("counting freqs from a reader", move |b| b.iter(|| {
+        count_freqs(&bytes3);
+    }));
     c.bench_function("building tree", move |b| b.iter(|| {
         build_tree(&freqs);
     }));
     c.bench_function("encoding with tree", move |b| b.iter(|| {
-        encode(&bytes1, &tree);
+        encode::<u128>(&bytes1, &tree);
+    }));
+    c.bench_function("encoding with tree, u64 scratch", move |b| b.iter(|| {
+        encode::<u64>(&bytes2, &tree1);
     }));
 
 
     //c.bench_function("encoding bytes", move |b| b.iter(|| encode(&mut bytes)));
 }
 
-criterion_group!(benches, encoding_benchmark);
+// Some text-like input, with a few common bytes, and many rare ones
+fn skewed_bytes() -> Vec<u8> {
+    (0..256 * 1000u32).map(|i| {
+        let x = i.wrapping_mul(2_654_435_761) >> 24;
+        if x < 128 { b' ' + (x % 8) as u8 } else { x as u8 }
+    }).collect()
+}
+
+fn decoding_benchmark(c: &mut Criterion) {
+    let bytes = skewed_bytes();
+    let len = bytes.len() as u32;
+    let mut encoded = Vec::new();
+    coding::encode_stream(&mut io::Cursor::new(&bytes), &mut encoded).unwrap();
+    let header = encoded[..5].to_vec();
+    c.bench(
+        "decoding",
+        Benchmark::new("stream", move |b| b.iter(|| {
+            coding::decode_stream(&mut &encoded[..], &mut EmptyWriter).unwrap();
+        })).throughput(Throughput::Bytes(len))
+    );
+    c.bench_function("parsing header", move |b| b.iter(|| {
+        Header::read(&mut &header[..]).unwrap();
+    }));
+
+    let freqs = build_freqs(&bytes);
+    let tree = build_tree(&freqs);
+    let mut freqs_table = Vec::new();
+    freqs.write(&mut freqs_table).unwrap();
+    let mut succinct_table = Vec::new();
+    tree.write_succinct(&mut succinct_table).unwrap();
+    c.bench_function("reading tree from frequencies", move |b| b.iter(|| {
+        build_tree(&coding::Frequencies::read(&mut &freqs_table[..]).unwrap());
+    }));
+    c.bench_function("reading succinct tree", move |b| b.iter(|| {
+        coding::HuffTree::read_succinct(&mut &succinct_table[..]).unwrap();
+    }));
+}
+
+criterion_group!(benches, encoding_benchmark, decoding_benchmark);
 criterion_main!(benches);
\ No newline at end of file
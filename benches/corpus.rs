@@ -0,0 +1,66 @@
+// Runs the codec over every file in a corpus, such as the Canterbury or Silesia corpora,
+// reporting the compression ratio and speed for each file.
+//
+// The corpus is read from the directory in HUFFMAN_CORPUS, or `corpus/` by default.
+// To use the Canterbury corpus:
+//
+//     mkdir corpus && curl https://corpus.canterbury.ac.nz/resources/cantrbry.tar.gz | tar -xz -C corpus
+//     cargo bench --bench corpus
+use std::env;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+extern crate huffman;
+use huffman::coding;
+
+
+fn main() -> io::Result<()> {
+    let dir = PathBuf::from(env::var_os("HUFFMAN_CORPUS").unwrap_or_else(|| "corpus".into()));
+    if !dir.is_dir() {
+        eprintln!("no corpus in `{}`, set HUFFMAN_CORPUS to a directory of files to compress", dir.display());
+        return Ok(())
+    }
+    let mut paths: Vec<PathBuf> = fs::read_dir(&dir)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<io::Result<_>>()?;
+    paths.retain(|path| path.is_file());
+    paths.sort();
+
+    println!("{:<24}{:<10}{:>12}{:>12}{:>10}{:>14}{:>14}", "file", "method", "size", "encoded", "ratio", "encode MB/s", "decode MB/s");
+    let mut totals = (0, 0);
+    for path in paths {
+        let data = fs::read(&path)?;
+        let mut encoded = Vec::new();
+        let encoded_stats = coding::encode_stream(&mut io::Cursor::new(&data), &mut encoded)?;
+        let mut decoded = Vec::with_capacity(data.len());
+        let decoded_stats = coding::decode_stream(&mut &encoded[..], &mut decoded)?;
+        if decoded != data {
+            let msg = format!("decoding `{}` doesn't give it back", path.display());
+            return Err(io::Error::new(io::ErrorKind::InvalidData, msg))
+        }
+
+        let megabytes = data.len() as f64 / 1_000_000.0;
+        let name = path.file_name().map_or_else(String::new, |name| name.to_string_lossy().into_owned());
+        println!(
+            "{:<24}{:<10}{:>12}{:>12}{:>9.2}%{:>14.1}{:>14.1}",
+            name,
+            encoded_stats.method,
+            data.len(),
+            encoded.len(),
+            encoded.len() as f64 / data.len().max(1) as f64 * 100.0,
+            megabytes / encoded_stats.elapsed.as_secs_f64(),
+            megabytes / decoded_stats.elapsed.as_secs_f64()
+        );
+        totals.0 += data.len();
+        totals.1 += encoded.len();
+    }
+    println!(
+        "{:<34}{:>12}{:>12}{:>9.2}%",
+        "total",
+        totals.0,
+        totals.1,
+        totals.1 as f64 / totals.0.max(1) as f64 * 100.0
+    );
+    Ok(())
+}
@@ -0,0 +1,23 @@
+// Compresses a buffer in memory, and decompresses it again.
+//
+//     cargo run --example compress_buffer
+use std::io;
+
+extern crate huffman;
+use huffman::coding;
+
+
+fn main() -> io::Result<()> {
+    let text = "she sells sea shells by the sea shore, ".repeat(20);
+
+    // Encoding reads its input twice, so it needs to be able to seek back to the start
+    let mut encoded = Vec::new();
+    let stats = coding::encode_stream(&mut io::Cursor::new(text.as_bytes()), &mut encoded)?;
+    println!("encoded {} bytes into {} with the {} method", stats.bytes_in, stats.bytes_out, stats.method);
+
+    let mut decoded = Vec::new();
+    coding::decode_stream(&mut &encoded[..], &mut decoded)?;
+    assert_eq!(decoded, text.as_bytes());
+    println!("decoded them back");
+    Ok(())
+}
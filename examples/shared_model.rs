@@ -0,0 +1,60 @@
+// Trains a model on some sample messages, and then uses it to encode many short messages,
+// without writing a header or table for each of them, which would outweigh their data.
+//
+//     cargo run --example shared_model
+use std::io;
+use std::thread;
+
+extern crate huffman;
+use huffman::coding::{Codebook, Frequencies, HuffTree, HuffWriter, OwnedHuffReader};
+
+
+fn main() -> io::Result<()> {
+    let messages = [
+        "GET /index.html HTTP/1.1",
+        "GET /about.html HTTP/1.1",
+        "POST /login HTTP/1.1",
+        "GET /favicon.ico HTTP/1.1"
+    ];
+
+    // Every byte the messages use needs to be in the model
+    let samples = messages.concat();
+    let freqs = Frequencies::count_reader(&mut samples.as_bytes()).expect("counting can't fail");
+    let tree = HuffTree::from_freqs(&freqs);
+
+    // The codes get worked out once, and each message gets a writer of its own,
+    // since finishing a message uses its writer up
+    let codebook = Codebook::from_tree(&tree);
+    let mut encoded = Vec::new();
+    for message in &messages {
+        let mut writer = HuffWriter::from_codebook(&codebook);
+        let mut bytes = Vec::new();
+        for byte in message.bytes() {
+            writer.write_byte(byte, &mut bytes)?;
+        }
+        let stats = writer.finish(&mut bytes)?;
+        println!("{:<28} {} bytes -> {} bytes, {} bits", message, message.len(), bytes.len(), stats.bits);
+        encoded.push(bytes);
+    }
+
+    // Owned readers share the same flattened tree, so decoding can happen on other threads
+    let reader = OwnedHuffReader::new(&tree);
+    let decoders: Vec<_> = encoded.into_iter().map(|bytes| {
+        let mut reader = reader.clone();
+        thread::spawn(move || -> io::Result<String> {
+            let mut decoded = Vec::new();
+            for &byte in &bytes {
+                if !reader.feed(byte, &mut decoded)? {
+                    break;
+                }
+            }
+            Ok(String::from_utf8_lossy(&decoded).into_owned())
+        })
+    }).collect();
+    for (message, decoder) in messages.iter().zip(decoders) {
+        let decoded = decoder.join().expect("decoding thread panicked")?;
+        assert_eq!(&decoded, message);
+    }
+    println!("decoded every message on its own thread");
+    Ok(())
+}
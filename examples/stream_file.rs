@@ -0,0 +1,36 @@
+// Encodes a file into another one, streaming it instead of loading it into memory,
+// and then checks it by decoding the result.
+//
+//     cargo run --example stream_file -- README.md README.md.huf
+use std::env;
+use std::fs::File;
+use std::io;
+
+extern crate huffman;
+use huffman::coding;
+
+
+fn main() -> io::Result<()> {
+    let args: Vec<String> = env::args().collect();
+    if args.len() != 3 {
+        eprintln!("usage: stream_file <input> <output>");
+        return Ok(())
+    }
+
+    // The stream functions add their own buffering
+    let stats = coding::encode_stream(&mut File::open(&args[1])?, &mut File::create(&args[2])?)?;
+    println!(
+        "encoded {} bytes into {} with the {} method, in {:?}",
+        stats.bytes_in,
+        stats.bytes_out,
+        stats.method,
+        stats.elapsed
+    );
+
+    let decoded = coding::decode_stream(&mut File::open(&args[2])?, &mut io::sink())?;
+    if decoded.checksum != stats.checksum {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "the output doesn't decode to the input"))
+    }
+    println!("checked that it decodes back to the same {} bytes", decoded.bytes_out);
+    Ok(())
+}
@@ -0,0 +1,210 @@
+//! This module contains an ASCII armor for encoded files, wrapping their bytes
+//! in base64 lines between a textual header and footer, so that they can
+//! be pasted into emails or text files without getting mangled.
+use std::io;
+
+
+/// The line starting an armored file
+pub const BEGIN: &str = "-----BEGIN HUFFMAN-----";
+/// The line ending an armored file
+pub const END: &str = "-----END HUFFMAN-----";
+
+const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+// How many bytes we put on each line, which becomes 76 characters
+const LINE_BYTES: usize = 57;
+
+
+/// Check whether some data starts like an armored file
+pub fn is_armored(data: &[u8]) -> bool {
+    data.starts_with(BEGIN.as_bytes())
+}
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+
+/// A writer armoring the bytes written to it into another writer
+pub struct Encoder<W> {
+    inner: W,
+    line: Vec<u8>
+}
+
+impl <W: io::Write> Encoder<W> {
+    /// Create a new encoder, writing out the header line immediately
+    pub fn new(mut inner: W) -> io::Result<Self> {
+        writeln!(inner, "{}", BEGIN)?;
+        Ok(Encoder { inner, line: Vec::with_capacity(LINE_BYTES) })
+    }
+
+    fn write_line(&mut self) -> io::Result<()> {
+        let mut encoded = Vec::with_capacity(LINE_BYTES / 3 * 4 + 1);
+        for group in self.line.chunks(3) {
+            let mut bytes = [0; 3];
+            bytes[..group.len()].copy_from_slice(group);
+            let n = (u32::from(bytes[0]) << 16) | (u32::from(bytes[1]) << 8) | u32::from(bytes[2]);
+            for i in 0..4 {
+                if i <= group.len() {
+                    encoded.push(ALPHABET[(n >> (18 - 6 * i)) as usize & 0x3F]);
+                } else {
+                    encoded.push(b'=');
+                }
+            }
+        }
+        encoded.push(b'\n');
+        self.line.clear();
+        self.inner.write_all(&encoded)
+    }
+
+    /// Write out the last line, and the footer, returning the underlying writer
+    pub fn finish(mut self) -> io::Result<W> {
+        if !self.line.is_empty() {
+            self.write_line()?;
+        }
+        writeln!(self.inner, "{}", END)?;
+        Ok(self.inner)
+    }
+}
+
+impl <W: io::Write> io::Write for Encoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let take = buf.len().min(LINE_BYTES - self.line.len());
+        self.line.extend_from_slice(&buf[..take]);
+        if self.line.len() == LINE_BYTES {
+            self.write_line()?;
+        }
+        Ok(take)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+
+/// A reader removing the armor from an underlying reader
+pub struct Decoder<R> {
+    inner: R,
+    decoded: Vec<u8>,
+    pos: usize,
+    done: bool,
+    // Whether the last line ended with padding, which only the last line of data can
+    padded: bool
+}
+
+impl <R: io::BufRead> Decoder<R> {
+    /// Create a new decoder, reading the header line immediately
+    pub fn new(mut inner: R) -> io::Result<Self> {
+        let mut line = Vec::new();
+        inner.read_until(b'\n', &mut line)?;
+        if line.trim_ascii() != BEGIN.as_bytes() {
+            return Err(invalid_data("missing armor header"))
+        }
+        Ok(Decoder { inner, decoded: Vec::new(), pos: 0, done: false, padded: false })
+    }
+
+    fn decode_line(&mut self, line: &[u8]) -> io::Result<()> {
+        self.decoded.clear();
+        self.pos = 0;
+        if !line.len().is_multiple_of(4) {
+            return Err(invalid_data("armored line has an invalid length"))
+        }
+        if self.padded {
+            return Err(invalid_data("armor padding before the end of the data"))
+        }
+        let groups = line.len() / 4;
+        for (i, group) in line.chunks(4).enumerate() {
+            let mut n = 0;
+            let mut padding = 0;
+            for (j, &c) in group.iter().enumerate() {
+                // Only the last one or two characters of the last group can be padding,
+                // and nothing but padding can follow it
+                let last = i + 1 == groups && j >= 2;
+                if (c == b'=' && !last) || (c != b'=' && padding > 0) {
+                    return Err(invalid_data("armor padding before the end of the data"))
+                }
+                let value = match c {
+                    b'A'..=b'Z' => c - b'A',
+                    b'a'..=b'z' => c - b'a' + 26,
+                    b'0'..=b'9' => c - b'0' + 52,
+                    b'+' => 62,
+                    b'/' => 63,
+                    b'=' => {
+                        padding += 1;
+                        0
+                    }
+                    _ => return Err(invalid_data("invalid character in armored line"))
+                };
+                n = (n << 6) | u32::from(value);
+            }
+            let bytes = [(n >> 16) as u8, (n >> 8) as u8, n as u8];
+            self.decoded.extend_from_slice(&bytes[..3 - padding]);
+            self.padded = padding > 0;
+        }
+        Ok(())
+    }
+}
+
+impl <R: io::BufRead> io::Read for Decoder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut line = Vec::new();
+        while self.pos == self.decoded.len() && !self.done {
+            line.clear();
+            if self.inner.read_until(b'\n', &mut line)? == 0 {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "missing armor footer"))
+            }
+            let line = line.trim_ascii();
+            if line == END.as_bytes() {
+                self.done = true;
+            } else {
+                self.decode_line(line)?;
+            }
+        }
+        let available = &self.decoded[self.pos..];
+        let read = available.len().min(buf.len());
+        buf[..read].copy_from_slice(&available[..read]);
+        self.pos += read;
+        Ok(read)
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::io::{Read, Write};
+    use super::{Decoder, Encoder};
+
+    #[test]
+    fn encoding_then_decoding_works() {
+        for len in (0..10).chain(vec![57, 114, 200]) {
+            let input: Vec<u8> = (0..len).map(|i| (i * 37) as u8).collect();
+            let mut encoder = Encoder::new(Vec::new()).unwrap();
+            encoder.write_all(&input).unwrap();
+            let armored = encoder.finish().unwrap();
+            assert!(armored.iter().all(|b| b.is_ascii()));
+            let mut decoded = Vec::new();
+            Decoder::new(&armored[..]).unwrap().read_to_end(&mut decoded).unwrap();
+            assert_eq!(decoded, input);
+        }
+    }
+
+    #[test]
+    fn padding_only_ends_the_data() {
+        let armored = ["aGVsbG8hPw==\n", "aG=sbG8hPw==\n", "aGVsbG8hP=w=\n", "aGVs=G8hPw==\n", "aGVsbG8=\nPw==\n"];
+        let decoded: Vec<_> = armored.iter().map(|lines| {
+            let text = format!("-----BEGIN HUFFMAN-----\n{}-----END HUFFMAN-----\n", lines);
+            let mut decoded = Vec::new();
+            Decoder::new(text.as_bytes()).unwrap().read_to_end(&mut decoded).map(|_| decoded).ok()
+        }).collect();
+        assert_eq!(decoded, vec![Some(b"hello!?".to_vec()), None, None, None, None]);
+    }
+
+    #[test]
+    fn encoding_matches_base64() {
+        let mut encoder = Encoder::new(Vec::new()).unwrap();
+        encoder.write_all(b"hello!?").unwrap();
+        let armored = encoder.finish().unwrap();
+        let expected = "-----BEGIN HUFFMAN-----\naGVsbG8hPw==\n-----END HUFFMAN-----\n";
+        assert_eq!(String::from_utf8(armored).unwrap(), expected);
+    }
+}
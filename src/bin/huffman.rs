@@ -1,13 +1,10 @@
-use std::io;
-
-extern crate structopt;
-use structopt::StructOpt;
+use std::process;
 
 extern crate huffman;
 use huffman::cli;
 
 
-fn main() -> io::Result<()> {
-    let opt = cli::Opt::from_args();
-    opt.dispatch()
+fn main() {
+    let result = cli::Opt::from_args_or_path().and_then(|opt| opt.dispatch());
+    process::exit(cli::report(result))
 }
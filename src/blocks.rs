@@ -0,0 +1,315 @@
+//! This module splits data into blocks encoded on their own, followed by an index of where
+//! each of them is, so that any part of the data can be decoded without decoding what comes
+//! before it, and compressed files can be read in place by code that seeks around them.
+//!
+//! Every block is a whole encoded file, with its own header, and the index after the last one
+//! holds, for each block, the number of bytes it takes, and the number of bytes it decodes to,
+//! both as big endian u64s, followed by the CRC-32 of its bytes, as a big endian u32.
+//! The file ends with the number of blocks, as a big endian u64, followed by the 8 bytes `HUFBLKIX`.
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use crate::checksum::Crc32;
+use crate::coding;
+
+
+/// How many bytes a block holds by default, except for the last one
+pub const BLOCK_SIZE: usize = 1024 * 1024;
+/// The most bytes a block can hold, where an index saying otherwise is taken to be damaged
+pub const MAX_BLOCK_SIZE: usize = 1024 * 1024 * 1024;
+/// How many decoded blocks a SeekableDecoder keeps around
+pub const CACHED_BLOCKS: usize = 4;
+
+// The bytes ending an indexed file
+const MAGIC: &[u8; 8] = b"HUFBLKIX";
+// How many bytes the number of blocks and the magic bytes take
+const TRAILER_SIZE: u64 = 16;
+// How many bytes each block takes up in the index
+const ENTRY_SIZE: u64 = 20;
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn invalid_input(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, msg)
+}
+
+
+/// A writer encoding the bytes written to it in blocks, writing out the index once it's finished
+pub struct BlockWriter<W> {
+    inner: W,
+    block_size: usize,
+    block: Vec<u8>,
+    encoded: Vec<u8>,
+    index: Vec<(u64, u64, u32)>
+}
+
+impl <W: Write> BlockWriter<W> {
+    pub fn new(inner: W) -> Self {
+        BlockWriter { inner, block_size: BLOCK_SIZE, block: Vec::new(), encoded: Vec::new(), index: Vec::new() }
+    }
+
+    /// Split the data into blocks of this many bytes, failing with InvalidInput
+    /// unless it's more than 0, and at most MAX_BLOCK_SIZE
+    pub fn with_block_size(inner: W, block_size: usize) -> io::Result<Self> {
+        if block_size == 0 {
+            return Err(invalid_input("blocks need to hold at least one byte"))
+        }
+        if block_size > MAX_BLOCK_SIZE {
+            return Err(invalid_input(&format!("blocks can hold at most {} bytes", MAX_BLOCK_SIZE)))
+        }
+        Ok(BlockWriter { block_size, ..BlockWriter::new(inner) })
+    }
+
+    fn write_block(&mut self) -> io::Result<()> {
+        self.encoded.clear();
+        coding::encode_stream(&mut io::Cursor::new(&self.block), &mut self.encoded)?;
+        self.inner.write_all(&self.encoded)?;
+        let mut crc = Crc32::new();
+        crc.update(&self.encoded);
+        self.index.push((self.encoded.len() as u64, self.block.len() as u64, crc.sum()));
+        self.block.clear();
+        Ok(())
+    }
+
+    /// Write out the last block, and the index, returning the underlying writer
+    pub fn finish(mut self) -> io::Result<W> {
+        if !self.block.is_empty() {
+            self.write_block()?;
+        }
+        for &(encoded, decoded, crc) in &self.index {
+            self.inner.write_all(&encoded.to_be_bytes())?;
+            self.inner.write_all(&decoded.to_be_bytes())?;
+            self.inner.write_all(&crc.to_be_bytes())?;
+        }
+        self.inner.write_all(&(self.index.len() as u64).to_be_bytes())?;
+        self.inner.write_all(MAGIC)?;
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+}
+
+impl <W: Write> Write for BlockWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let taken = buf.len().min(self.block_size - self.block.len());
+        self.block.extend_from_slice(&buf[..taken]);
+        if self.block.len() == self.block_size {
+            self.write_block()?;
+        }
+        Ok(taken)
+    }
+
+    /// This doesn't write out the current block, since more bytes could go in it
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+
+// Where a block is in an indexed file, and where its bytes go in the decoded data
+#[derive(Clone, Copy, Debug)]
+struct Block {
+    offset: u64,
+    len: u64,
+    start: u64,
+    size: u64,
+    crc: u32
+}
+
+/// A reader over the decoded data of an indexed file, which can seek anywhere in it,
+/// decoding only the blocks read from, and keeping the last few of them around
+pub struct SeekableDecoder<R> {
+    inner: R,
+    blocks: Vec<Block>,
+    len: u64,
+    pos: u64,
+    // The most recently used block comes last
+    cache: Vec<(usize, Vec<u8>)>
+}
+
+impl <R: Read + Seek> SeekableDecoder<R> {
+    /// Read the index at the end of a file written by a BlockWriter
+    pub fn new(mut inner: R) -> io::Result<Self> {
+        let file_len = inner.seek(SeekFrom::End(0))?;
+        if file_len < TRAILER_SIZE {
+            return Err(invalid_data("the file is too short to end with an index"))
+        }
+        let mut trailer = [0; TRAILER_SIZE as usize];
+        inner.seek(SeekFrom::Start(file_len - TRAILER_SIZE))?;
+        inner.read_exact(&mut trailer)?;
+        if &trailer[8..] != MAGIC {
+            return Err(invalid_data("the file doesn't end with an index of its blocks"))
+        }
+        let mut count = [0; 8];
+        count.copy_from_slice(&trailer[..8]);
+        let count = u64::from_be_bytes(count);
+        let index_len = count.checked_mul(ENTRY_SIZE)
+            .filter(|&len| len <= file_len - TRAILER_SIZE)
+            .ok_or_else(|| invalid_data("the index has more blocks than the file could hold"))?;
+        let index_start = file_len - TRAILER_SIZE - index_len;
+        inner.seek(SeekFrom::Start(index_start))?;
+        let mut index = vec![0; index_len as usize];
+        inner.read_exact(&mut index)?;
+
+        let mut blocks = Vec::with_capacity(count as usize);
+        let (mut offset, mut start) = (0u64, 0u64);
+        for entry in index.chunks(ENTRY_SIZE as usize) {
+            let mut len = [0; 8];
+            let mut size = [0; 8];
+            let mut crc = [0; 4];
+            len.copy_from_slice(&entry[..8]);
+            size.copy_from_slice(&entry[8..16]);
+            crc.copy_from_slice(&entry[16..]);
+            let (len, size, crc) = (u64::from_be_bytes(len), u64::from_be_bytes(size), u32::from_be_bytes(crc));
+            if size > MAX_BLOCK_SIZE as u64 {
+                return Err(invalid_data(&format!("the index says block {} decodes to more than a block can hold", blocks.len())))
+            }
+            blocks.push(Block { offset, len, start, size, crc });
+            offset = offset.checked_add(len).ok_or_else(|| invalid_data("the blocks are larger than the file"))?;
+            start = start.checked_add(size).ok_or_else(|| invalid_data("the blocks decode to too many bytes"))?;
+        }
+        if offset != index_start {
+            return Err(invalid_data("the blocks don't add up to where the index starts"))
+        }
+        Ok(SeekableDecoder { inner, blocks, len: start, pos: 0, cache: Vec::new() })
+    }
+
+    /// How many bytes the whole file decodes to
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// How many blocks the data was split into
+    pub fn block_count(&self) -> usize {
+        self.blocks.len()
+    }
+
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+
+    // The decoded bytes of a block, decoding it unless it's cached
+    fn block(&mut self, i: usize) -> io::Result<&[u8]> {
+        match self.cache.iter().position(|&(cached, _)| cached == i) {
+            Some(found) => {
+                let entry = self.cache.remove(found);
+                self.cache.push(entry);
+            }
+            None => {
+                let block = self.blocks[i];
+                self.inner.seek(SeekFrom::Start(block.offset))?;
+                // The index was checked to fit in the file, so this is no larger than it
+                let mut encoded = vec![0; block.len as usize];
+                self.inner.read_exact(&mut encoded)?;
+                let mut crc = Crc32::new();
+                crc.update(&encoded);
+                if crc.sum() != block.crc {
+                    return Err(invalid_data(&format!("block {} is damaged, its checksum doesn't match the index", i)))
+                }
+                // The index can't be trusted to say how much to set aside, but decoding stops past it
+                let limits = coding::Limits { max_output: Some(block.size), ..Default::default() };
+                let options = coding::DecodeOptions { limits, ..Default::default() };
+                let mut decoded = Vec::new();
+                coding::decode_stream_with(&encoded[..], &mut decoded, options)?;
+                if decoded.len() as u64 != block.size {
+                    return Err(invalid_data(&format!("block {} doesn't decode to as many bytes as the index says", i)))
+                }
+                if self.cache.len() == CACHED_BLOCKS {
+                    self.cache.remove(0);
+                }
+                self.cache.push((i, decoded));
+            }
+        }
+        // The block was just put at the end
+        Ok(&self.cache.last().unwrap().1)
+    }
+}
+
+impl <R: Read + Seek> Read for SeekableDecoder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos >= self.len || buf.is_empty() {
+            return Ok(0)
+        }
+        let pos = self.pos;
+        // The last block starting at or before the position, skipping empty ones
+        let i = self.blocks.partition_point(|block| block.start + block.size <= pos);
+        let start = self.blocks[i].start;
+        let block = self.block(i)?;
+        let from = (pos - start) as usize;
+        let read = buf.len().min(block.len() - from);
+        buf[..read].copy_from_slice(&block[from..from + read]);
+        self.pos += read as u64;
+        Ok(read)
+    }
+}
+
+impl <R: Read + Seek> Seek for SeekableDecoder<R> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let target = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => self.len.checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.pos.checked_add_signed(offset)
+        };
+        self.pos = target.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "can't seek before the start of the data"))?;
+        Ok(self.pos)
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::io::{self, Read, Seek, SeekFrom, Write};
+    use super::{BlockWriter, SeekableDecoder};
+
+    #[test]
+    fn any_part_of_the_data_can_be_read() {
+        let data: Vec<u8> = (0..3500u32).map(|i| (i % 7 * 30 + i / 100 % 3) as u8).collect();
+        let mut writer = BlockWriter::with_block_size(Vec::new(), 1000).unwrap();
+        writer.write_all(&data).unwrap();
+        let file = writer.finish().unwrap();
+        let e = BlockWriter::with_block_size(Vec::new(), 0).err().unwrap();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+        assert!(BlockWriter::with_block_size(Vec::new(), super::MAX_BLOCK_SIZE + 1).is_err());
+
+        let mut reader = SeekableDecoder::new(io::Cursor::new(&file)).unwrap();
+        assert_eq!((reader.len(), reader.block_count()), (3500, 4));
+        let mut all = Vec::new();
+        reader.read_to_end(&mut all).unwrap();
+        assert_eq!(all, data);
+
+        // A range across the end of a block
+        reader.seek(SeekFrom::Start(990)).unwrap();
+        let mut range = [0; 20];
+        reader.read_exact(&mut range).unwrap();
+        assert_eq!(range, data[990..1010]);
+        reader.seek(SeekFrom::End(-5)).unwrap();
+        let mut end = Vec::new();
+        reader.read_to_end(&mut end).unwrap();
+        assert_eq!(end, data[3495..]);
+        assert!(reader.seek(SeekFrom::Current(-4000)).is_err());
+
+        assert!(SeekableDecoder::new(io::Cursor::new(&file[..file.len() - 1])).is_err());
+
+        // Damage to the second block is only noticed when reading it, and says which it is
+        let mut damaged = file.clone();
+        let second = SeekableDecoder::new(io::Cursor::new(&file)).unwrap().blocks[1].offset as usize;
+        damaged[second + 20] ^= 1;
+        let mut reader = SeekableDecoder::new(io::Cursor::new(&damaged)).unwrap();
+        reader.read_exact(&mut range).unwrap();
+        reader.seek(SeekFrom::Start(1500)).unwrap();
+        let e = reader.read(&mut range).unwrap_err();
+        assert_eq!(e.to_string(), "block 1 is damaged, its checksum doesn't match the index");
+
+        // An index saying a block decodes to more than it does, or than any block could
+        let entry = |i: usize| file.len() - 16 - (4 - i) * 20;
+        let mut lying = file.clone();
+        lying[entry(0) + 8..entry(0) + 16].copy_from_slice(&999u64.to_be_bytes());
+        let mut reader = SeekableDecoder::new(io::Cursor::new(&lying)).unwrap();
+        assert_eq!(reader.read(&mut range).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        lying[entry(0) + 8..entry(0) + 16].copy_from_slice(&(1u64 << 62).to_be_bytes());
+        assert!(SeekableDecoder::new(io::Cursor::new(&lying)).is_err());
+    }
+}
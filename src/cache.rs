@@ -0,0 +1,134 @@
+//! This module keeps the frequencies files were encoded with in a cache directory,
+//! keyed by their extension, so that encoding more files of the same kind can reuse
+//! them, instead of counting their bytes first.
+use std::env;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use crate::coding::Frequencies;
+use crate::header::Method;
+
+
+/// How long a model gets reused, before the bytes of a file are counted again to refresh it
+pub const MAX_AGE: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+// The methods models are kept for, since the others don't use frequencies
+const METHODS: [Method; 2] = [Method::Huffman, Method::RleHuffman];
+
+
+/// The kind of file a path holds, as far as the cache goes, which is its extension
+pub fn key(path: &str) -> String {
+    match Path::new(path).extension() {
+        Some(extension) => format!("ext-{}", extension.to_string_lossy().to_lowercase()),
+        None => "no-ext".to_string()
+    }
+}
+
+// Every byte needs to be in a model for any file to be coded with it, so the missing ones
+// get the smallest count, and the others get scaled up to stay ahead of them
+fn smoothed(freqs: &Frequencies) -> Frequencies {
+    let mut counts = [1; 256];
+    for &(count, byte) in freqs.pairs() {
+        counts[byte as usize] += count as u64 * 255;
+    }
+    Frequencies::from_counts(&counts)
+}
+
+
+/// A directory of models, with one for each kind of file and method
+pub struct ModelCache {
+    dir: PathBuf,
+    max_age: Duration
+}
+
+impl ModelCache {
+    pub fn new(dir: PathBuf, max_age: Duration) -> Self {
+        ModelCache { dir, max_age }
+    }
+
+    /// Use the cache in `~/.cache/huffman/models`, following the XDG conventions
+    pub fn open() -> io::Result<Self> {
+        let dir = match env::var_os("XDG_CACHE_HOME") {
+            Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+            _ => {
+                let home = env::var_os("HOME").ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "HOME isn't set"))?;
+                PathBuf::from(home).join(".cache")
+            }
+        };
+        Ok(ModelCache::new(dir.join("huffman").join("models"), MAX_AGE))
+    }
+
+    fn path(&self, key: &str, method: Method) -> PathBuf {
+        self.dir.join(format!("{}.{}.json", key, method))
+    }
+
+    /// The method and frequencies of the newest model for a kind of file, unless they're all
+    /// too old, where only the model of the given method is looked at, if there is one
+    pub fn get(&self, key: &str, method: Option<Method>) -> Option<(Method, Frequencies)> {
+        let now = SystemTime::now();
+        let mut newest: Option<(Method, SystemTime, Frequencies)> = None;
+        for &candidate in METHODS.iter().filter(|&&candidate| method.is_none_or(|method| method == candidate)) {
+            let path = self.path(key, candidate);
+            let modified = match fs::metadata(&path).and_then(|metadata| metadata.modified()) {
+                Ok(modified) => modified,
+                Err(_) => continue
+            };
+            // A clock that went backwards makes a model look new, which does no harm
+            let age = now.duration_since(modified).unwrap_or_default();
+            if age > self.max_age || newest.as_ref().is_some_and(|&(_, newer, _)| newer >= modified) {
+                continue;
+            }
+            // A model that can't be read is as good as missing, since it gets written again
+            if let Ok(freqs) = fs::read_to_string(&path).and_then(|json| Frequencies::from_json(&json)) {
+                newest = Some((candidate, modified, freqs));
+            }
+        }
+        newest.map(|(method, _, freqs)| (method, freqs))
+    }
+
+    /// Save the frequencies a file of some kind was encoded with, adding the bytes
+    /// it didn't have, so that other files can be coded with them
+    pub fn put(&self, key: &str, method: Method, freqs: &Frequencies) -> io::Result<()> {
+        fs::create_dir_all(&self.dir)?;
+        let path = self.path(key, method);
+        // Renaming a finished file into place keeps others from reading half of it
+        let partial = path.with_extension(format!("json.{}", std::process::id()));
+        fs::write(&partial, smoothed(freqs).to_json())?;
+        fs::rename(&partial, &path)
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::env;
+    use std::fs;
+    use std::time::SystemTime;
+    use crate::coding::Frequencies;
+    use crate::header::Method;
+    use super::{key, ModelCache, MAX_AGE};
+
+    #[test]
+    fn models_are_reused_until_they_get_old() {
+        assert_eq!(key("logs/app.LOG"), "ext-log");
+        assert_eq!(key("Makefile"), "no-ext");
+        let dir = env::temp_dir().join(format!("huffman-cache-{}", std::process::id()));
+        let cache = ModelCache::new(dir.clone(), MAX_AGE);
+        assert!(cache.get("ext-txt", None).is_none());
+
+        let freqs = Frequencies::count_reader(&mut &b"aaab"[..]).unwrap();
+        cache.put("ext-txt", Method::RleHuffman, &freqs).unwrap();
+        let (method, model) = cache.get("ext-txt", None).unwrap();
+        assert_eq!(method, Method::RleHuffman);
+        // Every byte can be coded, with the ones the file had coming first
+        assert_eq!(model.pairs().len(), 256);
+        assert_eq!(model.pairs()[..2], freqs.pairs()[..]);
+        assert!(cache.get("ext-txt", Some(Method::Huffman)).is_none());
+
+        let file = fs::File::options().write(true).open(dir.join("ext-txt.rle.json")).unwrap();
+        file.set_modified(SystemTime::now() - 2 * MAX_AGE).unwrap();
+        assert!(cache.get("ext-txt", None).is_none());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,91 @@
+//! This module contains the CRC-32 checksum, used to check that data
+//! survived being encoded and decoded intact.
+use std::io;
+
+
+const fn make_table() -> [u32; 256] {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+const TABLE: [u32; 256] = make_table();
+
+
+/// Calculates the CRC-32 of the bytes written to it, along with how many there were
+#[derive(Clone, Debug)]
+pub struct Crc32 {
+    state: u32,
+    len: u64
+}
+
+impl Crc32 {
+    pub fn new() -> Self {
+        Crc32 { state: !0, len: 0 }
+    }
+
+    /// Add more bytes to the checksum
+    pub fn update(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.state = TABLE[((self.state ^ u32::from(byte)) & 0xFF) as usize] ^ (self.state >> 8);
+        }
+        self.len += bytes.len() as u64;
+    }
+
+    /// The checksum of all the bytes so far
+    pub fn sum(&self) -> u32 {
+        !self.state
+    }
+
+    /// How many bytes have been added so far
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    /// Whether or not no bytes have been added so far
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+impl Default for Crc32 {
+    fn default() -> Self {
+        Crc32::new()
+    }
+}
+
+impl io::Write for Crc32 {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.update(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::Crc32;
+
+    #[test]
+    fn crc32_matches_known_value() {
+        let mut crc = Crc32::new();
+        crc.update(b"1234");
+        crc.update(b"56789");
+        assert_eq!(crc.sum(), 0xCBF4_3926);
+        assert_eq!(crc.len(), 9);
+    }
+}
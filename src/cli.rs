@@ -1,78 +1,2685 @@
-use std::fs::File;
+use std::collections::HashMap;
+use std::env;
+use std::error;
+use std::ffi::OsString;
+use std::fmt;
+use std::fs::{self, File};
 use std::io;
-use std::io::{Read, Seek};
+use std::io::{BufRead, IsTerminal, Read, Seek, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, Once};
+use std::sync::mpsc;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
 use crate::structopt::StructOpt;
+use crate::armor;
+use crate::blocks;
+use crate::cache;
+use crate::codegen;
 use crate::coding;
+use crate::config::Config;
+use crate::context::{Context, Named};
+use crate::daemon;
+use crate::header::{self, Header, Method};
+#[cfg(feature = "http")]
+use crate::http;
+use crate::inspect;
+use crate::man;
+#[cfg(unix)]
+use crate::mapped;
+use crate::pipeline;
+use crate::watch;
 
 
+/// The extension files encoded without giving an output file get
+pub const EXTENSION: &str = ".huf";
+
+
+#[derive(Clone, Debug, StructOpt)]
+pub struct EncodeOpt {
+    /// The input file to encode
+    input: String,
+    #[structopt(raw(conflicts_with = r#""output""#))]
+    /// More files to encode the same way, in parallel
+    more: Vec<String>,
+    #[structopt(short = "o")]
+    /// The output file to put the encoded data into, instead of the input file with the suffix added
+    output: Option<String>,
+    #[structopt(long = "suffix")]
+    /// The extension added to the input file to name the output, instead of .huf
+    suffix: Option<String>,
+    #[structopt(short = "f", long = "force")]
+    /// Overwrite the output file if it exists, without asking
+    force: bool,
+    #[structopt(short = "n", long = "no-clobber", raw(conflicts_with = r#""force""#))]
+    /// Skip the file if its output exists, instead of asking
+    no_clobber: bool,
+    #[structopt(long = "backup", raw(conflicts_with = r#""no_clobber""#, require_equals = "true"))]
+    /// Keep the output file being overwritten as NAME~ (simple), NAME.~N~ (numbered),
+    /// or numbered if it already has numbered backups (existing), which is the default,
+    /// unless VERSION_CONTROL says otherwise
+    backup: Option<Option<Backup>>,
+    #[structopt(long = "fsync")]
+    /// Make sure the output, and its entry in its directory, are on disk before finishing,
+    /// for media where writes that are only buffered can get lost
+    fsync: bool,
+    #[structopt(long = "ignore-errors")]
+    /// Go on with the other files when one of them fails, saying how many did at the end
+    ignore_errors: bool,
+    #[structopt(short = "j", long = "jobs")]
+    /// How many of the files to work on at once, instead of one per core,
+    /// or one with --progress, --stats, --timings or --limit-rate
+    jobs: Option<usize>,
+    #[structopt(long = "reproducible", raw(conflicts_with = r#""cache_models""#))]
+    /// Make the output depend only on the input and the options given on the command line,
+    /// ignoring the method and armor set in the configuration file or the environment
+    reproducible: bool,
+    #[structopt(short = "m", long = "method")]
+    /// Force a compression method (stored, huffman, rle, words, utf8, tokens, ngrams or rice),
+    /// instead of picking the smallest of stored, huffman and rle
+    method: Option<Method>,
+    #[structopt(long = "ngram-size")]
+    /// How many bytes the symbols of the ngrams method have, instead of 2
+    ngram_size: Option<usize>,
+    #[structopt(long = "stride")]
+    /// Split the input into planes, one for each byte of records this many bytes long,
+    /// like 4 for 32 bit samples, before compressing it
+    stride: Option<u8>,
+    #[structopt(long = "coder", default_value = "huffman")]
+    /// Build the tree of the huffman and rle methods with this algorithm (huffman, shannon-fano or hu-tucker),
+    /// where the others are never smaller than Huffman trees, but are there to compare against
+    coder: coding::Coder,
+    #[structopt(long = "armor")]
+    /// Wrap the output in base64 text, so that it can be pasted into emails and such
+    armor: bool,
+    #[structopt(long = "no-armor", raw(conflicts_with = r#""armor""#))]
+    /// Turn off --armor, when the configuration file or the environment turns it on
+    no_armor: bool,
+    #[structopt(long = "verify")]
+    /// Decode the output after writing it, and delete it if that doesn't give back the input
+    verify: bool,
+    #[structopt(long = "no-verify", raw(conflicts_with = r#""verify""#))]
+    /// Turn off --verify, when the configuration file or the environment turns it on
+    no_verify: bool,
+    #[structopt(long = "dump-freqs")]
+    /// Write the frequencies the input was encoded with to this file, as JSON
+    dump_freqs: Option<String>,
+    #[structopt(long = "load-freqs", raw(alias = r#""freq-file""#))]
+    /// Encode with the frequencies in this JSON file, instead of counting them,
+    /// which get written to the output, unless it's raw
+    load_freqs: Option<String>,
+    #[structopt(long = "dict", raw(conflicts_with_all = r#"&["load_freqs", "load_tree", "raw", "cache_models", "single_read"]"#))]
+    /// Encode with the frequencies in this JSON file as a preset dictionary, leaving them out of the output,
+    /// which only records their ID, so that decoding it needs `--dict` with the same file
+    dict: Option<String>,
+    #[structopt(long = "dump-tree")]
+    /// Write the tree the huffman and rle methods coded the input with to this file, as JSON
+    dump_tree: Option<String>,
+    #[structopt(long = "load-tree")]
+    /// Encode with the tree in this JSON file, instead of building one, which gets written to the output
+    load_tree: Option<String>,
+    #[structopt(long = "raw")]
+    /// Only write the coded data, without a header or frequencies, which need to come from --load-freqs
+    raw: bool,
+    #[structopt(long = "cache-models")]
+    /// Encode with the frequencies of the last file with the same extension, kept in ~/.cache/huffman/models,
+    /// instead of counting them, and keep the frequencies of this one there when none are, or they're a week old
+    cache_models: bool,
+    #[structopt(long = "level", raw(conflicts_with_all = r#"&["fast", "best"]"#))]
+    /// How hard to try, from 1, the fastest, to 9, the smallest, which can also be given as -1 to -9.
+    /// Up to 5, the model is built from the start of the input, from 64K to 16M of it, which is only read once,
+    /// 6 counts all of it, and 7 to 9 also try n-grams, and then words and UTF-8 characters [default: 6]
+    level: Option<coding::Level>,
+    #[structopt(long = "fast", raw(conflicts_with = r#""best""#))]
+    /// The same as --level 1
+    fast: bool,
+    #[structopt(long = "best")]
+    /// The same as --level 9
+    best: bool,
+    #[structopt(long = "record-size")]
+    /// Record the size of the input in the header, so that `decode --mmap` can set aside room for it up front
+    record_size: bool,
+    #[structopt(long = "single-read")]
+    /// Build the model from the first MiB of the input, and encode it while reading it once,
+    /// only reading it again if the output comes out more than about 3% larger than it needs to be
+    single_read: bool,
+    #[structopt(long = "pipeline")]
+    /// Read the input and write the output on their own threads, while encoding on this one
+    pipeline: bool,
+    #[structopt(long = "no-pipeline", raw(conflicts_with = r#""pipeline""#))]
+    /// Turn off --pipeline, when the configuration file or the environment turns it on
+    no_pipeline: bool,
+    #[structopt(long = "timings")]
+    /// Show how long each phase of encoding took
+    timings: bool,
+    #[structopt(long = "no-timings", raw(conflicts_with = r#""timings""#))]
+    /// Turn off --timings, when the configuration file or the environment turns it on
+    no_timings: bool,
+    #[structopt(long = "trace")]
+    /// Describe each of the first N bytes the huffman and rle methods code on stderr,
+    /// with its code, and the bit of the coded data it starts at
+    trace: Option<u64>,
+    #[structopt(long = "progress")]
+    /// Report progress on stderr in some format, where only json is supported,
+    /// writing lines like {"bytes_in": 1024, "bytes_out": 600, "ratio": 0.586, "eta": 1.5}
+    progress: Option<ProgressFormat>,
+    #[structopt(long = "stats")]
+    /// Show the ratio and speed so far on stderr, updated as the file gets read,
+    /// so that a file that isn't compressing can be stopped early
+    stats: bool,
+    #[structopt(long = "limit-rate")]
+    /// Read and write at most this many bytes per second between both files, like 512K, 10M or 1G
+    limit_rate: Option<Rate>
+}
+
+impl EncodeOpt {
+    fn new(input: &str, output: String) -> Self {
+        EncodeOpt {
+            input: input.to_string(),
+            more: Vec::new(),
+            output: Some(output),
+            suffix: None,
+            force: false,
+            no_clobber: false,
+            backup: None,
+            fsync: false,
+            ignore_errors: false,
+            jobs: None,
+            reproducible: false,
+            method: None,
+            ngram_size: None,
+            stride: None,
+            coder: coding::Coder::Huffman,
+            armor: false,
+            no_armor: false,
+            verify: false,
+            no_verify: false,
+            dump_freqs: None,
+            load_freqs: None,
+            dict: None,
+            dump_tree: None,
+            load_tree: None,
+            raw: false,
+            cache_models: false,
+            level: None,
+            fast: false,
+            best: false,
+            record_size: false,
+            single_read: false,
+            pipeline: false,
+            no_pipeline: false,
+            timings: false,
+            no_timings: false,
+            trace: None,
+            progress: None,
+            stats: false,
+            limit_rate: None
+        }
+    }
+}
+
+#[derive(Clone, Debug, StructOpt)]
+pub struct DecodeOpt {
+    /// The input file to decode
+    input: String,
+    #[structopt(raw(conflicts_with = r#""output""#))]
+    /// More files to decode the same way, in parallel
+    more: Vec<String>,
+    #[structopt(short = "o")]
+    /// The output file to put the decoded text into, instead of the input file with the suffix removed
+    output: Option<String>,
+    #[structopt(long = "suffix")]
+    /// The extension removed from the input file to name the output, instead of .huf
+    suffix: Option<String>,
+    #[structopt(short = "f", long = "force")]
+    /// Overwrite the output file if it exists, without asking
+    force: bool,
+    #[structopt(short = "n", long = "no-clobber", raw(conflicts_with = r#""force""#))]
+    /// Skip the file if its output exists, instead of asking
+    no_clobber: bool,
+    #[structopt(long = "backup", raw(conflicts_with = r#""no_clobber""#, require_equals = "true"))]
+    /// Keep the output file being overwritten as NAME~ (simple), NAME.~N~ (numbered),
+    /// or numbered if it already has numbered backups (existing), which is the default,
+    /// unless VERSION_CONTROL says otherwise
+    backup: Option<Option<Backup>>,
+    #[structopt(long = "fsync")]
+    /// Make sure the output, and its entry in its directory, are on disk before finishing,
+    /// for media where writes that are only buffered can get lost
+    fsync: bool,
+    #[structopt(long = "ignore-errors")]
+    /// Go on with the other files when one of them fails, saying how many did at the end
+    ignore_errors: bool,
+    #[structopt(short = "j", long = "jobs")]
+    /// How many of the files to work on at once, instead of one per core,
+    /// or one with --progress, --stats, --timings or --limit-rate
+    jobs: Option<usize>,
+    #[structopt(long = "offset", default_value = "0")]
+    /// Skip this many decoded bytes before writing any out
+    offset: u64,
+    #[structopt(long = "length")]
+    /// Stop after decoding this many bytes
+    length: Option<u64>,
+    #[structopt(long = "raw")]
+    /// Decode data written by `encode --raw`, using the frequencies from --load-freqs
+    raw: bool,
+    #[structopt(long = "recover")]
+    /// Keep what could be decoded from data that's cut short or corrupt, instead of deleting the output
+    recover: bool,
+    #[structopt(long = "keep-broken")]
+    /// Keep the output of a decode that fails, holding whatever was decoded before that, instead of deleting it
+    keep_broken: bool,
+    #[structopt(long = "load-freqs")]
+    /// The JSON file with the frequencies raw data was encoded with
+    load_freqs: Option<String>,
+    #[structopt(long = "dict", raw(conflicts_with = r#""raw""#))]
+    /// The JSON file with the frequencies data encoded with `--dict` was coded with
+    dict: Option<String>,
+    #[structopt(short = "m", long = "method")]
+    /// The method raw data was encoded with (huffman or rle)
+    method: Option<Method>,
+    #[structopt(long = "pipeline")]
+    /// Read the input and write the output on their own threads, while decoding on this one
+    pipeline: bool,
+    #[structopt(long = "no-pipeline", raw(conflicts_with = r#""pipeline""#))]
+    /// Turn off --pipeline, when the configuration file or the environment turns it on
+    no_pipeline: bool,
+    #[structopt(long = "max-output-size")]
+    /// Fail once the data decodes to more than this many bytes, like 512K, 10M or 1G,
+    /// for files that can't be trusted not to decode to far more than they take
+    max_output_size: Option<Size>,
+    #[structopt(long = "mmap")]
+    /// Set aside room for the whole output, at the size the header records, and decode straight
+    /// into a mapping of it, instead of writing it out a buffer at a time. This only works with local
+    /// files recording their size, of up to 1G, unless --max-output-size allows more
+    mmap: bool,
+    #[structopt(long = "timings")]
+    /// Show how long each phase of decoding took
+    timings: bool,
+    #[structopt(long = "no-timings", raw(conflicts_with = r#""timings""#))]
+    /// Turn off --timings, when the configuration file or the environment turns it on
+    no_timings: bool,
+    #[structopt(long = "trace")]
+    /// Describe each of the first N bytes decoded with the huffman and rle methods on stderr,
+    /// with its code, and the bit of the coded data it starts at
+    trace: Option<u64>,
+    #[structopt(long = "progress")]
+    /// Report progress on stderr in some format, where only json is supported,
+    /// writing lines like {"bytes_in": 1024, "bytes_out": 600, "ratio": 0.586, "eta": 1.5}
+    progress: Option<ProgressFormat>,
+    #[structopt(long = "stats")]
+    /// Show the ratio and speed so far on stderr, updated as the file gets read,
+    /// so that a file that isn't compressing can be stopped early
+    stats: bool,
+    #[structopt(long = "limit-rate")]
+    /// Read and write at most this many bytes per second between both files, like 512K, 10M or 1G
+    limit_rate: Option<Rate>
+}
+
+const ENVIRONMENT: &str = "ENVIRONMENT:
+    HUFFMAN_CONFIG      The configuration file to use instead of ~/.config/huffman/config.toml
+    HUFFMAN_METHOD      The method to encode with
+    HUFFMAN_ARMOR       Set to 1 or 0 to turn --armor on or off
+    HUFFMAN_VERIFY      Set to 1 or 0 to turn --verify on or off
+    HUFFMAN_PIPELINE    Set to 1 or 0 to turn --pipeline on or off
+    HUFFMAN_TIMINGS     Set to 1 or 0 to turn --timings on or off
+    HUFFMAN_JOBS        How many files are worked on at once, also read from HUFFMAN_THREADS
+    HUFFMAN_SUFFIX      The extension added to encoded files, when their output isn't given
+
+These take precedence over the configuration file, but not over the command line.";
+
 #[derive(Debug, StructOpt)]
-#[structopt(name = "huffman")]
+#[structopt(
+    name = "huffman",
+    raw(usage = r#""huffman [--config <PATH>] <SUBCOMMAND>\n    huffman [--config <PATH>] <FILE>""#),
+    raw(after_help = "ENVIRONMENT")
+)]
+/// Encodes or decodes FILE when given without a subcommand,
+/// depending on whether or not it's an encoded file.
+/// Defaults for options are read from ~/.config/huffman/config.toml, or the file given with --config.
 pub enum Opt {
     #[structopt(name = "encode")]
     /// Encode a file
-    Encode {
-        /// The input file to encode
+    Encode(EncodeOpt),
+    #[structopt(name = "decode")]
+    /// Decode a file
+    Decode(DecodeOpt),
+    #[structopt(name = "test")]
+    /// Check that encoded files decode, without writing anything out
+    Test {
+        #[structopt(short = "r", long = "recursive")]
+        /// Test every encoded file under the directories given, and those under them
+        recursive: bool,
+        #[structopt(long = "suffix")]
+        /// The extension of the encoded files to look for with -r, instead of .huf
+        suffix: Option<String>,
+        #[structopt(short = "j", long = "jobs")]
+        /// How many files to test at once, instead of one per core
+        jobs: Option<usize>,
+        #[structopt(raw(required = "true"))]
+        /// The encoded files to test, or the directories to look for them in with -r
+        inputs: Vec<String>
+    },
+    #[structopt(name = "inspect")]
+    /// Describe the header and data of an encoded file
+    Inspect {
+        /// The encoded file to inspect
+        input: String,
+        #[structopt(long = "dump", default_value = "0")]
+        /// How many symbols at the start of the data to show the codes of
+        dump: usize
+    },
+    #[structopt(name = "migrate")]
+    /// Upgrade a file encoded by an older version of this program to the current format
+    Migrate {
+        /// The encoded file to upgrade
         input: String,
         #[structopt(short = "o")]
-        /// The output file to put the decoded text into
-        output: String
+        /// The output file to put the upgraded file into
+        output: String,
+        #[structopt(short = "f", long = "force")]
+        /// Overwrite the output file if it exists, without asking
+        force: bool
     },
-    #[structopt(name = "decode")]
-    /// Decode a file
-    Decode {
-        /// The input file to decode
+    #[structopt(name = "explain")]
+    /// Show how much each byte of a file would save by Huffman coding it
+    Explain {
+        /// The file to explain
+        input: String
+    },
+    #[structopt(name = "freq")]
+    /// Count the bytes in a file, and write out their frequencies as JSON
+    Freq {
+        /// The file to count the bytes of
         input: String,
         #[structopt(short = "o")]
-        /// The output file to put the decoded text into
+        /// The output file to put the frequencies into, for use with `encode --load-freqs`
         output: String
+    },
+    #[structopt(name = "tune")]
+    /// Try every compression method on a file, and recommend the best one
+    Tune {
+        /// The file to try compressing
+        input: String,
+        #[structopt(long = "sample")]
+        /// Only try compressing this many bytes from the start of the file
+        sample: Option<u64>
+    },
+    #[structopt(name = "compare")]
+    /// Compress a file with this tool, and with gzip and zstd, comparing their ratios and speeds
+    Compare {
+        /// The file to compress
+        input: String
+    },
+    #[structopt(name = "selftest")]
+    /// Encode and decode generated data with every method, checking that it comes back intact
+    Selftest,
+    #[structopt(name = "codegen")]
+    /// Generate source code with static tables for encoding and decoding with a model
+    Codegen {
+        /// A file encoded with the huffman method, or a JSON file of frequencies,
+        /// like `freq` and --dump-freqs write, whose frequencies are used as the model
+        model: String,
+        #[structopt(long = "lang", default_value = "rust")]
+        /// The language to generate code for (rust or c)
+        lang: codegen::Lang,
+        #[structopt(short = "o")]
+        /// The output file to put the code into
+        output: String,
+        #[structopt(short = "f", long = "force")]
+        /// Overwrite the output file if it exists, without asking
+        force: bool
+    },
+    #[structopt(name = "watch")]
+    /// Watch a directory, encoding files that show up or change in it once they stop changing
+    Watch {
+        /// The directory to watch
+        dir: String,
+        #[structopt(long = "pattern", default_value = "*")]
+        /// Only encode files whose names match this pattern, where * matches anything and ? any character
+        pattern: String,
+        #[structopt(long = "debounce", default_value = "1000")]
+        /// How many milliseconds a file needs to go unchanged before it gets encoded
+        debounce: u64,
+        #[structopt(long = "delete-source")]
+        /// Delete files once they've been encoded
+        delete_source: bool,
+        #[structopt(long = "suffix")]
+        /// The extension added to encoded files, instead of .huf
+        suffix: Option<String>,
+        #[structopt(short = "m", long = "method")]
+        /// Force a compression method, like encode does
+        method: Option<Method>,
+        #[structopt(long = "armor")]
+        /// Wrap the outputs in base64 text
+        armor: bool,
+        #[structopt(long = "no-armor", raw(conflicts_with = r#""armor""#))]
+        /// Turn off --armor, when the configuration file or the environment turns it on
+        no_armor: bool,
+        #[structopt(short = "j", long = "jobs")]
+        /// How many files to encode at once, instead of one per core
+        jobs: Option<usize>
+    },
+    #[structopt(name = "man", raw(setting = "structopt::clap::AppSettings::Hidden"))]
+    /// Write out a man page for this program, in roff, for packagers
+    Man,
+    #[structopt(name = "daemon")]
+    /// Answer encoding and decoding requests over a Unix socket, keeping models in memory
+    Daemon {
+        #[structopt(long = "socket")]
+        /// The path of the socket to listen on
+        socket: String,
+        #[structopt(long = "model")]
+        /// A model requests can use, given as NAME=PATH, where PATH is a JSON file of frequencies
+        model: Vec<String>
     }
 }
 
 impl Opt {
+    /// Parse the command line arguments, where a lone path without a subcommand
+    /// gets decoded if it's an encoded file, and encoded otherwise
+    pub fn from_args_or_path() -> io::Result<Self> {
+        let mut args: Vec<OsString> = env::args_os().collect();
+        // The configuration file applies to every subcommand, so it comes before them
+        let config_path = if args.get(1).is_some_and(|arg| arg == "--config") {
+            if args.len() < 3 {
+                return Err(invalid_input("--config needs the path of a configuration file"))
+            }
+            let path = args.remove(2);
+            args.remove(1);
+            Some(PathBuf::from(path))
+        } else {
+            None
+        };
+        let mut config = Config::load(config_path)?;
+        config.apply_env()?;
+        expand_levels(&mut args);
+        #[cfg(windows)]
+        expand_wildcards(&mut args);
+        let mut opt = match Opt::from_iter_safe(&args) {
+            Ok(opt) => opt,
+            Err(e) => match args.get(1).and_then(|arg| arg.to_str()) {
+                Some(path) if args.len() == 2 && Path::new(path).is_file() => Opt::for_path(path, &config)?,
+                _ => e.exit()
+            }
+        };
+        opt.apply_config(&config);
+        Ok(opt)
+    }
+
+    // Fill in the options that weren't given with the defaults from the configuration
+    fn apply_config(&mut self, config: &Config) {
+        match self {
+            Opt::Encode(opt) => {
+                // The suffix only changes where the output goes, and the rest how it gets there
+                if !opt.reproducible {
+                    opt.method = opt.method.or(config.method);
+                    opt.armor |= config.armor && !opt.no_armor;
+                }
+                opt.suffix = opt.suffix.take().or_else(|| config.suffix.clone());
+                opt.jobs = opt.jobs.or(config.jobs);
+                opt.verify |= config.verify && !opt.no_verify;
+                opt.pipeline |= config.pipeline && !opt.no_pipeline;
+                opt.timings |= config.timings && !opt.no_timings;
+            }
+            Opt::Decode(opt) => {
+                opt.suffix = opt.suffix.take().or_else(|| config.suffix.clone());
+                opt.jobs = opt.jobs.or(config.jobs);
+                opt.pipeline |= config.pipeline && !opt.no_pipeline;
+                opt.timings |= config.timings && !opt.no_timings;
+            }
+            Opt::Test { suffix, jobs, .. } => {
+                *suffix = suffix.take().or_else(|| config.suffix.clone());
+                *jobs = jobs.or(config.jobs);
+            }
+            Opt::Watch { suffix, method, armor, no_armor, jobs, .. } => {
+                *suffix = suffix.take().or_else(|| config.suffix.clone());
+                *method = method.or(config.method);
+                *armor |= config.armor && !*no_armor;
+                *jobs = jobs.or(config.jobs);
+            }
+            _ => {}
+        }
+    }
+
+    // Decode or encode a file, depending on its magic bytes, putting the result
+    // next to it, with the extension removed or added
+    fn for_path(path: &str, config: &Config) -> io::Result<Self> {
+        let mut input_file = io::BufReader::new(open(path)?);
+        let start = input_file.fill_buf()?;
+        let encoded = start.starts_with(&header::MAGIC) || armor::is_armored(start);
+        let suffix = config.suffix.as_deref().unwrap_or(EXTENSION);
+        if !encoded && path.strip_suffix(suffix).is_some_and(|stem| !stem.is_empty()) {
+            let msg = format!("`{}` has the {} extension, but isn't an encoded file", path, suffix);
+            return Err(io::Error::new(io::ErrorKind::InvalidData, msg))
+        }
+        let output = output_name(path, suffix, encoded);
+        if fs::metadata(&output).is_ok() {
+            let msg = format!("`{}` already exists", output);
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists, msg))
+        }
+        eprintln!("{} `{}` into `{}`", if encoded { "decoding" } else { "encoding" }, path, output);
+        Ok(if encoded { Opt::decode_to(path, output) } else { Opt::encode_to(path, output) })
+    }
+
+    fn encode_to(input: &str, output: String) -> Self {
+        Opt::Encode(EncodeOpt::new(input, output))
+    }
+
+    fn decode_to(input: &str, output: String) -> Self {
+        Opt::Decode(DecodeOpt {
+            input: input.to_string(),
+            more: Vec::new(),
+            output: Some(output),
+            suffix: None,
+            force: false,
+            no_clobber: false,
+            backup: None,
+            fsync: false,
+            ignore_errors: false,
+            jobs: None,
+            offset: 0,
+            length: None,
+            raw: false,
+            recover: false,
+            keep_broken: false,
+            load_freqs: None,
+            dict: None,
+            method: None,
+            pipeline: false,
+            no_pipeline: false,
+            max_output_size: None,
+            mmap: false,
+            timings: false,
+            no_timings: false,
+            trace: None,
+            progress: None,
+            stats: false,
+            limit_rate: None
+        })
+    }
+
     /// Handle all the cases of the options, and run the corresponding
     /// sub programs.
     pub fn dispatch(self) -> io::Result<()> {
         match self {
-            Opt::Decode { input, output } => decode(input, output),
-            Opt::Encode { input, output } => encode(input, output)
+            Opt::Decode(opt) => {
+                let run = |input| decode(DecodeOpt { input, ..opt.clone() });
+                let reporting = opt.progress.is_some() || opt.stats || opt.timings || opt.limit_rate.is_some();
+                let jobs = if reporting { 1 } else { job_count(opt.jobs)? };
+                for_each_input(&opt.input, &opt.more, opt.ignore_errors, jobs, "skipped or only partly decoded", run)
+            }
+            Opt::Encode(opt) => {
+                // Every file would write over what the one before it dumped
+                if !opt.more.is_empty() && (opt.dump_freqs.is_some() || opt.dump_tree.is_some()) {
+                    return Err(invalid_input("--dump-freqs and --dump-tree only go with a single input"))
+                }
+                let run = |input| encode(EncodeOpt { input, ..opt.clone() });
+                let reporting = opt.progress.is_some() || opt.stats || opt.timings || opt.limit_rate.is_some();
+                let jobs = if reporting { 1 } else { job_count(opt.jobs)? };
+                for_each_input(&opt.input, &opt.more, opt.ignore_errors, jobs, "skipped", run)
+            }
+            Opt::Test { recursive, suffix, jobs, inputs } => {
+                let suffix = suffix.unwrap_or_else(|| EXTENSION.to_string());
+                test_files(inputs, recursive, &suffix, job_count(jobs)?)
+            }
+            Opt::Inspect { input, dump } => inspect_file(input, dump),
+            Opt::Migrate { input, output, force } => migrate(input, output, force),
+            Opt::Explain { input } => {
+                let mut input_file = open(&input)?;
+                inspect::explain(&mut input_file, &mut io::stdout().lock())
+            }
+            Opt::Freq { input, output } => count_freqs(input, output),
+            Opt::Tune { input, sample } => tune(input, sample),
+            Opt::Compare { input } => compare(input),
+            Opt::Selftest => selftest(),
+            Opt::Codegen { model, lang, output, force } => generate_code(model, lang, output, force),
+            Opt::Watch { dir, pattern, debounce, delete_source, suffix, method, armor, jobs, .. } => {
+                let suffix = suffix.unwrap_or_else(|| EXTENSION.to_string());
+                let mut opt = EncodeOpt::new(&dir, String::new());
+                opt.method = method;
+                opt.armor = armor;
+                watch(dir, pattern, Duration::from_millis(debounce), delete_source, suffix, &opt, job_count(jobs)?)
+            }
+            Opt::Man => man::write(&Opt::clap(), "encode and decode files with Huffman codes", &mut io::stdout().lock()),
+            Opt::Daemon { socket, model } => run_daemon(socket, model)
+        }
+    }
+
+    /// Run the encode or decode command in an argument vector on streams instead of files,
+    /// to embed the tool in another program, or test it without touching the filesystem.
+    /// The arguments leave out the input and output files, like `["huffman", "encode", "--armor"]`.
+    pub fn run_on_streams<I, R, W>(args: I, mut input: R, mut output: W) -> io::Result<Outcome>
+    where
+        I: IntoIterator,
+        I::Item: Into<OsString>,
+        R: Read + Seek,
+        W: Write
+    {
+        // The streams stand in for the files the arguments need
+        let mut args: Vec<OsString> = args.into_iter().map(Into::into).collect();
+        args.extend(vec!["-".into(), "-o".into(), "-".into()]);
+        expand_levels(&mut args);
+        let opt = Opt::from_iter_safe(args).map_err(|e| invalid_input(&e.message))?;
+        match opt {
+            Opt::Encode(opt) => {
+                let with_files = opt.verify || opt.pipeline || opt.timings || opt.cache_models;
+                if with_files || opt.progress.is_some() || opt.stats || opt.limit_rate.is_some() {
+                    let msg = "--verify, --pipeline, --timings, --cache-models, --progress, --stats and --limit-rate only work with files";
+                    return Err(invalid_input(msg))
+                }
+                let (mut options, _) = encode_options(&opt)?;
+                let tracer = opt.trace.map(|limit| coding::Tracer::new(limit, io::stderr()));
+                options.trace = tracer.as_ref();
+                let stats = encode_into(&mut input, &mut output, opt.armor, options)?;
+                dump_freqs(opt.dump_freqs, &stats)?;
+                dump_tree(opt.dump_tree, &stats)?;
+                Ok(Outcome::Encoded(stats))
+            }
+            Opt::Decode(opt) => {
+                if opt.pipeline || opt.timings || opt.recover || opt.progress.is_some() || opt.stats || opt.limit_rate.is_some() {
+                    let msg = "--pipeline, --timings, --recover, --progress, --stats and --limit-rate only work with files";
+                    return Err(invalid_input(msg))
+                }
+                let mut options = decode_options(&opt)?;
+                let tracer = opt.trace.map(|limit| coding::Tracer::new(limit, io::stderr()));
+                options.trace = tracer.as_ref();
+                let stats = decode_into(io::BufReader::new(input), output, opt.offset, opt.length, options)?;
+                Ok(Outcome::Decoded(stats))
+            }
+            _ => Err(invalid_input("only the encode and decode commands can run on streams"))
+        }
+    }
+}
+
+
+// Turn gzip style levels, like -9, into --level 9, since flags can't be digits
+fn expand_levels(args: &mut [OsString]) {
+    for arg in args.iter_mut() {
+        let level = match arg.to_str().map(str::as_bytes) {
+            Some(&[b'-', digit @ b'1'..=b'9']) => digit - b'0',
+            _ => continue
+        };
+        *arg = format!("--level={}", level).into();
+    }
+}
+
+// Expand the wildcards in the files given to encode, decode and test, which the shell does
+// everywhere but on Windows, leaving those that don't match anything as they are
+#[cfg(any(windows, test))]
+fn expand_wildcards(args: &mut Vec<OsString>) {
+    if !matches!(args.get(1).and_then(|arg| arg.to_str()), Some("encode" | "decode" | "test")) {
+        return
+    }
+    let mut expanded = args[..2].to_vec();
+    for arg in args.drain(2..) {
+        let pattern = arg.to_str().filter(|arg| !arg.starts_with('-') && arg.contains(['*', '?']));
+        let matched = pattern.map(matching_files).unwrap_or_default();
+        if matched.is_empty() {
+            expanded.push(arg);
+        } else {
+            expanded.extend(matched);
+        }
+    }
+    *args = expanded;
+}
+
+// The files whose names match a pattern, like logs\*.log, ignoring case like Windows does,
+// where only the name can have wildcards
+#[cfg(any(windows, test))]
+fn matching_files(pattern: &str) -> Vec<OsString> {
+    let path = Path::new(pattern);
+    let name = match path.file_name().and_then(|name| name.to_str()) {
+        Some(name) => name.to_lowercase(),
+        None => return Vec::new()
+    };
+    let dir = path.parent().filter(|parent| !parent.as_os_str().is_empty());
+    let entries = match fs::read_dir(dir.unwrap_or(Path::new("."))) {
+        Ok(entries) => entries,
+        Err(_) => return Vec::new()
+    };
+    let mut matched: Vec<OsString> = entries.filter_map(Result::ok)
+        .filter(|entry| watch::matches(&name, &entry.file_name().to_string_lossy().to_lowercase()))
+        .map(|entry| dir.map_or(entry.file_name(), |dir| dir.join(entry.file_name()).into_os_string()))
+        .collect();
+    matched.sort();
+    matched
+}
+
+
+/// What running a command on streams did
+#[derive(Debug)]
+pub enum Outcome {
+    Encoded(coding::EncodeStats),
+    /// Decoding only has statistics when it didn't stop early because of --length
+    Decoded(Option<coding::DecodeStats>)
+}
+
+
+// Skips over a certain number of bytes, and then writes through at most
+// a certain number of bytes, refusing to write any more
+struct Window<W> {
+    inner: W,
+    skip: u64,
+    remaining: u64
+}
+
+impl <W: io::Write> io::Write for Window<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.skip > 0 {
+            let skipped = buf.len().min(self.skip.min(usize::MAX as u64) as usize);
+            self.skip -= skipped as u64;
+            return Ok(skipped)
+        }
+        let take = buf.len().min(self.remaining.min(usize::MAX as u64) as usize);
+        let written = self.inner.write(&buf[..take])?;
+        self.remaining -= written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+// Keeps track of how long is spent reading from or writing to a file, even on another thread
+struct Timed<T> {
+    inner: T,
+    nanos: Arc<AtomicU64>
+}
+
+impl <T> Timed<T> {
+    fn new(inner: T) -> Self {
+        Timed { inner, nanos: Arc::new(AtomicU64::new(0)) }
+    }
+
+    fn time<F: FnOnce(&mut T) -> R, R>(&mut self, f: F) -> R {
+        let start = Instant::now();
+        let result = f(&mut self.inner);
+        self.nanos.fetch_add(start.elapsed().as_nanos() as u64, Ordering::Relaxed);
+        result
+    }
+}
+
+impl <R: io::Read> io::Read for Timed<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.time(|inner| inner.read(buf))
+    }
+}
+
+impl <S: io::Seek> io::Seek for Timed<S> {
+    fn seek(&mut self, pos: io::SeekFrom) -> io::Result<u64> {
+        self.time(|inner| inner.seek(pos))
+    }
+}
+
+impl <W: io::Write> io::Write for Timed<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.time(|inner| inner.write(buf))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.time(|inner| inner.flush())
+    }
+}
+
+// Print how long each phase took, followed by the time spent reading and writing files,
+// which overlaps with the phases, unless that happened on other threads
+fn print_timings(phases: &[(&str, Duration)], io_nanos: &[Arc<AtomicU64>], total: Duration) {
+    let millis = |time: Duration| time.as_secs_f64() * 1000.0;
+    let io_time: u64 = io_nanos.iter().map(|nanos| nanos.load(Ordering::Relaxed)).sum();
+    eprintln!("{:<20}{:>12}", "phase", "ms");
+    for &(phase, time) in phases {
+        eprintln!("{:<20}{:>12.3}", phase, millis(time));
+    }
+    eprintln!("{:<20}{:>12.3}", "total", millis(total));
+    eprintln!("{:<20}{:>12.3}", "of which I/O", millis(Duration::from_nanos(io_time)));
+}
+
+// Counts the bytes going through a file, keeping track of the position in it,
+// along with the total, which keeps going up when the file is read again
+struct Counted<T> {
+    inner: T,
+    pos: Arc<AtomicU64>,
+    total: Arc<AtomicU64>
+}
+
+impl <T> Counted<T> {
+    fn new(inner: T) -> Self {
+        Counted { inner, pos: Arc::new(AtomicU64::new(0)), total: Arc::new(AtomicU64::new(0)) }
+    }
+
+    fn count(&self, bytes: usize) {
+        self.pos.fetch_add(bytes as u64, Ordering::Relaxed);
+        self.total.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+}
+
+impl <R: io::Read> io::Read for Counted<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.count(read);
+        Ok(read)
+    }
+}
+
+impl <S: io::Seek> io::Seek for Counted<S> {
+    fn seek(&mut self, pos: io::SeekFrom) -> io::Result<u64> {
+        let pos = self.inner.seek(pos)?;
+        self.pos.store(pos, Ordering::Relaxed);
+        Ok(pos)
+    }
+}
+
+impl <W: io::Write> io::Write for Counted<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.count(written);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+
+// Parse a number of bytes, which can have a K, M or G suffix for multiples of 1024
+fn parse_bytes(s: &str) -> Option<u64> {
+    let (digits, multiple) = match s.char_indices().last() {
+        Some((i, 'k')) | Some((i, 'K')) => (&s[..i], 1 << 10),
+        Some((i, 'm')) | Some((i, 'M')) => (&s[..i], 1 << 20),
+        Some((i, 'g')) | Some((i, 'G')) => (&s[..i], 1 << 30),
+        _ => (s, 1)
+    };
+    digits.parse::<u64>().ok().and_then(|n| n.checked_mul(multiple))
+}
+
+/// A number of bytes per second, which can have a K, M or G suffix for multiples of 1024
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Rate(pub u64);
+
+impl FromStr for Rate {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match parse_bytes(s) {
+            Some(rate) if rate > 0 => Ok(Rate(rate)),
+            _ => Err(format!("invalid rate `{}`, expected a number of bytes per second like 512K, 10M or 1G", s))
+        }
+    }
+}
+
+/// A number of bytes, which can have a K, M or G suffix for multiples of 1024
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Size(pub u64);
+
+impl FromStr for Size {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match parse_bytes(s) {
+            Some(size) => Ok(Size(size)),
+            None => Err(format!("invalid size `{}`, expected a number of bytes like 512K, 10M or 1G", s))
+        }
+    }
+}
+
+// Keeps the bytes going through some files under a rate, by sleeping whenever they get ahead of it
+struct Limiter {
+    rate: u64,
+    start: Instant,
+    bytes: u64
+}
+
+impl Limiter {
+    fn new(rate: Rate) -> Arc<Mutex<Self>> {
+        Arc::new(Mutex::new(Limiter { rate: rate.0, start: Instant::now(), bytes: 0 }))
+    }
+}
+
+// Throttles the bytes going through a file, sharing a limit with other files
+struct Throttled<T> {
+    inner: T,
+    limiter: Option<Arc<Mutex<Limiter>>>
+}
+
+impl <T> Throttled<T> {
+    fn new(inner: T, limiter: Option<Arc<Mutex<Limiter>>>) -> Self {
+        Throttled { inner, limiter }
+    }
+
+    fn throttle(&self, bytes: usize) {
+        let ahead = match &self.limiter {
+            Some(limiter) => {
+                let mut limiter = limiter.lock().unwrap_or_else(|e| e.into_inner());
+                limiter.bytes += bytes as u64;
+                let due = Duration::from_secs_f64(limiter.bytes as f64 / limiter.rate as f64);
+                due.checked_sub(limiter.start.elapsed())
+            }
+            None => None
+        };
+        // Sleeping without the lock lets the other file go on until it catches up too
+        if let Some(ahead) = ahead {
+            thread::sleep(ahead);
         }
     }
 }
 
-fn encode(input: String, output: String) -> io::Result<()> {
-    let mut input_file = File::open(input)?;
-    let output_file = File::create(output)?;
-    let mut output_writer = io::BufWriter::new(output_file);
+impl <R: io::Read> io::Read for Throttled<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.throttle(read);
+        Ok(read)
+    }
+}
 
-    let input_copy = input_file.try_clone()?;
-    let freqs = coding::Frequencies::count_bytes(input_copy.bytes())?;
-    freqs.write(&mut output_writer)?;
+impl <S: io::Seek> io::Seek for Throttled<S> {
+    fn seek(&mut self, pos: io::SeekFrom) -> io::Result<u64> {
+        self.inner.seek(pos)
+    }
+}
 
-    let tree = coding::HuffTree::from_freqs(&freqs);
-    let mut encoder = coding::HuffWriter::from_tree(&tree);
-    input_file.seek(io::SeekFrom::Start(0))?;
-    for maybe_byte in input_file.bytes() {
-        let byte = maybe_byte?;
-        encoder.write_byte(byte, &mut output_writer)?;
+impl <W: io::Write> io::Write for Throttled<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.throttle(written);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
     }
-    encoder.end_transmission(&mut output_writer)
 }
 
-fn decode(input: String, output: String) -> io::Result<()> {
-    let mut input_file = File::open(input)?;
-    let output_file = File::create(output)?;
-    let mut output_writer = io::BufWriter::new(output_file);
 
-    let freqs = coding::Frequencies::read(&mut input_file)?;
-    let tree = coding::HuffTree::from_freqs(&freqs);
-    let mut reader = coding::HuffReader::new(&tree);
+/// How the file an output overwrites gets backed up, following the names of GNU tools
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Backup {
+    /// As NAME~
+    Simple,
+    /// As NAME.~N~, with N one more than the last backup
+    Numbered,
+    /// Numbered if the file has numbered backups already, and simple otherwise
+    Existing
+}
 
-    for maybe_byte in input_file.bytes() {
-        let byte = maybe_byte?;
-        let can_feed = reader.feed(byte, &mut output_writer)?;
-        if !can_feed {
-            break;
+impl Backup {
+    // The kind of backup --backup makes without one given, which is taken from VERSION_CONTROL
+    fn from_env() -> io::Result<Self> {
+        match env::var("VERSION_CONTROL") {
+            Ok(value) if !value.is_empty() => value.parse().map_err(|e: String| invalid_input(&format!("VERSION_CONTROL: {}", e))),
+            _ => Ok(Backup::Existing)
         }
     }
+
+    // Where a file gets backed up to
+    fn path(self, path: &Path) -> io::Result<PathBuf> {
+        let name = path.file_name().ok_or_else(|| invalid_input("only files can be backed up"))?.to_string_lossy();
+        let simple = path.with_file_name(format!("{}~", name));
+        if self == Backup::Simple {
+            return Ok(simple)
+        }
+        let prefix = format!("{}.~", name);
+        let dir = match path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new(".")
+        };
+        let mut last = None;
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?.file_name();
+            let n = entry.to_str()
+                .and_then(|entry| entry.strip_prefix(&prefix))
+                .and_then(|rest| rest.strip_suffix('~'))
+                .and_then(|n| n.parse::<u64>().ok());
+            last = last.max(n);
+        }
+        Ok(match (self, last) {
+            (Backup::Existing, None) => simple,
+            (_, last) => path.with_file_name(format!("{}{}~", prefix, last.map_or(1, |n| n + 1)))
+        })
+    }
+}
+
+impl FromStr for Backup {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "simple" | "never" => Ok(Backup::Simple),
+            "numbered" | "t" => Ok(Backup::Numbered),
+            "existing" | "nil" => Ok(Backup::Existing),
+            _ => Err(format!("unknown backup `{}`, expected simple, numbered or existing", s))
+        }
+    }
+}
+
+// Move a file about to be overwritten to where it gets backed up, unless it's not a regular file,
+// like /dev/stdout, or doesn't exist
+fn back_up(path: &str, backup: Option<Backup>) -> io::Result<()> {
+    let backup = match backup {
+        Some(backup) if fs::metadata(path).is_ok_and(|metadata| metadata.is_file()) => backup,
+        _ => return Ok(())
+    };
+    let to = backup.path(Path::new(path))?;
+    fs::rename(path, &to).context(|| format!("while backing up `{}` to `{}`", path, to.display()))
+}
+
+
+/// The formats progress can be reported in
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ProgressFormat {
+    Json
+}
+
+impl FromStr for ProgressFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "json" => Ok(ProgressFormat::Json),
+            _ => Err(format!("unknown progress format `{}`, expected json", s))
+        }
+    }
+}
+
+// How often progress gets reported
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(500);
+
+// How far along encoding or decoding is, every time it gets reported
+struct Snapshot {
+    // Where reading is in the input, which goes back to the start when it gets read twice
+    bytes_in: u64,
+    bytes_out: u64,
+    // How many bytes of input were read in all
+    read: u64,
+    elapsed: Duration,
+    finished: bool
+}
+
+// Reports how far along encoding or decoding is from another thread, until it's finished
+struct Progress {
+    stop: mpsc::Sender<()>,
+    thread: JoinHandle<()>
+}
+
+impl Progress {
+    // Start reporting on the input and output, every PROGRESS_INTERVAL, and once more at the end
+    fn start<I, O, F>(input: &Counted<I>, output: &Counted<O>, mut report: F) -> Self
+    where
+        F: FnMut(&Snapshot) + Send + 'static
+    {
+        let (bytes_in, read, bytes_out) = (input.pos.clone(), input.total.clone(), output.total.clone());
+        let (stop, stopped) = mpsc::channel();
+        let start = Instant::now();
+        let thread = thread::spawn(move || loop {
+            let finished = !matches!(stopped.recv_timeout(PROGRESS_INTERVAL), Err(mpsc::RecvTimeoutError::Timeout));
+            report(&Snapshot {
+                bytes_in: bytes_in.load(Ordering::Relaxed),
+                bytes_out: bytes_out.load(Ordering::Relaxed),
+                read: read.load(Ordering::Relaxed),
+                elapsed: start.elapsed(),
+                finished
+            });
+            if finished {
+                return
+            }
+        });
+        Progress { stop, thread }
+    }
+
+    // Report progress as JSON lines, where `work` is how many bytes of input
+    // will be read in all, which is more than its size when it gets read twice
+    fn json<I, O>(input: &Counted<I>, output: &Counted<O>, work: u64) -> Self {
+        Progress::start(input, output, move |snapshot| eprintln!("{}", progress_line(snapshot, work)))
+    }
+
+    // Report the running ratio and throughput, on a line that gets rewritten on terminals
+    fn stats<I, O>(input: &Counted<I>, output: &Counted<O>) -> Self {
+        let terminal = io::stderr().is_terminal();
+        Progress::start(input, output, move |snapshot| match (terminal, snapshot.finished) {
+            (true, false) => eprint!("\r{}\x1b[K", stats_line(snapshot)),
+            (true, true) => eprintln!("\r{}\x1b[K", stats_line(snapshot)),
+            (false, _) => eprintln!("{}", stats_line(snapshot))
+        })
+    }
+
+    // Stop reporting, after a last report
+    fn finish(self) {
+        let _ = self.stop.send(());
+        let _ = self.thread.join();
+    }
+}
+
+// The JSON line of --progress
+fn progress_line(snapshot: &Snapshot, work: u64) -> serde_json::Value {
+    let &Snapshot { bytes_in, bytes_out, read, elapsed, finished } = snapshot;
+    // A thousandth is more than precise enough for showing progress
+    let round = |x: f64| (x * 1000.0).round() / 1000.0;
+    let ratio = if bytes_in == 0 { None } else { Some(round(bytes_out as f64 / bytes_in as f64)) };
+    let eta = match (finished, read) {
+        (true, _) => Some(0.0),
+        (false, 0) => None,
+        // The size of the input isn't known
+        (false, _) if work == 0 => None,
+        (false, read) => Some(round(elapsed.as_secs_f64() * work.saturating_sub(read) as f64 / read as f64))
+    };
+    serde_json::json!({ "bytes_in": bytes_in, "bytes_out": bytes_out, "ratio": ratio, "eta": eta })
+}
+
+// The line of --stats, with sizes in MB, like tune shows them
+fn stats_line(snapshot: &Snapshot) -> String {
+    let megabytes = |bytes: u64| bytes as f64 / 1_000_000.0;
+    let secs = snapshot.elapsed.as_secs_f64();
+    let speed = if secs > 0.0 { megabytes(snapshot.read) / secs } else { 0.0 };
+    // Encoding doesn't write anything while counting the input, which says nothing about the ratio yet
+    let ratio = match (snapshot.bytes_in, snapshot.bytes_out) {
+        (_, 0) | (0, _) => "no ratio yet".to_string(),
+        (bytes_in, bytes_out) => format!("ratio {:.2}%", bytes_out as f64 / bytes_in as f64 * 100.0)
+    };
+    format!("{:.1} MB in, {:.1} MB out, {}, {:.1} MB/s", megabytes(snapshot.bytes_in), megabytes(snapshot.bytes_out), ratio, speed)
+}
+
+
+fn invalid_input(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, msg)
+}
+
+// Set once Ctrl-C is pressed while encoding or decoding
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+// Make Ctrl-C cancel encoding or decoding, instead of killing the program,
+// so that we get to remove the partially written output. The handler is only installed
+// the first time, so that this can be called for every file.
+fn cancel_on_interrupt() -> io::Result<&'static AtomicBool> {
+    static INSTALLED: Once = Once::new();
+    let mut result = Ok(());
+    INSTALLED.call_once(|| {
+        result = ctrlc::set_handler(|| INTERRUPTED.store(true, Ordering::Relaxed)).map_err(io::Error::other);
+    });
+    result.map(|_| &INTERRUPTED)
+}
+
+/// The exit status when everything went fine
+pub const SUCCESS_STATUS: i32 = 0;
+/// The exit status when something failed
+pub const ERROR_STATUS: i32 = 1;
+/// The exit status when nothing failed, but something was skipped, like a file whose output exists
+pub const WARNING_STATUS: i32 = 2;
+/// The exit status to use when the program fails because Ctrl-C was pressed
+pub const INTERRUPTED_STATUS: i32 = 130;
+
+// The error decoding a damaged file with --recover, after keeping what could be decoded
+#[derive(Debug)]
+struct Recovered {
+    bytes: u64,
+    source: io::Error
+}
+
+impl fmt::Display for Recovered {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}, kept the {} bytes decoded before that", self.source, self.bytes)
+    }
+}
+
+impl error::Error for Recovered {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        Some(&self.source)
+    }
+}
+
+// The error of a run over several files where some of them failed, or were skipped,
+// after each of them was reported
+#[derive(Debug)]
+struct Failures {
+    failed: usize,
+    warned: usize,
+    total: usize,
+    // What happened to the files that only warned
+    warning: &'static str
+}
+
+impl fmt::Display for Failures {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (self.failed, self.warned) {
+            (0, warned) => write!(f, "{} of the {} files were {}", warned, self.total, self.warning),
+            (failed, 0) => write!(f, "{} of the {} files failed", failed, self.total),
+            (failed, warned) => write!(f, "{} of the {} files failed, and {} of the rest were {}", failed, self.total, warned, self.warning)
+        }
+    }
+}
+
+impl error::Error for Failures {}
+
+// Run an operation on each input, on up to `jobs` threads, reporting on them in order, where a file
+// being skipped doesn't stop the ones after it, but failing does, unless errors are ignored,
+// in which case every file is tried, and the files that only warned are counted as having
+// had the warning happen to them
+fn for_each_input<F>(first: &str, more: &[String], ignore_errors: bool, jobs: usize, warning: &'static str, run: F) -> io::Result<()>
+where
+    F: Fn(String) -> io::Result<()> + Sync
+{
+    if more.is_empty() {
+        return run(first.to_string())
+    }
+    let paths: Vec<_> = std::iter::once(first).chain(more.iter().map(String::as_str)).map(PathBuf::from).collect();
+    // Files that haven't been started when one fails are left alone, and come after it
+    let stopped = AtomicBool::new(false);
+    let results = run_all(&paths, jobs, |path| {
+        if stopped.load(Ordering::Relaxed) {
+            return Ok(())
+        }
+        let result = run(path.to_string_lossy().into_owned());
+        if matches!(result, Err(ref e) if status(e) != WARNING_STATUS && !ignore_errors) {
+            stopped.store(true, Ordering::Relaxed);
+        }
+        result
+    });
+    let mut failures = Failures { failed: 0, warned: 0, total: paths.len(), warning };
+    for result in results {
+        let e = match result {
+            Ok(()) => continue,
+            Err(e) => e
+        };
+        match status(&e) {
+            WARNING_STATUS => failures.warned += 1,
+            ERROR_STATUS if ignore_errors => failures.failed += 1,
+            _ => return Err(e)
+        }
+        report(Err(e));
+    }
+    if failures.failed + failures.warned == 0 {
+        return Ok(())
+    }
+    Err(io::Error::other(failures))
+}
+
+// The status to exit with after an error
+fn status(e: &io::Error) -> i32 {
+    let inner = e.get_ref();
+    let skipped = e.kind() == io::ErrorKind::AlreadyExists;
+    let recovered = inner.is_some_and(|inner| inner.is::<Recovered>());
+    let only_warned = inner.and_then(|inner| inner.downcast_ref::<Failures>()).is_some_and(|failures| failures.failed == 0);
+    if coding::is_cancelled(e) {
+        INTERRUPTED_STATUS
+    } else if skipped || recovered || only_warned {
+        WARNING_STATUS
+    } else {
+        ERROR_STATUS
+    }
+}
+
+/// Report the outcome of running the program, returning the status to exit with,
+/// following the conventions of gzip.
+pub fn report(result: io::Result<()>) -> i32 {
+    let e = match result {
+        Ok(()) => return SUCCESS_STATUS,
+        Err(e) => e
+    };
+    let status = status(&e);
+    match status {
+        INTERRUPTED_STATUS => eprintln!("huffman: interrupted"),
+        // Only happens when refusing to overwrite a file
+        _ if e.kind() == io::ErrorKind::AlreadyExists => eprintln!("huffman: {}, skipped", e),
+        _ => eprintln!("huffman: {}", e)
+    }
+    status
+}
+
+fn open(path: &str) -> io::Result<File> {
+    File::open(path).context(|| format!("while opening `{}`", path))
+}
+
+// The output file of encoding or decoding a file without -o, which gets the suffix added when encoding,
+// and removed when decoding, or .out added instead when the file doesn't have the suffix
+fn output_name(input: &str, suffix: &str, decoding: bool) -> String {
+    match input.strip_suffix(suffix).filter(|stem| !stem.is_empty()) {
+        _ if !decoding => format!("{}{}", input, suffix),
+        Some(stem) => stem.to_string(),
+        None => format!("{}.out", input)
+    }
+}
+
+// Where the output goes when -o isn't given, which is next to the input,
+// or in the current directory for URLs, named after the last part of their path
+fn default_output(input: &str, suffix: Option<&str>, decoding: bool) -> io::Result<String> {
+    let suffix = suffix.unwrap_or(EXTENSION);
+    if suffix.is_empty() {
+        return Err(invalid_input("the suffix can't be empty"))
+    }
+    #[cfg(feature = "http")]
+    {
+        if http::is_url(input) {
+            let name = input.rsplit('/').next().filter(|name| !name.is_empty() && !name.contains(':'));
+            let name = name.ok_or_else(|| invalid_input("URLs without a file name need their output given with -o"))?;
+            return Ok(output_name(name, suffix, decoding))
+        }
+    }
+    Ok(output_name(input, suffix, decoding))
+}
+
+// The kind of backup --backup asks for, if it's given
+fn backup(option: Option<Option<Backup>>) -> io::Result<Option<Backup>> {
+    match option {
+        Some(Some(backup)) => Ok(Some(backup)),
+        Some(None) => Backup::from_env().map(Some),
+        None => Ok(None)
+    }
+}
+
+// Create the output file, refusing to overwrite an existing one unless forced to,
+// or told to go ahead after asking, which only happens when someone is there to answer
+fn create_output(path: &str, force: bool, no_clobber: bool, backup: Option<Backup>) -> io::Result<File> {
+    let create = || {
+        back_up(path, backup)?;
+        File::create(path).context(|| format!("while creating `{}`", path))
+    };
+    if force {
+        return create()
+    }
+    match fs::OpenOptions::new().write(true).create_new(true).open(path) {
+        Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => {
+            let interactive = io::stdin().is_terminal() && io::stderr().is_terminal();
+            if !no_clobber && interactive && confirm(&format!("`{}` already exists, overwrite?", path))? {
+                create()
+            } else {
+                Err(io::Error::new(io::ErrorKind::AlreadyExists, format!("`{}` already exists", path)))
+            }
+        }
+        result => result.context(|| format!("while creating `{}`", path))
+    }
+}
+
+// Ask a yes or no question on the terminal, where anything but yes means no
+fn confirm(question: &str) -> io::Result<bool> {
+    // Files worked on in parallel ask their questions one at a time
+    static ASKING: Mutex<()> = Mutex::new(());
+    let _asking = ASKING.lock().unwrap_or_else(|e| e.into_inner());
+    eprint!("huffman: {} [y/N] ", question);
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer)?;
+    Ok(matches!(answer.trim(), "y" | "Y" | "yes"))
+}
+
+// Remove the output of an operation that failed, leaving the error as is
+fn remove_on_error<T>(result: io::Result<T>, output: &str) -> io::Result<T> {
+    // Outputs like /dev/stdout or /dev/full aren't ours to remove
+    let is_file = fs::metadata(output).is_ok_and(|metadata| metadata.is_file());
+    if result.is_err() && is_file {
+        // The original error matters more than failing to remove the file
+        let _ = fs::remove_file(output);
+    }
+    result
+}
+
+// Make sure an output written successfully, and its entry in its directory, are on disk, with --fsync
+fn synced<T>(result: io::Result<T>, output: &str, fsync: bool) -> io::Result<T> {
+    let value = result?;
+    let path = Path::new(output);
+    // Outputs like /dev/stdout aren't synced, since they're not files that can be lost
+    if !fsync || !fs::metadata(path).is_ok_and(|metadata| metadata.is_file()) {
+        return Ok(value)
+    }
+    let sync = |file: File| file.sync_all();
+    // Windows only syncs files opened for writing
+    fs::OpenOptions::new().write(true).open(path).and_then(sync).context(|| format!("while syncing `{}`", output))?;
+    // Windows can't open directories, and updates their entries along with the files
+    #[cfg(unix)]
+    {
+        let dir = match path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new(".")
+        };
+        File::open(dir).and_then(sync).context(|| format!("while syncing `{}`", dir.display()))?;
+    }
+    Ok(value)
+}
+
+// Remove the output of an operation that failed, unless it's kept, in which case the error says so
+fn keep_on_error<T>(result: io::Result<T>, output: &str, keep: bool) -> io::Result<T> {
+    if keep {
+        return result.context(|| format!("kept the incomplete output in `{}`", output))
+    }
+    remove_on_error(result, output)
+}
+
+// Where encoding or decoding reads from, which is a file, or the body of a response
+enum Input {
+    File(File),
+    // Decoding streams the body as it arrives
+    #[cfg(feature = "http")]
+    Remote(io::BufReader<std::net::TcpStream>),
+    // Encoding reads the input twice, which a response can't do, so it's kept around
+    #[cfg(feature = "http")]
+    Buffered(io::Cursor<Vec<u8>>)
+}
+
+impl io::Read for Input {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Input::File(file) => file.read(buf),
+            #[cfg(feature = "http")]
+            Input::Remote(body) => body.read(buf),
+            #[cfg(feature = "http")]
+            Input::Buffered(body) => body.read(buf)
+        }
+    }
+}
+
+impl io::Seek for Input {
+    fn seek(&mut self, pos: io::SeekFrom) -> io::Result<u64> {
+        match self {
+            Input::File(file) => file.seek(pos),
+            #[cfg(feature = "http")]
+            Input::Remote(_) => Err(io::Error::new(io::ErrorKind::Unsupported, "can't seek in a response")),
+            #[cfg(feature = "http")]
+            Input::Buffered(body) => body.seek(pos)
+        }
+    }
+}
+
+// Open the input of encoding or decoding, which can be a URL with the http feature,
+// returning it along with its size, or 0 if that isn't known
+#[cfg_attr(not(feature = "http"), allow(unused_variables))]
+fn open_input(path: &str, seekable: bool) -> io::Result<(Input, u64)> {
+    #[cfg(feature = "http")]
+    {
+        if http::is_url(path) {
+            let response = http::get(path).context(|| format!("while fetching `{}`", path))?;
+            if !seekable {
+                return Ok((Input::Remote(response.body), response.length.unwrap_or(0)))
+            }
+            let mut body = response.body;
+            let mut data = Vec::new();
+            body.read_to_end(&mut data).context(|| format!("while fetching `{}`", path))?;
+            let size = data.len() as u64;
+            return Ok((Input::Buffered(io::Cursor::new(data)), size))
+        }
+    }
+    let file = open(path)?;
+    advise_sequential(&file);
+    let size = file.metadata()?.len();
+    Ok((Input::File(file), size))
+}
+
+// Tell the kernel that we're reading a file from start to finish, so that it reads further
+// ahead, and drops the pages we're done with sooner instead of crowding out the rest of the cache
+#[cfg(any(target_os = "linux", target_os = "android", target_os = "freebsd"))]
+fn advise_sequential(file: &File) {
+    use std::os::unix::io::AsRawFd;
+    // This is only a hint, so it doesn't matter if the kernel ignores it
+    unsafe {
+        libc::posix_fadvise(file.as_raw_fd(), 0, 0, libc::POSIX_FADV_SEQUENTIAL);
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android", target_os = "freebsd")))]
+fn advise_sequential(_file: &File) {}
+
+// Read a frequency model written by `--dump-freqs` or `freq`
+fn load_freqs(path: &str) -> io::Result<coding::Frequencies> {
+    let json = fs::read_to_string(path).context(|| format!("while reading `{}`", path))?;
+    coding::Frequencies::from_json(&json).context(|| format!("while loading frequencies from `{}`", path))
+}
+
+
+// Check that the options go together, and load the model, if there is one,
+// returning the options for encoding, and for decoding the output if it's raw
+fn encode_options(opt: &EncodeOpt) -> io::Result<(coding::EncodeOptions<'static>, Option<coding::Raw>)> {
+    let uses_freqs = match opt.method {
+        None | Some(Method::Huffman) | Some(Method::RleHuffman) => true,
+        Some(Method::Stored) => opt.load_freqs.is_none() && opt.dict.is_none() && !opt.raw,
+        Some(Method::Words) | Some(Method::Utf8) | Some(Method::Tokens) | Some(Method::Ngrams) | Some(Method::Rice) => false
+    };
+    if !uses_freqs && (opt.load_freqs.is_some() || opt.dict.is_some() || opt.dump_freqs.is_some() || opt.raw) {
+        return Err(invalid_input("only the huffman and rle methods use frequencies"))
+    }
+    if opt.coder != coding::Coder::Huffman && (opt.raw || opt.dict.is_some() || !uses_freqs) {
+        return Err(invalid_input("only the huffman and rle methods can use another coder, without --raw or --dict"))
+    }
+    if opt.raw && opt.load_freqs.is_none() {
+        return Err(invalid_input("raw data needs the frequencies from --load-freqs"))
+    }
+    if opt.cache_models && (opt.method == Some(Method::Stored) || !uses_freqs || opt.load_freqs.is_some() || opt.raw) {
+        return Err(invalid_input("--cache-models only works with the huffman and rle methods, without --load-freqs or --raw"))
+    }
+    let byte_method = matches!(opt.method, None | Some(Method::Huffman) | Some(Method::RleHuffman));
+    if !byte_method && (opt.trace.is_some() || opt.dump_tree.is_some() || opt.load_tree.is_some()) {
+        return Err(invalid_input("--trace, --dump-tree and --load-tree only work with the huffman and rle methods"))
+    }
+    let given_model = opt.load_freqs.is_some() || opt.dict.is_some() || opt.load_tree.is_some() || opt.raw || opt.cache_models;
+    if opt.single_read && (!uses_freqs || given_model) {
+        let msg = "--single-read only works with the huffman and rle methods, without --load-freqs, --load-tree, --raw or --cache-models";
+        return Err(invalid_input(msg))
+    }
+    if opt.ngram_size.is_some() && opt.method != Some(Method::Ngrams) {
+        return Err(invalid_input("--ngram-size only works with the ngrams method"))
+    }
+    let level = match (opt.level, opt.fast, opt.best) {
+        (Some(level), _, _) => level,
+        (None, true, _) => coding::Level::FASTEST,
+        (None, _, true) => coding::Level::BEST,
+        (None, false, false) => coding::Level::DEFAULT
+    };
+    // Levels only pick the model and methods when nothing else does
+    let sample = if uses_freqs && !given_model { level.sample() } else { None };
+    let alternatives = if opt.method.is_none() && !given_model && opt.coder == coding::Coder::Huffman && opt.trace.is_none() {
+        level.alternatives().to_vec()
+    } else {
+        Vec::new()
+    };
+    let with_tree = opt.load_freqs.is_some() || opt.raw || opt.cache_models || opt.coder != coding::Coder::Huffman;
+    if opt.load_tree.is_some() && with_tree {
+        return Err(invalid_input("--load-tree can't go with --load-freqs, --raw, --cache-models or --coder"))
+    }
+    let model = match opt.load_freqs.as_ref().or(opt.dict.as_ref()) {
+        Some(path) => Some(load_freqs(path)?),
+        None => None
+    };
+    let raw = if opt.raw {
+        // The model was checked for above
+        let method = opt.method.unwrap_or(Method::Huffman);
+        model.clone().map(|freqs| coding::Raw { method, freqs })
+    } else {
+        None
+    };
+    let tree = match &opt.load_tree {
+        Some(path) => {
+            let json = fs::read_to_string(path).context(|| format!("while reading `{}`", path))?;
+            Some(coding::HuffTree::from_json(&json).context(|| format!("while loading the tree from `{}`", path))?)
+        }
+        None => None
+    };
+    let options = coding::EncodeOptions {
+        method: opt.method,
+        coder: opt.coder,
+        model,
+        tree,
+        ngram_size: opt.ngram_size,
+        stride: opt.stride,
+        decoded_size: None,
+        optimistic: if opt.single_read { Some(coding::OPTIMISTIC_PREFIX) } else { sample },
+        alternatives,
+        preset: opt.dict.is_some(),
+        raw: opt.raw,
+        cancel: None,
+        trace: None
+    };
+    Ok((options, raw))
+}
+
+fn encode(opt: EncodeOpt) -> io::Result<()> {
+    let (mut options, raw) = encode_options(&opt)?;
+    let cancel = cancel_on_interrupt()?;
+    options.cancel = Some(cancel);
+    let tracer = opt.trace.map(|limit| coding::Tracer::new(limit, io::stderr()));
+    options.trace = tracer.as_ref();
+    // Verifying the output needs the dictionary it was encoded with
+    let dict = if options.preset { options.model.clone() } else { None };
+    let cache = if opt.cache_models { Some(cache::ModelCache::open()?) } else { None };
+    let key = cache::key(&opt.input);
+    let cached = match cache.as_ref().and_then(|cache| cache.get(&key, opt.method)) {
+        Some((method, freqs)) => {
+            options.method = Some(method);
+            options.model = Some(freqs);
+            true
+        }
+        None => false
+    };
+
+    let output = match &opt.output {
+        Some(output) => output.clone(),
+        None => default_output(&opt.input, opt.suffix.as_deref(), false)?
+    };
+    let (input_file, size) = open_input(&opt.input, true)?;
+    if opt.record_size {
+        options.decoded_size = Some(size);
+    }
+    // Without a model, the input is read once to count it, and again to encode it,
+    // unless it's encoded optimistically, which only reads it again if it has to
+    let passes = if options.model.is_some() || options.tree.is_some() || options.optimistic.is_some() { 1 } else { 2 };
+    let work = size * passes * (1 + options.alternatives.len() as u64);
+    let input_file = Timed::new(input_file);
+    let output_file = Timed::new(Named::new(create_output(&output, opt.force, opt.no_clobber, backup(opt.backup)?)?, &output));
+    let io_nanos = [input_file.nanos.clone(), output_file.nanos.clone()];
+    let limiter = opt.limit_rate.map(Limiter::new);
+    let input_file = Counted::new(Throttled::new(input_file, limiter.clone()));
+    let output_file = Counted::new(Throttled::new(output_file, limiter));
+    let progress = opt.progress.map(|_| Progress::json(&input_file, &output_file, work));
+    let live_stats = if opt.stats { Some(Progress::stats(&input_file, &output_file)) } else { None };
+    let encoded = if opt.pipeline {
+        let mut output_writer = pipeline::Writer::new(output_file);
+        encode_into(&mut pipeline::Reader::new(input_file), &mut output_writer, opt.armor, options)
+    } else {
+        let mut output_writer = io::BufWriter::new(output_file);
+        encode_into(&mut io::BufReader::new(input_file), &mut output_writer, opt.armor, options)
+    };
+    for progress in progress.into_iter().chain(live_stats) {
+        progress.finish();
+    }
+    let result = match encoded.context(|| format!("while encoding `{}`", opt.input)) {
+        Ok(stats) if opt.verify => {
+            let verified = verify_output(&stats, &output, raw, dict, cancel);
+            verified.context(|| format!("while verifying `{}`", output)).map(|_| stats)
+        }
+        result => result
+    };
+    let stats = remove_on_error(synced(result, &output, opt.fsync), &output)?;
+    if let (Some(cache), false, Some(freqs)) = (&cache, cached, &stats.freqs) {
+        if stats.method == Method::Huffman || stats.method == Method::RleHuffman {
+            // The file is encoded either way, so a model that can't be kept only costs the next one a count
+            if let Err(e) = cache.put(&key, stats.method, freqs) {
+                eprintln!("huffman: couldn't cache the frequencies of `{}`: {}", opt.input, e);
+            }
+        }
+    }
+    if opt.timings {
+        print_timings(&stats.phases, &io_nanos, stats.elapsed);
+    }
+    dump_freqs(opt.dump_freqs, &stats)?;
+    dump_tree(opt.dump_tree, &stats)
+}
+
+fn dump_freqs(path: Option<String>, stats: &coding::EncodeStats) -> io::Result<()> {
+    if let (Some(path), Some(freqs)) = (path, &stats.freqs) {
+        fs::write(&path, freqs.to_json()).context(|| format!("while writing `{}`", path))?;
+    }
     Ok(())
 }
+
+fn dump_tree(path: Option<String>, stats: &coding::EncodeStats) -> io::Result<()> {
+    if let (Some(path), Some(tree)) = (path, &stats.tree) {
+        fs::write(&path, tree.to_json()).context(|| format!("while writing `{}`", path))?;
+    }
+    Ok(())
+}
+
+fn encode_into<R, W>(
+    input_file: &mut R,
+    output_writer: &mut W,
+    armor: bool,
+    options: coding::EncodeOptions
+) -> io::Result<coding::EncodeStats>
+where
+    R: Read + Seek,
+    W: Write
+{
+    let stats = if armor {
+        let mut armored = armor::Encoder::new(&mut *output_writer)?;
+        let stats = coding::encode_stream_with(input_file, &mut armored, options)?;
+        armored.finish()?;
+        stats
+    } else {
+        coding::encode_stream_with(input_file, &mut *output_writer, options)?
+    };
+    output_writer.flush()?;
+    Ok(stats)
+}
+
+// Check that decoding the output gives us back the same bytes as the input
+fn verify_output(
+    expected: &coding::EncodeStats,
+    output: &str,
+    raw: Option<coding::Raw>,
+    dict: Option<coding::Frequencies>,
+    cancel: &AtomicBool
+) -> io::Result<()> {
+    let output_file = io::BufReader::new(open(output)?);
+    let options = coding::DecodeOptions { raw, dict, limits: coding::Limits::default(), cancel: Some(cancel), trace: None };
+    let actual = coding::decode_stream_with(output_file, &mut io::sink(), options)?;
+    if actual.checksum != expected.checksum || actual.bytes_out != expected.bytes_in {
+        let msg = "verification failed, decoding the output doesn't give back the input";
+        return Err(io::Error::new(io::ErrorKind::InvalidData, msg))
+    }
+    Ok(())
+}
+
+// Check that the options go together, and load the frequencies of raw data, or the dictionary
+fn decode_options(opt: &DecodeOpt) -> io::Result<coding::DecodeOptions<'static>> {
+    let streamed = opt.offset != 0 || opt.length.is_some() || opt.pipeline || opt.recover;
+    if opt.mmap && (streamed || opt.raw || opt.progress.is_some() || opt.stats || opt.limit_rate.is_some() || opt.timings) {
+        let msg = "--mmap decodes everything straight into the output, at the size the header records, so it can't go \
+            with --offset, --length, --pipeline, --recover, --raw, --progress, --stats, --limit-rate or --timings";
+        return Err(invalid_input(msg))
+    }
+    if opt.mmap && cfg!(not(unix)) {
+        return Err(invalid_input("--mmap is only supported on Unix"))
+    }
+    let raw = match (opt.raw, &opt.load_freqs, opt.method) {
+        (false, None, None) => None,
+        (false, _, _) => return Err(invalid_input("frequencies and methods are only given for raw data")),
+        (true, None, _) => return Err(invalid_input("raw data needs the frequencies from --load-freqs")),
+        (true, Some(path), method) => {
+            let method = method.unwrap_or(Method::Huffman);
+            if method != Method::Huffman && method != Method::RleHuffman {
+                return Err(invalid_input("raw data can only use the huffman and rle methods"))
+            }
+            Some(coding::Raw { method, freqs: load_freqs(path)? })
+        }
+    };
+    let dict = match &opt.dict {
+        Some(path) => Some(load_freqs(path)?),
+        None => None
+    };
+    let limits = coding::Limits { max_output: opt.max_output_size.map(|size| size.0), ..coding::Limits::default() };
+    Ok(coding::DecodeOptions { raw, dict, limits, cancel: None, trace: None })
+}
+
+fn decode(opt: DecodeOpt) -> io::Result<()> {
+    let mut options = decode_options(&opt)?;
+    options.cancel = Some(cancel_on_interrupt()?);
+    let tracer = opt.trace.map(|limit| coding::Tracer::new(limit, io::stderr()));
+    options.trace = tracer.as_ref();
+    let output = match &opt.output {
+        Some(output) => output.clone(),
+        None => default_output(&opt.input, opt.suffix.as_deref(), true)?
+    };
+    #[cfg(unix)]
+    {
+        if opt.mmap {
+            let size = mapped_size(&opt.input, &options)?;
+            // Refusing to overwrite the output leaves it where it is, instead of removing it like a failure
+            drop(create_output(&output, opt.force, opt.no_clobber, backup(opt.backup)?)?);
+            let result = synced(decode_mapped(&opt, options, &output, size), &output, opt.fsync);
+            return keep_on_error(result, &output, opt.keep_broken)
+        }
+    }
+    let (mut input_file, work) = open_input(&opt.input, false)?;
+    let indexed = !opt.raw && has_index(&mut input_file)?;
+    let input_file = Timed::new(input_file);
+    let output_file = Timed::new(Named::new(create_output(&output, opt.force, opt.no_clobber, backup(opt.backup)?)?, &output));
+    let io_nanos = [input_file.nanos.clone(), output_file.nanos.clone()];
+    let limiter = opt.limit_rate.map(Limiter::new);
+    let input_file = Counted::new(Throttled::new(input_file, limiter.clone()));
+    let output_file = Counted::new(Throttled::new(output_file, limiter));
+    let bytes_out = output_file.total.clone();
+    let progress = opt.progress.map(|_| Progress::json(&input_file, &output_file, work));
+    let live_stats = if opt.stats { Some(Progress::stats(&input_file, &output_file)) } else { None };
+    let result = if indexed {
+        let output_writer = io::BufWriter::new(output_file);
+        blocks::SeekableDecoder::new(input_file)
+            .and_then(|reader| decode_indexed(reader, output_writer, opt.offset, opt.length, &options.limits))
+    } else if opt.pipeline {
+        let output_writer = pipeline::Writer::new(output_file);
+        decode_into(pipeline::Reader::new(input_file), output_writer, opt.offset, opt.length, options)
+    } else {
+        let output_writer = io::BufWriter::new(output_file);
+        decode_into(io::BufReader::new(input_file), output_writer, opt.offset, opt.length, options)
+    };
+    for progress in progress.into_iter().chain(live_stats) {
+        progress.finish();
+    }
+    let result = result.context(|| format!("while decoding `{}`", opt.input));
+    let stats = match result {
+        // The output holds everything decoded before the damage, once the writers are dropped
+        Err(e) if opt.recover && (e.kind() == io::ErrorKind::UnexpectedEof || e.kind() == io::ErrorKind::InvalidData) => {
+            let recovered = Recovered { bytes: bytes_out.load(Ordering::Relaxed), source: e };
+            return Err(io::Error::new(recovered.source.kind(), recovered))
+        }
+        result => keep_on_error(synced(result, &output, opt.fsync), &output, opt.keep_broken)?
+    };
+    match stats {
+        Some(stats) if opt.timings => print_timings(&stats.phases, &io_nanos, stats.elapsed),
+        // Decoding stopped early to only write out a window of the output
+        None if opt.timings => eprintln!("timings are only shown when decoding everything, from a file without a block index"),
+        _ => {}
+    }
+    Ok(())
+}
+
+// The most room --mmap sets aside for the output, unless --max-output-size allows more
+#[cfg(unix)]
+const MAX_MAPPED_SIZE: u64 = 1 << 30;
+
+// The size the header of a local file records its data decoding to, once the header is checked
+// against its checksum, failing for the files --mmap can't set aside room for up front
+#[cfg(unix)]
+fn mapped_size(path: &str, options: &coding::DecodeOptions) -> io::Result<u64> {
+    let refuse = |why: &str| invalid_input(&format!("--mmap can't decode `{}`, {}", path, why));
+    #[cfg(feature = "http")]
+    {
+        if http::is_url(path) {
+            return Err(refuse("only local files can be decoded into a mapping"))
+        }
+    }
+    let file = open(path)?;
+    // Reading the header out of a pipe would leave nothing for decoding to read it from again
+    if !file.metadata()?.is_file() {
+        return Err(refuse("only regular files can be decoded into a mapping"))
+    }
+    let mut file = io::BufReader::new(file);
+    if armor::is_armored(file.fill_buf()?) {
+        return Err(refuse("armored files don't record their size"))
+    }
+    let header = coding::check_header(&mut file, options).context(|| format!("while decoding `{}`", path))?;
+    let size = header.decoded_size.ok_or_else(|| refuse("its header doesn't record its size, like `encode --record-size` has it do"))?;
+    // A header can record any size, so the room set aside is bounded even when decoding isn't
+    if options.limits.max_output.is_none() && size > MAX_MAPPED_SIZE {
+        let msg = format!("the data decodes to {} bytes, more than the {} set aside without --max-output-size", size, MAX_MAPPED_SIZE);
+        return Err(io::Error::new(io::ErrorKind::InvalidData, msg)).context(|| format!("while decoding `{}`", path))
+    }
+    Ok(size)
+}
+
+// Decode a file straight into a mapping of the output, which has been created already,
+// set aside at the size its header records
+#[cfg(unix)]
+fn decode_mapped(opt: &DecodeOpt, options: coding::DecodeOptions, output: &str, size: u64) -> io::Result<()> {
+    let file = fs::OpenOptions::new().read(true).write(true).open(output).context(|| format!("while opening `{}`", output))?;
+    let mut mapped = mapped::MappedWriter::new(file, size).context(|| format!("while mapping `{}`", output))?;
+    let input_file = io::BufReader::new(open(&opt.input)?);
+    let decoded = coding::decode_stream_with(input_file, &mut mapped, options);
+    decoded.and_then(|_| mapped.finish()).context(|| format!("while decoding `{}`", opt.input))
+}
+
+// Whether the input is a local file ending with an index of its blocks, from a blocks::BlockWriter,
+// leaving it at its start. Files that only happen to end with the same bytes are decoded the usual way
+fn has_index(input: &mut Input) -> io::Result<bool> {
+    let file = match input {
+        Input::File(file) if file.metadata()?.is_file() => file,
+        _ => return Ok(false)
+    };
+    let indexed = blocks::SeekableDecoder::new(&*file).is_ok();
+    file.seek(io::SeekFrom::Start(0))?;
+    Ok(indexed)
+}
+
+// Decode the window of the data of an indexed file starting at the offset, only decoding the blocks it covers
+fn decode_indexed<R, W>(
+    mut reader: blocks::SeekableDecoder<R>,
+    mut output_writer: W,
+    offset: u64,
+    length: Option<u64>,
+    limits: &coding::Limits
+) -> io::Result<Option<coding::DecodeStats>>
+where
+    R: Read + Seek,
+    W: Write
+{
+    let available = reader.len().saturating_sub(offset);
+    let length = length.map_or(available, |length| length.min(available));
+    if let Some(max) = limits.max_output.filter(|&max| length > max) {
+        let msg = format!("the data decodes to {} bytes, more than the {} allowed", length, max);
+        return Err(io::Error::new(io::ErrorKind::InvalidData, msg))
+    }
+    reader.seek(io::SeekFrom::Start(offset))?;
+    io::copy(&mut reader.take(length), &mut output_writer)?;
+    output_writer.flush()?;
+    Ok(None)
+}
+
+fn decode_into<R, W>(
+    input_file: R,
+    mut output_writer: W,
+    offset: u64,
+    length: Option<u64>,
+    options: coding::DecodeOptions
+) -> io::Result<Option<coding::DecodeStats>>
+where
+    R: BufRead,
+    W: Write
+{
+    let mut stats = None;
+    if offset == 0 && length.is_none() {
+        stats = Some(coding::decode_stream_with(input_file, &mut output_writer, options)?);
+    } else {
+        // Without an index into the data, we have to decode everything before the offset
+        let mut window = Window {
+            inner: &mut output_writer,
+            skip: offset,
+            remaining: length.unwrap_or(u64::MAX)
+        };
+        match coding::decode_stream_with(input_file, &mut window, options) {
+            // This is how we stop decoding once we have enough bytes
+            Err(ref e) if e.kind() == io::ErrorKind::WriteZero && window.remaining == 0 => {}
+            result => {
+                stats = Some(result?);
+            }
+        }
+    }
+    output_writer.flush()?;
+    Ok(stats)
+}
+
+fn count_freqs(input: String, output: String) -> io::Result<()> {
+    let freqs = coding::Frequencies::count_reader(&mut open(&input)?)?;
+    fs::write(&output, freqs.to_json()).context(|| format!("while writing `{}`", output))
+}
+
+fn tune(input: String, sample: Option<u64>) -> io::Result<()> {
+    let mut data = Vec::new();
+    open(&input)?.take(sample.unwrap_or(u64::MAX)).read_to_end(&mut data)?;
+    if data.is_empty() {
+        return Err(invalid_input("there's nothing to compress in an empty file"))
+    }
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    writeln!(out, "{:<24}{:>12}{:>10}{:>14}{:>14}", "method", "size", "ratio", "encode MB/s", "decode MB/s")?;
+
+    let methods = [Method::Stored, Method::Huffman, Method::RleHuffman, Method::Words, Method::Utf8, Method::Tokens, Method::Ngrams, Method::Rice];
+    let huffman = methods.iter().map(|&method| (method, coding::Coder::Huffman));
+    // The other coders are only there to compare against, and never get recommended
+    let others = [coding::Coder::ShannonFano, coding::Coder::HuTucker].iter().flat_map(|&coder| {
+        [Method::Huffman, Method::RleHuffman].iter().map(move |&method| (method, coder))
+    });
+    let mut best = (Method::Stored, u64::MAX);
+    for (method, coder) in huffman.chain(others) {
+        let mut encoded = Vec::new();
+        let mut input = io::Cursor::new(&data);
+        let options = coding::EncodeOptions { method: Some(method), coder, ..Default::default() };
+        let encoded_stats = coding::encode_stream_with(&mut input, &mut encoded, options)?;
+        let decoded_stats = coding::decode_stream_with(&encoded[..], &mut io::sink(), Default::default())?;
+        let encode_secs = encoded_stats.elapsed.as_secs_f64();
+        let decode_secs = decoded_stats.elapsed.as_secs_f64();
+
+        let size = encoded_stats.bytes_out;
+        let ratio = size as f64 / data.len() as f64 * 100.0;
+        let megabytes = data.len() as f64 / 1_000_000.0;
+        writeln!(
+            out,
+            "{:<24}{:>12}{:>9.2}%{:>14.1}{:>14.1}",
+            match coder {
+                coding::Coder::Huffman => method.to_string(),
+                coder => format!("{}, {}", method, coder)
+            },
+            size,
+            ratio,
+            megabytes / encode_secs,
+            megabytes / decode_secs
+        )?;
+        if size < best.1 && coder == coding::Coder::Huffman {
+            best = (method, size);
+        }
+    }
+
+    writeln!(out)?;
+    match best.0 {
+        Method::Stored | Method::Huffman | Method::RleHuffman => {
+            writeln!(out, "recommended: no flags, the default picks {}", best.0)
+        }
+        Method::Words | Method::Utf8 | Method::Tokens | Method::Ngrams | Method::Rice => writeln!(out, "recommended: --method {}", best.0)
+    }
+}
+
+// The other compressors compare runs, with the arguments compressing and decompressing between stdin and stdout
+const COMPRESSORS: [(&str, &[&str], &[&str]); 2] = [
+    ("gzip", &["-c", "-6"], &["-d", "-c"]),
+    ("zstd", &["-c", "-q", "-3"], &["-d", "-c", "-q"])
+];
+
+// Run a program on some input, returning what it wrote out, and how long that took
+fn run_program(program: &str, args: &[&str], input: &[u8]) -> io::Result<(Vec<u8>, Duration)> {
+    let start = Instant::now();
+    let mut child = process::Command::new(program)
+        .args(args)
+        .stdin(process::Stdio::piped())
+        .stdout(process::Stdio::piped())
+        .stderr(process::Stdio::null())
+        .spawn()?;
+    let mut stdin = child.stdin.take().unwrap();
+    let mut stdout = child.stdout.take().unwrap();
+    // The input is written on another thread, so that neither side waits for the other to read
+    let mut output = Vec::new();
+    thread::scope(|scope| {
+        let writing = scope.spawn(move || stdin.write_all(input));
+        stdout.read_to_end(&mut output)?;
+        writing.join().unwrap()
+    })?;
+    if !child.wait()?.success() {
+        return Err(io::Error::other(format!("{} failed", program)))
+    }
+    Ok((output, start.elapsed()))
+}
+
+// Compress and decompress data with another program, returning the compressed size,
+// and how long each took, or what went wrong
+fn try_program(program: &str, compress: &[&str], decompress: &[&str], data: &[u8]) -> Result<(usize, Duration, Duration), String> {
+    let (compressed, encode) = run_program(program, compress, data).map_err(|e| match e.kind() {
+        io::ErrorKind::NotFound => "not installed".to_string(),
+        _ => e.to_string()
+    })?;
+    let (decompressed, decode) = run_program(program, decompress, &compressed).map_err(|e| e.to_string())?;
+    if decompressed != data {
+        return Err("didn't give back the input".to_string())
+    }
+    Ok((compressed.len(), encode, decode))
+}
+
+fn compare(input: String) -> io::Result<()> {
+    let mut data = Vec::new();
+    open(&input)?.read_to_end(&mut data)?;
+    if data.is_empty() {
+        return Err(invalid_input("there's nothing to compress in an empty file"))
+    }
+    let mut encoded = Vec::new();
+    let encoded_stats = coding::encode_stream(&mut io::Cursor::new(&data), &mut encoded)?;
+    let decoded_stats = coding::decode_stream_with(&encoded[..], &mut io::sink(), Default::default())?;
+    let mut results = vec![("huffman", Ok((encoded.len(), encoded_stats.elapsed, decoded_stats.elapsed)))];
+    for &(program, compress, decompress) in &COMPRESSORS {
+        results.push((program, try_program(program, compress, decompress, &data)));
+    }
+
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    writeln!(out, "{:<24}{:>12}{:>10}{:>14}{:>14}", "compressor", "size", "ratio", "encode MB/s", "decode MB/s")?;
+    let megabytes = data.len() as f64 / 1_000_000.0;
+    for (name, result) in results {
+        match result {
+            Ok((size, encode, decode)) => writeln!(
+                out,
+                "{:<24}{:>12}{:>9.2}%{:>14.1}{:>14.1}",
+                name,
+                size,
+                size as f64 / data.len() as f64 * 100.0,
+                megabytes / encode.as_secs_f64(),
+                megabytes / decode.as_secs_f64()
+            )?,
+            Err(e) => writeln!(out, "{:<24}{}", name, e)?
+        }
+    }
+    Ok(())
+}
+
+// Generate the inputs selftest tries, which are the same every time
+fn selftest_patterns() -> Vec<(&'static str, Vec<u8>)> {
+    // A xorshift generator is plenty for this, and keeps the patterns reproducible
+    let mut state = 0x2545_F491_4F6C_DD1Du64;
+    let mut random = move || {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        state
+    };
+    let noise: Vec<u8> = (0..65536).map(|_| random() as u8).collect();
+    let runs = (0..1000).flat_map(|i| vec![(i % 7) as u8; i % 50 + 1]).collect();
+    let skewed = (0..65536).map(|_| (random() as u32).leading_zeros() as u8 + b'a').collect();
+    let all_bytes = (0..=255).cycle().take(4096).collect();
+    let text = "the quick brown fox jumps over the lazy dog, écoutez: ¿qué? 狐狸\n".repeat(200).into_bytes();
+    vec![
+        ("empty", Vec::new()),
+        ("single byte", vec![b'x']),
+        ("random", noise),
+        ("runs", runs),
+        ("skewed", skewed),
+        ("all bytes", all_bytes),
+        ("text", text)
+    ]
+}
+
+fn selftest() -> io::Result<()> {
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    let methods = [Method::Stored, Method::Huffman, Method::RleHuffman, Method::Words, Method::Utf8, Method::Tokens, Method::Ngrams, Method::Rice];
+    let mut failures = 0;
+    for (name, data) in selftest_patterns() {
+        for &method in &methods {
+            let mut encoded = Vec::new();
+            let options = coding::EncodeOptions { method: Some(method), ..Default::default() };
+            let mut decoded = Vec::new();
+            let result = coding::encode_stream_with(&mut io::Cursor::new(&data), &mut encoded, options)
+                .and_then(|_| coding::decode_stream_with(&encoded[..], &mut decoded, Default::default()));
+            let outcome = match result {
+                Ok(_) if decoded == data => "ok".to_string(),
+                Ok(_) => "FAILED, decoding gave back different bytes".to_string(),
+                Err(e) => format!("FAILED, {}", e)
+            };
+            if outcome != "ok" {
+                failures += 1;
+            }
+            writeln!(out, "{:<16}{:<12}{}", name, method, outcome)?;
+        }
+    }
+    if failures > 0 {
+        let msg = format!("{} of the self tests failed", failures);
+        return Err(io::Error::new(io::ErrorKind::InvalidData, msg))
+    }
+    writeln!(out, "\nall self tests passed")
+}
+
+fn inspect_file(input: String, dump: usize) -> io::Result<()> {
+    let mut input_file = io::BufReader::new(open(&input)?);
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    if armor::is_armored(input_file.fill_buf()?) {
+        writeln!(out, "armored file, offsets are in the bytes inside the armor")?;
+        inspect::inspect(&mut armor::Decoder::new(input_file)?, &mut out, dump)
+    } else {
+        inspect::inspect(&mut input_file, &mut out, dump)
+    }
+}
+
+fn migrate(input: String, output: String, force: bool) -> io::Result<()> {
+    let mut input_file = io::BufReader::new(open(&input)?);
+    let mut output_writer = io::BufWriter::new(create_output(&output, force, false, None)?);
+    let migrated = coding::migrate_stream(&mut input_file, &mut output_writer).and_then(|version| {
+        output_writer.flush()?;
+        Ok(version)
+    });
+    let version = remove_on_error(migrated.context(|| format!("while migrating `{}`", input)), &output)?;
+    if version == header::VERSION {
+        eprintln!("huffman: `{}` was already in version {} of the format, and was copied as is", input, version);
+    }
+    Ok(())
+}
+
+// Read the frequencies at the start of a file encoded with the huffman method,
+// or in a JSON file of frequencies
+fn read_model(path: String) -> io::Result<coding::HuffTree> {
+    let mut model_file = io::BufReader::new(open(&path)?);
+    if !model_file.fill_buf()?.starts_with(&header::MAGIC) {
+        return load_freqs(&path).map(|freqs| coding::HuffTree::from_freqs(&freqs))
+    }
+    let header = Header::read(&mut model_file)?;
+    if header.method != Method::Huffman || header.dict_id.is_some() {
+        return Err(invalid_input("the model must be a file encoded with the huffman method, without a preset dictionary"))
+    }
+    if header.succinct_tree {
+        coding::HuffTree::read_succinct(&mut model_file)
+    } else {
+        coding::Frequencies::read(&mut model_file).map(|freqs| coding::HuffTree::from_freqs(&freqs))
+    }
+}
+
+fn generate_code(model: String, lang: codegen::Lang, output: String, force: bool) -> io::Result<()> {
+    let tree = read_model(model)?;
+    let mut output_writer = io::BufWriter::new(create_output(&output, force, false, None)?);
+    let written = codegen::generate(&tree, lang, &mut output_writer).and_then(|_| output_writer.flush());
+    remove_on_error(written, &output)
+}
+
+// Encode the files that show up in a directory with the options of `opt`, until Ctrl-C is pressed,
+// where failing to encode a file doesn't stop us from encoding the next ones
+fn watch(dir: String, pattern: String, debounce: Duration, delete_source: bool, suffix: String, opt: &EncodeOpt, jobs: usize) -> io::Result<()> {
+    // Every file would already seem to be encoded
+    if suffix.is_empty() {
+        return Err(invalid_input("the suffix can't be empty"))
+    }
+    let cancel = cancel_on_interrupt()?;
+    let mut watcher = watch::Watcher::new(PathBuf::from(&dir), pattern, suffix.clone())
+        .context(|| format!("while watching `{}`", dir))?;
+    eprintln!("watching `{}`, press Ctrl-C to stop", dir);
+    while !cancel.load(Ordering::Relaxed) {
+        thread::sleep(debounce);
+        let paths = watcher.poll().context(|| format!("while watching `{}`", dir))?;
+        for (path, result) in paths.iter().zip(encode_all(&paths, opt, &suffix, delete_source, jobs)) {
+            match result {
+                Err(ref e) if coding::is_cancelled(e) => return result,
+                Err(e) => eprintln!("huffman: {}", e),
+                Ok(()) => eprintln!("encoded `{}` into `{}{}`", path.display(), path.display(), suffix)
+            }
+        }
+    }
+    Ok(())
+}
+
+// How many threads --jobs asks for, which is one per core when it isn't given
+fn job_count(jobs: Option<usize>) -> io::Result<usize> {
+    match jobs {
+        Some(0) => Err(invalid_input("--jobs needs to be at least 1")),
+        Some(jobs) => Ok(jobs),
+        None => Ok(thread::available_parallelism().map_or(1, |n| n.get()))
+    }
+}
+
+// Encode files next to themselves, with the options of `opt`, on up to `jobs` threads,
+// returning the result for each file in order
+fn encode_all(paths: &[PathBuf], opt: &EncodeOpt, suffix: &str, delete_source: bool, jobs: usize) -> Vec<io::Result<()>> {
+    run_all(paths, jobs, |path| {
+        let input = path.to_string_lossy().into_owned();
+        let output = Some(format!("{}{}", input, suffix));
+        // A file that changed after being encoded gets encoded again
+        encode(EncodeOpt { input: input.clone(), output, force: true, ..opt.clone() })?;
+        if delete_source {
+            fs::remove_file(path).context(|| format!("while deleting `{}`", input))?;
+        }
+        Ok(())
+    })
+}
+
+// Run an operation on each file, on up to `jobs` threads, returning the result for each file in order
+fn run_all<F>(paths: &[PathBuf], jobs: usize, run: F) -> Vec<io::Result<()>>
+where
+    F: Fn(&Path) -> io::Result<()> + Sync
+{
+    let next = AtomicUsize::new(0);
+    let results: Vec<_> = paths.iter().map(|_| Mutex::new(None)).collect();
+    thread::scope(|scope| {
+        for _ in 0..jobs.min(paths.len()) {
+            scope.spawn(|| {
+                loop {
+                    let i = next.fetch_add(1, Ordering::Relaxed);
+                    let path = match paths.get(i) {
+                        Some(path) => path,
+                        None => break
+                    };
+                    let result = run(path);
+                    let cancelled = matches!(result, Err(ref e) if coding::is_cancelled(e));
+                    *results[i].lock().unwrap() = Some(result);
+                    if cancelled {
+                        break;
+                    }
+                }
+            });
+        }
+    });
+    // Files left over after Ctrl-C don't get a result, and neither need one
+    results.into_iter().map_while(|result| result.into_inner().unwrap()).collect()
+}
+
+// Find the files with the suffix of encoded files under a directory, in the order of their paths,
+// keeping the errors reading directories instead of stopping at them
+fn find_encoded(dir: &Path, suffix: &str, found: &mut Vec<PathBuf>, errors: &mut Vec<io::Error>) {
+    let entries = fs::read_dir(dir).and_then(|entries| entries.collect::<io::Result<Vec<_>>>());
+    let mut entries = match entries.context(|| format!("while reading `{}`", dir.display())) {
+        Ok(entries) => entries,
+        Err(e) => return errors.push(e)
+    };
+    entries.sort_by_key(|entry| entry.file_name());
+    for entry in entries {
+        let path = entry.path();
+        // Links to directories aren't followed, since they can loop back
+        match entry.file_type().context(|| format!("while reading `{}`", path.display())) {
+            Ok(kind) if kind.is_dir() => find_encoded(&path, suffix, found, errors),
+            Ok(_) if path.is_file() && entry.file_name().to_string_lossy().ends_with(suffix) => found.push(path),
+            Ok(_) => {}
+            Err(e) => errors.push(e)
+        }
+    }
+}
+
+// Decode a file without keeping what it decodes to, to check that it's not damaged
+fn test_file(path: &Path, cancel: &AtomicBool) -> io::Result<()> {
+    let name = path.to_string_lossy();
+    let input_file = io::BufReader::new(Named::new(open(&name)?, &name));
+    let options = coding::DecodeOptions { cancel: Some(cancel), ..coding::DecodeOptions::default() };
+    coding::decode_stream_with(input_file, &mut io::sink(), options)?;
+    Ok(())
+}
+
+// Test files on up to `jobs` threads, reporting each of them as it's done, and how many failed at the end
+fn test_files(inputs: Vec<String>, recursive: bool, suffix: &str, jobs: usize) -> io::Result<()> {
+    let cancel = cancel_on_interrupt()?;
+    let mut paths = Vec::new();
+    let mut errors = Vec::new();
+    for input in inputs {
+        let path = PathBuf::from(input);
+        if recursive && path.is_dir() {
+            find_encoded(&path, suffix, &mut paths, &mut errors);
+        } else {
+            paths.push(path);
+        }
+    }
+    for e in &errors {
+        eprintln!("huffman: {}", e);
+    }
+    let results = run_all(&paths, jobs, |path| {
+        let result = test_file(path, cancel).context(|| format!("while testing `{}`", path.display()));
+        match &result {
+            Ok(()) => println!("{}: ok", path.display()),
+            Err(e) if coding::is_cancelled(e) => {}
+            Err(e) => eprintln!("huffman: {}", e)
+        }
+        result
+    });
+    let mut failed = errors.len();
+    for result in results {
+        match result {
+            Err(ref e) if coding::is_cancelled(e) => return result,
+            Err(_) => failed += 1,
+            Ok(()) => {}
+        }
+    }
+    if failed > 0 {
+        return Err(io::Error::other(Failures { failed, warned: 0, total: errors.len() + paths.len(), warning: "skipped" }))
+    }
+    println!("tested {} files, and none of them failed", paths.len());
+    Ok(())
+}
+
+// Serve requests on a socket until Ctrl-C is pressed, answering each connection on its own thread
+#[cfg(unix)]
+fn run_daemon(socket: String, models: Vec<String>) -> io::Result<()> {
+    use std::os::unix::fs::FileTypeExt;
+    use std::os::unix::net::{UnixListener, UnixStream};
+
+    let mut loaded = HashMap::new();
+    for model in models {
+        let (name, path) = model.split_once('=').ok_or_else(|| invalid_input("models are given as NAME=PATH"))?;
+        loaded.insert(name.to_string(), daemon::Model::new(&load_freqs(path)?));
+    }
+    let models = Arc::new(loaded);
+
+    // A socket left behind by a daemon that's gone can be replaced, but not one that's in use
+    if let Ok(metadata) = fs::symlink_metadata(&socket) {
+        if !metadata.file_type().is_socket() {
+            return Err(invalid_input(&format!("`{}` exists, and isn't a socket", socket)))
+        }
+        if UnixStream::connect(&socket).is_ok() {
+            let msg = format!("`{}` is in use by another daemon", socket);
+            return Err(io::Error::new(io::ErrorKind::AddrInUse, msg))
+        }
+        fs::remove_file(&socket).context(|| format!("while removing `{}`", socket))?;
+    }
+    let listener = UnixListener::bind(&socket).context(|| format!("while listening on `{}`", socket))?;
+    // Accepting without blocking lets us notice Ctrl-C
+    listener.set_nonblocking(true)?;
+    let cancel = cancel_on_interrupt()?;
+    eprintln!("listening on `{}`, press Ctrl-C to stop", socket);
+    let result = loop {
+        if cancel.load(Ordering::Relaxed) {
+            break Ok(())
+        }
+        match listener.accept() {
+            Ok((stream, _)) => {
+                let models = models.clone();
+                thread::spawn(move || {
+                    let result = stream.set_nonblocking(false).and_then(|_| daemon::serve(stream, models));
+                    if let Err(e) = result {
+                        eprintln!("huffman: {}", e);
+                    }
+                });
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => thread::sleep(Duration::from_millis(100)),
+            Err(e) => break Err(e)
+        }
+    };
+    let _ = fs::remove_file(&socket);
+    result
+}
+
+#[cfg(not(unix))]
+fn run_daemon(_socket: String, _models: Vec<String>) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "the daemon needs Unix sockets"))
+}
+
+#[cfg(test)]
+mod test {
+    use std::ffi::OsString;
+    use std::fs;
+    use std::io;
+    use std::io::Write;
+    use std::sync::Mutex;
+    use std::sync::atomic::AtomicBool;
+    use std::time::Duration;
+    use structopt::StructOpt;
+    use crate::armor;
+    use crate::blocks::BlockWriter;
+    use crate::coding;
+    use crate::config::Config;
+    use crate::header::Method;
+    use super::{create_output, default_output, encode_all, watch, expand_wildcards, find_encoded, for_each_input, report, stats_line, test_file, try_program, Backup, EncodeOpt, Opt, Outcome, Rate, Recovered, Size, Snapshot, ERROR_STATUS, SUCCESS_STATUS, WARNING_STATUS};
+
+    #[test]
+    fn running_on_streams_works() {
+        let input = b"a man, a plan, a canal: panama";
+        let mut encoded = Vec::new();
+        let args = vec!["huffman", "encode", "--method", "rle", "--armor"];
+        match Opt::run_on_streams(args, io::Cursor::new(&input[..]), &mut encoded).unwrap() {
+            Outcome::Encoded(stats) => assert_eq!(stats.bytes_in, input.len() as u64),
+            outcome => panic!("expected encoding, got {:?}", outcome)
+        }
+        let mut decoded = Vec::new();
+        let args = vec!["huffman", "decode", "--offset", "2", "--length", "3"];
+        Opt::run_on_streams(args, io::Cursor::new(encoded), &mut decoded).unwrap();
+        assert_eq!(decoded, b"man");
+
+        let args = vec!["huffman", "inspect"];
+        assert!(Opt::run_on_streams(args, io::Cursor::new(&input[..]), io::sink()).is_err());
+    }
+
+    #[test]
+    fn parsing_rates_works() {
+        assert_eq!("512".parse(), Ok(Rate(512)));
+        assert_eq!("10M".parse(), Ok(Rate(10 << 20)));
+        assert_eq!("1g".parse(), Ok(Rate(1 << 30)));
+        assert!("0".parse::<Rate>().is_err());
+        assert!("M".parse::<Rate>().is_err());
+        assert!("10MB".parse::<Rate>().is_err());
+        assert_eq!("0".parse(), Ok(Size(0)));
+        assert_eq!("64K".parse(), Ok(Size(64 << 10)));
+        assert!("-1".parse::<Size>().is_err());
+    }
+
+    #[test]
+    fn outputs_are_named_after_the_suffix() {
+        assert_eq!(default_output("notes.txt", None, false).unwrap(), "notes.txt.huf");
+        assert_eq!(default_output("notes.txt", Some(".hz"), false).unwrap(), "notes.txt.hz");
+        assert_eq!(default_output("notes.txt.hz", Some(".hz"), true).unwrap(), "notes.txt");
+        assert_eq!(default_output("notes.txt.huf", Some(".hz"), true).unwrap(), "notes.txt.huf.out");
+        assert_eq!(default_output(".hz", Some(".hz"), true).unwrap(), ".hz.out");
+        assert!(default_output("notes.txt", Some(""), false).is_err());
+    }
+
+    #[test]
+    fn stats_show_the_ratio_once_something_was_written() {
+        let mut snapshot = Snapshot { bytes_in: 4_000_000, bytes_out: 0, read: 4_000_000, elapsed: Duration::from_secs(2), finished: false };
+        assert_eq!(stats_line(&snapshot), "4.0 MB in, 0.0 MB out, no ratio yet, 2.0 MB/s");
+        snapshot.bytes_out = 2_500_000;
+        assert_eq!(stats_line(&snapshot), "4.0 MB in, 2.5 MB out, ratio 62.50%, 2.0 MB/s");
+    }
+
+    #[test]
+    fn configured_flags_can_be_turned_off() {
+        let config = Config { armor: true, verify: true, timings: true, ..Default::default() };
+        let mut opt = Opt::from_iter_safe(["huffman", "encode", "--no-armor", "--no-timings", "notes.txt"]).unwrap();
+        opt.apply_config(&config);
+        match opt {
+            Opt::Encode(opt) => assert_eq!((opt.armor, opt.verify, opt.timings), (false, true, false)),
+            _ => unreachable!()
+        }
+        assert!(Opt::from_iter_safe(["huffman", "encode", "--armor", "--no-armor", "notes.txt"]).is_err());
+        let mut opt = Opt::from_iter_safe(["huffman", "watch", "--no-armor", "inbox"]).unwrap();
+        opt.apply_config(&config);
+        match opt {
+            Opt::Watch { armor, .. } => assert!(!armor),
+            _ => unreachable!()
+        }
+    }
+
+    #[test]
+    fn reproducible_outputs_ignore_the_configuration() {
+        let config = Config { method: Some(Method::RleHuffman), armor: true, suffix: Some(".hz".to_string()), ..Default::default() };
+        for (reproducible, method) in [(false, Some(Method::RleHuffman)), (true, None)] {
+            let args = ["huffman", "encode", "notes.txt"].iter().chain(if reproducible { Some(&"--reproducible") } else { None });
+            let mut opt = Opt::from_iter_safe(args).unwrap();
+            opt.apply_config(&config);
+            match opt {
+                Opt::Encode(opt) => assert_eq!((opt.method, opt.armor, opt.suffix.as_deref()), (method, !reproducible, Some(".hz"))),
+                _ => unreachable!()
+            }
+        }
+        assert!(Opt::from_iter_safe(["huffman", "encode", "--reproducible", "--cache-models", "notes.txt"]).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn other_programs_get_compared() {
+        let (size, _, _) = try_program("cat", &[], &[], b"abracadabra").unwrap();
+        assert_eq!(size, 11);
+        assert_eq!(try_program("huffman-no-such-program", &[], &[], b"abc").unwrap_err(), "not installed");
+        assert_eq!(try_program("head", &["-c", "3"], &[], b"abracadabra").unwrap_err(), "didn't give back the input");
+    }
+
+    #[test]
+    fn encoding_many_files_keeps_their_order() {
+        let dir = std::env::temp_dir().join(format!("huffman-encode-all-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let names = ["a.log", "missing.log", "b.log", "c.log"];
+        for name in names.iter().filter(|&&name| name != "missing.log") {
+            fs::write(dir.join(name), name.repeat(100)).unwrap();
+        }
+        let paths: Vec<_> = names.iter().map(|name| dir.join(name)).collect();
+        let opt = EncodeOpt::new("", String::new());
+        let results = encode_all(&paths, &opt, ".huf", true, 2);
+        let succeeded: Vec<_> = results.iter().map(|result| result.is_ok()).collect();
+        assert_eq!(succeeded, vec![true, false, true, true]);
+        assert!(dir.join("c.log.huf").exists() && !dir.join("c.log").exists());
+        let empty = watch(dir.display().to_string(), "*".to_string(), Duration::from_millis(1), false, String::new(), &opt, 1);
+        assert_eq!(empty.unwrap_err().to_string(), "the suffix can't be empty");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn overwritten_outputs_get_backed_up() {
+        let dir = std::env::temp_dir().join(format!("huffman-backup-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("a.txt");
+        let output = path.to_str().unwrap();
+        fs::write(&path, "first").unwrap();
+        drop(create_output(output, true, false, Some(Backup::Existing)).unwrap());
+        assert_eq!(fs::read(dir.join("a.txt~")).unwrap(), b"first");
+        fs::write(&path, "second").unwrap();
+        drop(create_output(output, true, false, Some(Backup::Numbered)).unwrap());
+        fs::write(&path, "third").unwrap();
+        drop(create_output(output, true, false, Some(Backup::Existing)).unwrap());
+        assert_eq!(fs::read(dir.join("a.txt.~1~")).unwrap(), b"second");
+        assert_eq!(fs::read(dir.join("a.txt.~2~")).unwrap(), b"third");
+        assert_eq!(fs::read(&path).unwrap(), b"");
+        assert_eq!("nil".parse(), Ok(Backup::Existing));
+        assert!("sometimes".parse::<Backup>().is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn only_checked_sizes_get_mapped() {
+        let dir = std::env::temp_dir().join(format!("huffman-mmap-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("a.huf");
+        let input = path.to_str().unwrap();
+        let encode = |decoded_size| {
+            let options = coding::EncodeOptions { decoded_size, ..Default::default() };
+            let mut encoded = Vec::new();
+            coding::encode_stream_with(&mut io::Cursor::new(b"abracadabra"), &mut encoded, options).unwrap();
+            encoded
+        };
+        let mut armored = Vec::new();
+        let mut armor = armor::Encoder::new(&mut armored).unwrap();
+        armor.write_all(&encode(Some(11))).unwrap();
+        armor.finish().unwrap();
+        let mut encoded = encode(Some(11));
+        fs::write(&path, &encoded).unwrap();
+        assert_eq!(super::mapped_size(input, &Default::default()).unwrap(), 11);
+        // An output that's already there is left alone when decoding refuses to overwrite it
+        let out = dir.join("out.txt");
+        fs::write(&out, "mine").unwrap();
+        let args = ["huffman", "decode", "--mmap", "--no-clobber", input, "-o", out.to_str().unwrap()];
+        let e = Opt::from_iter_safe(args).unwrap().dispatch().unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::AlreadyExists);
+        assert_eq!(fs::read(&out).unwrap(), b"mine");
+        let args = ["huffman", "decode", "--mmap", "--force", input, "-o", out.to_str().unwrap()];
+        Opt::from_iter_safe(args).unwrap().dispatch().unwrap();
+        assert_eq!(fs::read(&out).unwrap(), b"abracadabra");
+        // The size is the last field of the header, which its checksum covers
+        encoded[12] = 0x7f;
+        fs::write(&path, &encoded).unwrap();
+        assert!(super::mapped_size(input, &Default::default()).is_err());
+        for encoded in [encode(None), armored] {
+            fs::write(&path, &encoded).unwrap();
+            let e = super::mapped_size(input, &Default::default()).unwrap_err();
+            assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn indexed_files_only_decode_the_blocks_asked_for() {
+        let dir = std::env::temp_dir().join(format!("huffman-indexed-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let data: Vec<u8> = (0..3500u32).map(|i| (i % 7 * 30 + i / 100 % 3) as u8).collect();
+        let mut writer = BlockWriter::with_block_size(Vec::new(), 1000).unwrap();
+        writer.write_all(&data).unwrap();
+        let mut file = writer.finish().unwrap();
+        // Damage to the first block doesn't matter to a range after it
+        file[20] ^= 1;
+        let path = dir.join("a.huf");
+        fs::write(&path, &file).unwrap();
+        let out = dir.join("out");
+        let decode = |args: &[&str]| {
+            let mut all = vec!["huffman", "decode", "-f", path.to_str().unwrap(), "-o", out.to_str().unwrap()];
+            all.extend(args);
+            Opt::from_iter_safe(all).unwrap().dispatch()
+        };
+        decode(&["--offset", "2500", "--length", "600"]).unwrap();
+        assert_eq!(fs::read(&out).unwrap(), data[2500..3100]);
+        decode(&["--offset", "3400"]).unwrap();
+        assert_eq!(fs::read(&out).unwrap(), data[3400..]);
+        assert!(decode(&[]).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn failing_files_stop_the_run_unless_errors_are_ignored() {
+        let inputs = ["b.txt".to_string(), "c.txt".to_string(), "d.txt".to_string()];
+        let run = |ignore_errors, jobs| {
+            let tried = Mutex::new(Vec::new());
+            let result = for_each_input("a.txt", &inputs, ignore_errors, jobs, "skipped", |input| {
+                tried.lock().unwrap().push(input.clone());
+                match input.as_str() {
+                    "b.txt" => Err(io::Error::new(io::ErrorKind::AlreadyExists, "`b.txt.huf` already exists")),
+                    "c.txt" => Err(io::Error::new(io::ErrorKind::PermissionDenied, "while opening `c.txt`")),
+                    _ => Ok(())
+                }
+            });
+            (report(result), tried.into_inner().unwrap().len())
+        };
+        assert_eq!(run(false, 1), (ERROR_STATUS, 3));
+        assert_eq!(run(true, 1), (ERROR_STATUS, 4));
+        assert_eq!(run(true, 4), (ERROR_STATUS, 4));
+        let skipped = for_each_input("a.txt", &inputs[..1], false, 2, "skipped", |_| Err(io::Error::from(io::ErrorKind::AlreadyExists)));
+        assert_eq!(skipped.as_ref().unwrap_err().to_string(), "2 of the 2 files were skipped");
+        assert_eq!(report(skipped), WARNING_STATUS);
+        let dumped = Opt::from_iter_safe(["huffman", "encode", "--dump-tree", "tree.json", "a.txt", "b.txt"]).unwrap();
+        assert_eq!(dumped.dispatch().unwrap_err().kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn encoded_files_under_a_directory_get_tested() {
+        let dir = std::env::temp_dir().join(format!("huffman-test-{}", std::process::id()));
+        fs::create_dir_all(dir.join("logs")).unwrap();
+        let mut encoded = Vec::new();
+        crate::coding::encode_stream(&mut io::Cursor::new("abracadabra".repeat(50)), &mut encoded).unwrap();
+        fs::write(dir.join("logs/b.huf"), &encoded).unwrap();
+        fs::write(dir.join("a.huf"), &encoded[..encoded.len() / 2]).unwrap();
+        fs::write(dir.join("a.txt"), b"abracadabra").unwrap();
+
+        let (mut found, mut errors) = (Vec::new(), Vec::new());
+        find_encoded(&dir, ".huf", &mut found, &mut errors);
+        assert_eq!(found, vec![dir.join("a.huf"), dir.join("logs/b.huf")]);
+        find_encoded(&dir.join("missing"), ".huf", &mut found, &mut errors);
+        assert_eq!((found.len(), errors.len()), (2, 1));
+        let cancel = AtomicBool::new(false);
+        assert!(test_file(&found[0], &cancel).is_err());
+        assert!(test_file(&found[1], &cancel).is_ok());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn wildcards_get_expanded() {
+        let dir = std::env::temp_dir().join(format!("huffman-wildcards-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        for name in ["a.log", "B.LOG", "c.txt"] {
+            fs::write(dir.join(name), name).unwrap();
+        }
+        let pattern = dir.join("*.log").into_os_string();
+        let mut encode: Vec<OsString> = vec!["huffman".into(), "encode".into(), "-f".into(), pattern.clone(), "x?.txt".into()];
+        expand_wildcards(&mut encode);
+        let (upper, lower) = (dir.join("B.LOG").into_os_string(), dir.join("a.log").into_os_string());
+        assert_eq!(encode, vec!["huffman".into(), "encode".into(), "-f".into(), upper, lower, OsString::from("x?.txt")]);
+        let mut watch: Vec<OsString> = vec!["huffman".into(), "watch".into(), pattern];
+        expand_wildcards(&mut watch);
+        assert_eq!(watch.len(), 3);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn exit_statuses_follow_gzip() {
+        assert_eq!(report(Ok(())), SUCCESS_STATUS);
+        assert_eq!(report(Err(io::Error::new(io::ErrorKind::AlreadyExists, "`a.huf` exists"))), WARNING_STATUS);
+        assert_eq!(report(Err(io::Error::other("broken"))), ERROR_STATUS);
+        let recovered = Recovered { bytes: 10, source: io::Error::new(io::ErrorKind::UnexpectedEof, "cut short") };
+        assert_eq!(report(Err(io::Error::new(io::ErrorKind::UnexpectedEof, recovered))), WARNING_STATUS);
+    }
+}
@@ -0,0 +1,97 @@
+//! This module frames messages compressed with a shared model, for message based protocols.
+//! Every frame is the length of the compressed message, as a big endian u32, followed by
+//! that message, coded as raw Huffman codes, like the daemon does with its models.
+//!
+//! `FrameCodec` has the shape of the `Encoder` and `Decoder` traits of `tokio_util::codec`,
+//! appending frames to a buffer, and taking them out of one once they've fully arrived,
+//! so implementing those traits for it only takes passing a `BytesMut` along.
+use std::io;
+use std::sync::Arc;
+use crate::daemon::{Model, MAX_PAYLOAD};
+
+
+// How many bytes the length of a frame takes
+const LENGTH_SIZE: usize = 4;
+
+
+/// Compresses messages into frames, and decompresses them back, with a model shared
+/// by every codec using it, like all the connections of a server
+#[derive(Clone)]
+pub struct FrameCodec {
+    model: Arc<Model>,
+    max_frame: u32
+}
+
+impl FrameCodec {
+    pub fn new(model: Arc<Model>) -> Self {
+        FrameCodec { model, max_frame: MAX_PAYLOAD }
+    }
+
+    /// Reject frames longer than this many bytes, instead of waiting for them to arrive
+    pub fn with_max_frame(model: Arc<Model>, max_frame: u32) -> Self {
+        FrameCodec { model, max_frame }
+    }
+
+    /// Compress a message, appending its frame to a buffer
+    pub fn encode(&mut self, message: &[u8], dst: &mut Vec<u8>) -> io::Result<()> {
+        let encoded = self.model.encode(message)?;
+        if encoded.len() > self.max_frame as usize {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("frames can't be longer than {} bytes", self.max_frame)))
+        }
+        dst.extend_from_slice(&(encoded.len() as u32).to_be_bytes());
+        dst.extend_from_slice(&encoded);
+        Ok(())
+    }
+
+    /// Take the first frame out of a buffer, returning its message,
+    /// or None if the whole frame hasn't arrived yet
+    pub fn decode(&mut self, src: &mut Vec<u8>) -> io::Result<Option<Vec<u8>>> {
+        if src.len() < LENGTH_SIZE {
+            return Ok(None)
+        }
+        let len = u32::from_be_bytes([src[0], src[1], src[2], src[3]]);
+        if len > self.max_frame {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("frames can't be longer than {} bytes", self.max_frame)))
+        }
+        let end = LENGTH_SIZE + len as usize;
+        if src.len() < end {
+            return Ok(None)
+        }
+        let message = self.model.decode(&src[LENGTH_SIZE..end])?;
+        src.drain(..end);
+        Ok(Some(message))
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use crate::coding::Frequencies;
+    use crate::daemon::Model;
+    use super::FrameCodec;
+
+    #[test]
+    fn frames_come_out_once_they_arrive() {
+        let freqs = Frequencies::count_reader(&mut &b"hello world"[..]).unwrap();
+        let mut codec = FrameCodec::new(Arc::new(Model::new(&freqs)));
+        let mut sent = Vec::new();
+        codec.encode(b"hello", &mut sent).unwrap();
+        codec.encode(b"world", &mut sent).unwrap();
+
+        // The frames arrive a byte at a time
+        let mut received = Vec::new();
+        let mut messages = Vec::new();
+        for &byte in &sent {
+            received.push(byte);
+            if let Some(message) = codec.decode(&mut received).unwrap() {
+                messages.push(message);
+            }
+        }
+        assert_eq!(messages, [b"hello".to_vec(), b"world".to_vec()]);
+        assert!(received.is_empty());
+
+        let mut small = FrameCodec::with_max_frame(Arc::new(Model::new(&freqs)), 2);
+        assert!(small.decode(&mut sent.clone()).is_err());
+    }
+}
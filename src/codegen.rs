@@ -0,0 +1,135 @@
+//! This module generates source code containing static tables for a Huffman tree,
+//! so that programs can encode and decode data without building the tree at runtime.
+use std::io;
+use std::str::FromStr;
+use crate::coding::{HuffTree, HuffWriter};
+
+
+/// The languages we can generate tables for
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Lang {
+    Rust,
+    C
+}
+
+impl FromStr for Lang {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "rust" => Ok(Lang::Rust),
+            "c" => Ok(Lang::C),
+            _ => Err(format!("unknown language `{}`, expected rust or c", s))
+        }
+    }
+}
+
+
+const ENCODE_DOC: &str = "The code of each byte, sent from the least significant bit, \
+    and its length in bits. Bytes with a length of 0 can't be encoded.";
+const DECODE_DOC: &str = "The children of each branch of the tree, starting with the root. \
+    The next bit picks the first child if it is 0, and the second otherwise. \
+    Children below 256 are bytes, 256 is the end of the transmission, and \
+    any other child is the branch at that index minus 257.";
+
+/// Write out source code for the tables of this tree in some language
+pub fn generate<W: io::Write>(tree: &HuffTree, lang: Lang, writer: &mut W) -> io::Result<()> {
+    let encoder = HuffWriter::from_tree(tree);
+    let codes: Vec<_> = (0..=255).map(|b| encoder.code(b).unwrap_or((0, 0))).collect();
+    let (eof_bits, eof_len) = encoder.eof_code();
+    let branches = tree.flatten();
+    match lang {
+        Lang::Rust => {
+            writeln!(writer, "// Generated by `huffman codegen`, do not edit.")?;
+            writeln!(writer)?;
+            writeln!(writer, "/// {}", ENCODE_DOC)?;
+            writeln!(writer, "pub const ENCODE: [(u128, u8); 256] = [")?;
+            for (bits, len) in codes {
+                writeln!(writer, "    ({:#x}, {}),", bits, len)?;
+            }
+            writeln!(writer, "];")?;
+            writeln!(writer)?;
+            writeln!(writer, "/// The code for the end of the transmission, and its length in bits.")?;
+            writeln!(writer, "pub const EOF: (u128, u8) = ({:#x}, {});", eof_bits, eof_len)?;
+            writeln!(writer)?;
+            writeln!(writer, "/// {}", DECODE_DOC)?;
+            writeln!(writer, "pub const DECODE: [(u16, u16); {}] = [", branches.len())?;
+            for (left, right) in branches {
+                writeln!(writer, "    ({}, {}),", left, right)?;
+            }
+            writeln!(writer, "];")
+        }
+        Lang::C => {
+            writeln!(writer, "/* Generated by `huffman codegen`, do not edit. */")?;
+            writeln!(writer)?;
+            writeln!(writer, "#include <stdint.h>")?;
+            writeln!(writer)?;
+            writeln!(writer, "/* {} */", ENCODE_DOC)?;
+            writeln!(writer, "/* Each code is split into its low and high 64 bits. */")?;
+            writeln!(writer, "static const uint64_t HUFFMAN_ENCODE[256][2] = {{")?;
+            for &(bits, _) in &codes {
+                writeln!(writer, "    {{{:#x}u, {:#x}u}},", bits as u64, (bits >> 64) as u64)?;
+            }
+            writeln!(writer, "}};")?;
+            writeln!(writer, "static const uint8_t HUFFMAN_ENCODE_LEN[256] = {{")?;
+            for &(_, len) in &codes {
+                writeln!(writer, "    {},", len)?;
+            }
+            writeln!(writer, "}};")?;
+            writeln!(writer)?;
+            writeln!(writer, "/* The code for the end of the transmission, and its length in bits. */")?;
+            writeln!(
+                writer,
+                "static const uint64_t HUFFMAN_EOF[2] = {{{:#x}u, {:#x}u}};",
+                eof_bits as u64,
+                (eof_bits >> 64) as u64
+            )?;
+            writeln!(writer, "static const uint8_t HUFFMAN_EOF_LEN = {};", eof_len)?;
+            writeln!(writer)?;
+            writeln!(writer, "/* {} */", DECODE_DOC)?;
+            writeln!(writer, "static const uint16_t HUFFMAN_DECODE[{}][2] = {{", branches.len())?;
+            for (left, right) in branches {
+                writeln!(writer, "    {{{}, {}}},", left, right)?;
+            }
+            writeln!(writer, "}};")
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use crate::coding::{Frequencies, HuffTree, HuffWriter};
+    use super::{generate, Lang};
+
+    #[test]
+    fn tables_get_generated() {
+        let freqs = Frequencies::count_reader(&mut &b"abracadabra"[..]).unwrap();
+        let tree = HuffTree::from_freqs(&freqs);
+        let encoder = HuffWriter::from_tree(&tree);
+        let (a_bits, a_len) = encoder.code(b'a').unwrap();
+        let (eof_bits, eof_len) = encoder.eof_code();
+        let branches = tree.flatten();
+
+        let mut rust = Vec::new();
+        generate(&tree, Lang::Rust, &mut rust).unwrap();
+        let rust = String::from_utf8(rust).unwrap();
+        let encode = rust.lines().skip_while(|line| !line.starts_with("pub const ENCODE")).skip(1);
+        let encode: Vec<_> = encode.take_while(|line| *line != "];").collect();
+        assert_eq!(encode.len(), 256);
+        assert_eq!(encode[b'a' as usize], format!("    ({:#x}, {}),", a_bits, a_len));
+        assert_eq!(encode[b'z' as usize], "    (0x0, 0),");
+        assert!(rust.contains(&format!("pub const EOF: (u128, u8) = ({:#x}, {});", eof_bits, eof_len)));
+        assert!(rust.contains(&format!("pub const DECODE: [(u16, u16); {}] = [", branches.len())));
+        assert!(rust.contains(&format!("    ({}, {}),", branches[0].0, branches[0].1)));
+
+        let mut c = Vec::new();
+        generate(&tree, Lang::C, &mut c).unwrap();
+        let c = String::from_utf8(c).unwrap();
+        assert!(c.contains("static const uint64_t HUFFMAN_ENCODE[256][2] = {"));
+        assert!(c.contains(&format!("static const uint8_t HUFFMAN_EOF_LEN = {};", eof_len)));
+        assert!(c.contains(&format!("static const uint16_t HUFFMAN_DECODE[{}][2] = {{", branches.len())));
+        assert!(c.contains(&format!("    {{{}, {}}},", branches[0].0, branches[0].1)));
+        assert_eq!("go".parse::<Lang>(), Err("unknown language `go`, expected rust or c".to_string()));
+    }
+}
@@ -1,7 +1,26 @@
 //! This module contains the data structures and functions related
 //! to actually encoding data with Huffman coding
+use std::collections::VecDeque;
+use std::error;
+use std::fmt;
 use std::io;
+use std::io::{BufRead, Read, Write};
+use std::iter;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use crate::armor;
+use crate::checksum::Crc32;
+use crate::context::{self, Context};
+use crate::header::{Header, Method, VERSION};
+use crate::inspect::{show_byte, show_code};
 use crate::queue::PriorityQueue;
+use crate::rice;
+use crate::rle;
+use crate::spool::Spool;
+use crate::stride;
+use crate::symbols;
 
 
 // Like write_u64, but we may not write all the bytes
@@ -20,11 +39,16 @@ fn write_u128_trimmed<W: io::Write>(writer: &mut W, mut num: u128, significant:
     writer.write_all(&bytes[..num_bytes])
 }
 
-// uses reverse network order, because we write bits in from LSB to MSB
-// in the u64, so we want the first byte to be the least significant
-fn write_u128<W: io::Write>(writer: &mut W, num: u128) -> io::Result<()> {
-    write_u128_trimmed(writer, num, 128)
-}
+
+/// The length of the longest code a tree can have, since codes
+/// need to fit in the 128 bits they're stored in
+pub const MAX_CODE_LEN: usize = 127;
+
+
+/// The child standing for the end of the transmission in a flattened tree
+pub const EOF_LEAF: usize = 256;
+/// The child standing for the first branch in a flattened tree
+pub const FIRST_BRANCH: usize = 257;
 
 
 /// A struct holding the frequencies of each character,
@@ -40,28 +64,93 @@ impl Frequencies {
     /// Count the number of occurrences of each byte in order to build
     /// up a struct of Frequencies
     pub fn count_bytes<E, I : IntoIterator<Item=Result<u8, E>>>(bytes: I) -> Result<Self, E> {
-        let mut acc: Vec<u64> = vec![0;256];
+        let mut acc = [0; 256];
         for maybe_byte in bytes {
             let b = maybe_byte?;
             // Always fine since the byte is in the index
             acc[b as usize] += 1;
         }
-        // There will always be at least one byte
-        let max = acc.iter().max().unwrap();
-        let mut pairs = Vec::with_capacity(acc.len());
+        Ok(Frequencies::from_counts(&acc))
+    }
+
+    /// Count the number of occurrences of each byte read from a reader, a buffer at a time,
+    /// which is much faster than going through count_bytes one byte at a time
+    pub fn count_reader<R: io::Read>(reader: &mut R) -> io::Result<Self> {
+        let mut counts = Histogram([0; 256]);
+        io::copy(reader, &mut counts)?;
+        Ok(Frequencies::from_counts(&counts.0))
+    }
+
+    /// Build up the frequencies from the number of occurrences of each byte
+    pub fn from_counts(counts: &[u64; 256]) -> Self {
+        let max = counts.iter().max().cloned().unwrap_or(0);
+        let mut pairs = Vec::with_capacity(counts.len());
         // This guarantees a consistent ordering of pairs, and thus of the H Tree
-        let mut byte = 0;
-        for &count in &acc {
+        for (byte, &count) in counts.iter().enumerate() {
             if count != 0 {
                 pairs.push(((count * 255 / max) as u8, byte as u8));
             }
-            byte += 1
         }
         // Sort pairs in reverse order by count
         pairs.sort_by(|(count1, _), (count2, _)| count2.cmp(count1));
+        Frequencies { pairs }
+    }
+
+    /// Build up the frequencies from the probability of each byte, like statistical tools give,
+    /// where bytes that are left out, or have a probability of 0, can't be coded.
+    /// The probabilities get rounded to counts out of 255 for the most likely byte,
+    /// which keeps their proportions to within a fraction of a percent of that byte.
+    pub fn from_probabilities(probabilities: &[(u8, f64)]) -> io::Result<Self> {
+        let invalid_input = |msg: String| io::Error::new(io::ErrorKind::InvalidInput, msg);
+        let mut by_byte = [0.0; 256];
+        let mut seen = [false; 256];
+        for &(byte, probability) in probabilities {
+            // This also rules out NaN
+            if !(0.0..=1.0).contains(&probability) {
+                let msg = format!("byte {} has a probability of {}, which isn't between 0 and 1", byte, probability);
+                return Err(invalid_input(msg))
+            }
+            if seen[byte as usize] {
+                return Err(invalid_input(format!("byte {} has more than one probability", byte)))
+            }
+            seen[byte as usize] = true;
+            by_byte[byte as usize] = probability;
+        }
+        // Leave some room for rounding errors in whatever computed the probabilities
+        let total: f64 = by_byte.iter().sum();
+        if total > 1.0 + 1e-6 {
+            return Err(invalid_input(format!("the probabilities add up to {}, which is more than 1", total)))
+        }
+        let max = by_byte.iter().cloned().fold(0.0, f64::max);
+        if max == 0.0 {
+            return Err(invalid_input("at least one byte needs a probability above 0".to_string()))
+        }
+        let mut pairs = Vec::with_capacity(probabilities.len());
+        for (byte, &probability) in by_byte.iter().enumerate() {
+            if probability > 0.0 {
+                pairs.push(((probability / max * 255.0).round() as u8, byte as u8));
+            }
+        }
+        // The same order as from_counts
+        pairs.sort_by(|(count1, _), (count2, _)| count2.cmp(count1));
         Ok(Frequencies { pairs })
     }
 
+    /// The (frequency, byte) pairs, in the order they're written out,
+    /// which is by decreasing frequency
+    pub fn pairs(&self) -> &[(u8, u8)] {
+        &self.pairs
+    }
+
+    /// The CRC-32 of the frequencies, as they're written out, which files coded with
+    /// them as a preset dictionary record, so that decoding can check it has the same ones
+    pub fn id(&self) -> u32 {
+        let mut crc = Crc32::new();
+        // Writing to a checksum can't fail
+        self.write(&mut crc).unwrap();
+        crc.sum()
+    }
+
     /// This function writes the frequencies as a sequence of
     /// (byte, frequency) pairs, preceded by the number of pairs
     /// it can read.
@@ -79,27 +168,73 @@ impl Frequencies {
         Ok(())
     }
 
-    /// Attempt to read the frequencies from a some source
+    /// Attempt to read the frequencies from a some source, which need to have between 1 and 256
+    /// pairs, each for a different byte. Errors give offsets from the start of the frequencies.
     pub fn read<R: io::Read>(reader: &mut R) -> io::Result<Self> {
         let mut num_buf: [u8; 4] = [0; 4];
         reader.read_exact(&mut num_buf)?;
-        let num = 
-            ((num_buf[0] as usize) << 24) | 
-            ((num_buf[1] as usize) << 16) |
-            ((num_buf[2] as usize) << 8)  |
-            (num_buf[3] as usize);
-        let mut pair_buf = vec![0; num * 2];
-        reader.read_exact(&mut pair_buf)?;
-        let mut pairs = Vec::with_capacity(num);
-        let mut i = 0;
-        while i < pair_buf.len() - 1 {
-            pairs.push((pair_buf[i + 1], pair_buf[i]));
-            i += 2
+        let num = u32::from_be_bytes(num_buf);
+        if num == 0 || num > 256 {
+            return Err(invalid_data(&format!("there are {} pairs, instead of between 1 and 256", num)))
+        }
+        let mut pair_buf = vec![0; num as usize * 2];
+        reader.read_exact(&mut pair_buf).context(|| format!("while reading the {} pairs, from byte 4 of the frequencies", num))?;
+        let mut offsets = [None; 256];
+        let mut pairs = Vec::with_capacity(pair_buf.len() / 2);
+        for (i, pair) in pair_buf.chunks_exact(2).enumerate() {
+            let (byte, count) = (pair[0], pair[1]);
+            let offset = 4 + 2 * i;
+            if let Some(first) = offsets[byte as usize] {
+                let msg = format!("byte {} has a second pair at byte {} of the frequencies, after the one at byte {}", byte, offset, first);
+                return Err(invalid_data(&msg))
+            }
+            offsets[byte as usize] = Some(offset);
+            pairs.push((count, byte));
+        }
+        Ok(Frequencies { pairs })
+    }
+
+    /// Describe the frequencies as a JSON object, with a list of
+    /// `{"byte": 101, "count": 255}` objects under `"frequencies"`
+    pub fn to_json(&self) -> String {
+        let pairs: Vec<_> = self.pairs.iter().map(|&(count, byte)| {
+            serde_json::json!({ "byte": byte, "count": count })
+        }).collect();
+        let json = serde_json::json!({ "frequencies": pairs });
+        // Serializing a value we built ourselves can't fail
+        serde_json::to_string_pretty(&json).unwrap()
+    }
+
+    /// Attempt to read the frequencies from JSON written by `to_json`
+    pub fn from_json(json: &str) -> io::Result<Self> {
+        let value: serde_json::Value = serde_json::from_str(json)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let entries = value["frequencies"].as_array()
+            .ok_or_else(|| invalid_data("expected a list of frequencies"))?;
+        let mut seen = [false; 256];
+        let mut pairs = Vec::with_capacity(entries.len());
+        for entry in entries {
+            let field = |name| entry[name].as_u64().filter(|&n| n <= 255).ok_or_else(|| {
+                invalid_data(&format!("expected a `{}` between 0 and 255 in {}", name, entry))
+            });
+            let byte = field("byte")? as u8;
+            let count = field("count")? as u8;
+            if seen[byte as usize] {
+                return Err(invalid_data(&format!("byte {} has more than one frequency", byte)))
+            }
+            seen[byte as usize] = true;
+            pairs.push((count, byte));
         }
+        // The same order as from_counts, so that the same tree gets built
+        pairs.sort_by(|(count1, byte1), (count2, byte2)| count2.cmp(count1).then(byte1.cmp(byte2)));
         Ok(Frequencies { pairs })
     }
 }
 
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
 
 /// Represents a Huffman decoding tree.
 /// 
@@ -118,131 +253,2590 @@ pub enum HuffTree {
 }
 
 impl HuffTree {
+    /// Build the tree for some frequencies, breaking ties between equal weights
+    /// the same way every version has, since decoding frequency tables and raw data
+    /// relies on building the same tree as the encoder did
     pub fn from_freqs(freqs: &Frequencies) -> Self {
+        HuffTree::build_limited(freqs, |freqs, floor| HuffTree::build(freqs, floor, false))
+    }
+
+    /// Build the tree for the probability of each byte, as checked and rounded by Frequencies::from_probabilities,
+    /// which gives the same tree as the frequencies would
+    pub fn from_probabilities(probabilities: &[(u8, f64)]) -> io::Result<Self> {
+        Frequencies::from_probabilities(probabilities).map(|freqs| HuffTree::from_freqs(&freqs))
+    }
+
+    /// Build a tree for some frequencies, which codes them just as compactly as from_freqs,
+    /// but breaks ties between equal weights by merging the shallowest trees first.
+    /// This minimizes the variance of the code lengths, keeping the longest code short,
+    /// but the tree needs to be written out, since from_freqs won't build it again.
+    pub fn with_min_variance(freqs: &Frequencies) -> Self {
+        HuffTree::build_limited(freqs, |freqs, floor| HuffTree::build(freqs, floor, true))
+    }
+
+    /// Build a Shannon–Fano tree for some frequencies, which lines the bytes up from most to least
+    /// frequent, and splits them in two where both halves weigh about the same, over and over.
+    /// This never codes the bytes more compactly than Huffman's algorithm, and often less so.
+    pub fn shannon_fano(freqs: &Frequencies) -> Self {
+        HuffTree::build_limited(freqs, HuffTree::build_shannon_fano)
+    }
+
+    /// Build an alphabetic tree for some frequencies with the Hu–Tucker algorithm, where the leaves
+    /// are in order, starting with the end of the transmission, and then the bytes from lowest to highest.
+    /// The codes are a bit longer than with Huffman's algorithm, but comparing what two inputs
+    /// get coded as, bit by bit, orders them the same way as comparing their bytes.
+    pub fn hu_tucker(freqs: &Frequencies) -> Self {
+        HuffTree::build_limited(freqs, HuffTree::build_hu_tucker)
+    }
+
+    fn build_limited<F: Fn(&Frequencies, u64) -> HuffTree>(freqs: &Frequencies, build: F) -> Self {
+        trace_span!("build_tree", pairs = freqs.pairs.len());
+        let mut tree = build(freqs, 0);
+        // Lots of rare bytes can make the codes longer than we can write,
+        // so we add to every frequency, evening them out, until that's not the case
+        let mut floor = 1;
+        while tree.depth() > MAX_CODE_LEN {
+            trace_event!(depth = tree.depth(), floor, "rebuilding a tree that is too deep");
+            tree = build(freqs, floor);
+            floor *= 2;
+        }
+        tree
+    }
+
+    // Build the tree, after adding floor to every frequency, where the queue is ordered
+    // by weight, and then by depth, if we're minimizing the variance of the code lengths
+    fn build(freqs: &Frequencies, floor: u64, min_variance: bool) -> Self {
         let pairs: Vec<_> = freqs.pairs.iter().map(|&(count, byte)| {
-            (count as u64, HuffTree::Known(byte))
+            ((count as u64 + floor, 0), HuffTree::Known(byte))
         }).collect();
         let mut q = PriorityQueue::from_data(pairs);
-        q.insert(0, HuffTree::EOF);
-        while let Some(((count1, tree1), (count2, tree2))) = q.remove_two() {
+        q.insert((floor, 0), HuffTree::EOF);
+        while let Some((((count1, depth1), tree1), ((count2, depth2), tree2))) = q.remove_two() {
             let branch = HuffTree::Branch(Box::new(tree1), Box::new(tree2));
-            q.insert(count1 + count2, branch);
+            let depth = if min_variance { 1 + depth1.max(depth2) } else { 0 };
+            q.insert((count1 + count2, depth), branch);
         }
         // The q will always have one left
         q.remove().unwrap().1
     }
+
+    // Build the Shannon–Fano tree, after adding floor to every frequency,
+    // where None stands for the end of the transmission
+    fn build_shannon_fano(freqs: &Frequencies, floor: u64) -> Self {
+        // Every symbol gets a sliver of weight on top, less than any count, so that runs of
+        // equal weights, like all the zeros, get split down the middle instead of one at a time
+        let weight = |count| (count + floor) * 512 + 1;
+        let mut symbols: Vec<_> = freqs.pairs.iter().map(|&(count, byte)| (weight(count as u64), Some(byte))).collect();
+        symbols.push((weight(0), None));
+        // Frequencies read from a file aren't necessarily sorted
+        symbols.sort_by(|(count1, _), (count2, _)| count2.cmp(count1));
+        HuffTree::split(&symbols)
+    }
+
+    // Split symbols sorted by weight where both halves weigh the closest, and then each half
+    fn split(symbols: &[(u64, Option<u8>)]) -> Self {
+        if let [(_, symbol)] = symbols {
+            return match symbol {
+                Some(byte) => HuffTree::Known(*byte),
+                None => HuffTree::EOF
+            }
+        }
+        let total: u64 = symbols.iter().map(|(count, _)| count).sum();
+        let mut before = 0;
+        let (_, at) = (1..symbols.len()).map(|at| {
+            before += symbols[at - 1].0;
+            ((2 * before).abs_diff(total), at)
+        }).min().unwrap();
+        let left = HuffTree::split(&symbols[..at]);
+        let right = HuffTree::split(&symbols[at..]);
+        HuffTree::Branch(Box::new(left), Box::new(right))
+    }
+
+    // Build the alphabetic tree, after adding floor to every frequency
+    fn build_hu_tucker(freqs: &Frequencies, floor: u64) -> Self {
+        let mut symbols: Vec<_> = freqs.pairs.iter().map(|&(count, byte)| (count as u64 + floor, Some(byte))).collect();
+        symbols.sort_by_key(|&(_, byte)| byte);
+        symbols.insert(0, (floor, None));
+        // First, we merge the two lightest nodes over and over, like Huffman's algorithm,
+        // but only nodes with no leaf between them, to find how deep each leaf goes.
+        // Nodes hold their weight, whether they're a leaf, and the symbols under them.
+        let mut nodes: Vec<_> = symbols.iter().enumerate().map(|(i, &(count, _))| (count, true, vec![i])).collect();
+        let mut depths = vec![0; symbols.len()];
+        while nodes.len() > 1 {
+            // Ties go to the leftmost pair
+            let mut best = (u64::MAX, 0, 0);
+            for i in 0..nodes.len() {
+                for j in i + 1..nodes.len() {
+                    if nodes[i].0 + nodes[j].0 < best.0 {
+                        best = (nodes[i].0 + nodes[j].0, i, j);
+                    }
+                    if nodes[j].1 {
+                        break;
+                    }
+                }
+            }
+            let (weight, i, j) = best;
+            let (_, _, merged) = nodes.remove(j);
+            let node = &mut nodes[i];
+            *node = (weight, false, node.2.drain(..).chain(merged).collect());
+            for &symbol in &node.2 {
+                depths[symbol] += 1;
+            }
+        }
+        // Then, we put the leaves back in order, at those depths, which Hu and Tucker showed always works
+        let leaves = symbols.iter().zip(&depths).map(|(&(_, symbol), &depth)| {
+            let leaf = match symbol {
+                Some(byte) => HuffTree::Known(byte),
+                None => HuffTree::EOF
+            };
+            (depth, leaf)
+        });
+        HuffTree::from_ordered_leaves(leaves).unwrap()
+    }
+
+    // Build the tree with these leaves from left to right, at these depths,
+    // returning None if they don't make up a tree where every branch has two children
+    fn from_ordered_leaves<I: IntoIterator<Item = (usize, HuffTree)>>(leaves: I) -> Option<Self> {
+        // The depths on the stack only ever go up, so the top two
+        // nodes have to be siblings once they're at the same depth
+        let mut stack: Vec<(usize, HuffTree)> = Vec::new();
+        for leaf in leaves {
+            stack.push(leaf);
+            while stack.len() >= 2 && stack[stack.len() - 1].0 == stack[stack.len() - 2].0 {
+                let (depth, right) = stack.pop().unwrap();
+                let (_, left) = stack.pop().unwrap();
+                stack.push((depth.checked_sub(1)?, HuffTree::Branch(Box::new(left), Box::new(right))));
+            }
+        }
+        match stack.pop() {
+            Some((0, tree)) if stack.is_empty() => Some(tree),
+            _ => None
+        }
+    }
+
+    /// The length of the code of every byte, followed by that of the end of the transmission,
+    /// where bytes that aren't in the tree have a length of 0
+    pub fn to_code_lengths(&self) -> [u8; 257] {
+        let mut lengths = [0; 257];
+        let mut trees = vec![(self, 0)];
+        while let Some((tree, depth)) = trees.pop() {
+            match tree {
+                HuffTree::Branch(left, right) => {
+                    trees.push((left, depth + 1));
+                    trees.push((right, depth + 1));
+                }
+                HuffTree::Known(byte) => lengths[*byte as usize] = depth,
+                HuffTree::EOF => lengths[EOF_LEAF] = depth
+            }
+        }
+        lengths
+    }
+
+    /// Attempt to build the canonical tree for the lengths of the codes of every byte,
+    /// followed by that of the end of the transmission, like to_code_lengths gives.
+    /// The leaves go from left to right by increasing length, and then by byte, with
+    /// the end of the transmission last, which is how DEFLATE and most other formats
+    /// assign codes given their lengths, so that they can be exchanged with them.
+    pub fn from_code_lengths(lengths: &[u8]) -> io::Result<Self> {
+        let invalid_input = |msg: &str| io::Error::new(io::ErrorKind::InvalidInput, msg);
+        if lengths.len() != 257 {
+            return Err(invalid_input("there should be 257 code lengths, 256 for bytes, and one for the end of the transmission"))
+        }
+        // Without any bytes, the end of the transmission is the whole tree
+        if lengths.iter().all(|&length| length == 0) {
+            return Ok(HuffTree::EOF)
+        }
+        if lengths[EOF_LEAF] == 0 {
+            return Err(invalid_input("the end of the transmission needs a code"))
+        }
+        if lengths.iter().any(|&length| length as usize > MAX_CODE_LEN) {
+            return Err(invalid_input(&format!("codes can't be longer than {} bits", MAX_CODE_LEN)))
+        }
+        let mut symbols: Vec<_> = (0..lengths.len()).filter(|&symbol| lengths[symbol] > 0).collect();
+        symbols.sort_by_key(|&symbol| lengths[symbol]);
+        let leaves = symbols.into_iter().map(|symbol| {
+            let leaf = if symbol == EOF_LEAF { HuffTree::EOF } else { HuffTree::Known(symbol as u8) };
+            (lengths[symbol] as usize, leaf)
+        });
+        HuffTree::from_ordered_leaves(leaves).ok_or_else(|| invalid_input("the code lengths don't make up a complete tree"))
+    }
+
+    /// How many bytes writing the tree out with write_succinct takes
+    pub fn succinct_size(&self) -> u64 {
+        let leaves = self.leaf_count() as u64;
+        2 + (2 * leaves - 1).div_ceil(8) + leaves - 1
+    }
+
+    /// Write out the shape of the tree, along with its leaves, taking about 10 bits a leaf.
+    /// This starts with the position of the end of the transmission among the leaves,
+    /// as 2 bytes, then a bit for each node, in pre-order, 1 for branches and 0 for leaves,
+    /// padded to a whole byte, and finally the byte of every other leaf, in pre-order.
+    pub fn write_succinct<W: io::Write>(&self, writer: &mut W) -> io::Result<()> {
+        let mut shape: BitWriter = BitWriter::new();
+        let mut shape_bytes = Vec::new();
+        let mut leaves = Vec::new();
+        let mut eof = 0;
+        let mut pending = vec![self];
+        while let Some(tree) = pending.pop() {
+            match tree {
+                HuffTree::Branch(left, right) => {
+                    shape.write_bits(1, 1, &mut shape_bytes)?;
+                    pending.push(right);
+                    pending.push(left);
+                }
+                HuffTree::Known(byte) => {
+                    shape.write_bits(0, 1, &mut shape_bytes)?;
+                    leaves.push(*byte);
+                }
+                HuffTree::EOF => {
+                    shape.write_bits(0, 1, &mut shape_bytes)?;
+                    eof = leaves.len();
+                }
+            }
+        }
+        shape.flush_bits(&mut shape_bytes)?;
+        writer.write_all(&(eof as u16).to_be_bytes())?;
+        writer.write_all(&shape_bytes)?;
+        writer.write_all(&leaves)
+    }
+
+    /// Attempt to read a tree written with write_succinct
+    pub fn read_succinct<R: io::Read>(reader: &mut R) -> io::Result<Self> {
+        let mut eof = [0; 2];
+        reader.read_exact(&mut eof)?;
+        let eof = u16::from_be_bytes(eof) as usize;
+        // We read bits until every branch has both of its children
+        let mut shape = Vec::new();
+        let mut missing = 1;
+        let mut byte = [0];
+        while missing > 0 {
+            if shape.len() % 8 == 0 {
+                reader.read_exact(&mut byte)?;
+            }
+            let is_branch = (byte[0] >> (shape.len() % 8)) & 1 == 1;
+            shape.push(is_branch);
+            if is_branch {
+                missing += 1;
+            } else {
+                missing -= 1;
+            }
+            // 257 leaves need 256 branches
+            if shape.len() > 513 {
+                return Err(invalid_data("tree has too many leaves"))
+            }
+        }
+        let leaf_count = shape.iter().filter(|&&is_branch| !is_branch).count();
+        if eof >= leaf_count {
+            return Err(invalid_data("tree is missing the end of the transmission"))
+        }
+        let mut leaves = vec![0; leaf_count - 1];
+        reader.read_exact(&mut leaves)?;
+        let mut seen = [false; 256];
+        for &byte in &leaves {
+            if seen[byte as usize] {
+                return Err(invalid_data(&format!("byte {} is in the tree more than once", byte)))
+            }
+            seen[byte as usize] = true;
+        }
+        let mut leaf = 0;
+        HuffTree::from_shape(&mut shape.into_iter(), &mut leaves.into_iter(), eof, &mut leaf, 0)
+    }
+
+    // Build a tree from the pre-order shape and leaves of write_succinct,
+    // which are known to be complete, counting leaves to find the end of the transmission
+    fn from_shape<S, L>(shape: &mut S, leaves: &mut L, eof: usize, leaf: &mut usize, depth: usize) -> io::Result<Self>
+    where
+        S: Iterator<Item = bool>,
+        L: Iterator<Item = u8>
+    {
+        if depth > MAX_CODE_LEN {
+            return Err(invalid_data("tree is too deep"))
+        }
+        if shape.next() == Some(true) {
+            let left = HuffTree::from_shape(shape, leaves, eof, leaf, depth + 1)?;
+            let right = HuffTree::from_shape(shape, leaves, eof, leaf, depth + 1)?;
+            return Ok(HuffTree::Branch(Box::new(left), Box::new(right)))
+        }
+        let tree = if *leaf == eof {
+            HuffTree::EOF
+        } else {
+            HuffTree::Known(leaves.next().unwrap())
+        };
+        *leaf += 1;
+        Ok(tree)
+    }
+
+    /// Describe the tree as nested JSON objects, where branches hold the subtrees `"0"` and `"1"`,
+    /// reached by following those bits, and leaves hold their `"byte"`, or `"eof": true`
+    /// for the end of the transmission, along with their `"code"`, like `"0110"`
+    pub fn to_json(&self) -> String {
+        // Serializing a value we built ourselves can't fail
+        serde_json::to_string_pretty(&self.json_node(&mut String::new())).unwrap()
+    }
+
+    // The JSON of a subtree, whose root has this code
+    fn json_node(&self, code: &mut String) -> serde_json::Value {
+        match self {
+            HuffTree::Branch(left, right) => {
+                code.push('0');
+                let left = left.json_node(code);
+                code.pop();
+                code.push('1');
+                let right = right.json_node(code);
+                code.pop();
+                serde_json::json!({ "0": left, "1": right })
+            }
+            HuffTree::Known(byte) => serde_json::json!({ "byte": byte, "code": code }),
+            HuffTree::EOF => serde_json::json!({ "eof": true, "code": code })
+        }
+    }
+
+    /// Attempt to read a tree from JSON written by `to_json`, or by another tool. Leaves can
+    /// leave out their code, but it needs to match where they are when it's there.
+    pub fn from_json(json: &str) -> io::Result<Self> {
+        let value: serde_json::Value = serde_json::from_str(json)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let mut seen = [false; EOF_LEAF + 1];
+        let tree = HuffTree::from_json_node(&value, &mut String::new(), &mut seen)?;
+        if !seen[EOF_LEAF] {
+            return Err(invalid_data("the tree needs a leaf with `\"eof\": true`"))
+        }
+        Ok(tree)
+    }
+
+    // Read the subtree with this code, marking the symbols of its leaves as seen
+    fn from_json_node(value: &serde_json::Value, code: &mut String, seen: &mut [bool]) -> io::Result<Self> {
+        if code.len() > MAX_CODE_LEN {
+            return Err(invalid_data(&format!("the tree is deeper than {} levels", MAX_CODE_LEN)))
+        }
+        let at = if code.is_empty() { "the root".to_string() } else { format!("code {}", code) };
+        let node = value.as_object().ok_or_else(|| invalid_data(&format!("expected an object at {}", at)))?;
+        match (node.get("0"), node.get("1")) {
+            (Some(left), Some(right)) => {
+                code.push('0');
+                let left = HuffTree::from_json_node(left, code, seen)?;
+                code.pop();
+                code.push('1');
+                let right = HuffTree::from_json_node(right, code, seen)?;
+                code.pop();
+                return Ok(HuffTree::Branch(Box::new(left), Box::new(right)))
+            }
+            (None, None) => {}
+            _ => return Err(invalid_data(&format!("the branch at {} needs both a `0` and a `1`", at)))
+        }
+        let (symbol, tree) = match (node.get("byte"), node.get("eof")) {
+            (Some(byte), None) => {
+                let byte = byte.as_u64().filter(|&byte| byte <= 255)
+                    .ok_or_else(|| invalid_data(&format!("expected a `byte` between 0 and 255 at {}", at)))?;
+                (byte as usize, HuffTree::Known(byte as u8))
+            }
+            (None, Some(serde_json::Value::Bool(true))) => (EOF_LEAF, HuffTree::EOF),
+            _ => return Err(invalid_data(&format!("the leaf at {} needs either a `byte`, or `\"eof\": true`", at)))
+        };
+        if let Some(given) = node.get("code") {
+            if given.as_str() != Some(code.as_str()) {
+                return Err(invalid_data(&format!("the leaf at {} says its code is {}", at, given)))
+            }
+        }
+        if std::mem::replace(&mut seen[symbol], true) {
+            let name = if symbol == EOF_LEAF { "the end of the transmission".to_string() } else { format!("byte {}", symbol) };
+            return Err(invalid_data(&format!("{} has a second leaf, at {}", name, at)))
+        }
+        Ok(tree)
+    }
+
+    /// Flatten the tree into a list of its branches, starting with the root,
+    /// and numbered breadth first. Each branch holds its two children, where children
+    /// below EOF_LEAF are bytes, EOF_LEAF is the end of the transmission, and any other
+    /// child is the branch at that index minus FIRST_BRANCH.
+    pub fn flatten(&self) -> Vec<(usize, usize)> {
+        let mut branches = Vec::new();
+        let mut pending = VecDeque::new();
+        let mut next_index = 1;
+        pending.push_back(self);
+        while let Some(tree) = pending.pop_front() {
+            if let HuffTree::Branch(left, right) = tree {
+                let mut children = [0; 2];
+                for (child, subtree) in children.iter_mut().zip(&[left, right]) {
+                    *child = match subtree.as_ref() {
+                        HuffTree::Known(byte) => *byte as usize,
+                        HuffTree::EOF => EOF_LEAF,
+                        HuffTree::Branch(_, _) => {
+                            pending.push_back(subtree);
+                            next_index += 1;
+                            FIRST_BRANCH + next_index - 1
+                        }
+                    };
+                }
+                branches.push((children[0], children[1]));
+            }
+        }
+        branches
+    }
+
+    /// The length of the longest path from the root to a leaf,
+    /// which is the length of the longest code
+    pub fn depth(&self) -> usize {
+        match self {
+            HuffTree::Branch(left, right) => 1 + left.depth().max(right.depth()),
+            _ => 0
+        }
+    }
+
+    /// How many leaves the tree has, including the one for the end of the transmission
+    pub fn leaf_count(&self) -> usize {
+        match self {
+            HuffTree::Branch(left, right) => left.leaf_count() + right.leaf_count(),
+            _ => 1
+        }
+    }
+
+    /// The sum of the depth of each byte, weighted by its frequency,
+    /// which is how many bits coding bytes with these frequencies would take
+    pub fn weighted_path_length(&self, freqs: &Frequencies) -> u64 {
+        let mut counts = [0; 256];
+        for &(count, byte) in &freqs.pairs {
+            counts[byte as usize] = u64::from(count);
+        }
+        let mut total = 0;
+        let mut trees = vec![(self, 0)];
+        while let Some((tree, depth)) = trees.pop() {
+            match tree {
+                HuffTree::Branch(left, right) => {
+                    trees.push((left, depth + 1));
+                    trees.push((right, depth + 1));
+                }
+                HuffTree::Known(byte) => total += counts[*byte as usize] * depth,
+                HuffTree::EOF => {}
+            }
+        }
+        total
+    }
 }
 
 
 
-/// A writer using a hufftree to write bytes to some source
-pub struct HuffWriter {
-    map: Box<[(u128, usize); 256]>,
-    eof: (u128, usize),
-    shift: usize,
-    scratch: u128
+/// The code of every symbol, and the symbol of every code, where symbols below EOF_LEAF are bytes,
+/// and EOF_LEAF is the end of the transmission. Codes are given as their bits, with the first one
+/// in the lowest position, along with their length, the same way HuffWriter::code gives them.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Codebook {
+    // The code of every symbol, where those that can't be coded have a length of 0
+    codes: Vec<(u128, usize)>,
+    tree: HuffTree
 }
 
-impl HuffWriter {
-    pub fn from_tree(start_tree: &HuffTree) -> Self {
-        let mut trees = Vec::new();
-        trees.push((start_tree, 0, 0));
-        // Uninitialized values are never actually reached
-        let mut map = Box::new([(0, 0); 256]);
-        let mut eof = (0, 0);
-        while let Some((tree, bits, shift)) = trees.pop() {
+impl Codebook {
+    /// Read off the code of every leaf of a tree
+    pub fn from_tree(tree: &HuffTree) -> Self {
+        let mut codes = vec![(0, 0); EOF_LEAF + 1];
+        let mut trees = vec![(tree, 0, 0)];
+        while let Some((tree, bits, length)) = trees.pop() {
             match tree {
                 HuffTree::Branch(left, right) => {
-                    trees.push((left, bits, shift + 1));
-                    trees.push((right, (1 << shift) | bits, shift + 1));
+                    trees.push((left, bits, length + 1));
+                    trees.push((right, (1 << length) | bits, length + 1));
                 }
-                HuffTree::EOF => eof = (bits, shift),
-                HuffTree::Known(byte) => { map[*byte as usize] = (bits, shift) }
+                HuffTree::Known(byte) => codes[*byte as usize] = (bits, length),
+                HuffTree::EOF => codes[EOF_LEAF] = (bits, length)
+            }
+        }
+        Codebook { codes, tree: tree.clone() }
+    }
+
+    /// Attempt to assign canonical codes given their lengths, like HuffTree::from_code_lengths
+    pub fn from_code_lengths(lengths: &[u8]) -> io::Result<Self> {
+        HuffTree::from_code_lengths(lengths).map(|tree| Codebook::from_tree(&tree))
+    }
+
+    /// Attempt to gather codes given as `(symbol, bits, length)`, which need to include
+    /// the end of the transmission, and leave no sequence of bits without a symbol,
+    /// where no code can be the start of another
+    pub fn from_codes(codes: &[(usize, u128, usize)]) -> io::Result<Self> {
+        let invalid_input = |msg: String| io::Error::new(io::ErrorKind::InvalidInput, msg);
+        let mut by_symbol = vec![None; EOF_LEAF + 1];
+        for &(symbol, bits, length) in codes {
+            if symbol > EOF_LEAF {
+                return Err(invalid_input(format!("symbol {} is neither a byte nor the end of the transmission", symbol)))
+            }
+            if length > MAX_CODE_LEN {
+                return Err(invalid_input(format!("codes can't be longer than {} bits", MAX_CODE_LEN)))
+            }
+            if bits >> length != 0 {
+                return Err(invalid_input(format!("the code of symbol {} has bits past its length", symbol)))
+            }
+            if by_symbol[symbol].is_some() {
+                return Err(invalid_input(format!("symbol {} has more than one code", symbol)))
+            }
+            by_symbol[symbol] = Some((bits, length));
+        }
+        if by_symbol[EOF_LEAF].is_none() {
+            return Err(invalid_input("the end of the transmission needs a code".to_string()))
+        }
+        // Going through the codes in the order of their bits puts the leaves in order
+        let mut symbols: Vec<_> = (0..by_symbol.len()).filter(|&symbol| by_symbol[symbol].is_some()).collect();
+        symbols.sort_by_key(|&symbol| by_symbol[symbol].map(|(bits, length)| (bits.reverse_bits(), length)));
+        let leaves = symbols.into_iter().map(|symbol| {
+            let leaf = if symbol == EOF_LEAF { HuffTree::EOF } else { HuffTree::Known(symbol as u8) };
+            (by_symbol[symbol].unwrap().1, leaf)
+        });
+        let tree = HuffTree::from_ordered_leaves(leaves)
+            .ok_or_else(|| invalid_input("the codes don't make up a complete tree".to_string()))?;
+        let codebook = Codebook::from_tree(&tree);
+        // The leaves being at the right depths doesn't mean that they're in the right places
+        for (symbol, code) in by_symbol.into_iter().enumerate() {
+            if codebook.codes[symbol] != code.unwrap_or((0, 0)) {
+                return Err(invalid_input("some codes are the start of others".to_string()))
+            }
+        }
+        Ok(codebook)
+    }
+
+    /// The code of a symbol, if it has one
+    pub fn code(&self, symbol: usize) -> Option<(u128, usize)> {
+        match self.codes.get(symbol) {
+            Some(&(_, 0)) | None => None,
+            Some(&code) => Some(code)
+        }
+    }
+
+    /// The symbol a code stands for, if it stands for one
+    pub fn symbol(&self, bits: u128, length: usize) -> Option<usize> {
+        let mut tree = &self.tree;
+        for i in 0..length {
+            tree = match tree {
+                HuffTree::Branch(left, right) => if (bits >> i) & 1 == 0 { left } else { right },
+                _ => return None
+            };
+        }
+        match tree {
+            HuffTree::Branch(_, _) => None,
+            HuffTree::Known(byte) => Some(*byte as usize),
+            HuffTree::EOF => Some(EOF_LEAF)
+        }
+    }
+
+    /// Describe the codes as a JSON object, with a list of `{"symbol": 101, "code": "0110"}`
+    /// objects under `"codes"`, where symbol 256 is the end of the transmission,
+    /// and the bits of each code are in the order they're written out
+    pub fn to_json(&self) -> String {
+        // The end of the transmission has an empty code when it's the only symbol
+        let coded = self.codes.iter().enumerate().filter(|&(symbol, &(_, length))| length > 0 || symbol == EOF_LEAF);
+        let codes: Vec<_> = coded.map(|(symbol, &(bits, length))| {
+            let code: String = (0..length).map(|i| if (bits >> i) & 1 == 0 { '0' } else { '1' }).collect();
+            serde_json::json!({ "symbol": symbol, "code": code })
+        }).collect();
+        let json = serde_json::json!({ "codes": codes });
+        // Serializing a value we built ourselves can't fail
+        serde_json::to_string_pretty(&json).unwrap()
+    }
+
+    /// Attempt to read codes from JSON written by `to_json`
+    pub fn from_json(json: &str) -> io::Result<Self> {
+        let value: serde_json::Value = serde_json::from_str(json)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let entries = value["codes"].as_array()
+            .ok_or_else(|| invalid_data("expected a list of codes"))?;
+        let mut codes = Vec::with_capacity(entries.len());
+        for entry in entries {
+            let symbol = entry["symbol"].as_u64().filter(|&symbol| symbol <= EOF_LEAF as u64)
+                .ok_or_else(|| invalid_data(&format!("expected a `symbol` between 0 and 256 in {}", entry)))?;
+            let code = entry["code"].as_str().filter(|code| code.chars().all(|c| c == '0' || c == '1'))
+                .ok_or_else(|| invalid_data(&format!("expected a `code` made of 0s and 1s in {}", entry)))?;
+            if code.len() > MAX_CODE_LEN {
+                return Err(invalid_data(&format!("codes can't be longer than {} bits", MAX_CODE_LEN)))
+            }
+            let bits = code.bytes().rev().fold(0, |bits, c| (bits << 1) | u128::from(c == b'1'));
+            codes.push((symbol as usize, bits, code.len()));
+        }
+        Codebook::from_codes(&codes).map_err(|e| invalid_data(&e.to_string()))
+    }
+}
+
+
+/// Calculate how many bytes Huffman coding a source with these byte counts
+/// would produce, including the frequencies written before the data.
+pub fn encoded_size(counts: &[u64; 256]) -> u64 {
+    let freqs = Frequencies::from_counts(counts);
+    let tree = HuffTree::from_freqs(&freqs);
+    let writer = HuffWriter::from_tree(&tree);
+    let mut bits = writer.eof.1 as u64;
+    for (byte, &count) in counts.iter().enumerate() {
+        bits += count * writer.map[byte].1 as u64;
+    }
+    table_size(&freqs, &tree) + bits.div_ceil(8)
+}
+
+/// How many bytes describing a tree takes, picking the smallest of
+/// writing out the frequencies it's built from, or its shape
+pub fn table_size(freqs: &Frequencies, tree: &HuffTree) -> u64 {
+    (4 + 2 * freqs.pairs.len() as u64).min(tree.succinct_size())
+}
+
+
+/// The word a BitWriter accumulates bits in before writing them out.
+/// With u128, any code can be added in one go, but u64 is faster on
+/// targets without native 64 bit shifts, and splits up longer codes.
+pub trait Scratch: Copy {
+    /// How many bits the word holds
+    const BITS: usize;
+
+    /// The lowest bits of a code, as many as fit
+    fn from_code(bits: u128) -> Self;
+
+    fn to_code(self) -> u128;
+
+    /// Add bits above the first `shift` bits
+    fn or_shifted(self, bits: Self, shift: usize) -> Self;
+
+    /// Drop the first `shift` bits, which may be all of them
+    fn shr(self, shift: usize) -> Self;
+}
+
+macro_rules! impl_scratch {
+    ($t:ty) => {
+        impl Scratch for $t {
+            const BITS: usize = <$t>::BITS as usize;
+
+            fn from_code(bits: u128) -> Self {
+                bits as $t
+            }
+
+            fn to_code(self) -> u128 {
+                self as u128
+            }
+
+            fn or_shifted(self, bits: Self, shift: usize) -> Self {
+                self | bits.checked_shl(shift as u32).unwrap_or(0)
+            }
+
+            fn shr(self, shift: usize) -> Self {
+                self.checked_shr(shift as u32).unwrap_or(0)
             }
         }
-        HuffWriter { map, eof, shift: 0, scratch: 0 }
+    };
+}
+
+impl_scratch!(u64);
+impl_scratch!(u128);
+
+
+/// Accumulates bits, writing them out to some source a scratch word at a time,
+/// from the least significant bit to the most significant one.
+#[derive(Clone, Debug, Default)]
+pub struct BitWriter<S: Scratch = u128> {
+    shift: usize,
+    scratch: S,
+    written: u64
+}
+
+impl <S: Scratch> BitWriter<S> {
+    pub fn new() -> Self {
+        BitWriter { shift: 0, scratch: S::from_code(0), written: 0 }
+    }
+
+    /// Write the lowest `bit_size` bits of `bits`
+    pub fn write_bits<W: io::Write>(&mut self, mut bits: u128, mut bit_size: usize, writer: &mut W) -> io::Result<()> {
+        // Codes longer than the scratch word get added a word at a time
+        while bit_size > S::BITS {
+            self.write_word(S::from_code(bits), S::BITS, writer)?;
+            bits >>= S::BITS;
+            bit_size -= S::BITS;
+        }
+        self.write_word(S::from_code(bits), bit_size, writer)
     }
 
-    fn write_bits<W: io::Write>(&mut self, bits: u128, bit_size: usize, writer: &mut W) -> io::Result<()> {
-        self.scratch |= bits << self.shift; 
+    fn write_word<W: io::Write>(&mut self, bits: S, bit_size: usize, writer: &mut W) -> io::Result<()> {
+        self.scratch = self.scratch.or_shifted(bits, self.shift);
         self.shift += bit_size;
-        if self.shift >= 128 {
-            self.shift -= 128;
+        if self.shift >= S::BITS {
+            self.shift -= S::BITS;
             let to_write = self.scratch;
-            self.scratch = bits >> (bit_size - self.shift);
-            write_u128(writer, to_write)
+            self.scratch = bits.shr(bit_size - self.shift);
+            self.written += S::BITS as u64 / 8;
+            write_u128_trimmed(writer, to_write.to_code(), S::BITS)
         } else {
             Ok(())
         }
     }
 
-    pub fn write_byte<W: io::Write>(&mut self, byte: u8, writer: &mut W) -> io::Result<()> {
-        let (bits, bit_size) = self.map[byte as usize];
-        self.write_bits(bits, bit_size, writer)
+    /// How many bits have been written so far, including those not written out yet
+    pub fn bit_position(&self) -> u64 {
+        self.written * 8 + self.shift as u64
     }
 
-    /// Write the end of the transmission, flushing out the remaining bits, and writing
-    /// the EOF symbol
-    pub fn end_transmission<W: io::Write>(&mut self, writer: &mut W) -> io::Result<()> {
-        let (bits, bit_size) = self.eof;
-        self.write_bits(bits, bit_size, writer)?;
+    /// Write out the whole bytes of the bits we're still holding on to,
+    /// keeping the bits of the last byte, which more bits can go in
+    pub fn flush_bytes<W: io::Write>(&mut self, writer: &mut W) -> io::Result<()> {
+        let whole = self.shift / 8 * 8;
+        write_u128_trimmed(writer, self.scratch.to_code(), whole)?;
+        self.scratch = self.scratch.shr(whole);
+        self.shift -= whole;
+        self.written += whole as u64 / 8;
+        Ok(())
+    }
+
+    /// Write out the bits we're still holding on to, padding the last byte with zeros
+    pub fn flush_bits<W: io::Write>(&mut self, writer: &mut W) -> io::Result<()> {
         // this won't write anything if self.shift is 0, avoiding writing the last bytes twice
-        write_u128_trimmed(writer, self.scratch, self.shift)
+        write_u128_trimmed(writer, self.scratch.to_code(), self.shift)
     }
 }
 
 
-/// A struct allowing us to incrementally feed in bits
-/// (one byte at a time) and have it decode them using a
-/// Huffman tree
-pub struct HuffReader<'a> {
-    top_tree: &'a HuffTree,
-    tree: &'a HuffTree,
+/// A snapshot of how far a HuffWriter got, allowing encoding to resume from there later,
+/// after truncating the output to the bytes written so far, and skipping the symbols encoded.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Checkpoint {
+    /// The bits that haven't been written out yet
+    pub scratch: u128,
+    /// How many bits haven't been written out yet
+    pub shift: usize,
+    /// How many bytes have been written out
+    pub bytes_written: u64,
+    /// How many symbols have been encoded
+    pub symbols: u64
 }
 
-impl <'a> HuffReader<'a> {
-    pub fn new(tree: &'a HuffTree) -> Self {
-        HuffReader { top_tree: tree, tree }
+impl Checkpoint {
+    /// Write the checkpoint out as 33 bytes
+    pub fn write<W: io::Write>(&self, writer: &mut W) -> io::Result<()> {
+        writer.write_all(&self.scratch.to_le_bytes())?;
+        writer.write_all(&[self.shift as u8])?;
+        writer.write_all(&self.bytes_written.to_be_bytes())?;
+        writer.write_all(&self.symbols.to_be_bytes())
     }
 
-    /// Feed a byte to this reader
-    /// Return true if the reader can continue to accept input
-    pub fn feed<W: io::Write>(&mut self, mut byte: u8, writer: &mut W) -> io::Result<bool> {
-        let mut i = 0;
-        while i < 8 {
-            match self.tree {
-                HuffTree::Branch(left, right) => {
-                    if byte & 1 == 0 {
-                        self.tree = &left;
-                    } else {
-                        self.tree = &right;
-                    }
-                    byte >>= 1;
-                    i += 1;
-                }
-                HuffTree::Known(byte) => {
-                    writer.write_all(&[*byte])?;
-                    self.tree = self.top_tree;
-                }
-                HuffTree::EOF => return Ok(false)
-            }
+    /// Attempt to read a checkpoint written with write
+    pub fn read<R: io::Read>(reader: &mut R) -> io::Result<Self> {
+        let mut buf = [0; 33];
+        reader.read_exact(&mut buf)?;
+        let mut scratch = [0; 16];
+        scratch.copy_from_slice(&buf[..16]);
+        let shift = buf[16] as usize;
+        if shift >= 128 {
+            return Err(invalid_data("checkpoint has too many bits left to write"))
         }
-        Ok(true)
+        let mut bytes_written = [0; 8];
+        bytes_written.copy_from_slice(&buf[17..25]);
+        let mut symbols = [0; 8];
+        symbols.copy_from_slice(&buf[25..]);
+        Ok(Checkpoint {
+            scratch: u128::from_le_bytes(scratch),
+            shift,
+            bytes_written: u64::from_be_bytes(bytes_written),
+            symbols: u64::from_be_bytes(symbols)
+        })
     }
 }
 
 
-#[cfg(test)]
-mod test {
-    use super::{HuffTree, Frequencies};
+/// A writer using a hufftree to write bytes to some source,
+/// accumulating bits in a u128 unless another Scratch word is picked
+pub struct HuffWriter<S: Scratch = u128> {
+    map: Box<[(u128, usize); 256]>,
+    eof: (u128, usize),
+    bits: BitWriter<S>,
+    count: u64
+}
 
-    #[test]
-    fn huff_tree_freqs_works() {
-        let mut freqs = Frequencies { pairs: Vec::new() };
-        freqs.pairs.push((100, 69));
-        freqs.pairs.push((2, 71));
-        freqs.pairs.push((1, 70));
-        let tree = HuffTree::Branch(
+impl HuffWriter {
+    pub fn from_tree(start_tree: &HuffTree) -> Self {
+        HuffWriter::with_scratch(start_tree)
+    }
+
+    /// Create a writer using the codes of a codebook
+    pub fn from_codebook(codebook: &Codebook) -> Self {
+        let mut map = Box::new([(0, 0); 256]);
+        map.copy_from_slice(&codebook.codes[..EOF_LEAF]);
+        HuffWriter { map, eof: codebook.codes[EOF_LEAF], bits: BitWriter::new(), count: 0 }
+    }
+}
+
+impl <S: Scratch> HuffWriter<S> {
+    /// Create a writer for a tree, accumulating bits in a different word
+    pub fn with_scratch(start_tree: &HuffTree) -> Self {
+        let mut writer = HuffWriter { map: Box::new([(0, 0); 256]), eof: (0, 0), bits: BitWriter::new(), count: 0 };
+        writer.fill_codes(start_tree);
+        writer
+    }
+
+    /// Start over with another tree, as if the writer was just created,
+    /// but reusing its allocation, which helps when encoding many small inputs.
+    /// Encoding a stream, and so the commands encoding several files, still create a writer
+    /// for each of them, since finish takes it to write the end of the transmission.
+    pub fn reset(&mut self, start_tree: &HuffTree) {
+        *self.map = [(0, 0); 256];
+        self.bits = BitWriter::new();
+        self.count = 0;
+        self.fill_codes(start_tree);
+    }
+
+    fn fill_codes(&mut self, start_tree: &HuffTree) {
+        let mut trees = Vec::new();
+        trees.push((start_tree, 0, 0));
+        // Bytes that aren't in the tree keep a length of 0
+        while let Some((tree, bits, shift)) = trees.pop() {
+            match tree {
+                HuffTree::Branch(left, right) => {
+                    trees.push((left, bits, shift + 1));
+                    trees.push((right, (1 << shift) | bits, shift + 1));
+                }
+                HuffTree::EOF => self.eof = (bits, shift),
+                HuffTree::Known(byte) => { self.map[*byte as usize] = (bits, shift) }
+            }
+        }
+    }
+
+    /// The code for a byte, and its length in bits, if the tree contains that byte
+    pub fn code(&self, byte: u8) -> Option<(u128, usize)> {
+        match self.map[byte as usize] {
+            (_, 0) => None,
+            code => Some(code)
+        }
+    }
+
+    /// The code for the end of the transmission, and its length in bits
+    pub fn eof_code(&self) -> (u128, usize) {
+        self.eof
+    }
+
+    /// Write the code for a byte, failing if the tree doesn't contain that byte
+    pub fn write_byte<W: io::Write>(&mut self, byte: u8, writer: &mut W) -> io::Result<()> {
+        let (bits, bit_size) = self.map[byte as usize];
+        if bit_size == 0 {
+            let msg = format!("byte {:#04x} not in tree", byte);
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, msg))
+        }
+        self.count += 1;
+        self.bits.write_bits(bits, bit_size, writer)
+    }
+
+    /// How many bytes have been written, not counting the end of the transmission
+    pub fn symbol_count(&self) -> u64 {
+        self.count
+    }
+
+    /// How many bits the codes written so far take up
+    pub fn bit_position(&self) -> u64 {
+        self.bits.bit_position()
+    }
+
+    /// Write out every whole byte of the codes so far, for the other end to decode them
+    /// right away, keeping the bits of the last byte, until more codes fill it up
+    pub fn flush_bytes<W: io::Write>(&mut self, writer: &mut W) -> io::Result<()> {
+        self.bits.flush_bytes(writer)
+    }
+
+    /// Take a snapshot of how far the writer got
+    pub fn checkpoint(&self) -> Checkpoint {
+        Checkpoint {
+            scratch: self.bits.scratch.to_code(),
+            shift: self.bits.shift,
+            bytes_written: self.bits.written,
+            symbols: self.count
+        }
+    }
+
+    /// Create a writer for a tree, continuing from a checkpoint
+    /// taken with a writer for that same tree and scratch word,
+    /// failing if the checkpoint has more bits left than the scratch word holds
+    pub fn resume(tree: &HuffTree, checkpoint: Checkpoint) -> io::Result<Self> {
+        if checkpoint.shift >= S::BITS {
+            let msg = format!("checkpoint has {} bits left to write, but the writer only holds {}", checkpoint.shift, S::BITS);
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, msg))
+        }
+        let mut writer = HuffWriter::with_scratch(tree);
+        writer.bits = BitWriter {
+            shift: checkpoint.shift,
+            scratch: S::from_code(checkpoint.scratch),
+            written: checkpoint.bytes_written
+        };
+        writer.count = checkpoint.symbols;
+        Ok(writer)
+    }
+
+    /// Write the end of the transmission, flushing out the remaining bits, and writing
+    /// the EOF symbol. Writing more bytes after this corrupts the output, which finish rules out.
+    #[deprecated(note = "use finish, which takes the writer, so that nothing can be written after the end")]
+    pub fn end_transmission<W: io::Write>(&mut self, writer: &mut W) -> io::Result<()> {
+        let (bits, bit_size) = self.eof;
+        self.bits.write_bits(bits, bit_size, writer)?;
+        self.bits.flush_bits(writer)
+    }
+
+    /// Write the EOF symbol, and flush out the remaining bits, returning how much was written
+    pub fn finish<W: io::Write>(mut self, writer: &mut W) -> io::Result<WriterStats> {
+        let (bits, bit_size) = self.eof;
+        self.bits.write_bits(bits, bit_size, writer)?;
+        let stats = WriterStats { symbols: self.count, bits: self.bits.bit_position() };
+        self.bits.flush_bits(writer)?;
+        Ok(stats)
+    }
+}
+
+/// What a HuffWriter or SymbolWriter wrote, once it's finished
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct WriterStats {
+    /// How many symbols were written, not counting the end of the transmission
+    pub symbols: u64,
+    /// How many bits the codes took up, including the end of the transmission,
+    /// but not the padding of the last byte
+    pub bits: u64
+}
+
+impl WriterStats {
+    /// How many bytes were written, including the padding of the last byte
+    pub fn bytes(&self) -> u64 {
+        self.bits.div_ceil(8)
+    }
+}
+
+
+// Codes the bytes of another iterator as they get pulled, a few output bytes at a time
+struct EncodeIter<I> {
+    bytes: I,
+    codes: Option<HuffWriter>,
+    out: Vec<u8>,
+    pos: usize
+}
+
+impl <I: Iterator<Item=u8>> Iterator for EncodeIter<I> {
+    type Item = u8;
+
+    fn next(&mut self) -> Option<u8> {
+        while self.pos == self.out.len() {
+            self.out.clear();
+            self.pos = 0;
+            let codes = self.codes.as_mut()?;
+            match self.bytes.next() {
+                Some(byte) => {
+                    // Writing to a Vec can't fail, so the only error is a byte missing from the tree
+                    if let Err(e) = codes.write_byte(byte, &mut self.out) {
+                        panic!("{}", e);
+                    }
+                }
+                None => {
+                    // The codes were taken just above
+                    let _ = self.codes.take().unwrap().finish(&mut self.out);
+                }
+            }
+        }
+        self.pos += 1;
+        Some(self.out[self.pos - 1])
+    }
+}
+
+/// Lazily encode the bytes of an iterator with a tree, giving back the raw codes, ending with
+/// the end of the transmission, without a header or table, as they're pulled from the iterator.
+///
+/// # Panics
+/// Pulling a byte from the returned iterator panics if the input has a byte the tree doesn't contain.
+pub fn encode_iter<I: IntoIterator<Item=u8>>(tree: &HuffTree, bytes: I) -> impl Iterator<Item=u8> {
+    let codes = Some(HuffWriter::from_tree(tree));
+    EncodeIter { bytes: bytes.into_iter(), codes, out: Vec::with_capacity(16), pos: 0 }
+}
+
+
+/// A struct allowing us to incrementally feed in bits
+/// (one byte at a time) and have it decode them using a
+/// Huffman tree
+pub struct HuffReader<'a> {
+    top_tree: &'a HuffTree,
+    tree: &'a HuffTree,
+    count: u64,
+    bits: u64,
+    // The bytes decoded by feed_slice, kept around to reuse the allocation
+    decoded: Vec<u8>
+}
+
+impl <'a> HuffReader<'a> {
+    pub fn new(tree: &'a HuffTree) -> Self {
+        HuffReader { top_tree: tree, tree, count: 0, bits: 0, decoded: Vec::new() }
+    }
+
+    // Follow the bits of a byte down the tree, passing along every byte we reach,
+    // and returning false once we reach the end of the transmission
+    fn feed_bits<F: FnMut(u8)>(&mut self, mut byte: u8, mut emit: F) -> bool {
+        for _ in 0..8 {
+            if let HuffTree::Branch(left, right) = self.tree {
+                self.tree = if byte & 1 == 0 { left } else { right };
+                byte >>= 1;
+                self.bits += 1;
+            }
+            match self.tree {
+                HuffTree::Known(byte) => {
+                    emit(*byte);
+                    self.count += 1;
+                    self.tree = self.top_tree;
+                }
+                HuffTree::EOF => return false,
+                HuffTree::Branch(_, _) => {}
+            }
+        }
+        true
+    }
+
+    /// Feed a byte to this reader
+    /// Return true if the reader can continue to accept input
+    pub fn feed<W: io::Write>(&mut self, byte: u8, writer: &mut W) -> io::Result<bool> {
+        // Every code takes at least a bit, so a byte holds at most 8 of them
+        let mut decoded = [0; 8];
+        let mut len = 0;
+        let can_feed = self.feed_bits(byte, |byte| {
+            decoded[len] = byte;
+            len += 1;
+        });
+        writer.write_all(&decoded[..len])?;
+        Ok(can_feed)
+    }
+
+    /// Feed a whole slice of bytes to this reader, writing out what they decode to in one go.
+    /// Return how many bytes were used, which stops short after the end of the transmission,
+    /// and whether or not the reader can continue to accept input.
+    pub fn feed_slice<W: io::Write>(&mut self, input: &[u8], writer: &mut W) -> io::Result<(usize, bool)> {
+        let mut decoded = std::mem::take(&mut self.decoded);
+        let mut result = (input.len(), true);
+        for (i, &byte) in input.iter().enumerate() {
+            if !self.feed_bits(byte, |byte| decoded.push(byte)) {
+                result = (i + 1, false);
+                break;
+            }
+        }
+        let written = writer.write_all(&decoded);
+        decoded.clear();
+        self.decoded = decoded;
+        written.map(|_| result)
+    }
+
+    /// How many bytes have been read, not counting the end of the transmission
+    pub fn symbol_count(&self) -> u64 {
+        self.count
+    }
+
+    /// How many bits have been followed down the tree, up to the end of the transmission
+    pub fn bit_position(&self) -> u64 {
+        self.bits
+    }
+
+    /// Once the end of the transmission was reached, how many bits of the byte it ended in
+    /// were used, from its lowest bit up, where the rest are padding
+    pub fn last_byte_bits(&self) -> Option<u32> {
+        match self.tree {
+            HuffTree::EOF => Some(last_byte_bits(self.bits)),
+            _ => None
+        }
+    }
+}
+
+
+// How many bits of the last byte some number of bits from the start of a byte end in,
+// where none are used when there are no bits at all
+fn last_byte_bits(bits: u64) -> u32 {
+    match bits {
+        0 => 0,
+        bits => ((bits - 1) % 8 + 1) as u32
+    }
+}
+
+/// How far a call to `OwnedHuffReader::decode_into` got
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Decoded {
+    /// How many bytes of the input were used up
+    pub read: usize,
+    /// How many bytes were decoded into the output
+    pub written: usize,
+    /// Whether the end of the transmission was reached
+    pub finished: bool
+}
+
+/// Like a HuffReader, but holding a flattened copy of the tree instead of borrowing it,
+/// so that it can be moved across threads, or stored anywhere. Cloning the reader
+/// shares that copy, letting one tree serve many decoders.
+#[derive(Clone, Debug)]
+pub struct OwnedHuffReader {
+    branches: Arc<[(usize, usize)]>,
+    branch: usize,
+    count: u64,
+    bits: u64,
+    // How many bits of the next byte were used before decode_into ran out of room
+    skip: u32,
+    finished: bool
+}
+
+impl OwnedHuffReader {
+    pub fn new(tree: &HuffTree) -> Self {
+        OwnedHuffReader { branches: tree.flatten().into(), branch: 0, count: 0, bits: 0, skip: 0, finished: false }
+    }
+
+    /// Create a reader decoding the codes of a codebook
+    pub fn from_codebook(codebook: &Codebook) -> Self {
+        OwnedHuffReader::new(&codebook.tree)
+    }
+
+    /// Feed a byte to this reader
+    /// Return true if the reader can continue to accept input
+    pub fn feed<W: io::Write>(&mut self, byte: u8, writer: &mut W) -> io::Result<bool> {
+        // A tree without any branches only has the end of the transmission
+        if self.branches.is_empty() {
+            self.finished = true;
+            return Ok(false)
+        }
+        for bit in std::mem::take(&mut self.skip)..8 {
+            let (left, right) = self.branches[self.branch];
+            let child = if (byte >> bit) & 1 == 0 { left } else { right };
+            self.bits += 1;
+            if child < EOF_LEAF {
+                writer.write_all(&[child as u8])?;
+                self.count += 1;
+                self.branch = 0;
+            } else if child == EOF_LEAF {
+                self.finished = true;
+                return Ok(false)
+            } else {
+                self.branch = child - FIRST_BRANCH;
+            }
+        }
+        Ok(true)
+    }
+
+    /// Decode as much of the input as fits in the output, without allocating anything.
+    /// A byte the output filled up in the middle of isn't counted as read, and decoding
+    /// picks up where it left off in that byte once it's given again, with more room.
+    pub fn decode_into(&mut self, input: &[u8], out: &mut [u8]) -> Decoded {
+        let mut written = 0;
+        if self.branches.is_empty() {
+            self.finished = true;
+            return Decoded { read: 0, written, finished: true }
+        }
+        for (read, &byte) in input.iter().enumerate() {
+            for bit in self.skip..8 {
+                let (left, right) = self.branches[self.branch];
+                let child = if (byte >> bit) & 1 == 0 { left } else { right };
+                if child < EOF_LEAF {
+                    // Leave this bit to be followed again, once there's room for its byte
+                    if written == out.len() {
+                        self.skip = bit;
+                        return Decoded { read, written, finished: false }
+                    }
+                    out[written] = child as u8;
+                    written += 1;
+                    self.count += 1;
+                    self.branch = 0;
+                } else if child == EOF_LEAF {
+                    self.bits += 1;
+                    self.skip = 0;
+                    self.finished = true;
+                    return Decoded { read: read + 1, written, finished: true }
+                } else {
+                    self.branch = child - FIRST_BRANCH;
+                }
+                self.bits += 1;
+            }
+            self.skip = 0;
+        }
+        Decoded { read: input.len(), written, finished: false }
+    }
+
+    /// How many bytes have been read, not counting the end of the transmission
+    pub fn symbol_count(&self) -> u64 {
+        self.count
+    }
+
+    /// How many bits have been followed down the tree, up to the end of the transmission
+    pub fn bit_position(&self) -> u64 {
+        self.bits
+    }
+
+    /// Once the end of the transmission was reached, how many bits of the byte it ended in
+    /// were used, like HuffReader::last_byte_bits
+    pub fn last_byte_bits(&self) -> Option<u32> {
+        Some(last_byte_bits(self.bits)).filter(|_| self.finished)
+    }
+}
+
+
+// Counts the occurrences of each byte written to it
+struct Histogram([u64; 256]);
+
+impl io::Write for Histogram {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        for &byte in buf {
+            self.0[byte as usize] += 1;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// Adapts a HuffWriter into a writer, encoding every byte written to it
+struct HuffSink<'a, W> {
+    encoder: &'a mut HuffWriter,
+    writer: &'a mut W,
+    trace: Option<&'a Tracer>
+}
+
+impl <'a, W: io::Write> io::Write for HuffSink<'a, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        for &byte in buf {
+            if let Some(tracer) = self.trace {
+                let code = self.encoder.map[byte as usize];
+                if !tracer.symbol(byte, code, self.encoder.bit_position())? {
+                    self.trace = None;
+                }
+            }
+            self.encoder.write_byte(byte, self.writer)?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+
+/// Describes each of the first few bytes Huffman coded, along with their code, and the bit
+/// of the coded data it starts at, to chase down bugs in other implementations of the format
+pub struct Tracer {
+    limit: u64,
+    traced: AtomicU64,
+    out: Mutex<Box<dyn io::Write + Send>>
+}
+
+impl Tracer {
+    /// Describe up to `limit` bytes, writing a line for each to `out`
+    pub fn new<W: io::Write + Send + 'static>(limit: u64, out: W) -> Self {
+        Tracer { limit, traced: AtomicU64::new(0), out: Mutex::new(Box::new(out)) }
+    }
+
+    // Describe a byte, returning whether there's room for more
+    fn symbol(&self, byte: u8, code: (u128, usize), bit: u64) -> io::Result<bool> {
+        let index = self.traced.fetch_add(1, Ordering::Relaxed);
+        if index < self.limit {
+            let mut out = self.out.lock().unwrap();
+            writeln!(out, "symbol {}: {} at bit {}, code {}", index, show_byte(byte), bit, show_code(code))?;
+        }
+        Ok(index + 1 < self.limit)
+    }
+}
+
+impl fmt::Debug for Tracer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Tracer").field("limit", &self.limit).finish()
+    }
+}
+
+// Traces the bytes decoded, like HuffSink does with the bytes encoded
+struct TraceWriter<'a, W> {
+    writer: &'a mut W,
+    codes: HuffWriter,
+    bit: u64,
+    trace: Option<&'a Tracer>
+}
+
+impl <'a, W: io::Write> io::Write for TraceWriter<'a, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        for &byte in buf {
+            let tracer = match self.trace {
+                Some(tracer) => tracer,
+                None => break
+            };
+            let code = self.codes.map[byte as usize];
+            if !tracer.symbol(byte, code, self.bit)? {
+                self.trace = None;
+            }
+            self.bit += code.1 as u64;
+        }
+        self.writer.write_all(buf)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+
+/// The error encoding or decoding fails with after being cancelled
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Cancelled;
+
+impl fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "cancelled")
+    }
+}
+
+impl error::Error for Cancelled {}
+
+/// Check whether an error comes from encoding or decoding being cancelled
+pub fn is_cancelled(e: &io::Error) -> bool {
+    context::root_cause(e).get_ref().is_some_and(|inner| inner.is::<Cancelled>())
+}
+
+
+// Keeps track of how many bytes pass through it, along with their checksum,
+// failing to read anything once it's been cancelled
+struct Tally<'a, T> {
+    inner: T,
+    crc: Crc32,
+    cancel: Option<&'a AtomicBool>
+}
+
+impl <'a, T> Tally<'a, T> {
+    fn new(inner: T, cancel: Option<&'a AtomicBool>) -> Self {
+        Tally { inner, crc: Crc32::new(), cancel }
+    }
+
+    fn check_cancel(&self) -> io::Result<()> {
+        match self.cancel {
+            Some(cancel) if cancel.load(Ordering::Relaxed) => Err(io::Error::other(Cancelled)),
+            _ => Ok(())
+        }
+    }
+}
+
+impl <'a, R: io::Read> io::Read for Tally<'a, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.check_cancel()?;
+        let read = self.inner.read(buf)?;
+        self.crc.update(&buf[..read]);
+        Ok(read)
+    }
+}
+
+impl <'a, R: io::BufRead> io::BufRead for Tally<'a, R> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        self.check_cancel()?;
+        self.inner.fill_buf()
+    }
+
+    fn consume(&mut self, amt: usize) {
+        // The bytes being consumed are still in the buffer, so this can't fail
+        if let Ok(buf) = self.inner.fill_buf() {
+            self.crc.update(&buf[..amt]);
+        }
+        self.inner.consume(amt)
+    }
+}
+
+impl <'a, R: io::Seek> io::Seek for Tally<'a, R> {
+    // We only ever seek back to the start, to read the input again
+    fn seek(&mut self, pos: io::SeekFrom) -> io::Result<u64> {
+        self.crc = Crc32::new();
+        self.inner.seek(pos)
+    }
+}
+
+impl <'a, W: io::Write> io::Write for Tally<'a, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.crc.update(&buf[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+
+// Splits the time spent encoding or decoding into phases
+struct Stopwatch {
+    last: Instant,
+    phases: Vec<(&'static str, Duration)>
+}
+
+impl Stopwatch {
+    fn new() -> Self {
+        Stopwatch { last: Instant::now(), phases: Vec::new() }
+    }
+
+    // End the current phase, giving it a name
+    fn lap(&mut self, phase: &'static str) {
+        let now = Instant::now();
+        self.phases.push((phase, now - self.last));
+        self.last = now;
+    }
+}
+
+
+/// How the trees of the huffman and rle methods get built
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Coder {
+    /// Huffman's algorithm, which gives the shortest codes
+    #[default]
+    Huffman,
+    /// Shannon–Fano coding, which is mostly there to be compared with Huffman's algorithm
+    ShannonFano,
+    /// The Hu–Tucker algorithm, which keeps the codes in the same order as the bytes
+    HuTucker
+}
+
+impl FromStr for Coder {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "huffman" => Ok(Coder::Huffman),
+            "shannon-fano" => Ok(Coder::ShannonFano),
+            "hu-tucker" => Ok(Coder::HuTucker),
+            _ => Err(format!("unknown coder `{}`, expected huffman, shannon-fano or hu-tucker", s))
+        }
+    }
+}
+
+impl fmt::Display for Coder {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Coder::Huffman => "huffman",
+            Coder::ShannonFano => "shannon-fano",
+            Coder::HuTucker => "hu-tucker"
+        };
+        f.pad(name)
+    }
+}
+
+
+/// A gzip style compression level, from 1, the fastest, to 9, giving the smallest output,
+/// standing for how much of the input the model is built from, and which methods are tried
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Level(u8);
+
+impl Level {
+    pub const FASTEST: Level = Level(1);
+    /// Counts the whole input, and picks the smallest of stored, huffman and rle
+    pub const DEFAULT: Level = Level(6);
+    pub const BEST: Level = Level(9);
+
+    pub fn new(level: u8) -> io::Result<Self> {
+        if !(1..=9).contains(&level) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("invalid level {}, expected 1 to 9", level)))
+        }
+        Ok(Level(level))
+    }
+
+    pub fn get(self) -> u8 {
+        self.0
+    }
+
+    /// How many bytes at the start of the input the model is built from, reading it only once,
+    /// or None when the whole input is counted first
+    pub fn sample(self) -> Option<u64> {
+        match self.0 {
+            1 => Some(64 * 1024),
+            2 => Some(256 * 1024),
+            3 => Some(1024 * 1024),
+            4 => Some(4 * 1024 * 1024),
+            5 => Some(16 * 1024 * 1024),
+            _ => None
+        }
+    }
+
+    /// The methods, along with their n-gram size, tried on top of the usual ones, see EncodeOptions::alternatives
+    pub fn alternatives(self) -> &'static [(Method, Option<usize>)] {
+        match self.0 {
+            7 => &[(Method::Ngrams, Some(2))],
+            8 => &[(Method::Ngrams, Some(2)), (Method::Words, None), (Method::Utf8, None)],
+            9 => &[(Method::Ngrams, Some(2)), (Method::Ngrams, Some(3)), (Method::Ngrams, Some(4)), (Method::Words, None), (Method::Utf8, None)],
+            _ => &[]
+        }
+    }
+
+    /// The options encoding at this level, without anything else
+    pub fn options(self) -> EncodeOptions<'static> {
+        EncodeOptions { optimistic: self.sample(), alternatives: self.alternatives().to_vec(), ..Default::default() }
+    }
+}
+
+impl Default for Level {
+    fn default() -> Self {
+        Level::DEFAULT
+    }
+}
+
+impl FromStr for Level {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.parse() {
+            Ok(level @ 1..=9) => Ok(Level(level)),
+            _ => Err(format!("invalid level `{}`, expected 1 to 9", s))
+        }
+    }
+}
+
+
+/// How encode_stream_with should encode its input
+#[derive(Clone, Debug, Default)]
+pub struct EncodeOptions<'a> {
+    /// The method to use, instead of the smallest of stored, huffman and rle
+    pub method: Option<Method>,
+    /// How to build the tree of the huffman and rle methods, where anything
+    /// but Huffman's algorithm needs the tree to be written out
+    pub coder: Coder,
+    /// The frequencies to code the input with, instead of counting them
+    pub model: Option<Frequencies>,
+    /// The tree to code the input with, instead of building one, which then gets written out
+    pub tree: Option<HuffTree>,
+    /// How many bytes the symbols of the ngrams method have, instead of NGRAM_SIZE
+    pub ngram_size: Option<usize>,
+    /// Split the input into planes of records this many bytes long before coding it,
+    /// which decoding undoes
+    pub stride: Option<u8>,
+    /// The size of the input, to record in the header, so that decoding can set aside room
+    /// for the output up front. Encoding fails if the input turns out to have another size.
+    pub decoded_size: Option<u64>,
+    /// Build the model of the byte methods from this many bytes at the start of the input,
+    /// reading it only once, unless that makes the output too much larger, see OPTIMISTIC_SLACK
+    pub optimistic: Option<u64>,
+    /// Other methods, along with their n-gram size, to encode the input with after encoding it
+    /// with these options, keeping whichever output is the smallest
+    pub alternatives: Vec<(Method, Option<usize>)>,
+    /// Leave the model out of the output, recording its ID in the header instead, so that decoding
+    /// needs to be given it as a dictionary, which only works with the huffman and rle methods
+    pub preset: bool,
+    /// Leave out the header and frequencies, which only works with the huffman and rle methods
+    pub raw: bool,
+    /// Stop encoding, failing with Cancelled, once this is set
+    pub cancel: Option<&'a AtomicBool>,
+    /// Describe the first bytes the huffman and rle methods code
+    pub trace: Option<&'a Tracer>
+}
+
+/// How many bytes at the start of the input optimistic encoding builds its model from, by default
+pub const OPTIMISTIC_PREFIX: u64 = 1024 * 1024;
+/// Optimistic encoding reads the input again, with a model of all of it, when its output is larger
+/// by more than one part in this many than what that model is estimated to give
+pub const OPTIMISTIC_SLACK: u64 = 32;
+
+/// How decode_stream_with should decode its input
+#[derive(Clone, Debug, Default)]
+pub struct DecodeOptions<'a> {
+    /// The method and frequencies of raw data, which doesn't start with a header
+    pub raw: Option<Raw>,
+    /// The frequencies data encoded with a preset dictionary was coded with, which need
+    /// to have the ID its header records
+    pub dict: Option<Frequencies>,
+    /// How much decoding can take, for input that can't be trusted
+    pub limits: Limits,
+    /// Stop decoding, failing with Cancelled, once this is set
+    pub cancel: Option<&'a AtomicBool>,
+    /// Describe the first bytes decoded with the huffman and rle methods
+    pub trace: Option<&'a Tracer>
+}
+
+
+/// Bounds on what decoding a file can take, failing with InvalidData once it goes past one,
+/// where None leaves that unbounded. Files made to decode to far more than they take,
+/// or with huge tables, can't be told apart from others until they're being decoded.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Limits {
+    /// The most bytes the data can decode to, which is checked against the size
+    /// the header records, before decoding, and against the output, while decoding
+    pub max_output: Option<u64>,
+    /// The most entries the table after the header can have, which are symbols
+    /// with the symbol methods, and bytes with the huffman and rle methods
+    pub max_entries: Option<usize>,
+    /// The longest code the tree built from the table can have, in bits
+    pub max_code_len: Option<usize>
+}
+
+
+/// What happened while encoding a stream
+#[derive(Clone, Debug)]
+pub struct EncodeStats {
+    /// The method the input was encoded with
+    pub method: Method,
+    /// The frequencies the input was coded with, if the method uses any,
+    /// or those the huffman method would have used, if the input was stored
+    pub freqs: Option<Frequencies>,
+    /// The tree the input was coded with, with the huffman and rle methods
+    pub tree: Option<HuffTree>,
+    /// How many bytes were read from the input
+    pub bytes_in: u64,
+    /// How many bytes were written out
+    pub bytes_out: u64,
+    /// How many symbols were coded, not counting the end of the transmission:
+    /// bytes, after run length encoding them with the rle method, or words
+    /// and characters with the symbol methods. Stored input has none.
+    /// Rice coded input has one for each byte, without an end of transmission.
+    pub symbols: u64,
+    /// The CRC-32 of the input
+    pub checksum: u32,
+    /// How long encoding took
+    pub elapsed: Duration,
+    /// How long each phase of encoding took, in order: counting,
+    /// building the tree, and then encoding, or just copying stored input
+    pub phases: Vec<(&'static str, Duration)>
+}
+
+/// What happened while decoding a stream
+#[derive(Clone, Debug)]
+pub struct DecodeStats {
+    /// The method the input was encoded with
+    pub method: Method,
+    /// How many bytes were read from the input, including its armor
+    pub bytes_in: u64,
+    /// How many bytes were written out
+    pub bytes_out: u64,
+    /// How many symbols were decoded, counted like in EncodeStats
+    pub symbols: u64,
+    /// The CRC-32 of the output
+    pub checksum: u32,
+    /// How long decoding took
+    pub elapsed: Duration,
+    /// How long each phase of decoding took, in order: reading the header,
+    /// building the tree or reading the dictionary, and then decoding
+    pub phases: Vec<(&'static str, Duration)>
+}
+
+
+/// Encode everything in a source, picking the smallest of the stored, huffman and rle methods.
+/// The source is read twice from its start, once to count its bytes, and then to encode them.
+pub fn encode_stream<R, W>(input: &mut R, writer: &mut W) -> io::Result<EncodeStats>
+where
+    R: io::Read + io::Seek,
+    W: io::Write
+{
+    let mut input = io::BufReader::new(input);
+    let mut writer = io::BufWriter::new(writer);
+    let stats = encode_stream_with(&mut input, &mut writer, EncodeOptions::default())?;
+    writer.flush()?;
+    Ok(stats)
+}
+
+/// Decode everything in a source written by encode_stream, or the `encode` command
+pub fn decode_stream<R: io::Read, W: io::Write>(input: &mut R, writer: &mut W) -> io::Result<DecodeStats> {
+    let mut writer = io::BufWriter::new(writer);
+    let stats = decode_stream_with(io::BufReader::new(input), &mut writer, DecodeOptions::default())?;
+    writer.flush()?;
+    Ok(stats)
+}
+
+/// Encode everything in a source, like encode_stream, but with some options
+pub fn encode_stream_with<R, W>(input: &mut R, writer: &mut W, options: EncodeOptions) -> io::Result<EncodeStats>
+where
+    R: io::Read + io::Seek,
+    W: io::Write
+{
+    if !options.alternatives.is_empty() {
+        return encode_smallest(input, writer, options)
+    }
+    let EncodeOptions { method, coder, raw, cancel, .. } = options;
+    if coder != Coder::Huffman {
+        if raw {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "raw data can only be coded with Huffman trees"))
+        }
+        if let Some(method @ (Method::Words | Method::Utf8 | Method::Tokens | Method::Ngrams | Method::Rice)) = method {
+            let msg = format!("the {} method can only be coded with Huffman trees", method);
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, msg))
+        }
+    }
+    if options.tree.is_some() {
+        if options.model.is_some() || raw || coder != Coder::Huffman {
+            let msg = "a tree to code with can't go with frequencies, raw data, or another coder";
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, msg))
+        }
+        if let Some(method @ (Method::Words | Method::Utf8 | Method::Tokens | Method::Ngrams | Method::Rice)) = method {
+            let msg = format!("the {} method can't be coded with a given tree", method);
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, msg))
+        }
+    }
+    if options.optimistic.is_some() {
+        if options.model.is_some() || options.tree.is_some() || raw {
+            let msg = "optimistic encoding builds its own model, so it can't go with frequencies, a tree, or raw data";
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, msg))
+        }
+        if let Some(method @ (Method::Words | Method::Utf8 | Method::Tokens | Method::Ngrams | Method::Rice)) = method {
+            let msg = format!("the {} method can't be encoded optimistically", method);
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, msg))
+        }
+    }
+    if options.preset {
+        if options.model.is_none() || options.tree.is_some() || options.optimistic.is_some() || raw || coder != Coder::Huffman {
+            let msg = "a preset dictionary needs frequencies, and can't go with a tree, optimistic encoding, raw data, or another coder";
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, msg))
+        }
+        if let Some(method @ (Method::Stored | Method::Words | Method::Utf8 | Method::Tokens | Method::Ngrams | Method::Rice)) = method {
+            let msg = format!("the {} method can't be coded with a preset dictionary", method);
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, msg))
+        }
+    }
+    if raw && options.decoded_size.is_some() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "raw data has no header to record its size in"))
+    }
+    match options.stride {
+        Some(_) if raw => {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "raw data can't be split into planes"))
+        }
+        Some(stride) if stride < 2 => {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "records need to be at least 2 bytes long"))
+        }
+        _ => {}
+    }
+    if let Some(size) = options.ngram_size {
+        if size == 0 || size > symbols::MAX_SYMBOL_LEN {
+            let msg = format!("n-grams need to be between 1 and {} bytes long", symbols::MAX_SYMBOL_LEN);
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, msg))
+        }
+    }
+    trace_span!("encode", raw);
+    let start = Instant::now();
+    let mut watch = Stopwatch::new();
+    let mut input = Tally::new(input, cancel);
+    let mut writer = Tally::new(writer, None);
+    let decoded_size = options.decoded_size;
+    let (method, freqs, tree, symbols) = match options.stride {
+        Some(stride) => encode_method(&mut stride::Planes::new(&mut input, stride), &mut writer, options, &mut watch)?,
+        None => encode_method(&mut input, &mut writer, options, &mut watch)?
+    };
+    if let Some(size) = decoded_size.filter(|&size| size != input.crc.len()) {
+        let msg = format!("the input has {} bytes, not the {} recorded in the header", input.crc.len(), size);
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, msg))
+    }
+    trace_event!(bytes_in = input.crc.len(), bytes_out = writer.crc.len(), symbols, "encoded");
+    Ok(EncodeStats {
+        method,
+        freqs,
+        tree,
+        bytes_in: input.crc.len(),
+        bytes_out: writer.crc.len(),
+        symbols,
+        checksum: input.crc.sum(),
+        elapsed: start.elapsed(),
+        phases: watch.phases
+    })
+}
+
+// Encode the input with the options, and then with each of their alternatives,
+// keeping the smallest output in a spool until they've all been tried
+fn encode_smallest<R, W>(input: &mut R, writer: &mut W, options: EncodeOptions) -> io::Result<EncodeStats>
+where
+    R: io::Read + io::Seek,
+    W: io::Write
+{
+    let alternatives = options.alternatives.iter().map(|&(method, ngram_size)| EncodeOptions {
+        method: Some(method),
+        ngram_size,
+        optimistic: None,
+        alternatives: Vec::new(),
+        ..options.clone()
+    });
+    let first = EncodeOptions { alternatives: Vec::new(), ..options.clone() };
+    let mut smallest: Option<(Spool, EncodeStats)> = None;
+    for candidate in iter::once(first).chain(alternatives) {
+        input.seek(io::SeekFrom::Start(0))?;
+        let mut spool = Spool::new();
+        let stats = encode_stream_with(input, &mut spool, candidate)?;
+        if smallest.as_ref().is_none_or(|(kept, _)| spool.len() < kept.len()) {
+            smallest = Some((spool, stats));
+        }
+    }
+    // The options themselves always get tried
+    let (spool, stats) = smallest.unwrap();
+    spool.copy_to(writer)?;
+    Ok(stats)
+}
+
+// Encode the input with the method the options ask for, or the one picked for it,
+// returning the same things as encode_bytes
+fn encode_method<R, W>(
+    input: &mut R,
+    writer: &mut W,
+    options: EncodeOptions,
+    watch: &mut Stopwatch
+) -> io::Result<(Method, Option<Frequencies>, Option<HuffTree>, u64)>
+where
+    R: io::Read + io::Seek,
+    W: io::Write
+{
+    let header = |method| Header { stride: options.stride, decoded_size: options.decoded_size, ..Header::new(method) };
+    Ok(match options.method {
+        Some(Method::Words) => {
+            let symbols = encode_symbols(input, writer, header(Method::Words), &symbols::Words, watch)?;
+            (Method::Words, None, None, symbols)
+        }
+        Some(Method::Utf8) => {
+            let symbols = encode_symbols(input, writer, header(Method::Utf8), &symbols::Utf8, watch)?;
+            (Method::Utf8, None, None, symbols)
+        }
+        Some(Method::Tokens) => {
+            let symbols = encode_symbols(input, writer, header(Method::Tokens), &symbols::Varints, watch)?;
+            (Method::Tokens, None, None, symbols)
+        }
+        Some(Method::Ngrams) => {
+            let ngrams = symbols::Ngrams(options.ngram_size.unwrap_or(symbols::NGRAM_SIZE));
+            let symbols = encode_symbols(input, writer, header(Method::Ngrams), &ngrams, watch)?;
+            (Method::Ngrams, None, None, symbols)
+        }
+        Some(Method::Rice) => {
+            write_head(writer, header(Method::Rice), |_| Ok(()))?;
+            trace_span!("encode_data");
+            let mut encoder = rice::Encoder::new(&mut *writer);
+            let symbols = io::copy(input, &mut encoder)?;
+            encoder.finish()?;
+            watch.lap("encoding");
+            (Method::Rice, None, None, symbols)
+        }
+        _ if options.optimistic.is_some() => encode_optimistic(input, writer, options, watch)?,
+        _ => encode_bytes(input, writer, options, watch)?
+    })
+}
+
+// Encode the input with one of the methods working on bytes, returning the method,
+// the frequencies used, or those the huffman method would use, if it stores the input,
+// which are unknown when coding with a given tree, the tree used, and the number of bytes coded
+fn encode_bytes<R, W>(
+    input: &mut R,
+    writer: &mut W,
+    options: EncodeOptions,
+    watch: &mut Stopwatch
+) -> io::Result<(Method, Option<Frequencies>, Option<HuffTree>, u64)>
+where
+    R: io::Read + io::Seek,
+    W: io::Write
+{
+    let EncodeOptions { method, coder, model, tree: given_tree, raw, preset, stride, decoded_size, trace, .. } = options;
+    let (method, freqs) = match model {
+        // With a model, there's no need to count anything
+        Some(freqs) => (method.unwrap_or(Method::Huffman), Some(freqs)),
+        // Nor with a tree
+        None if given_tree.is_some() => (method.unwrap_or(Method::Huffman), None),
+        None => {
+            trace_span!("count");
+            let mut counts = Histogram([0; 256]);
+            let mut rle_counts = rle::Encoder::new(Histogram([0; 256]));
+            let mut buf = [0; 8192];
+            loop {
+                let read = match input.read(&mut buf) {
+                    Ok(0) => break,
+                    Ok(read) => read,
+                    Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(e) => return Err(e)
+                };
+                counts.write_all(&buf[..read])?;
+                rle_counts.write_all(&buf[..read])?;
+            }
+            let rle_counts = rle_counts.finish()?;
+            let method = method.unwrap_or_else(|| Method::choose(&counts.0, &rle_counts.0));
+            let counts = if method == Method::RleHuffman { &rle_counts.0 } else { &counts.0 };
+            watch.lap("counting");
+            (method, Some(Frequencies::from_counts(counts)))
+        }
+    };
+    trace_event!(%method, "chose method");
+    let (tree, succinct_tree) = match (given_tree, &freqs) {
+        (Some(tree), _) => (tree, true),
+        (None, Some(freqs)) => {
+            let tree = HuffTree::from_freqs(freqs);
+            // Decoders only build Huffman trees again from the frequencies, so other trees get written out,
+            // unless decoding is given the frequencies, and nothing gets written out
+            let succinct_tree = !preset && (coder != Coder::Huffman || tree.succinct_size() < 4 + 2 * freqs.pairs.len() as u64);
+            let tree = match coder {
+                Coder::ShannonFano => HuffTree::shannon_fano(freqs),
+                Coder::HuTucker => HuffTree::hu_tucker(freqs),
+                // A tree that gets written out doesn't need to be built again from the frequencies
+                Coder::Huffman if succinct_tree && !raw => HuffTree::with_min_variance(freqs),
+                Coder::Huffman => tree
+            };
+            (tree, succinct_tree)
+        }
+        (None, None) => unreachable!("the frequencies are only unknown with a given tree")
+    };
+    watch.lap("building tree");
+    let is_huffman = method == Method::Huffman || method == Method::RleHuffman;
+    if !raw {
+        let dict_id = freqs.as_ref().filter(|_| preset).map(Frequencies::id);
+        let header = Header { succinct_tree: succinct_tree && is_huffman, stride, decoded_size, dict_id, ..Header::new(method) };
+        write_head(writer, header, |head| match &freqs {
+            _ if !is_huffman => Ok(()),
+            // The frequencies are known without writing anything
+            _ if preset => Ok(()),
+            Some(freqs) if !succinct_tree => freqs.write(head),
+            _ => tree.write_succinct(head)
+        })?;
+    }
+
+    input.seek(io::SeekFrom::Start(0))?;
+    let symbols;
+    match method {
+        Method::Huffman | Method::RleHuffman => {
+            trace_span!("encode_data");
+            let mut encoder = HuffWriter::from_tree(&tree);
+            let mut sink = HuffSink { encoder: &mut encoder, writer, trace };
+            if method == Method::Huffman {
+                io::copy(input, &mut sink)?;
+            } else {
+                let mut rle_encoder = rle::Encoder::new(sink);
+                io::copy(input, &mut rle_encoder)?;
+                rle_encoder.finish()?;
+            }
+            symbols = encoder.finish(writer)?.symbols;
+            watch.lap("encoding");
+        }
+        Method::Stored => {
+            io::copy(input, writer)?;
+            watch.lap("copying");
+            return Ok((method, freqs, None, 0))
+        }
+        Method::Words | Method::Utf8 | Method::Tokens | Method::Ngrams => unreachable!("symbol methods aren't byte methods"),
+        Method::Rice => unreachable!("the rice method doesn't use frequencies")
+    }
+    Ok((method, freqs, Some(tree), symbols))
+}
+
+// The input of the first pass of optimistic encoding, which is the prefix the model was built from,
+// followed by the rest of the input, counting all of the bytes as they go by
+struct Counted<'a, R> {
+    prefix: io::Cursor<Vec<u8>>,
+    rest: &'a mut R,
+    counts: Histogram,
+    rle_counts: rle::Encoder<Histogram>
+}
+
+impl <'a, R: io::Read> io::Read for Counted<'a, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut read = self.prefix.read(buf)?;
+        if read == 0 {
+            read = self.rest.read(buf)?;
+        }
+        self.counts.write_all(&buf[..read])?;
+        self.rle_counts.write_all(&buf[..read])?;
+        Ok(read)
+    }
+}
+
+impl <'a, R> io::Seek for Counted<'a, R> {
+    // encode_bytes seeks back to the start before encoding, which nothing has been read from yet
+    fn seek(&mut self, pos: io::SeekFrom) -> io::Result<u64> {
+        match pos {
+            io::SeekFrom::Start(0) if self.prefix.position() == 0 => Ok(0),
+            _ => Err(io::Error::new(io::ErrorKind::Unsupported, "optimistic encoding reads its input once"))
+        }
+    }
+}
+
+// Encode the input with a model of its prefix, into a spool, while counting all of its bytes,
+// then write out the spool, unless a model of all the bytes would do much better,
+// in which case the input is read again and encoded with that model instead
+fn encode_optimistic<R, W>(
+    input: &mut R,
+    writer: &mut W,
+    options: EncodeOptions,
+    watch: &mut Stopwatch
+) -> io::Result<(Method, Option<Frequencies>, Option<HuffTree>, u64)>
+where
+    R: io::Read + io::Seek,
+    W: io::Write
+{
+    let limit = options.optimistic.unwrap_or(OPTIMISTIC_PREFIX);
+    let mut prefix = Vec::new();
+    input.by_ref().take(limit).read_to_end(&mut prefix)?;
+    let mut counts = Histogram([0; 256]);
+    counts.write_all(&prefix)?;
+    let mut rle_counts = rle::Encoder::new(Histogram([0; 256]));
+    rle_counts.write_all(&prefix)?;
+    let rle_counts = rle_counts.finish()?;
+    let method = options.method.unwrap_or_else(|| Method::choose(&counts.0, &rle_counts.0));
+    let mut counts = if method == Method::RleHuffman { rle_counts.0 } else { counts.0 };
+    // Input that fits in the prefix was counted whole, and there's nothing to be optimistic about
+    let whole = (prefix.len() as u64) < limit;
+    if !whole {
+        // The rest of the input can have bytes the prefix doesn't
+        for count in counts.iter_mut() {
+            *count = (*count).max(1);
+        }
+    }
+    watch.lap("counting");
+
+    let mut reader = Counted {
+        prefix: io::Cursor::new(prefix),
+        rest: input,
+        counts: Histogram([0; 256]),
+        rle_counts: rle::Encoder::new(Histogram([0; 256]))
+    };
+    let first = EncodeOptions {
+        method: Some(method),
+        model: Some(Frequencies::from_counts(&counts)),
+        optimistic: None,
+        ..options.clone()
+    };
+    if whole {
+        return encode_bytes(&mut reader, writer, first, watch)
+    }
+    let mut spool = Spool::new();
+    let result = encode_bytes(&mut reader, &mut spool, first, watch)?;
+
+    let Counted { counts, rle_counts, .. } = reader;
+    let total = counts.0.iter().sum::<u64>();
+    let rle_counts = rle_counts.finish()?;
+    let best = options.method.unwrap_or_else(|| Method::choose(&counts.0, &rle_counts.0));
+    let counts = if best == Method::RleHuffman { &rle_counts.0 } else { &counts.0 };
+    let header = Header { stride: options.stride, decoded_size: options.decoded_size, ..Header::new(best) };
+    let estimate = header.size() as u64 + match best {
+        Method::Stored => total,
+        _ => encoded_size(counts)
+    };
+    if spool.len() <= estimate + estimate / OPTIMISTIC_SLACK {
+        spool.copy_to(writer)?;
+        watch.lap("copying spool");
+        return Ok(result)
+    }
+    trace_event!(spooled = spool.len(), estimate, "reading the input again");
+    input.seek(io::SeekFrom::Start(0))?;
+    let second = EncodeOptions {
+        method: Some(best),
+        model: Some(Frequencies::from_counts(counts)),
+        optimistic: None,
+        ..options
+    };
+    encode_bytes(input, writer, second, watch)
+}
+
+// Encode the input with one of the symbol methods, after the header of that method,
+// returning the number of symbols coded
+fn encode_symbols<R, W, S>(
+    input: &mut R,
+    writer: &mut W,
+    header: Header,
+    splitter: &S,
+    watch: &mut Stopwatch
+) -> io::Result<u64>
+where
+    R: io::Read + io::Seek,
+    W: io::Write,
+    S: symbols::Splitter
+{
+    let dict = {
+        trace_span!("count_symbols");
+        symbols::Dictionary::count(input, splitter)?
+    };
+    watch.lap("counting");
+    trace_event!(symbols = dict.len(), "counted symbols");
+    write_head(writer, header, |head| dict.write(head))?;
+
+    input.seek(io::SeekFrom::Start(0))?;
+    trace_span!("encode_data");
+    let mut encoder = symbols::SymbolWriter::new(&dict);
+    symbols::split(input, splitter, |symbol| encoder.write_symbol(symbol, writer))?;
+    let symbols = encoder.finish(writer)?.symbols;
+    watch.lap("encoding");
+    Ok(symbols)
+}
+
+// Write a header, and then its table, followed by their checksum
+fn write_head<W, F>(writer: &mut W, header: Header, table: F) -> io::Result<()>
+where
+    W: io::Write,
+    F: FnOnce(&mut Tally<&mut W>) -> io::Result<()>
+{
+    let mut head = Tally::new(&mut *writer, None);
+    header.write(&mut head)?;
+    table(&mut head)?;
+    let sum = head.crc.sum();
+    writer.write_all(&sum.to_be_bytes())
+}
+
+/// The method and frequencies raw data was encoded with,
+/// since it doesn't start with a header and frequencies
+#[derive(Clone, Debug)]
+pub struct Raw {
+    pub method: Method,
+    pub freqs: Frequencies
+}
+
+// A writer failing once more than some number of bytes are written to it
+struct Capped<W> {
+    inner: W,
+    limit: Option<u64>,
+    written: u64
+}
+
+impl <W: io::Write> io::Write for Capped<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let buf = match self.limit {
+            Some(limit) if self.written == limit && !buf.is_empty() => {
+                return Err(invalid_data(&format!("the data decodes to more than the {} bytes allowed", limit)))
+            }
+            Some(limit) => &buf[..(limit - self.written).min(buf.len() as u64) as usize],
+            None => buf
+        };
+        let written = self.inner.write(buf)?;
+        self.written += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+// A reader keeping a copy of the bytes read from it
+struct Recorded<R> {
+    inner: R,
+    bytes: Vec<u8>
+}
+
+impl <R: io::Read> io::Read for Recorded<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.bytes.extend_from_slice(&buf[..read]);
+        Ok(read)
+    }
+}
+
+/// Upgrade a file written in an older version of the format to the current one, keeping its armor,
+/// if it has any, returning the version it was in. Only the header changes, so the data is copied as is,
+/// and files already in the current version come out the same.
+pub fn migrate_stream<R, W>(input: &mut R, writer: &mut W) -> io::Result<u8>
+where
+    R: io::BufRead,
+    W: io::Write
+{
+    if armor::is_armored(input.fill_buf()?) {
+        let mut armored = armor::Encoder::new(&mut *writer)?;
+        let version = migrate_inner(&mut io::BufReader::new(armor::Decoder::new(input)?), &mut armored)?;
+        armored.finish()?;
+        return Ok(version)
+    }
+    migrate_inner(input, writer)
+}
+
+fn migrate_inner<R, W>(input: &mut R, writer: &mut W) -> io::Result<u8>
+where
+    R: io::BufRead,
+    W: io::Write
+{
+    let mut head = Recorded { inner: &mut *input, bytes: Vec::new() };
+    let header = Header::read(&mut head).context(|| "while reading the header")?;
+    // A preset dictionary isn't in the file, and isn't needed to copy it
+    if header.dict_id.is_none() {
+        read_table(&mut head, header, None, &Limits::default())?;
+    }
+    let mut bytes = head.bytes;
+    if header.checksummed() {
+        writer.write_all(&bytes)?;
+    } else {
+        let table = bytes.split_off(header.size());
+        write_head(writer, Header { version: VERSION, ..header }, |head| head.write_all(&table))?;
+    }
+    io::copy(input, writer)?;
+    Ok(header.version)
+}
+
+/// Decode a source, removing its armor first, if it has any, with some options.
+/// Unless it's armored or stored, whatever follows the end of the encoded data is left in the source,
+/// so that the next file in it can be decoded by passing it in again, by reference.
+pub fn decode_stream_with<R, W>(input: R, writer: &mut W, options: DecodeOptions) -> io::Result<DecodeStats>
+where
+    R: io::BufRead,
+    W: io::Write
+{
+    let raw = options.raw.as_ref();
+    let dict = options.dict.as_ref();
+    let limits = &options.limits;
+    trace_span!("decode", raw = raw.is_some());
+    let start = Instant::now();
+    let mut watch = Stopwatch::new();
+    let mut input = Tally::new(input, options.cancel);
+    let mut writer = Tally::new(Capped { inner: writer, limit: limits.max_output, written: 0 }, None);
+    let (method, symbols, decoded_size) = if armor::is_armored(input.fill_buf()?) {
+        let mut input = io::BufReader::new(armor::Decoder::new(&mut input)?);
+        decode_inner(&mut input, &mut writer, raw, dict, limits, options.trace, &mut watch)?
+    } else {
+        decode_inner(&mut input, &mut writer, raw, dict, limits, options.trace, &mut watch)?
+    };
+    if let Some(size) = decoded_size.filter(|&size| size != writer.crc.len()) {
+        let msg = format!("the data decoded to {} bytes, not the {} its header records", writer.crc.len(), size);
+        return Err(invalid_data(&msg))
+    }
+    trace_event!(bytes_in = input.crc.len(), bytes_out = writer.crc.len(), symbols, "decoded");
+    Ok(DecodeStats {
+        method,
+        bytes_in: input.crc.len(),
+        bytes_out: writer.crc.len(),
+        symbols,
+        checksum: writer.crc.sum(),
+        elapsed: start.elapsed(),
+        phases: watch.phases
+    })
+}
+
+/// Read the header at the start of a source, and the table after it, checking them against
+/// the limits and their checksum, without decoding any of the data after them.
+/// This doesn't remove armor, and raw data, which has no header, fails.
+pub fn check_header<R: io::Read>(mut input: R, options: &DecodeOptions) -> io::Result<Header> {
+    let (header, _) = read_head(&mut input, options.dict.as_ref(), &options.limits, &mut Stopwatch::new())?;
+    Ok(header)
+}
+
+// Decode the input, returning the method it was encoded with, the number of symbols decoded,
+// and the size the header records the data decoding to, if it does
+fn decode_inner<R, W>(
+    input: &mut R,
+    writer: &mut W,
+    raw: Option<&Raw>,
+    dict: Option<&Frequencies>,
+    limits: &Limits,
+    trace: Option<&Tracer>,
+    watch: &mut Stopwatch
+) -> io::Result<(Method, u64, Option<u64>)>
+where
+    R: io::BufRead,
+    W: io::Write
+{
+    if let Some(raw) = raw {
+        let tree = HuffTree::from_freqs(&raw.freqs);
+        check_tree(&tree, raw.freqs.pairs.len(), limits)?;
+        return decode_coded(input, writer, raw.method, &tree, trace, watch).map(|n| (raw.method, n, None))
+    }
+    let (header, table) = read_head(input, dict, limits, watch)?;
+    let (method, symbols) = match header.stride {
+        Some(stride) => {
+            let mut records = stride::Records::new(writer, stride);
+            let decoded = decode_after_table(input, &mut records, header.method, table, trace, watch)?;
+            records.finish()?;
+            decoded
+        }
+        None => decode_after_table(input, writer, header.method, table, trace, watch)?
+    };
+    Ok((method, symbols, header.decoded_size))
+}
+
+// Read the header and the table after it, checking them against the limits, and against their checksum
+fn read_head<R: io::Read>(
+    input: &mut R,
+    dict: Option<&Frequencies>,
+    limits: &Limits,
+    watch: &mut Stopwatch
+) -> io::Result<(Header, Table)> {
+    // The header and table are summed as they're read, to check them before decoding anything
+    let mut head = Tally::new(&mut *input, None);
+    let header = Header::read(&mut head).context(|| "while reading the header")?;
+    watch.lap("reading header");
+    trace_event!(method = %header.method, succinct_tree = header.succinct_tree, stride = header.stride, "read header");
+    if let (Some(size), Some(max)) = (header.decoded_size, limits.max_output) {
+        if size > max {
+            return Err(invalid_data(&format!("the data decodes to {} bytes, more than the {} allowed", size, max)))
+        }
+    }
+    let dict = match (header.dict_id, dict) {
+        (Some(id), None) => {
+            let msg = format!("the data was encoded with preset dictionary {:08x}, which needs to be given to decode it", id);
+            return Err(invalid_data(&msg))
+        }
+        (Some(id), Some(dict)) if dict.id() != id => {
+            let msg = format!("the data was encoded with preset dictionary {:08x}, but the one given is {:08x}", id, dict.id());
+            return Err(invalid_data(&msg))
+        }
+        // A dictionary only gets used by data encoded with one
+        (Some(_), dict) => dict,
+        (None, _) => None
+    };
+    let table = match read_table(&mut head, header, dict, limits) {
+        // The table can't be checked before reading it, so it not making sense, or running past
+        // the end of the file, is how corruption shows up
+        Err(e) if header.checksummed() && matches!(e.kind(), io::ErrorKind::InvalidData | io::ErrorKind::UnexpectedEof) => {
+            return Err(e).context(|| "the header is corrupt, or cut short")
+        }
+        result => result?
+    };
+    let sum = head.crc.sum();
+    let table_end = head.crc.len();
+    if header.checksummed() {
+        let mut expected = [0; 4];
+        input.read_exact(&mut expected).context(|| "while reading the checksum of the header")?;
+        let expected = u32::from_be_bytes(expected);
+        if sum != expected {
+            let msg = format!(
+                "the header is corrupt, the checksum of its first {} bytes is {:08x}, but it records {:08x}",
+                table_end, sum, expected
+            );
+            return Err(invalid_data(&msg))
+        }
+    }
+    Ok((header, table))
+}
+
+// What the data after the header gets decoded with
+enum Table {
+    None,
+    Tree(HuffTree),
+    Dictionary(symbols::Dictionary)
+}
+
+// Read the table after the header, or build the tree from the preset dictionary,
+// checking that it's within the limits
+fn read_table<R: io::Read>(input: &mut R, header: Header, dict: Option<&Frequencies>, limits: &Limits) -> io::Result<Table> {
+    let (tree, entries) = match header.method {
+        Method::Stored | Method::Rice => return Ok(Table::None),
+        Method::Words | Method::Utf8 | Method::Tokens | Method::Ngrams => {
+            let max_entries = limits.max_entries.unwrap_or(usize::MAX);
+            let dict = symbols::Dictionary::read_at_most(input, max_entries).context(|| "while reading the dictionary")?;
+            if let Some(max) = limits.max_code_len.filter(|&max| dict.max_code_len() > max) {
+                let msg = format!("the dictionary has codes of {} bits, longer than the {} allowed", dict.max_code_len(), max);
+                return Err(invalid_data(&msg))
+            }
+            return Ok(Table::Dictionary(dict))
+        }
+        Method::Huffman | Method::RleHuffman if header.succinct_tree => {
+            let tree = HuffTree::read_succinct(input).context(|| "while reading the tree")?;
+            // The end of the transmission is a leaf, but not an entry
+            let entries = tree.leaf_count() - 1;
+            (tree, entries)
+        }
+        Method::Huffman | Method::RleHuffman if header.dict_id.is_some() => {
+            // decode_inner checked that this is the dictionary the header asks for
+            let dict = dict.expect("data encoded with a preset dictionary is decoded with it");
+            (HuffTree::from_freqs(dict), dict.pairs.len())
+        }
+        Method::Huffman | Method::RleHuffman => {
+            let freqs = Frequencies::read(input)
+                .context(|| format!("while reading the frequencies, which start at byte {}", header.size()))?;
+            (HuffTree::from_freqs(&freqs), freqs.pairs.len())
+        }
+    };
+    check_tree(&tree, entries, limits)?;
+    Ok(Table::Tree(tree))
+}
+
+// Check that a tree, built from a table with some number of entries, is within the limits
+fn check_tree(tree: &HuffTree, entries: usize, limits: &Limits) -> io::Result<()> {
+    if let Some(max) = limits.max_entries.filter(|&max| entries > max) {
+        return Err(invalid_data(&format!("the table has {} entries, more than the {} allowed", entries, max)))
+    }
+    if let Some(max) = limits.max_code_len.filter(|&max| tree.depth() > max) {
+        return Err(invalid_data(&format!("the tree has codes of {} bits, longer than the {} allowed", tree.depth(), max)))
+    }
+    Ok(())
+}
+
+// Decode the rest of the input, after the header and its table
+fn decode_after_table<R, W>(
+    input: &mut R,
+    writer: &mut W,
+    method: Method,
+    table: Table,
+    trace: Option<&Tracer>,
+    watch: &mut Stopwatch
+) -> io::Result<(Method, u64)>
+where
+    R: io::BufRead,
+    W: io::Write
+{
+    match (method, table) {
+        (Method::Stored, _) => {
+            io::copy(input, writer)?;
+            watch.lap("copying");
+            Ok((method, 0))
+        }
+        (Method::Rice, _) => {
+            let symbols = rice::decode(input, writer).context(|| "while decoding the data")?;
+            watch.lap("decoding");
+            Ok((method, symbols))
+        }
+        (_, Table::Dictionary(dict)) => decode_symbols(input, writer, &dict, watch).map(|n| (method, n)),
+        (_, Table::Tree(tree)) => decode_coded(input, writer, method, &tree, trace, watch).map(|n| (method, n)),
+        (_, Table::None) => unreachable!("the {} method has a table", method)
+    }
+}
+
+// Decode data coded with the huffman or rle method, returning the number of symbols decoded
+fn decode_coded<R, W>(
+    input: &mut R,
+    writer: &mut W,
+    method: Method,
+    tree: &HuffTree,
+    trace: Option<&Tracer>,
+    watch: &mut Stopwatch
+) -> io::Result<u64>
+where
+    R: io::BufRead,
+    W: io::Write
+{
+    watch.lap("building tree");
+    let symbols = if method == Method::RleHuffman {
+        decode_traced(input, &mut rle::Decoder::new(writer), tree, trace)
+    } else {
+        decode_traced(input, writer, tree, trace)
+    };
+    let symbols = symbols.context(|| "while decoding the data")?;
+    watch.lap("decoding");
+    Ok(symbols)
+}
+
+fn decode_traced<R, W>(input: &mut R, writer: &mut W, tree: &HuffTree, trace: Option<&Tracer>) -> io::Result<u64>
+where
+    R: io::BufRead,
+    W: io::Write
+{
+    match trace {
+        Some(tracer) => {
+            let mut writer = TraceWriter { writer, codes: HuffWriter::from_tree(tree), bit: 0, trace: Some(tracer) };
+            decode_huffman(input, &mut writer, tree)
+        }
+        None => decode_huffman(input, writer, tree)
+    }
+}
+
+fn decode_huffman<R, W>(input: &mut R, writer: &mut W, tree: &HuffTree) -> io::Result<u64>
+where
+    R: io::BufRead,
+    W: io::Write
+{
+    trace_span!("decode_data");
+    // The end of the transmission takes no bits when it's the whole tree
+    if let HuffTree::EOF = tree {
+        return Ok(0)
+    }
+    let mut reader = HuffReader::new(tree);
+    loop {
+        let buf = input.fill_buf()?;
+        if buf.is_empty() {
+            return Err(ended_early(reader.bit_position()))
+        }
+        let (used, can_feed) = reader.feed_slice(buf, writer)?;
+        input.consume(used);
+        if !can_feed {
+            return Ok(reader.symbol_count())
+        }
+    }
+}
+
+// The error for coded data ending at some bit, before its end of the transmission
+fn ended_early(bit: u64) -> io::Error {
+    let digits = bit.to_string();
+    let mut grouped = String::new();
+    for (i, digit) in digits.chars().enumerate() {
+        if i > 0 && (digits.len() - i).is_multiple_of(3) {
+            grouped.push(',');
+        }
+        grouped.push(digit);
+    }
+    let msg = format!("the data ended at bit {}, before the end of the transmission", grouped);
+    io::Error::new(io::ErrorKind::UnexpectedEof, msg)
+}
+
+fn decode_symbols<R, W>(input: &mut R, writer: &mut W, dict: &symbols::Dictionary, watch: &mut Stopwatch) -> io::Result<u64>
+where
+    R: io::BufRead,
+    W: io::Write
+{
+    watch.lap("reading dictionary");
+    trace_span!("decode_data", symbols = dict.len());
+    // Like in decode_huffman, an empty dictionary leaves the end of the transmission without any bits
+    if dict.is_empty() {
+        watch.lap("decoding");
+        return Ok(0)
+    }
+    let mut reader = symbols::SymbolReader::new(dict);
+
+    let mut decode_data = || -> io::Result<()> {
+        for maybe_byte in input.bytes() {
+            let byte = maybe_byte?;
+            let can_feed = reader.feed(byte, writer)?;
+            if !can_feed {
+                return Ok(())
+            }
+        }
+        Err(ended_early(reader.bit_position()))
+    };
+    decode_data().context(|| "while decoding the data")?;
+    watch.lap("decoding");
+    Ok(reader.symbol_count())
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::io;
+    use std::sync::{Arc, Mutex};
+    use std::sync::atomic::AtomicBool;
+    use std::thread;
+    use quickcheck::{Arbitrary, Gen, QuickCheck};
+    use crate::header::Method;
+    use super::{encode_iter, Checkpoint, Codebook, HuffTree, HuffReader, HuffWriter, Frequencies, OwnedHuffReader, Tracer, MAX_CODE_LEN};
+
+    // Inputs made of a few bytes, each much more common than the next,
+    // including inputs with only one distinct byte
+    #[derive(Clone, Debug)]
+    struct Skewed(Vec<u8>);
+
+    impl Arbitrary for Skewed {
+        fn arbitrary(g: &mut Gen) -> Self {
+            let alphabet: Vec<u8> = (0..u8::arbitrary(g) % 6 + 1).map(|_| u8::arbitrary(g)).collect();
+            let bytes = Vec::<u8>::arbitrary(g).into_iter().map(|x| {
+                // Each byte of the alphabet is about twice as likely as the next one
+                let rank = (x.leading_zeros() as usize).min(alphabet.len() - 1);
+                alphabet[rank]
+            });
+            Skewed(bytes.collect())
+        }
+
+        fn shrink(&self) -> Box<dyn Iterator<Item = Self>> {
+            Box::new(self.0.shrink().map(Skewed))
+        }
+    }
+
+    // Code some data with a tree, ending with the end of the transmission
+    fn encode_with(tree: &HuffTree, data: &[u8]) -> Vec<u8> {
+        let mut writer = HuffWriter::from_tree(tree);
+        let mut encoded = Vec::new();
+        for &byte in data {
+            writer.write_byte(byte, &mut encoded).unwrap();
+        }
+        writer.finish(&mut encoded).unwrap();
+        encoded
+    }
+
+    // Encode and decode with HuffWriter and HuffReader directly, as well as with the streams
+    fn round_trips(input: &[u8]) -> bool {
+        let freqs = Frequencies::count_reader(&mut &input[..]).unwrap();
+        let tree = HuffTree::from_freqs(&freqs);
+        let encoded = encode_with(&tree, input);
+        let mut decoded = Vec::new();
+        HuffReader::new(&tree).feed_slice(&encoded, &mut decoded).unwrap();
+
+        let mut streamed = Vec::new();
+        super::encode_stream(&mut io::Cursor::new(input), &mut streamed).unwrap();
+        let mut destreamed = Vec::new();
+        super::decode_stream(&mut &streamed[..], &mut destreamed).unwrap();
+        decoded == input && destreamed == input
+    }
+
+    #[test]
+    fn arbitrary_inputs_round_trip() {
+        fn any_bytes(input: Vec<u8>) -> bool {
+            round_trips(&input)
+        }
+        fn skewed_bytes(input: Skewed) -> bool {
+            round_trips(&input.0)
+        }
+        QuickCheck::new().quickcheck(any_bytes as fn(Vec<u8>) -> bool);
+        QuickCheck::new().quickcheck(skewed_bytes as fn(Skewed) -> bool);
+    }
+
+    #[test]
+    fn counting_a_reader_matches_counting_bytes() {
+        let input: Vec<u8> = (0..20_000u32).map(|i| (i * i % 251) as u8).collect();
+        let freqs = Frequencies::count_reader(&mut &input[..]).unwrap();
+        let by_byte = Frequencies::count_bytes(input.iter().map(|&b| Ok::<u8, ()>(b))).unwrap();
+        assert_eq!(freqs.pairs(), by_byte.pairs());
+        assert!(Frequencies::count_reader(&mut &[][..]).unwrap().pairs().is_empty());
+    }
+
+    #[test]
+    fn huff_tree_freqs_works() {
+        let mut freqs = Frequencies { pairs: Vec::new() };
+        freqs.pairs.push((100, 69));
+        freqs.pairs.push((2, 71));
+        freqs.pairs.push((1, 70));
+        let tree = HuffTree::Branch(
             Box::new(HuffTree::Branch(
                 Box::new(HuffTree::Branch(
                     Box::new(HuffTree::EOF), 
@@ -254,4 +2848,663 @@ mod test {
         );
         assert_eq!(HuffTree::from_freqs(&freqs), tree);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn tree_shapes_get_measured() {
+        let mut freqs = Frequencies { pairs: Vec::new() };
+        freqs.pairs.push((100, 69));
+        freqs.pairs.push((2, 71));
+        freqs.pairs.push((1, 70));
+        let tree = HuffTree::from_freqs(&freqs);
+        assert_eq!(tree.depth(), 3);
+        assert_eq!(tree.leaf_count(), 4);
+        assert_eq!(tree.weighted_path_length(&freqs), 100 + 2 * 2 + 3);
+    }
+
+    #[test]
+    fn min_variance_trees_are_shallower() {
+        // Ties between leaves and merged trees leave a choice, which matters here
+        let mut counts = [0; 256];
+        counts[..5].copy_from_slice(&[6, 5, 2, 2, 2]);
+        let freqs = Frequencies::from_counts(&counts);
+        let tree = HuffTree::from_freqs(&freqs);
+        let balanced = HuffTree::with_min_variance(&freqs);
+        assert_eq!(balanced.weighted_path_length(&freqs), tree.weighted_path_length(&freqs));
+        assert!(balanced.depth() < tree.depth(), "{} isn't less than {}", balanced.depth(), tree.depth());
+    }
+
+    #[test]
+    fn trees_can_come_from_probabilities() {
+        let tree = HuffTree::from_probabilities(&[(b'a', 0.5), (b'b', 0.25), (b'c', 0.125), (b'd', 0.125)]).unwrap();
+        let writer = HuffWriter::from_tree(&tree);
+        let lengths: Vec<_> = b"abcd".iter().map(|&byte| writer.code(byte).unwrap().1).collect();
+        assert_eq!(lengths, vec![1, 2, 3, 4]);
+        assert!(writer.code(b'e').is_none());
+        assert!(HuffTree::from_probabilities(&[(b'a', f64::NAN)]).is_err());
+        assert!(HuffTree::from_probabilities(&[(b'a', -0.5), (b'b', 1.0)]).is_err());
+        assert!(HuffTree::from_probabilities(&[(b'a', 0.5), (b'a', 0.5)]).is_err());
+        assert!(HuffTree::from_probabilities(&[(b'a', 0.75), (b'b', 0.75)]).is_err());
+        assert!(HuffTree::from_probabilities(&[(b'a', 0.0)]).is_err());
+    }
+
+    #[test]
+    fn code_lengths_round_trip() {
+        let mut counts = [0; 256];
+        for (i, count) in counts.iter_mut().enumerate().step_by(5) {
+            *count = (i as u64 * 7919) % 1000 + 1;
+        }
+        let tree = HuffTree::from_freqs(&Frequencies::from_counts(&counts));
+        let lengths = tree.to_code_lengths();
+        let canonical = HuffTree::from_code_lengths(&lengths).unwrap();
+        assert_eq!(canonical.to_code_lengths()[..], lengths[..]);
+        assert_eq!(HuffTree::from_code_lengths(&[0; 257]).unwrap(), HuffTree::EOF);
+
+        // The canonical codes of the shortest bytes come first
+        let mut lengths = [0; 257];
+        lengths[b'b' as usize] = 1;
+        lengths[b'a' as usize] = 2;
+        lengths[256] = 2;
+        let expected = HuffTree::Branch(
+            Box::new(HuffTree::Known(b'b')),
+            Box::new(HuffTree::Branch(Box::new(HuffTree::Known(b'a')), Box::new(HuffTree::EOF)))
+        );
+        assert_eq!(HuffTree::from_code_lengths(&lengths).unwrap(), expected);
+        lengths[256] = 3;
+        assert!(HuffTree::from_code_lengths(&lengths).is_err());
+        assert!(HuffTree::from_code_lengths(&lengths[..256]).is_err());
+    }
+
+    #[test]
+    fn malformed_frequencies_are_rejected() {
+        let read = |bytes: &[u8]| Frequencies::read(&mut &bytes[..]).map_err(|e| e.to_string());
+        assert_eq!(read(&[0, 0, 0, 2, b'a', 3, b'b', 1]).unwrap().pairs(), &[(3, b'a'), (1, b'b')]);
+        assert_eq!(read(&[0, 0, 0, 0]).unwrap_err(), "there are 0 pairs, instead of between 1 and 256");
+        assert!(read(&[0, 0, 1, 1]).unwrap_err().contains("257 pairs"));
+        assert_eq!(
+            read(&[0, 0, 0, 3, b'a', 3, b'b', 1, b'a', 1]).unwrap_err(),
+            "byte 97 has a second pair at byte 8 of the frequencies, after the one at byte 4"
+        );
+        assert!(read(&[0, 0, 0, 2, b'a', 3]).unwrap_err().starts_with("while reading the 2 pairs, from byte 4 of the frequencies"));
+    }
+
+    #[test]
+    fn errors_say_at_which_bit_the_data_ended() {
+        let data = b"abracadabra, abracadabra, abracadabra";
+        let tree = HuffTree::from_freqs(&Frequencies::count_reader(&mut &data[..]).unwrap());
+        let mut writer = HuffWriter::from_tree(&tree);
+        let mut encoded = Vec::new();
+        for &byte in data {
+            writer.write_byte(byte, &mut encoded).unwrap();
+        }
+        let bits = writer.bit_position();
+        let eof = writer.eof_code().1 as u64;
+        let stats = writer.finish(&mut encoded).unwrap();
+        assert_eq!((stats.symbols, stats.bits, stats.bytes()), (data.len() as u64, bits + eof, encoded.len() as u64));
+        let mut reader = HuffReader::new(&tree);
+        reader.feed_slice(&encoded, &mut Vec::new()).unwrap();
+        assert!(reader.bit_position() > bits);
+
+        let mut file = Vec::new();
+        super::encode_stream_with(&mut io::Cursor::new(&data[..]), &mut file, Default::default()).unwrap();
+        file.truncate(file.len() - 4);
+        let e = super::decode_stream(&mut &file[..], &mut Vec::new()).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof);
+        assert!(e.to_string().contains("the data ended at bit "), "{}", e);
+    }
+
+    // A buffer traces can be written to, and looked at afterwards
+    #[derive(Clone, Default)]
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+
+    impl io::Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn encoding_and_decoding_trace_the_same_codes() {
+        let data = b"abracadabra, abracadabra";
+        for &method in &[Method::Huffman, Method::RleHuffman] {
+            let (encoding, decoding) = (Shared::default(), Shared::default());
+            let tracer = Tracer::new(8, encoding.clone());
+            let options = super::EncodeOptions { method: Some(method), trace: Some(&tracer), ..Default::default() };
+            let mut encoded = Vec::new();
+            super::encode_stream_with(&mut io::Cursor::new(&data[..]), &mut encoded, options).unwrap();
+            let tracer = Tracer::new(8, decoding.clone());
+            let options = super::DecodeOptions { trace: Some(&tracer), ..Default::default() };
+            super::decode_stream_with(&encoded[..], &mut Vec::new(), options).unwrap();
+
+            let trace = String::from_utf8(encoding.0.lock().unwrap().clone()).unwrap();
+            assert_eq!(trace.lines().count(), 8);
+            assert!(trace.starts_with("symbol 0: 0x61 'a' at bit 0, code "), "{}", trace);
+            assert_eq!(trace.as_bytes(), &decoding.0.lock().unwrap()[..]);
+        }
+    }
+
+    #[test]
+    fn decoding_into_small_buffers_works() {
+        let data = b"abracadabra, abracadabra, abracadabra";
+        let tree = HuffTree::from_freqs(&Frequencies::count_reader(&mut &data[..]).unwrap());
+        let encoded = encode_with(&tree, data);
+        for &room in &[1, 3, 64] {
+            let mut reader = OwnedHuffReader::new(&tree);
+            let mut out = [0; 64];
+            let (mut decoded, mut input) = (Vec::new(), &encoded[..]);
+            loop {
+                let progress = reader.decode_into(input, &mut out[..room]);
+                decoded.extend_from_slice(&out[..progress.written]);
+                input = &input[progress.read..];
+                if progress.finished {
+                    break;
+                }
+            }
+            assert_eq!(&decoded[..], &data[..]);
+            assert!(input.is_empty());
+        }
+    }
+
+    #[test]
+    fn trees_round_trip_through_json() {
+        let data = b"abracadabra";
+        let tree = HuffTree::from_freqs(&Frequencies::count_reader(&mut &data[..]).unwrap());
+        assert_eq!(HuffTree::from_json(&tree.to_json()).unwrap(), tree);
+        assert_eq!(HuffTree::from_json(r#"{"eof": true}"#).unwrap(), HuffTree::EOF);
+        let tree = HuffTree::from_json(r#"{"0": {"byte": 97, "code": "0"}, "1": {"eof": true}}"#).unwrap();
+        assert_eq!(tree, HuffTree::Branch(Box::new(HuffTree::Known(b'a')), Box::new(HuffTree::EOF)));
+
+        let malformed = [
+            r#"{"byte": 97}"#,
+            r#"{"0": {"byte": 97}}"#,
+            r#"{"0": {"byte": 97, "code": "1"}, "1": {"eof": true}}"#,
+            r#"{"0": {"byte": 97}, "1": {"0": {"byte": 97}, "1": {"eof": true}}}"#,
+            r#"{"0": {"byte": 256}, "1": {"eof": true}}"#
+        ];
+        for json in malformed.iter() {
+            assert_eq!(HuffTree::from_json(json).unwrap_err().kind(), io::ErrorKind::InvalidData, "{}", json);
+        }
+    }
+
+    #[test]
+    fn codebooks_work_both_ways() {
+        // 0 for a, 10 for the end of the transmission, and 11 for b
+        let codebook = Codebook::from_codes(&[(b'a' as usize, 0b0, 1), (256, 0b01, 2), (b'b' as usize, 0b11, 2)]).unwrap();
+        assert_eq!(codebook.code(b'b' as usize), Some((0b11, 2)));
+        assert_eq!(codebook.code(b'c' as usize), None);
+        assert_eq!(codebook.symbol(0b01, 2), Some(256));
+        assert_eq!(codebook.symbol(0b1, 1), None);
+        assert_eq!(Codebook::from_json(&codebook.to_json()).unwrap(), codebook);
+
+        let mut writer = HuffWriter::from_codebook(&codebook);
+        let mut encoded = Vec::new();
+        for &byte in b"abba" {
+            writer.write_byte(byte, &mut encoded).unwrap();
+        }
+        writer.finish(&mut encoded).unwrap();
+        let mut reader = OwnedHuffReader::from_codebook(&codebook);
+        let mut decoded = Vec::new();
+        assert!(!reader.feed(encoded[0], &mut decoded).unwrap());
+        assert_eq!(decoded, b"abba");
+
+        assert!(Codebook::from_codes(&[(b'a' as usize, 0b0, 1), (256, 0b1, 2)]).is_err());
+        assert!(Codebook::from_codes(&[(b'a' as usize, 0b0, 1), (256, 0b1, 1), (b'b' as usize, 0b11, 2)]).is_err());
+        assert!(Codebook::from_codes(&[(b'a' as usize, 0b0, 1), (b'b' as usize, 0b1, 1)]).is_err());
+    }
+
+    #[test]
+    fn shannon_fano_trees_decode_like_any_other() {
+        // The textbook case where Shannon–Fano does worse than Huffman
+        let mut counts = [0; 256];
+        counts[..5].copy_from_slice(&[15, 7, 6, 6, 5]);
+        let freqs = Frequencies::from_counts(&counts);
+        let tree = HuffTree::shannon_fano(&freqs);
+        assert_eq!(tree.leaf_count(), 6);
+        assert!(tree.weighted_path_length(&freqs) > HuffTree::from_freqs(&freqs).weighted_path_length(&freqs));
+
+        let data = b"it was the best of times, it was the worst of times";
+        let options = super::EncodeOptions { method: Some(Method::Huffman), coder: super::Coder::ShannonFano, ..Default::default() };
+        let mut encoded = Vec::new();
+        super::encode_stream_with(&mut io::Cursor::new(&data[..]), &mut encoded, options).unwrap();
+        let mut decoded = Vec::new();
+        super::decode_stream(&mut &encoded[..], &mut decoded).unwrap();
+        assert_eq!(&decoded[..], &data[..]);
+    }
+
+    #[test]
+    fn succinct_trees_round_trip() {
+        let mut counts = [0; 256];
+        for (i, count) in counts.iter_mut().enumerate().step_by(3) {
+            *count = i as u64 + 1;
+        }
+        let tree = HuffTree::from_freqs(&Frequencies::from_counts(&counts));
+        let mut buf = Vec::new();
+        tree.write_succinct(&mut buf).unwrap();
+        assert_eq!(buf.len() as u64, tree.succinct_size());
+        assert_eq!(HuffTree::read_succinct(&mut &buf[..]).unwrap(), tree);
+
+        // The end of the transmission first, and then the same byte twice
+        let duplicated = [0, 0, 0b0000_1101, 7, 7];
+        assert!(HuffTree::read_succinct(&mut &duplicated[..]).is_err());
+    }
+
+    #[test]
+    fn scratch_words_write_the_same_bits() {
+        // A chain of bytes, with codes up to 100 bits long
+        let mut tree = HuffTree::EOF;
+        for byte in 0..100 {
+            tree = HuffTree::Branch(Box::new(HuffTree::Known(byte)), Box::new(tree));
+        }
+        let mut wide = HuffWriter::from_tree(&tree);
+        let mut narrow = HuffWriter::<u64>::with_scratch(&tree);
+        let mut wide_out = Vec::new();
+        let mut narrow_out = Vec::new();
+        for byte in (0..100).chain((0..100).rev()) {
+            wide.write_byte(byte, &mut wide_out).unwrap();
+            narrow.write_byte(byte, &mut narrow_out).unwrap();
+        }
+        wide.finish(&mut wide_out).unwrap();
+        narrow.finish(&mut narrow_out).unwrap();
+        assert_eq!(narrow_out, wide_out);
+    }
+
+    #[test]
+    fn encoding_iterators_gives_the_same_codes() {
+        let input = b"abracadabra".repeat(100);
+        let tree = HuffTree::from_freqs(&Frequencies::count_reader(&mut &input[..]).unwrap());
+        let expected = encode_with(&tree, &input);
+        let encoded: Vec<u8> = encode_iter(&tree, input.iter().copied()).collect();
+        assert_eq!(encoded, expected);
+        // Only the bytes filling the first scratch word are coded before it's asked for
+        let mut pulled = 0;
+        let first = encode_iter(&tree, input.iter().inspect(|_| pulled += 1).copied()).next();
+        assert_eq!(first, Some(expected[0]));
+        assert!(pulled < input.len());
+    }
+
+    #[test]
+    fn flattening_works() {
+        let tree = HuffTree::Branch(
+            Box::new(HuffTree::Branch(
+                Box::new(HuffTree::EOF),
+                Box::new(HuffTree::Known(70))
+            )),
+            Box::new(HuffTree::Known(69))
+        );
+        assert_eq!(tree.flatten(), vec![(258, 69), (256, 70)]);
+    }
+
+    #[test]
+    fn feeding_slices_stops_at_the_end() {
+        let input = b"abracadabra";
+        let freqs = Frequencies::count_reader(&mut &input[..]).unwrap();
+        let tree = HuffTree::from_freqs(&freqs);
+        let mut encoded = encode_with(&tree, input);
+        let len = encoded.len();
+        encoded.extend_from_slice(b"trailing");
+
+        let mut reader = HuffReader::new(&tree);
+        let mut decoded = Vec::new();
+        assert_eq!(reader.last_byte_bits(), None);
+        assert_eq!(reader.feed_slice(&encoded, &mut decoded).unwrap(), (len, false));
+        assert_eq!(&decoded[..], &input[..]);
+        let bits = reader.last_byte_bits().unwrap();
+        assert_eq!(reader.bit_position(), (len as u64 - 1) * 8 + bits as u64);
+
+        let mut owned = OwnedHuffReader::new(&tree);
+        let decoded = owned.decode_into(&encoded, &mut [0; 64]);
+        assert_eq!((decoded.read, owned.last_byte_bits()), (len, Some(bits)));
+    }
+
+    #[test]
+    fn concatenated_files_get_decoded_one_after_another() {
+        let inputs = ["abracadabra".repeat(20), "mississippi".repeat(20)];
+        let mut encoded = Vec::new();
+        for input in &inputs {
+            super::encode_stream(&mut io::Cursor::new(input), &mut encoded).unwrap();
+        }
+        let mut source = &encoded[..];
+        for input in &inputs {
+            let mut decoded = Vec::new();
+            super::decode_stream_with(&mut source, &mut decoded, Default::default()).unwrap();
+            assert_eq!(decoded, input.as_bytes());
+        }
+        assert!(source.is_empty());
+    }
+
+    #[test]
+    fn resuming_from_a_checkpoint_works() {
+        let input: Vec<u8> = (0..1000u32).map(|i| (i * i % 7) as u8).collect();
+        let freqs = Frequencies::count_reader(&mut &input[..]).unwrap();
+        let tree = HuffTree::from_freqs(&freqs);
+        let expected = encode_with(&tree, &input);
+
+        let mut writer = HuffWriter::from_tree(&tree);
+        let mut encoded = Vec::new();
+        for &byte in &input[..321] {
+            writer.write_byte(byte, &mut encoded).unwrap();
+        }
+        let mut saved = Vec::new();
+        writer.checkpoint().write(&mut saved).unwrap();
+        let checkpoint = Checkpoint::read(&mut &saved[..]).unwrap();
+        assert_eq!(checkpoint, writer.checkpoint());
+        assert_eq!(checkpoint.bytes_written, encoded.len() as u64);
+
+        let wide = Checkpoint { shift: 64, ..checkpoint };
+        assert!(HuffWriter::<u64>::resume(&tree, wide).is_err());
+        let mut writer: HuffWriter = HuffWriter::resume(&tree, checkpoint).unwrap();
+        for &byte in &input[checkpoint.symbols as usize..] {
+            writer.write_byte(byte, &mut encoded).unwrap();
+        }
+        writer.finish(&mut encoded).unwrap();
+        assert_eq!(encoded, expected);
+
+        // Starting over gives the same bits as a new writer
+        let mut writer = HuffWriter::from_tree(&tree);
+        writer.write_byte(input[0], &mut Vec::new()).unwrap();
+        writer.reset(&tree);
+        encoded.clear();
+        for &byte in &input {
+            writer.write_byte(byte, &mut encoded).unwrap();
+        }
+        writer.finish(&mut encoded).unwrap();
+        assert_eq!(encoded, expected);
+    }
+
+    #[test]
+    fn owned_readers_work_across_threads() {
+        let input = b"she sells sea shells by the sea shore";
+        let freqs = Frequencies::count_reader(&mut &input[..]).unwrap();
+        let tree = HuffTree::from_freqs(&freqs);
+        let encoded = encode_with(&tree, input);
+
+        let reader = OwnedHuffReader::new(&tree);
+        let threads: Vec<_> = (0..2).map(|_| {
+            let mut reader = reader.clone();
+            let encoded = encoded.clone();
+            thread::spawn(move || {
+                let mut decoded = Vec::new();
+                for &byte in &encoded {
+                    if !reader.feed(byte, &mut decoded).unwrap() {
+                        break;
+                    }
+                }
+                decoded
+            })
+        }).collect();
+        for thread in threads {
+            assert_eq!(&thread.join().unwrap()[..], &input[..]);
+        }
+    }
+
+    #[test]
+    fn rare_bytes_dont_make_codes_too_long() {
+        // Every byte but the first ends up with a frequency of 0,
+        // which would make a chain 256 branches deep
+        let mut counts = [1; 256];
+        counts[0] = 1_000_000;
+        let freqs = Frequencies::from_counts(&counts);
+        let tree = HuffTree::from_freqs(&freqs);
+        assert!(tree.depth() <= MAX_CODE_LEN);
+
+        let input: Vec<u8> = (0..=255).chain(0..=255).collect();
+        let encoded = encode_with(&tree, &input);
+        let mut reader = HuffReader::new(&tree);
+        let mut decoded = Vec::new();
+        for &byte in &encoded {
+            if !reader.feed(byte, &mut decoded).unwrap() {
+                break;
+            }
+        }
+        assert_eq!(decoded, input);
+    }
+
+    #[test]
+    fn json_round_trips() {
+        let mut counts = [0; 256];
+        counts[b'a' as usize] = 10;
+        counts[b'b' as usize] = 40;
+        counts[b'c' as usize] = 10;
+        let freqs = Frequencies::from_counts(&counts);
+        let read = Frequencies::from_json(&freqs.to_json()).unwrap();
+        assert_eq!(read.pairs(), freqs.pairs());
+        let duplicated = r#"{"frequencies": [{"byte": 1, "count": 2}, {"byte": 1, "count": 3}]}"#;
+        assert!(Frequencies::from_json(duplicated).is_err());
+        assert!(Frequencies::from_json(r#"{"frequencies": [{"byte": 256, "count": 2}]}"#).is_err());
+    }
+
+    #[test]
+    fn encoding_then_decoding_streams_works() {
+        for input in &[&b""[..], b"a", b"abracadabra", &[7; 1000]] {
+            let mut encoded = Vec::new();
+            let encoded_stats = super::encode_stream(&mut io::Cursor::new(input), &mut encoded).unwrap();
+            assert_eq!(encoded_stats.bytes_out, encoded.len() as u64);
+            let mut decoded = Vec::new();
+            let stats = super::decode_stream(&mut &encoded[..], &mut decoded).unwrap();
+            assert_eq!(&decoded[..], *input);
+            assert_eq!(stats.bytes_in, encoded.len() as u64);
+            assert_eq!(stats.bytes_out, input.len() as u64);
+            assert_eq!(stats.checksum, encoded_stats.checksum);
+            assert_eq!(stats.symbols, encoded_stats.symbols);
+            assert_eq!(encoded_stats.phases[0].0, "counting");
+            assert_eq!(stats.phases[0].0, "reading header");
+        }
+    }
+
+    // Inputs, the method they're encoded with, and the exact bytes that gives
+    const GOLDEN: &[(&[u8], Method, &[u8])] = &[
+        (include_bytes!("../testdata/stored.txt"), Method::Stored, include_bytes!("../testdata/stored.txt.huf")),
+        (
+            include_bytes!("../testdata/abracadabra.txt"),
+            Method::Huffman,
+            include_bytes!("../testdata/abracadabra.txt.huf")
+        ),
+        (include_bytes!("../testdata/bytes.bin"), Method::Huffman, include_bytes!("../testdata/bytes.bin.huf")),
+        (include_bytes!("../testdata/runs.txt"), Method::RleHuffman, include_bytes!("../testdata/runs.txt.huf")),
+        (include_bytes!("../testdata/words.txt"), Method::Words, include_bytes!("../testdata/words.txt.huf")),
+        (include_bytes!("../testdata/utf8.txt"), Method::Utf8, include_bytes!("../testdata/utf8.txt.huf"))
+    ];
+
+    #[test]
+    fn golden_files_match() {
+        for &(input, method, expected) in GOLDEN {
+            let mut encoded = Vec::new();
+            let options = super::EncodeOptions { method: Some(method), ..Default::default() };
+            super::encode_stream_with(&mut io::Cursor::new(input), &mut encoded, options).unwrap();
+            assert_eq!(encoded, expected, "encoding with the {} method changed", method);
+            let mut decoded = Vec::new();
+            super::decode_stream(&mut &expected[..], &mut decoded).unwrap();
+            assert_eq!(decoded, input);
+        }
+        // Files written with a frequency table, before succinct trees, still decode
+        let mut decoded = Vec::new();
+        super::decode_stream(&mut &include_bytes!("../testdata/abracadabra.freqs.huf")[..], &mut decoded).unwrap();
+        assert_eq!(decoded, b"abracadabra");
+        // And so do files written before headers had checksums
+        let mut decoded = Vec::new();
+        super::decode_stream(&mut &include_bytes!("../testdata/abracadabra.v1.huf")[..], &mut decoded).unwrap();
+        assert_eq!(decoded, b"abracadabra");
+    }
+
+    #[test]
+    fn limits_stop_decoding() {
+        let input = b"abracadabra ".repeat(100);
+        let decode = |method, decoded_size, limits| {
+            let options = super::EncodeOptions { method: Some(method), decoded_size, ..Default::default() };
+            let mut encoded = Vec::new();
+            super::encode_stream_with(&mut io::Cursor::new(&input), &mut encoded, options).unwrap();
+            let options = super::DecodeOptions { limits, ..Default::default() };
+            let mut decoded = Vec::new();
+            let result = super::decode_stream_with(&encoded[..], &mut decoded, options);
+            result.map(|_| decoded).map_err(|e| e.to_string())
+        };
+        let limits = |max_output, max_entries, max_code_len| super::Limits { max_output, max_entries, max_code_len };
+        for method in [Method::Huffman, Method::RleHuffman, Method::Words] {
+            assert_eq!(decode(method, None, limits(Some(1200), Some(6), Some(4))).unwrap(), input);
+            // Without a recorded size, the output is only caught while decoding
+            for decoded_size in [Some(1200), None] {
+                let e = decode(method, decoded_size, limits(Some(1199), None, None)).unwrap_err();
+                assert!(e.contains("more than the 1199"), "{}", e);
+            }
+            assert!(decode(method, None, limits(None, Some(1), None)).unwrap_err().contains("more than the 1 allowed"));
+            assert!(decode(method, None, limits(None, None, Some(1))).unwrap_err().contains("longer than the 1 allowed"));
+        }
+    }
+
+    #[test]
+    fn migrating_only_changes_the_header() {
+        let mut migrated = Vec::new();
+        let version = super::migrate_stream(&mut &include_bytes!("../testdata/abracadabra.v1.huf")[..], &mut migrated).unwrap();
+        assert_eq!(version, 1);
+        assert_eq!(migrated, include_bytes!("../testdata/abracadabra.txt.huf"));
+        let mut again = Vec::new();
+        assert_eq!(super::migrate_stream(&mut &migrated[..], &mut again).unwrap(), 2);
+        assert_eq!(again, migrated);
+        assert!(super::migrate_stream(&mut &b"HUF"[..], &mut Vec::new()).is_err());
+    }
+
+    #[test]
+    fn corrupt_headers_are_reported_before_decoding() {
+        let input = b"abracadabra";
+        for method in [Method::Stored, Method::Huffman, Method::Words] {
+            let options = super::EncodeOptions { method: Some(method), ..Default::default() };
+            let mut encoded = Vec::new();
+            super::encode_stream_with(&mut io::Cursor::new(input), &mut encoded, options).unwrap();
+            let mut table = &encoded[5..];
+            match method {
+                Method::Huffman => drop(HuffTree::read_succinct(&mut table).unwrap()),
+                Method::Words => drop(crate::symbols::Dictionary::read(&mut table).unwrap()),
+                _ => {}
+            }
+            // Every byte of the header and its table, after the magic bytes and version, and of their checksum
+            for i in 4..encoded.len() - table.len() + 4 {
+                let mut corrupt = encoded.clone();
+                corrupt[i] ^= 0x01;
+                let mut decoded = Vec::new();
+                let e = super::decode_stream(&mut &corrupt[..], &mut decoded).unwrap_err();
+                assert!(decoded.is_empty());
+                assert!(i == 4 || e.to_string().contains("the header is corrupt"), "{}", e);
+            }
+        }
+    }
+
+    #[test]
+    fn optimistic_encoding_reads_again_when_the_prefix_misleads() {
+        let encode = |input: &[u8], optimistic| {
+            let mut encoded = Vec::new();
+            let options = super::EncodeOptions { optimistic, ..Default::default() };
+            super::encode_stream_with(&mut io::Cursor::new(input), &mut encoded, options).unwrap();
+            let mut decoded = Vec::new();
+            super::decode_stream(&mut &encoded[..], &mut decoded).unwrap();
+            assert_eq!(decoded, input);
+            encoded.len()
+        };
+        let representative = b"abracadabra ".repeat(500);
+        let optimistic = encode(&representative, Some(1000));
+        let size = encode(&representative, None) as u64;
+        assert!(optimistic as u64 <= size + size / super::OPTIMISTIC_SLACK + 1);
+        // The rest of the input only has bytes the prefix doesn't, which get the longest codes
+        let mut misleading = b"abracadabra ".repeat(100);
+        misleading.extend(b"xyz".repeat(5000));
+        assert_eq!(encode(&misleading, Some(1000)), encode(&misleading, None));
+    }
+
+    #[test]
+    fn planes_round_trip_with_every_method() {
+        // 16 bit samples, whose high bytes barely change
+        let samples: Vec<u8> = (0..20_000u32).flat_map(|i| ((i * 37 % 1000) as u16 + 3000).to_le_bytes()).collect();
+        let methods = [Method::Stored, Method::Huffman, Method::RleHuffman, Method::Words, Method::Ngrams, Method::Rice];
+        let mut sizes = Vec::new();
+        for &method in &methods {
+            for stride in [None, Some(2)] {
+                let mut encoded = Vec::new();
+                let options = super::EncodeOptions { method: Some(method), stride, ..Default::default() };
+                super::encode_stream_with(&mut io::Cursor::new(&samples), &mut encoded, options).unwrap();
+                let mut decoded = Vec::new();
+                super::decode_stream(&mut &encoded[..], &mut decoded).unwrap();
+                assert_eq!(decoded, samples, "the {} method with a stride of {:?}", method, stride);
+                sizes.push(((method, stride), encoded.len()));
+            }
+        }
+        let size = |key| sizes.iter().find(|&&(found, _)| found == key).unwrap().1;
+        // Coding bytes one at a time doesn't care about their order, but pairs of them do
+        assert_eq!(size((Method::Huffman, None)), size((Method::Huffman, Some(2))) - 1);
+        assert!(size((Method::Ngrams, Some(2))) < size((Method::Ngrams, None)) * 3 / 4);
+    }
+
+    #[test]
+    fn cancelling_stops_encoding() {
+        let cancel = AtomicBool::new(true);
+        let options = super::EncodeOptions { cancel: Some(&cancel), ..Default::default() };
+        let mut encoded = Vec::new();
+        let result = super::encode_stream_with(&mut io::Cursor::new(b"abc"), &mut encoded, options);
+        assert!(super::is_cancelled(&result.unwrap_err()));
+    }
+
+    #[test]
+    fn higher_levels_keep_the_smallest_output() {
+        assert!("0".parse::<super::Level>().is_err());
+        assert_eq!("9".parse::<super::Level>().unwrap(), super::Level::BEST);
+        let input = b"the cat sat on the mat, and the cat sat on the hat. ".repeat(40);
+        let mut sizes = Vec::new();
+        for level in [super::Level::FASTEST, super::Level::DEFAULT, super::Level::BEST] {
+            let mut encoded = Vec::new();
+            super::encode_stream_with(&mut io::Cursor::new(&input), &mut encoded, level.options()).unwrap();
+            let mut decoded = Vec::new();
+            super::decode_stream(&mut &encoded[..], &mut decoded).unwrap();
+            assert_eq!(decoded, input);
+            sizes.push(encoded.len());
+        }
+        // The whole input fits in the sample of the fastest level
+        assert_eq!(sizes[0], sizes[1]);
+        assert!(sizes[2] < sizes[1]);
+    }
+
+    #[test]
+    fn recorded_sizes_get_checked() {
+        let input = b"abracadabra";
+        for method in [Method::Huffman, Method::Stored, Method::Words, Method::Rice] {
+            let options = super::EncodeOptions { method: Some(method), decoded_size: Some(11), ..Default::default() };
+            let mut encoded = Vec::new();
+            super::encode_stream_with(&mut io::Cursor::new(input), &mut encoded, options).unwrap();
+            let mut decoded = Vec::new();
+            super::decode_stream(&mut &encoded[..], &mut decoded).unwrap();
+            assert_eq!(decoded, input);
+            let header = super::check_header(&encoded[..], &Default::default()).unwrap();
+            assert_eq!(header.decoded_size, Some(11));
+            // The size is the last field of the header
+            encoded[12] += 1;
+            assert!(super::decode_stream(&mut &encoded[..], &mut Vec::new()).is_err());
+            assert!(super::check_header(&encoded[..], &Default::default()).is_err());
+        }
+        let options = super::EncodeOptions { decoded_size: Some(10), ..Default::default() };
+        assert!(super::encode_stream_with(&mut io::Cursor::new(input), &mut Vec::new(), options).is_err());
+    }
+
+    #[test]
+    fn preset_dictionaries_get_checked() {
+        let input = b"abracadabra";
+        let dict = Frequencies::count_reader(&mut &b"abracadabra, alakazam"[..]).unwrap();
+        let other = Frequencies::count_reader(&mut &input[..]).unwrap();
+        assert_ne!(dict.id(), other.id());
+        let options = super::EncodeOptions { model: Some(dict.clone()), preset: true, ..Default::default() };
+        let mut encoded = Vec::new();
+        super::encode_stream_with(&mut io::Cursor::new(input), &mut encoded, options).unwrap();
+        // The header, the ID, and then the codes
+        assert!(encoded.len() < 9 + input.len());
+
+        let decode = |dict: Option<&Frequencies>| {
+            let options = super::DecodeOptions { dict: dict.cloned(), ..Default::default() };
+            let mut decoded = Vec::new();
+            super::decode_stream_with(&encoded[..], &mut decoded, options).map(|_| decoded)
+        };
+        assert_eq!(decode(Some(&dict)).unwrap(), input);
+        assert!(decode(None).unwrap_err().to_string().contains(&format!("{:08x}", dict.id())));
+        assert!(decode(Some(&other)).is_err());
+        let options = super::EncodeOptions { preset: true, ..Default::default() };
+        assert!(super::encode_stream_with(&mut io::Cursor::new(input), &mut Vec::new(), options).is_err());
+    }
+}
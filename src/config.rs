@@ -0,0 +1,233 @@
+//! This module contains the configuration file, holding defaults for the options
+//! of the command line tool, so that they don't need to be passed every time.
+use std::env;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use crate::header::Method;
+
+
+/// Defaults for the options of the encode and decode commands,
+/// which flags given on the command line take precedence over
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Config {
+    /// The method to encode with, instead of picking one
+    pub method: Option<Method>,
+    pub armor: bool,
+    pub verify: bool,
+    pub pipeline: bool,
+    pub timings: bool,
+    /// How many files `encode`, `decode`, `test` and `watch` work on at once, instead of one per core
+    pub jobs: Option<usize>,
+    /// The extension to add to encoded files when their output isn't given, instead of `.huf`
+    pub suffix: Option<String>
+}
+
+// The options a configuration file can set
+const KEYS: [&str; 7] = ["method", "armor", "verify", "pipeline", "timings", "jobs", "suffix"];
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+
+// The values an option can have
+enum Value {
+    Bool(bool),
+    Int(u64),
+    Str(String)
+}
+
+// Parse the value of a `key = value` line, which is a boolean, a number, or a string in double quotes,
+// possibly followed by a comment
+fn parse_value(text: &str) -> Option<Value> {
+    let (value, rest) = if let Some(quoted) = text.strip_prefix('"') {
+        let mut value = String::new();
+        let mut chars = quoted.char_indices();
+        loop {
+            match chars.next()? {
+                (i, '"') => break (Value::Str(value), &quoted[i + 1..]),
+                (_, '\\') => match chars.next()?.1 {
+                    '"' => value.push('"'),
+                    '\\' => value.push('\\'),
+                    'n' => value.push('\n'),
+                    't' => value.push('\t'),
+                    _ => return None
+                },
+                (_, c) => value.push(c)
+            }
+        }
+    } else {
+        let end = text.find('#').unwrap_or(text.len());
+        match text[..end].trim() {
+            "true" => (Value::Bool(true), &text[end..]),
+            "false" => (Value::Bool(false), &text[end..]),
+            number => (Value::Int(number.parse().ok()?), &text[end..])
+        }
+    };
+    let rest = rest.trim_start();
+    if rest.is_empty() || rest.starts_with('#') {
+        Some(value)
+    } else {
+        None
+    }
+}
+
+impl Config {
+    /// Parse the text of a configuration file, which uses the part of TOML
+    /// made of `key = value` lines, with strings, booleans and numbers as values
+    pub fn parse(text: &str) -> io::Result<Self> {
+        let mut config = Config::default();
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let malformed = || invalid_data(&format!("line {} isn't of the form `key = value`", i + 1));
+            let (key, value) = line.split_once('=').ok_or_else(malformed)?;
+            // Like HUFFMAN_THREADS, `threads` is another name for `jobs`
+            let key = match key.trim() {
+                "threads" => "jobs",
+                key => key
+            };
+            if !KEYS.contains(&key) {
+                return Err(invalid_data(&format!("unknown option `{}`", key)))
+            }
+            let value = parse_value(value.trim()).ok_or_else(malformed)?;
+            config.set(key, value)?;
+        }
+        Ok(config)
+    }
+
+    /// Override options with the environment variables named after them,
+    /// such as `HUFFMAN_METHOD` or `HUFFMAN_VERIFY`, where `HUFFMAN_THREADS` also sets `jobs`
+    pub fn apply_env(&mut self) -> io::Result<()> {
+        self.apply_vars(|name| env::var(name).ok())
+    }
+
+    fn apply_vars<F: Fn(&str) -> Option<String>>(&mut self, var: F) -> io::Result<()> {
+        for key in KEYS.iter() {
+            let name = format!("HUFFMAN_{}", key.to_uppercase());
+            let text = match var(&name).or_else(|| var("HUFFMAN_THREADS").filter(|_| *key == "jobs")) {
+                Some(text) => text,
+                None => continue
+            };
+            let value = match (*key, text.as_str()) {
+                ("method", _) | ("suffix", _) => Value::Str(text.clone()),
+                ("jobs", _) => match text.parse() {
+                    Ok(jobs) => Value::Int(jobs),
+                    Err(_) => return Err(invalid_data(&format!("{} should be a number", name)))
+                },
+                (_, "1") | (_, "true") => Value::Bool(true),
+                (_, "0") | (_, "false") | (_, "") => Value::Bool(false),
+                _ => return Err(invalid_data(&format!("{} should be 1, 0, true or false", name)))
+            };
+            self.set(key, value).map_err(|e| invalid_data(&format!("in {}: {}", name, e)))?;
+        }
+        Ok(())
+    }
+
+    // Set an option, which is known to exist
+    fn set(&mut self, key: &str, value: Value) -> io::Result<()> {
+        let flag = || match value {
+            Value::Bool(b) => Ok(b),
+            _ => Err(invalid_data(&format!("`{}` should be true or false", key)))
+        };
+        let string = || match &value {
+            Value::Str(s) => Ok(s.as_str()),
+            _ => Err(invalid_data(&format!("`{}` should be a string", key)))
+        };
+        match key {
+            "method" => self.method = Some(string()?.parse().map_err(|e: String| invalid_data(&e))?),
+            "armor" => self.armor = flag()?,
+            "verify" => self.verify = flag()?,
+            "pipeline" => self.pipeline = flag()?,
+            "timings" => self.timings = flag()?,
+            "jobs" => match value {
+                Value::Int(0) => return Err(invalid_data("`jobs` needs to be at least 1")),
+                Value::Int(jobs) => self.jobs = Some(jobs as usize),
+                _ => return Err(invalid_data("`jobs` should be a number"))
+            },
+            "suffix" => {
+                let suffix = string()?;
+                if suffix.is_empty() {
+                    return Err(invalid_data("`suffix` can't be empty"))
+                }
+                self.suffix = Some(suffix.to_string());
+            }
+            _ => unreachable!()
+        }
+        Ok(())
+    }
+
+    /// Read the configuration file at a path, or in `HUFFMAN_CONFIG`, or at
+    /// `~/.config/huffman/config.toml` without either, where a missing file gives the default configuration
+    pub fn load(path: Option<PathBuf>) -> io::Result<Self> {
+        let path = path.or_else(|| env::var_os("HUFFMAN_CONFIG").filter(|path| !path.is_empty()).map(PathBuf::from));
+        let (path, required) = match path {
+            Some(path) => (path, true),
+            None => match default_path() {
+                Some(path) => (path, false),
+                None => return Ok(Config::default())
+            }
+        };
+        let text = match fs::read_to_string(&path) {
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound && !required => return Ok(Config::default()),
+            result => result?
+        };
+        Config::parse(&text).map_err(|e| invalid_data(&format!("in `{}`: {}", path.display(), e)))
+    }
+}
+
+// Where the configuration file is, following the XDG conventions
+fn default_path() -> Option<PathBuf> {
+    let dir = match env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(env::var_os("HOME")?).join(".config")
+    };
+    Some(dir.join("huffman").join("config.toml"))
+}
+
+
+#[cfg(test)]
+mod test {
+    use crate::header::Method;
+    use super::Config;
+
+    #[test]
+    fn parsing_works() {
+        let config = Config::parse("method = \"rle\"\narmor = true\nsuffix = \".hz\"\n").unwrap();
+        let expected = Config {
+            method: Some(Method::RleHuffman),
+            armor: true,
+            suffix: Some(".hz".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(config, expected);
+        let commented = "# defaults\nverify = true # always\nsuffix = \"#\\\"\"\n";
+        let config = Config::parse(commented).unwrap();
+        assert!(config.verify);
+        assert_eq!(config.suffix.as_deref(), Some("#\""));
+        assert!(Config::parse("armor = \"yes\"").is_err());
+        assert_eq!(Config::parse("jobs = 4 # cores").unwrap().jobs, Some(4));
+        assert_eq!(Config::parse("threads = 2").unwrap().jobs, Some(2));
+        assert!(Config::parse("jobs = 0").is_err());
+    }
+
+    #[test]
+    fn environment_variables_take_precedence() {
+        let mut config = Config::parse("armor = true\nverify = true\n").unwrap();
+        let vars = |name: &str| match name {
+            "HUFFMAN_ARMOR" => Some("0".to_string()),
+            "HUFFMAN_METHOD" => Some("words".to_string()),
+            "HUFFMAN_THREADS" => Some("3".to_string()),
+            _ => None
+        };
+        config.apply_vars(vars).unwrap();
+        assert!(!config.armor);
+        assert!(config.verify);
+        assert_eq!(config.method, Some(Method::Words));
+        assert_eq!(config.jobs, Some(3));
+        assert!(config.apply_vars(|name| Some(name.to_string())).is_err());
+    }
+}
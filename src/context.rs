@@ -0,0 +1,105 @@
+//! This module adds context to I/O errors, like the file or the part of the format
+//! being worked on when they happened, while keeping their kind, so that callers
+//! can still tell a missing file from corrupt data.
+use std::error;
+use std::fmt;
+use std::io;
+
+
+/// An error, along with what was being done when it happened
+#[derive(Debug)]
+pub struct WithContext {
+    pub context: String,
+    pub source: io::Error
+}
+
+impl fmt::Display for WithContext {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.context, self.source)
+    }
+}
+
+impl error::Error for WithContext {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        Some(&self.source)
+    }
+}
+
+/// Find the error at the bottom of any context added to this one
+pub fn root_cause(mut e: &io::Error) -> &io::Error {
+    while let Some(inner) = e.get_ref().and_then(|inner| inner.downcast_ref::<WithContext>()) {
+        e = &inner.source;
+    }
+    e
+}
+
+
+/// Adds context to the error of a result
+pub trait Context<T> {
+    /// Describe what was being done if this is an error, like "while reading the header"
+    fn context<S: Into<String>, F: FnOnce() -> S>(self, context: F) -> io::Result<T>;
+}
+
+impl <T> Context<T> for io::Result<T> {
+    fn context<S: Into<String>, F: FnOnce() -> S>(self, context: F) -> io::Result<T> {
+        self.map_err(|source| {
+            let kind = source.kind();
+            io::Error::new(kind, WithContext { context: context().into(), source })
+        })
+    }
+}
+
+
+/// A reader or writer naming the file it works on in its errors
+pub struct Named<T> {
+    inner: T,
+    name: String
+}
+
+impl <T> Named<T> {
+    pub fn new(inner: T, name: &str) -> Self {
+        Named { inner, name: name.to_string() }
+    }
+}
+
+impl <T: io::Read> io::Read for Named<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let name = &self.name;
+        self.inner.read(buf).context(|| format!("while reading `{}`", name))
+    }
+}
+
+impl <T: io::Seek> io::Seek for Named<T> {
+    fn seek(&mut self, pos: io::SeekFrom) -> io::Result<u64> {
+        let name = &self.name;
+        self.inner.seek(pos).context(|| format!("while seeking in `{}`", name))
+    }
+}
+
+impl <T: io::Write> io::Write for Named<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let name = &self.name;
+        self.inner.write(buf).context(|| format!("while writing `{}`", name))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let name = &self.name;
+        self.inner.flush().context(|| format!("while writing `{}`", name))
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::io;
+    use super::{root_cause, Context};
+
+    #[test]
+    fn context_keeps_the_kind() {
+        let result: io::Result<()> = Err(io::Error::new(io::ErrorKind::InvalidData, "bad tree"));
+        let e = result.context(|| "while reading the tree").context(|| "while decoding `a.huf`").unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(e.to_string(), "while decoding `a.huf`: while reading the tree: bad tree");
+        assert_eq!(root_cause(&e).to_string(), "bad tree");
+    }
+}
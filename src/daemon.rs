@@ -0,0 +1,206 @@
+//! This module serves encoding and decoding requests over a Unix socket, keeping models
+//! in memory, so that services can compress many small payloads without starting
+//! a process for each of them.
+//!
+//! Every request is a frame made of:
+//!
+//! - an operation byte, `E` to encode, or `D` to decode
+//! - the length of the name of a model, as a big endian u16, followed by that name,
+//!   where an empty name means encoding or decoding files with their own header
+//! - the length of the payload, as a big endian u32, followed by the payload
+//!
+//! Every response is a status byte, 0 for success, and 1 for failure, followed by
+//! the length of the data as a big endian u32, and the data, which is the encoded or
+//! decoded payload, or a message saying what went wrong. Payloads coded with a model
+//! are raw Huffman codes, without a header or table, as they'd outweigh small payloads.
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::sync::Arc;
+use crate::coding::{self, Codebook, Frequencies, HuffTree, HuffWriter, OwnedHuffReader};
+
+
+/// The operation byte of encoding requests
+pub const ENCODE: u8 = b'E';
+/// The operation byte of decoding requests
+pub const DECODE: u8 = b'D';
+/// The largest payload a request can have, and the most one can decode to
+pub const MAX_PAYLOAD: u32 = 64 * 1024 * 1024;
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+
+/// A model kept in memory, ready to code payloads with
+pub struct Model {
+    codebook: Codebook,
+    reader: OwnedHuffReader
+}
+
+impl Model {
+    pub fn new(freqs: &Frequencies) -> Self {
+        Model::from_codebook(Codebook::from_tree(&HuffTree::from_freqs(freqs)))
+    }
+
+    /// Code payloads with the codes of a codebook, instead of building them from frequencies
+    pub fn from_codebook(codebook: Codebook) -> Self {
+        let reader = OwnedHuffReader::from_codebook(&codebook);
+        Model { codebook, reader }
+    }
+
+    /// Code a payload as raw Huffman codes, ending with the end of the transmission
+    pub fn encode(&self, payload: &[u8]) -> io::Result<Vec<u8>> {
+        let mut writer = HuffWriter::from_codebook(&self.codebook);
+        let mut encoded = Vec::with_capacity(payload.len());
+        for &byte in payload {
+            writer.write_byte(byte, &mut encoded)?;
+        }
+        writer.finish(&mut encoded)?;
+        Ok(encoded)
+    }
+
+    /// Decode a payload coded with encode, failing if it ends before the end of the transmission,
+    /// or decodes to more than MAX_PAYLOAD bytes
+    pub fn decode(&self, payload: &[u8]) -> io::Result<Vec<u8>> {
+        let mut reader = self.reader.clone();
+        let mut decoded = Vec::with_capacity(payload.len() * 2);
+        for &byte in payload {
+            if !reader.feed(byte, &mut decoded)? {
+                return Ok(decoded)
+            }
+            if decoded.len() > MAX_PAYLOAD as usize {
+                return Err(invalid_data(&format!("payloads can't decode to more than {} bytes", MAX_PAYLOAD)))
+            }
+        }
+        Err(io::Error::new(io::ErrorKind::UnexpectedEof, "the payload ended before its end of transmission"))
+    }
+}
+
+
+// Carry out a request, failing if it can't be
+fn answer(op: u8, model: &str, payload: &[u8], models: &HashMap<String, Model>) -> io::Result<Vec<u8>> {
+    let model = match model {
+        "" => None,
+        name => Some(models.get(name).ok_or_else(|| invalid_data(&format!("unknown model `{}`", name)))?)
+    };
+    let mut output = Vec::new();
+    match (op, model) {
+        (ENCODE, Some(model)) => return model.encode(payload),
+        (DECODE, Some(model)) => return model.decode(payload),
+        (ENCODE, None) => {
+            coding::encode_stream(&mut io::Cursor::new(payload), &mut output)?;
+        }
+        (DECODE, None) => {
+            // A few bytes can decode to far more than would fit in a response
+            let limits = coding::Limits { max_output: Some(MAX_PAYLOAD as u64), ..Default::default() };
+            coding::decode_stream_with(payload, &mut output, coding::DecodeOptions { limits, ..Default::default() })?;
+        }
+        (op, _) => return Err(invalid_data(&format!("unknown operation {}", op)))
+    }
+    Ok(output)
+}
+
+// Read a request, returning None if the connection was closed in between requests
+fn read_request<R: Read>(reader: &mut R) -> io::Result<Option<(u8, String, Vec<u8>)>> {
+    let mut op = [0];
+    if reader.read(&mut op)? == 0 {
+        return Ok(None)
+    }
+    let mut len = [0; 2];
+    reader.read_exact(&mut len)?;
+    let mut model = vec![0; u16::from_be_bytes(len) as usize];
+    reader.read_exact(&mut model)?;
+    let model = String::from_utf8(model).map_err(|_| invalid_data("model names must be UTF-8"))?;
+    let mut len = [0; 4];
+    reader.read_exact(&mut len)?;
+    let len = u32::from_be_bytes(len);
+    if len > MAX_PAYLOAD {
+        return Err(invalid_data(&format!("payloads can't be larger than {} bytes", MAX_PAYLOAD)))
+    }
+    let mut payload = vec![0; len as usize];
+    reader.read_exact(&mut payload)?;
+    Ok(Some((op[0], model, payload)))
+}
+
+fn write_frame<W: Write>(writer: &mut W, status: u8, data: &[u8]) -> io::Result<()> {
+    writer.write_all(&[status])?;
+    writer.write_all(&(data.len() as u32).to_be_bytes())?;
+    writer.write_all(data)?;
+    writer.flush()
+}
+
+/// Answer the requests coming over a connection, until it's closed.
+/// Requests that fail get an error response, but malformed frames end the connection.
+pub fn serve<S: Read + Write>(mut stream: S, models: Arc<HashMap<String, Model>>) -> io::Result<()> {
+    while let Some((op, model, payload)) = read_request(&mut stream)? {
+        match answer(op, &model, &payload, &models) {
+            Ok(output) => write_frame(&mut stream, 0, &output)?,
+            Err(e) => write_frame(&mut stream, 1, e.to_string().as_bytes())?
+        }
+    }
+    Ok(())
+}
+
+/// Send a request to a daemon, returning the data of its response,
+/// or an error with the message it sent back
+pub fn request<S: Read + Write>(stream: &mut S, op: u8, model: &str, payload: &[u8]) -> io::Result<Vec<u8>> {
+    if model.len() > u16::MAX as usize || payload.len() > MAX_PAYLOAD as usize {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "the model name or payload is too long"))
+    }
+    stream.write_all(&[op])?;
+    stream.write_all(&(model.len() as u16).to_be_bytes())?;
+    stream.write_all(model.as_bytes())?;
+    stream.write_all(&(payload.len() as u32).to_be_bytes())?;
+    stream.write_all(payload)?;
+    stream.flush()?;
+    let mut header = [0; 5];
+    stream.read_exact(&mut header)?;
+    let mut data = vec![0; u32::from_be_bytes([header[1], header[2], header[3], header[4]]) as usize];
+    stream.read_exact(&mut data)?;
+    match header[0] {
+        0 => Ok(data),
+        _ => Err(io::Error::other(String::from_utf8_lossy(&data).into_owned()))
+    }
+}
+
+
+#[cfg(all(test, unix))]
+mod test {
+    use std::collections::HashMap;
+    use std::os::unix::net::UnixStream;
+    use std::sync::Arc;
+    use std::thread;
+    use crate::checksum::Crc32;
+    use crate::coding::Frequencies;
+    use crate::header::{Header, Method};
+    use super::{request, serve, Model, DECODE, ENCODE};
+
+    #[test]
+    fn requests_get_answered() {
+        let samples = b"GET /index.html HTTP/1.1";
+        let freqs = Frequencies::count_reader(&mut &samples[..]).unwrap();
+        let mut models = HashMap::new();
+        models.insert("http".to_string(), Model::new(&freqs));
+        let (mut client, server) = UnixStream::pair().unwrap();
+        let daemon = thread::spawn(move || serve(server, Arc::new(models)));
+
+        let message = b"GET /index.html";
+        let encoded = request(&mut client, ENCODE, "http", message).unwrap();
+        assert!(encoded.len() < message.len());
+        assert_eq!(request(&mut client, DECODE, "http", &encoded).unwrap(), message);
+        let encoded = request(&mut client, ENCODE, "", b"abracadabra").unwrap();
+        assert_eq!(request(&mut client, DECODE, "", &encoded).unwrap(), b"abracadabra");
+        assert!(request(&mut client, ENCODE, "ftp", message).is_err());
+
+        // A stored file, recording that it decodes to far more than a response can hold
+        let mut huge = Vec::new();
+        Header { decoded_size: Some(1 << 40), ..Header::new(Method::Stored) }.write(&mut huge).unwrap();
+        let mut crc = Crc32::new();
+        crc.update(&huge);
+        huge.extend_from_slice(&crc.sum().to_be_bytes());
+        let e = request(&mut client, DECODE, "", &huge).unwrap_err();
+        assert!(e.to_string().contains("more than the 67108864 allowed"));
+        drop(client);
+        daemon.join().unwrap().unwrap();
+    }
+}
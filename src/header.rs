@@ -0,0 +1,322 @@
+//! This module contains the header written at the start of every encoded file,
+//! identifying the format, and the method used to compress the rest of the file
+use std::fmt;
+use std::io;
+use std::str::FromStr;
+use crate::coding;
+
+
+/// The bytes every encoded file starts with
+pub const MAGIC: [u8; 3] = *b"HUF";
+/// The version of the format this program writes, where the header, and the table after it,
+/// are followed by their CRC-32, as a big endian u32, which decoding checks before the data
+pub const VERSION: u8 = 2;
+/// The first version of the format, which is still read, whose headers have no checksum
+pub const LEGACY_VERSION: u8 = 1;
+/// How many bytes the checksum of a header and its table takes
+pub const CHECKSUM_SIZE: usize = 4;
+/// How many bytes a header takes, without a stride
+pub const SIZE: usize = MAGIC.len() + 2;
+
+
+/// The method used to compress the data following the header
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Method {
+    /// The bytes are copied over as is
+    Stored,
+    /// The bytes are Huffman coded, after writing their frequencies
+    Huffman,
+    /// The bytes are run length encoded, and the result is then Huffman coded
+    RleHuffman,
+    /// The input is split into words and separators, which are Huffman coded
+    /// after writing a dictionary of them
+    Words,
+    /// The input is split into UTF-8 characters, which are Huffman coded
+    /// after writing a dictionary of them
+    Utf8,
+    /// The input is split into varint encoded token IDs, which are Huffman coded
+    /// after writing a dictionary of them
+    Tokens,
+    /// The input is split into n-grams, runs of a fixed number of bytes,
+    /// which are Huffman coded after writing a dictionary of them
+    Ngrams,
+    /// The bytes are Rice coded, in blocks picking their own parameter,
+    /// which suits small values, like the residuals of a predictor
+    Rice
+}
+
+/// Coding needs to save more than one part in this many of the input to be picked over storing it
+pub const STORE_UNDER_SAVINGS: u64 = 64;
+
+impl Method {
+    fn to_byte(self) -> u8 {
+        match self {
+            Method::Stored => 0,
+            Method::Huffman => 1,
+            Method::RleHuffman => 2,
+            Method::Words => 3,
+            Method::Utf8 => 4,
+            Method::Tokens => 5,
+            Method::Ngrams => 6,
+            Method::Rice => 7
+        }
+    }
+
+    fn from_byte(byte: u8) -> Option<Self> {
+        match byte {
+            0 => Some(Method::Stored),
+            1 => Some(Method::Huffman),
+            2 => Some(Method::RleHuffman),
+            3 => Some(Method::Words),
+            4 => Some(Method::Utf8),
+            5 => Some(Method::Tokens),
+            6 => Some(Method::Ngrams),
+            7 => Some(Method::Rice),
+            _ => None
+        }
+    }
+
+    /// Pick the method producing the smallest output, given the byte counts
+    /// of the input, and those of the input after run length encoding it.
+    /// Input that coding would barely shrink, like files that are already compressed,
+    /// gets stored instead, since it isn't worth decoding more slowly for.
+    pub fn choose(counts: &[u64; 256], rle_counts: &[u64; 256]) -> Self {
+        let stored = counts.iter().sum::<u64>();
+        let huffman = coding::encoded_size(counts);
+        let rle = coding::encoded_size(rle_counts);
+        if huffman.min(rle) + stored / STORE_UNDER_SAVINGS >= stored {
+            Method::Stored
+        } else if huffman <= rle {
+            Method::Huffman
+        } else {
+            Method::RleHuffman
+        }
+    }
+}
+
+impl FromStr for Method {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "stored" => Ok(Method::Stored),
+            "huffman" => Ok(Method::Huffman),
+            "rle" => Ok(Method::RleHuffman),
+            "words" => Ok(Method::Words),
+            "utf8" => Ok(Method::Utf8),
+            "tokens" => Ok(Method::Tokens),
+            "ngrams" => Ok(Method::Ngrams),
+            "rice" => Ok(Method::Rice),
+            _ => Err(format!("unknown method `{}`, expected stored, huffman, rle, words, utf8, tokens, ngrams or rice", s))
+        }
+    }
+}
+
+impl fmt::Display for Method {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Method::Stored => "stored",
+            Method::Huffman => "huffman",
+            Method::RleHuffman => "rle",
+            Method::Words => "words",
+            Method::Utf8 => "utf8",
+            Method::Tokens => "tokens",
+            Method::Ngrams => "ngrams",
+            Method::Rice => "rice"
+        };
+        f.pad(name)
+    }
+}
+
+
+// The bit of the method byte set when the tree is written out directly
+const SUCCINCT_TREE: u8 = 0x80;
+// The bit of the method byte set when the data was split into planes, whose stride follows
+const STRIDED: u8 = 0x40;
+// The bit of the method byte set when the size of the decoded data follows, after the stride
+const SIZED: u8 = 0x20;
+// The bit of the method byte set when the ID of a preset dictionary follows, after the size
+const PRESET: u8 = 0x10;
+
+
+/// The header at the start of an encoded file
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Header {
+    /// The version of the format, which is VERSION, unless the header was read from an older file
+    pub version: u8,
+    pub method: Method,
+    /// Whether the huffman and rle methods write out the shape of their tree,
+    /// instead of the frequencies it's built from
+    pub succinct_tree: bool,
+    /// The size of the records the data was split into planes of before coding it, if it was
+    pub stride: Option<u8>,
+    /// How many bytes the data decodes to, if that was recorded,
+    /// so that decoding can set aside room for all of them up front
+    pub decoded_size: Option<u64>,
+    /// The ID of the frequencies the huffman and rle methods coded the data with, if they were
+    /// left out, since they're a preset dictionary decoding is given, see Frequencies::id
+    pub dict_id: Option<u32>
+}
+
+impl Header {
+    pub fn new(method: Method) -> Self {
+        Header { version: VERSION, method, succinct_tree: false, stride: None, decoded_size: None, dict_id: None }
+    }
+
+    /// Whether the header and the table after it are followed by their checksum
+    pub fn checksummed(&self) -> bool {
+        self.version != LEGACY_VERSION
+    }
+
+    /// Write the magic bytes, the version, and then the method, along with whether or not the tree
+    /// is succinct, the stride, the decoded size, and the ID of the dictionary, when there are some
+    pub fn write<W: io::Write>(&self, writer: &mut W) -> io::Result<()> {
+        let mut flags = if self.succinct_tree { SUCCINCT_TREE } else { 0 };
+        if self.stride.is_some() {
+            flags |= STRIDED;
+        }
+        if self.decoded_size.is_some() {
+            flags |= SIZED;
+        }
+        if self.dict_id.is_some() {
+            flags |= PRESET;
+        }
+        writer.write_all(&MAGIC)?;
+        writer.write_all(&[self.version, self.method.to_byte() | flags])?;
+        if let Some(stride) = self.stride {
+            writer.write_all(&[stride])?;
+        }
+        if let Some(size) = self.decoded_size {
+            writer.write_all(&size.to_be_bytes())?;
+        }
+        match self.dict_id {
+            Some(id) => writer.write_all(&id.to_be_bytes()),
+            None => Ok(())
+        }
+    }
+
+    /// How many bytes this header takes
+    pub fn size(&self) -> usize {
+        SIZE + self.stride.is_some() as usize + self.decoded_size.map_or(0, |_| 8) + self.dict_id.map_or(0, |_| 4)
+    }
+
+    /// Attempt to read a header, failing if the source doesn't start
+    /// with one written by this version of the program, or an older one
+    pub fn read<R: io::Read>(reader: &mut R) -> io::Result<Self> {
+        let mut buf = [0; SIZE];
+        reader.read_exact(&mut buf)?;
+        if buf[..3] != MAGIC {
+            return Err(invalid_data("not a huffman encoded file"))
+        }
+        let version = buf[3];
+        if version != VERSION && version != LEGACY_VERSION {
+            return Err(invalid_data(&format!("unsupported format version {}", version)))
+        }
+        let succinct_tree = buf[4] & SUCCINCT_TREE != 0;
+        let method = match Method::from_byte(buf[4] & !(SUCCINCT_TREE | STRIDED | SIZED | PRESET)) {
+            Some(method @ Method::Huffman) | Some(method @ Method::RleHuffman) => method,
+            Some(method) if !succinct_tree => method,
+            _ => return Err(invalid_data(&format!("unknown compression method {}", buf[4])))
+        };
+        let stride = if buf[4] & STRIDED != 0 {
+            let mut stride = [0];
+            reader.read_exact(&mut stride)?;
+            if stride[0] < 2 {
+                return Err(invalid_data(&format!("invalid stride {}", stride[0])))
+            }
+            Some(stride[0])
+        } else {
+            None
+        };
+        let decoded_size = if buf[4] & SIZED != 0 {
+            let mut size = [0; 8];
+            reader.read_exact(&mut size)?;
+            Some(u64::from_be_bytes(size))
+        } else {
+            None
+        };
+        let dict_id = if buf[4] & PRESET != 0 {
+            if succinct_tree || (method != Method::Huffman && method != Method::RleHuffman) {
+                return Err(invalid_data("only the huffman and rle methods can code with a preset dictionary, instead of a tree"))
+            }
+            let mut id = [0; 4];
+            reader.read_exact(&mut id)?;
+            Some(u32::from_be_bytes(id))
+        } else {
+            None
+        };
+        Ok(Header { version, method, succinct_tree, stride, decoded_size, dict_id })
+    }
+}
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+
+#[cfg(test)]
+mod test {
+    use crate::coding;
+    use super::{Header, Method, LEGACY_VERSION, VERSION};
+
+    #[test]
+    fn writing_then_reading_works() {
+        let header = Header::new(Method::RleHuffman);
+        let mut buf = Vec::new();
+        header.write(&mut buf).unwrap();
+        assert_eq!(Header::read(&mut &buf[..]).unwrap(), header);
+        let header = Header { succinct_tree: true, ..Header::new(Method::Huffman) };
+        let mut buf = Vec::new();
+        header.write(&mut buf).unwrap();
+        assert_eq!(buf[4], 0x81);
+        assert_eq!(Header::read(&mut &buf[..]).unwrap(), header);
+        let header = Header { stride: Some(4), ..Header::new(Method::Words) };
+        let mut buf = Vec::new();
+        header.write(&mut buf).unwrap();
+        assert_eq!(buf[4..], [0x43, 4]);
+        assert_eq!(buf.len(), header.size());
+        assert_eq!(Header::read(&mut &buf[..]).unwrap(), header);
+        let header = Header { stride: Some(2), decoded_size: Some(0x0102), ..Header::new(Method::Huffman) };
+        let mut buf = Vec::new();
+        header.write(&mut buf).unwrap();
+        assert_eq!(buf[4..], [0x61, 2, 0, 0, 0, 0, 0, 0, 1, 2]);
+        assert_eq!(buf.len(), header.size());
+        assert_eq!(Header::read(&mut &buf[..]).unwrap(), header);
+        let header = Header { dict_id: Some(0xCAFE), ..Header::new(Method::RleHuffman) };
+        let mut buf = Vec::new();
+        header.write(&mut buf).unwrap();
+        assert_eq!(buf[4..], [0x12, 0, 0, 0xCA, 0xFE]);
+        assert_eq!(Header::read(&mut &buf[..]).unwrap(), header);
+        buf[4] = 0x13;
+        assert!(Header::read(&mut &buf[..]).is_err());
+
+        let mut buf = Vec::new();
+        Header::new(Method::Huffman).write(&mut buf).unwrap();
+        assert_eq!(buf[3], VERSION);
+        buf[3] = LEGACY_VERSION;
+        let header = Header::read(&mut &buf[..]).unwrap();
+        assert!(!header.checksummed());
+        buf[3] = VERSION + 1;
+        assert!(Header::read(&mut &buf[..]).is_err());
+    }
+
+    #[test]
+    fn choosing_picks_the_smallest() {
+        let mut counts = [0; 256];
+        let mut rle_counts = [0; 256];
+        assert_eq!(Method::choose(&counts, &rle_counts), Method::Stored);
+        counts[0] = 1000;
+        counts[1] = 10;
+        rle_counts[0] = 8;
+        rle_counts[1] = 10;
+        rle_counts[255] = 4;
+        assert_eq!(Method::choose(&counts, &rle_counts), Method::RleHuffman);
+        rle_counts[0] = 1000;
+        assert_eq!(Method::choose(&counts, &rle_counts), Method::Huffman);
+        // Close to uniform, like compressed data, where coding saves well under 1%
+        let mut counts = [1000; 256];
+        counts[..64].copy_from_slice(&[2200; 64]);
+        assert!(coding::encoded_size(&counts) < counts.iter().sum::<u64>());
+        assert_eq!(Method::choose(&counts, &counts), Method::Stored);
+    }
+}
@@ -0,0 +1,245 @@
+//! This module fetches files over plain HTTP, so that they can be encoded or decoded
+//! straight from a server. It only speaks enough HTTP/1.0 to stream a response body,
+//! without redirects, or TLS for https URLs.
+//!
+//! It also has the pieces of a custom `Content-Encoding`, for services sharing a model: bodies
+//! are raw Huffman codes, sent with the chunked transfer coding of HTTP/1.1, so that flushing
+//! the encoder sends every whole byte of codes so far as a chunk, without ending the response.
+use std::io::{self, BufRead, Read, Write};
+use std::net::TcpStream;
+use crate::coding::Codebook;
+use crate::stream;
+
+
+/// The name of the content coding of bodies made of raw Huffman codes
+pub const CONTENT_ENCODING: &str = "x-huffman";
+
+
+/// Check whether a path given on the command line is a URL
+pub fn is_url(path: &str) -> bool {
+    path.starts_with("http://") || path.starts_with("https://")
+}
+
+fn invalid_input(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, msg)
+}
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+
+/// The body of a response, along with its length, when the server gave one
+pub struct Response {
+    pub body: io::BufReader<TcpStream>,
+    pub length: Option<u64>
+}
+
+/// Send a GET request for a URL, returning the body of the response once its headers are read,
+/// and failing unless the server answered with success
+pub fn get(url: &str) -> io::Result<Response> {
+    if url.starts_with("https://") {
+        return Err(invalid_input("https URLs aren't supported, only http ones"))
+    }
+    let rest = url.strip_prefix("http://").ok_or_else(|| invalid_input("not an http URL"))?;
+    let (authority, path) = match rest.find('/') {
+        Some(slash) => rest.split_at(slash),
+        None => (rest, "/")
+    };
+    if authority.is_empty() || authority.contains('@') {
+        return Err(invalid_input("URLs need a host, without a user or password"))
+    }
+    let address = if authority.contains(':') { authority.to_string() } else { format!("{}:80", authority) };
+
+    let mut stream = TcpStream::connect(address)?;
+    // HTTP/1.0 keeps servers from chunking the body, and closes the connection after it
+    write!(stream, "GET {} HTTP/1.0\r\nHost: {}\r\nUser-Agent: huffman\r\n\r\n", path, authority)?;
+    let mut body = io::BufReader::new(stream);
+    let status = read_line(&mut body)?;
+    let code = status.split(' ').nth(1).unwrap_or("");
+    if !code.starts_with('2') {
+        let msg = format!("the server answered `{}`", status.trim_end());
+        return Err(io::Error::other(msg))
+    }
+    let mut length = None;
+    loop {
+        let line = read_line(&mut body)?;
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            if name.eq_ignore_ascii_case("content-length") {
+                length = Some(value.trim().parse().map_err(|_| invalid_data("invalid Content-Length"))?);
+            }
+        }
+    }
+    Ok(Response { body, length })
+}
+
+// Read a line of the headers, which are short, unlike whatever a broken server might send
+fn read_line<R: BufRead>(reader: &mut R) -> io::Result<String> {
+    let mut line = String::new();
+    reader.take(8192).read_line(&mut line)?;
+    if !line.ends_with('\n') {
+        return Err(invalid_data("the response ended before its headers did"))
+    }
+    Ok(line)
+}
+
+
+/// A writer sending everything written to it as chunks of the chunked transfer coding
+pub struct ChunkedWriter<W> {
+    inner: W
+}
+
+impl <W: Write> ChunkedWriter<W> {
+    pub fn new(inner: W) -> Self {
+        ChunkedWriter { inner }
+    }
+
+    pub fn get_ref(&self) -> &W {
+        &self.inner
+    }
+
+    /// Send the empty chunk ending the body, returning the underlying writer
+    pub fn finish(mut self) -> io::Result<W> {
+        self.inner.write_all(b"0\r\n\r\n")?;
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+}
+
+impl <W: Write> Write for ChunkedWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // An empty chunk would end the body
+        if buf.is_empty() {
+            return Ok(0)
+        }
+        write!(self.inner, "{:x}\r\n", buf.len())?;
+        self.inner.write_all(buf)?;
+        self.inner.write_all(b"\r\n")?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+
+/// A reader giving back the data of a body sent with the chunked transfer coding,
+/// up to the empty chunk ending it, skipping any trailers after that
+pub struct ChunkedReader<R> {
+    inner: R,
+    // How many bytes of the current chunk are left
+    left: u64,
+    done: bool
+}
+
+impl <R: BufRead> ChunkedReader<R> {
+    pub fn new(inner: R) -> Self {
+        ChunkedReader { inner, left: 0, done: false }
+    }
+
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+
+    // Read the size of the next chunk, skipping the trailers when it's the last one
+    fn next_chunk(&mut self) -> io::Result<()> {
+        let line = read_line(&mut self.inner)?;
+        let size = line.split(';').next().unwrap_or("").trim();
+        self.left = u64::from_str_radix(size, 16).map_err(|_| invalid_data("invalid chunk size"))?;
+        if self.left == 0 {
+            while !read_line(&mut self.inner)?.trim_end().is_empty() {}
+            self.done = true;
+        }
+        Ok(())
+    }
+}
+
+impl <R: BufRead> Read for ChunkedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.left == 0 && !self.done {
+            self.next_chunk()?;
+        }
+        if self.done || buf.is_empty() {
+            return Ok(0)
+        }
+        let limit = buf.len().min(self.left.min(usize::MAX as u64) as usize);
+        let read = self.inner.read(&mut buf[..limit])?;
+        if read == 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "the body ended in the middle of a chunk"))
+        }
+        self.left -= read as u64;
+        if self.left == 0 && !read_line(&mut self.inner)?.trim_end().is_empty() {
+            return Err(invalid_data("a chunk is longer than its size"))
+        }
+        Ok(read)
+    }
+}
+
+
+/// Encode a body with the `x-huffman` content coding, and the chunked transfer coding.
+/// Flushing the encoder sends the codes so far as a chunk, but for the last few bits,
+/// which wait for the codes sharing their byte. Once the encoder is finished,
+/// finishing the ChunkedWriter it holds ends the body.
+pub fn encode_body<W: Write>(writer: W, codebook: &Codebook) -> stream::Encoder<ChunkedWriter<W>> {
+    stream::Encoder::new(ChunkedWriter::new(writer), codebook)
+}
+
+/// Decode a body sent with the `x-huffman` content coding, and the chunked transfer coding
+pub fn decode_body<R: BufRead>(reader: R, codebook: &Codebook) -> stream::Decoder<ChunkedReader<R>> {
+    stream::Decoder::new(ChunkedReader::new(reader), codebook)
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::thread;
+    use crate::coding::{Codebook, Frequencies, HuffTree};
+    use super::{decode_body, encode_body, get};
+
+    #[test]
+    fn getting_streams_the_body() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = [0; 1024];
+            let read = stream.read(&mut request).unwrap();
+            assert!(request[..read].starts_with(b"GET /data.huf HTTP/1.0\r\n"));
+            stream.write_all(b"HTTP/1.0 200 OK\r\nContent-Length: 5\r\n\r\nhello").unwrap();
+        });
+        let mut response = get(&format!("http://127.0.0.1:{}/data.huf", port)).unwrap();
+        let mut body = Vec::new();
+        response.body.read_to_end(&mut body).unwrap();
+        assert_eq!((body, response.length), (b"hello".to_vec(), Some(5)));
+        server.join().unwrap();
+        assert!(get("https://example.com/").is_err());
+    }
+
+    #[test]
+    fn bodies_round_trip_in_chunks() {
+        let message = b"{\"id\": 1, \"name\": \"huffman\"}";
+        let freqs = Frequencies::count_reader(&mut &message[..]).unwrap();
+        let codebook = Codebook::from_tree(&HuffTree::from_freqs(&freqs));
+        let mut encoder = encode_body(Vec::new(), &codebook);
+        encoder.write_all(message).unwrap();
+        // Flushing sends a chunk right away, before the body ends
+        encoder.flush().unwrap();
+        assert!(!encoder.get_ref().get_ref().is_empty());
+        encoder.write_all(message).unwrap();
+        encoder.finish().unwrap();
+        let body = encoder.into_inner().finish().unwrap();
+        assert!(body.ends_with(b"0\r\n\r\n"));
+
+        let mut decoded = Vec::new();
+        decode_body(&body[..], &codebook).read_to_end(&mut decoded).unwrap();
+        assert_eq!(decoded, [&message[..], &message[..]].concat());
+        assert!(decode_body(&body[..body.len() - 6], &codebook).read_to_end(&mut Vec::new()).is_err());
+    }
+}
@@ -0,0 +1,305 @@
+//! This module contains reports describing the contents of encoded files,
+//! to help with debugging them, or understanding how they were compressed.
+use std::collections::HashMap;
+use std::io;
+use crate::checksum::Crc32;
+use crate::coding::{self, Frequencies, HuffTree, HuffWriter};
+use crate::header::{self, Header, Method};
+use crate::symbols::{Dictionary, SymbolWriter};
+
+
+// Keeps track of how many bytes have been read so far, and of their checksum
+struct Counted<R> {
+    inner: R,
+    pos: u64,
+    crc: Crc32
+}
+
+impl <R: io::Read> io::Read for Counted<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.pos += read as u64;
+        self.crc.update(&buf[..read]);
+        Ok(read)
+    }
+}
+
+
+// Show a byte in hex, along with the character it is, if it's printable
+pub(crate) fn show_byte(byte: u8) -> String {
+    if byte.is_ascii_graphic() || byte == b' ' {
+        format!("{:#04x} {:?}", byte, byte as char)
+    } else {
+        format!("{:#04x}", byte)
+    }
+}
+
+// Show a code as the bits it's made of, in the order they're sent
+pub(crate) fn show_code((bits, len): (u128, usize)) -> String {
+    (0..len).map(|i| if (bits >> i) & 1 == 1 { '1' } else { '0' }).collect()
+}
+
+// Show a symbol made of several bytes as a string, escaping anything unprintable
+fn show_symbol(symbol: &[u8]) -> String {
+    format!("{:?}", String::from_utf8_lossy(symbol))
+}
+
+
+/// Write out a description of an encoded file: the fields of its header,
+/// along with their byte offsets, the table its tree was built from,
+/// and the code of the first `dump` symbols of its data, along with their bit offsets.
+pub fn inspect<R: io::Read, W: io::Write>(input: &mut R, out: &mut W, dump: usize) -> io::Result<()> {
+    let mut input = Counted { inner: input, pos: 0, crc: Crc32::new() };
+    let header = Header::read(&mut input)?;
+    writeln!(out, "{:<8}{:<16}value", "offset", "field")?;
+    writeln!(out, "{:<8}{:<16}{}", 0, "magic", String::from_utf8_lossy(&header::MAGIC))?;
+    writeln!(out, "{:<8}{:<16}{}", 3, "version", header.version)?;
+    writeln!(out, "{:<8}{:<16}{}", 4, "method", header.method)?;
+    if let Some(stride) = header.stride {
+        writeln!(out, "{:<8}{:<16}{}", header::SIZE, "stride", stride)?;
+    }
+    if let Some(size) = header.decoded_size {
+        let offset = header::SIZE + header.stride.is_some() as usize;
+        writeln!(out, "{:<8}{:<16}{} bytes", offset, "decoded size", size)?;
+    }
+    if let Some(id) = header.dict_id {
+        let offset = header::SIZE + header.stride.is_some() as usize + header.decoded_size.map_or(0, |_| 8);
+        writeln!(out, "{:<8}{:<16}{:08x}", offset, "dictionary", id)?;
+    }
+
+    // Maps each code to a description of its symbol, to interpret the data
+    let mut codes = HashMap::new();
+    let mut eof = (0, 0);
+    match header.method {
+        Method::Stored | Method::Rice => {}
+        // The codes come from a dictionary that isn't in the file
+        Method::Huffman | Method::RleHuffman if header.dict_id.is_some() => {}
+        Method::Huffman | Method::RleHuffman if header.succinct_tree => {
+            let table_start = input.pos;
+            let tree = HuffTree::read_succinct(&mut input)?;
+            writeln!(out, "{:<8}{:<16}{} bytes", table_start, "succinct tree", input.pos - table_start)?;
+            let encoder = HuffWriter::from_tree(&tree);
+            writeln!(out, "{:<12}{:<12}{:<12}code", "", "byte", "frequency")?;
+            for byte in 0..=255 {
+                if let Some(code) = encoder.code(byte) {
+                    writeln!(out, "{:<12}{:<12}{:<12}{}", "", show_byte(byte), "-", show_code(code))?;
+                    codes.insert(code, show_byte(byte));
+                }
+            }
+            eof = encoder.eof_code();
+            writeln!(out, "{:<12}{:<12}{:<12}{}", "", "EOF", "-", show_code(eof))?;
+            writeln!(out, "{:<8}{:<16}{} leaves, {} deep", "", "tree", tree.leaf_count(), tree.depth())?;
+        }
+        Method::Huffman | Method::RleHuffman => {
+            let table_start = input.pos;
+            let freqs = Frequencies::read(&mut input)?;
+            writeln!(out, "{:<8}{:<16}{}", table_start, "pair count", freqs.pairs().len())?;
+            writeln!(out, "{:<8}frequencies", table_start + 4)?;
+            let tree = HuffTree::from_freqs(&freqs);
+            let encoder = HuffWriter::from_tree(&tree);
+            writeln!(out, "{:<12}{:<12}{:<12}code", "", "byte", "frequency")?;
+            for &(count, byte) in freqs.pairs() {
+                // Every byte in the frequencies is in the tree
+                let code = encoder.code(byte).unwrap();
+                writeln!(out, "{:<12}{:<12}{:<12}{}", "", show_byte(byte), count, show_code(code))?;
+                codes.insert(code, show_byte(byte));
+            }
+            eof = encoder.eof_code();
+            writeln!(out, "{:<12}{:<12}{:<12}{}", "", "EOF", 0, show_code(eof))?;
+            writeln!(out, "{:<8}{:<16}{} leaves, {} deep", "", "tree", tree.leaf_count(), tree.depth())?;
+        }
+        Method::Words | Method::Utf8 | Method::Tokens | Method::Ngrams => {
+            let table_start = input.pos;
+            let dict = Dictionary::read(&mut input)?;
+            writeln!(out, "{:<8}{:<16}{}", table_start, "symbol count", dict.len())?;
+            writeln!(out, "{:<8}dictionary", table_start + 4)?;
+            let encoder = SymbolWriter::new(&dict);
+            writeln!(out, "{:<12}{:<24}{:<12}code", "", "symbol", "weight")?;
+            for i in 0..dict.len() {
+                let symbol = show_symbol(dict.symbol(i));
+                let code = encoder.code(i);
+                writeln!(out, "{:<12}{:<24}{:<12}{}", "", symbol, dict.weight(i), show_code(code))?;
+                codes.insert(code, symbol);
+            }
+            eof = encoder.eof_code();
+            writeln!(out, "{:<12}{:<24}{:<12}{}", "", "EOF", 0, show_code(eof))?;
+        }
+    }
+
+    if header.checksummed() {
+        let (sum, offset) = (input.crc.sum(), input.pos);
+        let mut expected = [0; header::CHECKSUM_SIZE];
+        io::Read::read_exact(&mut input, &mut expected)?;
+        let expected = u32::from_be_bytes(expected);
+        let status = if sum == expected { "ok".to_string() } else { format!("corrupt, the header sums to {:08x}", sum) };
+        writeln!(out, "{:<8}{:<16}{:08x}, {}", offset, "checksum", expected, status)?;
+    }
+
+    let data_start = input.pos;
+    let mut data = Vec::new();
+    io::Read::read_to_end(&mut input, &mut data)?;
+    writeln!(out, "{:<8}{:<16}{} bytes", data_start, "data", data.len())?;
+    if header.method == Method::Rice {
+        return show_rice_blocks(&data, data_start, out)
+    }
+    if dump > 0 && header.method != Method::Stored && header.dict_id.is_none() {
+        writeln!(out)?;
+        dump_codes(&data, &codes, eof, dump, out)?;
+    }
+    Ok(())
+}
+
+// Go through the blocks of Rice coded data, printing out how many used each parameter
+fn show_rice_blocks<W: io::Write>(data: &[u8], data_start: u64, out: &mut W) -> io::Result<()> {
+    let mut blocks = [0u64; 16];
+    let mut pos = 0;
+    loop {
+        match data.get(pos..pos + 4) {
+            Some([0, 0, 0, 0]) => break,
+            Some(_) => {}
+            None => return writeln!(out, "{:<8}{:<16}data ended before the last block", data_start + pos as u64, "")
+        }
+        let header = match data.get(pos + 4..pos + 9) {
+            Some(header) => header,
+            None => return writeln!(out, "{:<8}{:<16}data ended in the header of a block", data_start + pos as u64, "")
+        };
+        blocks[(header[0] & 0x0F) as usize] += 1;
+        pos += 9 + u32::from_be_bytes([header[1], header[2], header[3], header[4]]) as usize;
+    }
+    writeln!(out, "{:<12}{:<16}blocks", "", "parameter")?;
+    for (parameter, &count) in blocks.iter().enumerate().filter(|&(_, &count)| count > 0) {
+        let zigzag = if parameter & 0x08 != 0 { ", zigzag" } else { "" };
+        writeln!(out, "{:<12}{:<16}{}", "", format!("k = {}{}", parameter & 0x07, zigzag), count)?;
+    }
+    Ok(())
+}
+
+// Go through the bits of the data, printing out each code we find along the way
+fn dump_codes<W: io::Write>(
+    data: &[u8],
+    codes: &HashMap<(u128, usize), String>,
+    eof: (u128, usize),
+    count: usize,
+    out: &mut W
+) -> io::Result<()> {
+    writeln!(out, "{:<12}{:<24}symbol", "bit", "code")?;
+    let mut code = (0, 0);
+    let mut start = 0;
+    let mut shown = 0;
+    for offset in 0..data.len() * 8 {
+        let bit = (data[offset / 8] >> (offset % 8)) & 1;
+        code.0 |= u128::from(bit) << code.1;
+        code.1 += 1;
+        if code == eof {
+            return writeln!(out, "{:<12}{:<24}EOF", start, show_code(code))
+        }
+        if let Some(symbol) = codes.get(&code) {
+            writeln!(out, "{:<12}{:<24}{}", start, show_code(code), symbol)?;
+            shown += 1;
+            if shown == count {
+                return Ok(())
+            }
+            start = offset + 1;
+            code = (0, 0);
+        } else if code.1 == 128 {
+            return writeln!(out, "{:<12}{:<24}no symbol has this code", start, "...")
+        }
+    }
+    writeln!(out, "{:<12}{:<24}data ended before EOF", start, show_code(code))
+}
+
+
+/// Write out a report of how well Huffman coding some input would work: for each byte,
+/// how often it occurs, the length of its code, and how many bits it takes up
+/// compared to storing it in 8 bits, followed by totals for the whole input.
+pub fn explain<R: io::Read, W: io::Write>(input: &mut R, out: &mut W) -> io::Result<()> {
+    let mut counts = [0u64; 256];
+    let mut buf = [0; 8192];
+    loop {
+        let read = match input.read(&mut buf) {
+            Ok(0) => break,
+            Ok(read) => read,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e)
+        };
+        for &byte in &buf[..read] {
+            counts[byte as usize] += 1;
+        }
+    }
+    let freqs = Frequencies::from_counts(&counts);
+    let tree = HuffTree::from_freqs(&freqs);
+    let encoder = HuffWriter::from_tree(&tree);
+
+    let mut bytes: Vec<u8> = (0..=255).filter(|&b| counts[b as usize] > 0).collect();
+    bytes.sort_by(|&a, &b| counts[b as usize].cmp(&counts[a as usize]));
+    writeln!(out, "{:<12}{:>12}{:>8}{:>14}{:>14}", "byte", "count", "length", "bits", "saved bits")?;
+    let mut total_bits = 0;
+    for byte in bytes {
+        let count = counts[byte as usize];
+        // Every byte that occurs is in the tree
+        let (_, len) = encoder.code(byte).unwrap();
+        let bits = count * len as u64;
+        let saved = (count * 8) as i64 - bits as i64;
+        writeln!(out, "{:<12}{:>12}{:>8}{:>14}{:>14}", show_byte(byte), count, len, bits, saved)?;
+        total_bits += bits;
+    }
+    let (_, eof_len) = encoder.eof_code();
+    let eof_len = eof_len as u64;
+    writeln!(out, "{:<12}{:>12}{:>8}{:>14}{:>14}", "EOF", 1, eof_len, eof_len, -(eof_len as i64))?;
+    total_bits += eof_len;
+
+    let input_bytes: u64 = counts.iter().sum();
+    let header_bytes = 5 + coding::table_size(&freqs, &tree);
+    let output_bytes = header_bytes + total_bits.div_ceil(8);
+    writeln!(out)?;
+    writeln!(out, "{:<24}{:>12}", "input bytes", input_bytes)?;
+    writeln!(out, "{:<24}{:>12}", "header bytes", header_bytes)?;
+    writeln!(out, "{:<24}{:>12}", "data bytes", total_bits.div_ceil(8))?;
+    writeln!(out, "{:<24}{:>12}", "output bytes", output_bytes)?;
+    if input_bytes > 0 {
+        let bits_per_byte = total_bits as f64 / input_bytes as f64;
+        let ratio = output_bytes as f64 / input_bytes as f64 * 100.0;
+        writeln!(out, "{:<24}{:>12.3}", "bits per byte", bits_per_byte)?;
+        writeln!(out, "{:<24}{:>11.2}%", "output / input", ratio)?;
+    }
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::{explain, inspect};
+
+    #[test]
+    fn inspecting_shows_codes() {
+        // The tree for these frequencies is described in coding's tests
+        let data = [
+            b'H', b'U', b'F', 1, 1,
+            0, 0, 0, 3, 69, 100, 71, 2, 70, 1,
+            0b0010_0101, 0
+        ];
+        let mut out = Vec::new();
+        inspect(&mut &data[..], &mut out, 10).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.contains("method          huffman"));
+        assert!(out.contains("            0x45 'E'    100         1"));
+        assert!(out.contains("        tree            4 leaves, 3 deep"));
+        assert!(out.contains("15      data            2 bytes"));
+        assert!(out.contains("0           1                       0x45 'E'"));
+        assert!(out.contains("1           01                      0x47 'G'"));
+        assert!(out.contains("3           001                     0x46 'F'"));
+        assert!(out.contains("6           000                     EOF"));
+    }
+
+    #[test]
+    fn explaining_totals_bits() {
+        let mut out = Vec::new();
+        explain(&mut &b"aaaabbc"[..], &mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.contains("0x61 'a'               4       1             4            28"));
+        assert!(out.contains("0x62 'b'               2       2             4            12"));
+        assert!(out.contains("EOF                    1       3             3            -3"));
+        assert!(out.contains("data bytes                         2"));
+        assert!(out.contains("output bytes                      13"));
+    }
+}
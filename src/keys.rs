@@ -0,0 +1,81 @@
+//! This module codes keys, like those of a sorted index, with order preserving codes,
+//! so that they can be stored compressed, and still be compared without decoding them.
+use std::io;
+use crate::coding::{Codebook, Frequencies, HuffTree, HuffWriter, OwnedHuffReader};
+
+
+/// Codes keys with a Hu–Tucker tree, so that comparing two encoded keys as bytes
+/// orders them the same way as comparing the keys themselves
+pub struct KeyCoder {
+    codebook: Codebook,
+    reader: OwnedHuffReader
+}
+
+impl KeyCoder {
+    /// Build a coder from how often each byte comes up in keys, where bytes that
+    /// weren't counted can still be coded, just with longer codes
+    pub fn new(counts: &[u64; 256]) -> Self {
+        let mut counts = *counts;
+        for count in counts.iter_mut() {
+            *count = count.saturating_add(1);
+        }
+        let tree = HuffTree::hu_tucker(&Frequencies::from_counts(&counts));
+        let codebook = Codebook::from_tree(&tree);
+        let reader = OwnedHuffReader::from_codebook(&codebook);
+        KeyCoder { codebook, reader }
+    }
+
+    pub fn encode(&self, key: &[u8]) -> Vec<u8> {
+        let mut writer = HuffWriter::from_codebook(&self.codebook);
+        let mut encoded = Vec::with_capacity(key.len());
+        // Every byte is in the tree, and writing to a vector can't fail
+        for &byte in key {
+            writer.write_byte(byte, &mut encoded).unwrap();
+        }
+        writer.finish(&mut encoded).unwrap();
+        // Writers fill bytes from their lowest bit up, but bytes compare from their highest bit down
+        for byte in encoded.iter_mut() {
+            *byte = byte.reverse_bits();
+        }
+        encoded
+    }
+
+    pub fn decode(&self, encoded: &[u8]) -> io::Result<Vec<u8>> {
+        let mut reader = self.reader.clone();
+        let mut decoded = Vec::with_capacity(encoded.len() * 2);
+        for &byte in encoded {
+            if !reader.feed(byte.reverse_bits(), &mut decoded)? {
+                return Ok(decoded)
+            }
+        }
+        Err(io::Error::new(io::ErrorKind::UnexpectedEof, "the key ended before its end of transmission"))
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::KeyCoder;
+
+    #[test]
+    fn encoded_keys_keep_their_order() {
+        let mut keys: Vec<&[u8]> = vec![
+            b"", b"a", b"a\0", b"ab", b"abc", b"abd", b"b", b"banana", b"band", b"zebra", b"\xff", b"\xff\xff"
+        ];
+        keys.sort();
+        // Plenty of keys like these, so that the bytes they don't use hardly count
+        let mut counts = [0; 256];
+        for &byte in keys.iter().flat_map(|key| key.iter()) {
+            counts[byte as usize] += 1000;
+        }
+        let coder = KeyCoder::new(&counts);
+        let encoded: Vec<_> = keys.iter().map(|key| coder.encode(key)).collect();
+        for pair in encoded.windows(2) {
+            assert!(pair[0] < pair[1], "{:?} isn't less than {:?}", pair[0], pair[1]);
+        }
+        for (key, encoded) in keys.iter().zip(&encoded) {
+            assert_eq!(&coder.decode(encoded).unwrap(), key);
+        }
+        assert!(coder.encode(b"banana").len() < b"banana".len());
+    }
+}
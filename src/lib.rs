@@ -1,4 +1,37 @@
+#[cfg(feature = "cli")]
 extern crate structopt;
+#[macro_use]
+mod trace;
+pub mod armor;
+pub mod blocks;
+#[cfg(feature = "cli")]
+pub mod cache;
+pub mod checksum;
+#[cfg(feature = "cli")]
 pub mod cli;
+pub mod codec;
+pub mod codegen;
 pub mod coding;
-mod queue;
\ No newline at end of file
+#[cfg(feature = "cli")]
+pub mod config;
+pub mod context;
+pub mod daemon;
+pub mod header;
+#[cfg(feature = "http")]
+pub mod http;
+pub mod inspect;
+pub mod keys;
+#[cfg(feature = "cli")]
+pub mod man;
+#[cfg(all(feature = "cli", unix))]
+pub mod mapped;
+pub mod pipeline;
+mod queue;
+pub mod rice;
+pub mod rle;
+pub mod spool;
+pub mod stream;
+pub mod stride;
+pub mod symbols;
+#[cfg(feature = "cli")]
+pub mod watch;
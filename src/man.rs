@@ -0,0 +1,176 @@
+//! This module writes a man page for the program, in roff, from the help of each of its
+//! subcommands, so that packagers can ship one that says the same as `--help` does.
+//!
+//! The help is generated by clap without wrapping, which leaves every flag, option and
+//! argument on a line of its own, under a heading like `FLAGS:`, with its description
+//! after the first run of several spaces.
+use std::io;
+use structopt::clap::{App, ErrorKind};
+
+
+/// How the format of encoded files is laid out, which the help doesn't say
+const FORMAT: &[&str] = &[
+    "Every encoded file starts with the bytes HUF, the version of the format, which is 2, \
+    and a byte holding the method the data was compressed with in its low bits, \
+    where 0x80 means the table is the shape of the tree instead of the frequencies of the bytes, \
+    0x40 that a byte giving the stride the data was split into planes with follows, \
+    0x20 that the number of bytes the data decodes to follows, as a big endian u64, \
+    and 0x10 that the ID of the preset dictionary the data was coded with follows, as a big endian u32.",
+    "The table of the method comes next, unless the data was coded with a preset dictionary, \
+    and then the CRC-32 of the header and the table, as a big endian u32, which is checked \
+    before decoding anything. The coded data follows, ending with the code of the end of the transmission.",
+    "Files in version 1 of the format have no checksum, and can be upgraded with migrate."
+];
+
+
+// Escape text so that roff shows it as is
+fn escape(text: &str) -> String {
+    let escaped = text.replace('\\', "\\e").replace('-', "\\-");
+    // Lines starting with these would be taken as requests
+    if escaped.starts_with('.') || escaped.starts_with('\'') {
+        return format!("\\&{}", escaped)
+    }
+    escaped
+}
+
+// The help of a subcommand, or of the program without one
+fn help(app: &App, subcommand: Option<&str>) -> String {
+    let name = app.get_name().to_string();
+    let args = Some(name.as_str()).into_iter().chain(subcommand).chain(Some("--help"));
+    match app.clone().set_term_width(0).get_matches_from_safe(args) {
+        Err(e) if e.kind == ErrorKind::HelpDisplayed => e.message,
+        _ => String::new()
+    }
+}
+
+// The lines before the first heading, and those under each heading, without the colon
+fn sections(help: &str) -> (Vec<&str>, Vec<(&str, Vec<&str>)>) {
+    let mut preamble = Vec::new();
+    let mut sections: Vec<(&str, Vec<&str>)> = Vec::new();
+    for line in help.lines() {
+        let heading = line.strip_suffix(':').filter(|title| !title.starts_with(' ') && title.chars().all(|c| c.is_ascii_uppercase() || c == ' '));
+        match (heading, sections.last_mut()) {
+            (Some(title), _) => sections.push((title, Vec::new())),
+            (None, Some((_, lines))) => lines.push(line),
+            (None, None) => preamble.push(line)
+        }
+    }
+    (preamble, sections)
+}
+
+// What a program or subcommand does, from the lines of its help before the first heading,
+// which start with its name and version, and then its author, unless it has none
+fn about(preamble: &[&str], author: Option<&str>) -> String {
+    let lines = preamble.iter().skip(1).filter(|&&line| !line.is_empty() && Some(line) != author);
+    lines.copied().collect::<Vec<_>>().join(" ")
+}
+
+// Write the lines of a section, where indented lines are entries, like a flag and its description,
+// and the others are paragraphs
+fn write_entries<W: io::Write>(writer: &mut W, lines: &[&str]) -> io::Result<()> {
+    for line in lines.iter().filter(|line| !line.trim().is_empty()) {
+        if !line.starts_with(' ') {
+            writeln!(writer, ".PP\n{}", escape(line))?;
+            continue;
+        }
+        let (term, description) = line.trim().split_once("  ").unwrap_or((line.trim(), ""));
+        // Every subcommand has these
+        if term == "-h, --help" || term == "-V, --version" {
+            continue;
+        }
+        writeln!(writer, ".TP\n\\fB{}\\fR\n{}", escape(term), escape(description.trim()))?;
+    }
+    Ok(())
+}
+
+/// Write a man page for a program, with a section for each of its subcommands,
+/// where the summary is the few words saying what it is, after its name
+pub fn write<W: io::Write>(app: &App, summary: &str, writer: &mut W) -> io::Result<()> {
+    let main = help(app, None);
+    let (preamble, main_sections) = sections(&main);
+    let name = app.get_name();
+    let title = preamble.first().copied().unwrap_or(name);
+    writeln!(writer, ".TH {} 1 \"\" \"{}\"", escape(&name.to_uppercase()), escape(title))?;
+    // Without a line for the author, there's only one for the name, and one for what the program does
+    let author = preamble.get(1).copied().filter(|_| preamble.iter().filter(|line| !line.is_empty()).count() > 2);
+    writeln!(writer, ".SH NAME\n{} \\- {}", escape(name), escape(summary))?;
+
+    let mut subcommands = Vec::new();
+    for (title, lines) in &main_sections {
+        match *title {
+            "USAGE" => {
+                writeln!(writer, ".SH SYNOPSIS")?;
+                for line in lines.iter().filter(|line| !line.trim().is_empty()) {
+                    writeln!(writer, "{}\n.br", escape(line.trim()))?;
+                }
+                writeln!(writer, ".SH DESCRIPTION\n{}", escape(&about(&preamble, author)))?;
+            }
+            "SUBCOMMANDS" => {
+                let names = lines.iter().filter_map(|line| line.split_whitespace().next());
+                subcommands.extend(names.filter(|&name| name != "help"));
+            }
+            // Every subcommand takes these, so they're only described once
+            "FLAGS" => {}
+            title => {
+                writeln!(writer, ".SH {}", escape(title))?;
+                write_entries(writer, lines)?;
+            }
+        }
+    }
+
+    writeln!(writer, ".SH COMMANDS")?;
+    for subcommand in subcommands {
+        let help = help(app, Some(subcommand));
+        let (preamble, sections) = sections(&help);
+        writeln!(writer, ".SS \"{} {}\"", escape(name), escape(subcommand))?;
+        writeln!(writer, "{}", escape(&about(&preamble, author)))?;
+        for (title, lines) in &sections {
+            match *title {
+                "USAGE" => {
+                    let usage = lines.iter().map(|line| line.trim()).find(|line| !line.is_empty()).unwrap_or("");
+                    writeln!(writer, ".PP\n\\fB{}\\fR", escape(usage))?;
+                }
+                _ => write_entries(writer, lines)?
+            }
+        }
+    }
+
+    writeln!(writer, ".SH FORMAT")?;
+    for paragraph in FORMAT {
+        writeln!(writer, ".PP\n{}", escape(paragraph))?;
+    }
+    if let Some(author) = author {
+        writeln!(writer, ".SH AUTHOR\n{}", escape(author))?;
+    }
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod test {
+    use structopt::clap::{App, Arg, SubCommand};
+    use super::write;
+
+    #[test]
+    fn subcommands_get_a_section() {
+        let app = App::new("tool")
+            .version("1.0")
+            .author("Someone")
+            .about("Does things")
+            .after_help("ENVIRONMENT:\n    TOOL_LEVEL    How hard to try\n\n.These override the defaults.")
+            .subcommand(SubCommand::with_name("run")
+                .about("Run things")
+                .arg(Arg::with_name("force").short("f").long("force").help("Run things, even if they ran already"))
+                .arg(Arg::with_name("input").required(true).help("The thing to run")));
+        let mut page = Vec::new();
+        write(&app, "does things", &mut page).unwrap();
+        let page = String::from_utf8(page).unwrap();
+        assert!(page.starts_with(".TH TOOL 1 \"\" \"tool 1.0\"\n.SH NAME\ntool \\- does things\n.SH SYNOPSIS\n"));
+        assert!(!page.contains("\\-\\-help"));
+        assert!(page.contains(".SH ENVIRONMENT\n.TP\n\\fBTOOL_LEVEL\\fR\nHow hard to try\n.PP\n\\&.These override the defaults.\n"));
+        assert!(page.contains(".SS \"tool run\"\nRun things\n.PP\n\\fBtool run [FLAGS] <input>\\fR\n"));
+        assert!(page.contains(".TP\n\\fB\\-f, \\-\\-force\\fR\nRun things, even if they ran already\n"));
+        assert!(page.contains(".TP\n\\fB<input>\\fR\nThe thing to run\n"));
+        assert!(page.ends_with(".SH AUTHOR\nSomeone\n"));
+    }
+}
@@ -0,0 +1,128 @@
+//! This module writes a file through a memory mapping of it, set aside at its full size
+//! up front, so that decoding a large file copies its bytes straight into the page cache,
+//! instead of going through a write call for every buffer.
+use std::convert::TryFrom;
+use std::fs::File;
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::ptr;
+
+
+// Set aside the blocks of a file up front, so that writing to the mapping can't run out of space,
+// which would kill the process with SIGBUS, rather than failing a write
+#[cfg(any(target_os = "linux", target_os = "android", target_os = "freebsd"))]
+fn allocate(file: &File, len: u64) -> io::Result<()> {
+    if len == 0 {
+        return Ok(())
+    }
+    // This returns the error, instead of setting errno
+    match unsafe { libc::posix_fallocate(file.as_raw_fd(), 0, len as libc::off_t) } {
+        0 => Ok(()),
+        errno => Err(io::Error::from_raw_os_error(errno))
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android", target_os = "freebsd")))]
+fn allocate(file: &File, len: u64) -> io::Result<()> {
+    file.set_len(len)
+}
+
+
+/// A writer filling a file of a known size through a mapping of it
+pub struct MappedWriter {
+    // Kept open for as long as it's mapped
+    _file: File,
+    ptr: *mut u8,
+    len: usize,
+    pos: usize
+}
+
+impl MappedWriter {
+    /// Set aside `len` bytes for a file, which needs to be open for reading and writing, and map them
+    pub fn new(file: File, len: u64) -> io::Result<Self> {
+        let too_large = || io::Error::new(io::ErrorKind::InvalidInput, "the file is too large to map");
+        if len > libc::off_t::MAX as u64 {
+            return Err(too_large())
+        }
+        let size = usize::try_from(len).map_err(|_| too_large())?;
+        allocate(&file, len)?;
+        file.set_len(len)?;
+        // Mapping nothing fails, and there's nothing to write anyway
+        if size == 0 {
+            return Ok(MappedWriter { _file: file, ptr: ptr::null_mut(), len: 0, pos: 0 })
+        }
+        let prot = libc::PROT_READ | libc::PROT_WRITE;
+        let mapping = unsafe { libc::mmap(ptr::null_mut(), size, prot, libc::MAP_SHARED, file.as_raw_fd(), 0) };
+        if mapping == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error())
+        }
+        Ok(MappedWriter { _file: file, ptr: mapping as *mut u8, len: size, pos: 0 })
+    }
+
+    /// Check that every byte set aside was written, which get written back
+    /// to the file once the mapping is removed, when the writer is dropped
+    pub fn finish(self) -> io::Result<()> {
+        if self.pos != self.len {
+            let msg = format!("only {} of the {} bytes set aside were written", self.pos, self.len);
+            return Err(io::Error::new(io::ErrorKind::InvalidData, msg))
+        }
+        Ok(())
+    }
+}
+
+impl io::Write for MappedWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.pos == self.len && !buf.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "more bytes were written than were set aside"))
+        }
+        let written = buf.len().min(self.len - self.pos);
+        // The mapping holds len bytes, and pos + written is at most len
+        unsafe {
+            ptr::copy_nonoverlapping(buf.as_ptr(), self.ptr.add(self.pos), written);
+        }
+        self.pos += written;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for MappedWriter {
+    fn drop(&mut self) {
+        if self.len > 0 {
+            // There's nothing to do about a mapping that can't be removed
+            unsafe {
+                libc::munmap(self.ptr as *mut libc::c_void, self.len);
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::env;
+    use std::fs::{self, OpenOptions};
+    use std::io::Write;
+    use super::MappedWriter;
+
+    #[test]
+    fn mapped_files_get_written() {
+        let path = env::temp_dir().join(format!("huffman-mapped-{}", std::process::id()));
+        let open = || OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&path).unwrap();
+        let mut writer = MappedWriter::new(open(), 11).unwrap();
+        writer.write_all(b"abra").unwrap();
+        writer.write_all(b"cadabra").unwrap();
+        assert!(writer.write_all(b"!").is_err());
+        writer.finish().unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"abracadabra");
+
+        let mut writer = MappedWriter::new(open(), 11).unwrap();
+        writer.write_all(b"abra").unwrap();
+        assert!(writer.finish().is_err());
+        MappedWriter::new(open(), 0).unwrap().finish().unwrap();
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,255 @@
+//! This module contains readers and writers doing their I/O on separate threads,
+//! passing chunks over bounded channels, so that the disk can be busy reading
+//! the input and writing the output while the codec is busy with the CPU.
+use std::io;
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::thread::{self, JoinHandle};
+
+
+// How many bytes are read or written at once
+const CHUNK_SIZE: usize = 64 * 1024;
+// How many chunks can be waiting in a channel before the sending thread blocks
+const DEPTH: usize = 4;
+
+
+fn stopped() -> io::Error {
+    io::Error::new(io::ErrorKind::BrokenPipe, "I/O thread stopped unexpectedly")
+}
+
+
+// Read chunks from a source, until it runs out, or the reader stops listening
+fn read_chunks<R: io::Read>(mut inner: R, sender: SyncSender<io::Result<Vec<u8>>>) -> R {
+    loop {
+        let mut chunk = vec![0; CHUNK_SIZE];
+        let result = match inner.read(&mut chunk) {
+            Ok(0) => return inner,
+            Ok(read) => {
+                chunk.truncate(read);
+                Ok(chunk)
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => Err(e)
+        };
+        let failed = result.is_err();
+        if sender.send(result).is_err() || failed {
+            return inner
+        }
+    }
+}
+
+
+/// A reader reading ahead from a source on another thread.
+/// Seeking stops that thread, seeks the source, and starts reading ahead again.
+pub struct Reader<R> {
+    receiver: Option<Receiver<io::Result<Vec<u8>>>>,
+    thread: Option<JoinHandle<R>>,
+    chunk: Vec<u8>,
+    pos: usize,
+    // How many bytes have been consumed since the start of the source
+    consumed: u64
+}
+
+impl <R: io::Read + Send + 'static> Reader<R> {
+    pub fn new(inner: R) -> Self {
+        let mut reader = Reader { receiver: None, thread: None, chunk: Vec::new(), pos: 0, consumed: 0 };
+        reader.start(inner);
+        reader
+    }
+
+    fn start(&mut self, inner: R) {
+        let (sender, receiver) = mpsc::sync_channel(DEPTH);
+        self.receiver = Some(receiver);
+        self.thread = Some(thread::spawn(move || read_chunks(inner, sender)));
+    }
+
+    // Stop reading ahead, getting back the source
+    fn stop(&mut self) -> io::Result<R> {
+        // Once the receiver is gone, the thread fails to send its next chunk, and returns
+        self.receiver = None;
+        self.chunk.clear();
+        self.pos = 0;
+        let thread = self.thread.take().ok_or_else(stopped)?;
+        thread.join().map_err(|_| stopped())
+    }
+}
+
+impl <R> io::Read for Reader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let available = io::BufRead::fill_buf(self)?;
+        let read = available.len().min(buf.len());
+        buf[..read].copy_from_slice(&available[..read]);
+        io::BufRead::consume(self, read);
+        Ok(read)
+    }
+}
+
+impl <R> io::BufRead for Reader<R> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        if self.pos == self.chunk.len() {
+            // A disconnected channel means that the source ran out
+            if let Some(Ok(result)) = self.receiver.as_ref().map(Receiver::recv) {
+                self.chunk = result?;
+                self.pos = 0;
+            }
+        }
+        Ok(&self.chunk[self.pos..])
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.pos += amt;
+        self.consumed += amt as u64;
+    }
+}
+
+impl <R: io::Read + io::Seek + Send + 'static> io::Seek for Reader<R> {
+    fn seek(&mut self, pos: io::SeekFrom) -> io::Result<u64> {
+        // The source is ahead of us, so seeking relative to it would be wrong
+        let pos = match pos {
+            io::SeekFrom::Current(offset) => io::SeekFrom::Start(self.consumed.wrapping_add(offset as u64)),
+            pos => pos
+        };
+        let mut inner = self.stop()?;
+        let result = inner.seek(pos);
+        // Even if seeking fails, the source should still be readable
+        self.start(inner);
+        self.consumed = result?;
+        Ok(self.consumed)
+    }
+}
+
+impl <R> Drop for Reader<R> {
+    fn drop(&mut self) {
+        self.receiver = None;
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+
+enum Message {
+    Data(Vec<u8>),
+    Flush
+}
+
+// Write out chunks, reporting back whenever we flush, or an error happens
+fn write_chunks<W: io::Write>(mut inner: W, receiver: Receiver<Message>, flushed: mpsc::Sender<io::Result<()>>) {
+    for message in receiver {
+        let result = match message {
+            Message::Data(chunk) => match inner.write_all(&chunk) {
+                Ok(()) => continue,
+                result => result
+            },
+            Message::Flush => inner.flush()
+        };
+        let failed = result.is_err();
+        if flushed.send(result).is_err() || failed {
+            return
+        }
+    }
+}
+
+
+/// A writer gathering bytes into chunks, which get written out to a sink on another thread.
+/// Flushing waits for everything written so far to reach the sink, returning
+/// any error that happened along the way.
+pub struct Writer {
+    sender: Option<SyncSender<Message>>,
+    flushed: Receiver<io::Result<()>>,
+    thread: Option<JoinHandle<()>>,
+    chunk: Vec<u8>
+}
+
+impl Writer {
+    pub fn new<W: io::Write + Send + 'static>(inner: W) -> Self {
+        let (sender, receiver) = mpsc::sync_channel(DEPTH);
+        let (flushed_sender, flushed) = mpsc::channel();
+        let thread = thread::spawn(move || write_chunks(inner, receiver, flushed_sender));
+        Writer { sender: Some(sender), flushed, thread: Some(thread), chunk: Vec::with_capacity(CHUNK_SIZE) }
+    }
+
+    fn send(&mut self, message: Message) -> io::Result<()> {
+        let sent = self.sender.as_ref().map(|sender| sender.send(message));
+        match sent {
+            Some(Ok(())) => Ok(()),
+            // The thread only stops early after an error, which it sent back
+            _ => Err(self.flushed.recv().map_or_else(|_| stopped(), |result| result.err().unwrap_or_else(stopped)))
+        }
+    }
+}
+
+impl io::Write for Writer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let take = buf.len().min(CHUNK_SIZE - self.chunk.len());
+        self.chunk.extend_from_slice(&buf[..take]);
+        if self.chunk.len() == CHUNK_SIZE {
+            let chunk = std::mem::replace(&mut self.chunk, Vec::with_capacity(CHUNK_SIZE));
+            self.send(Message::Data(chunk))?;
+        }
+        Ok(take)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if !self.chunk.is_empty() {
+            let chunk = std::mem::replace(&mut self.chunk, Vec::with_capacity(CHUNK_SIZE));
+            self.send(Message::Data(chunk))?;
+        }
+        self.send(Message::Flush)?;
+        self.flushed.recv().unwrap_or_else(|_| Err(stopped()))
+    }
+}
+
+impl Drop for Writer {
+    fn drop(&mut self) {
+        // Anything that wasn't flushed still gets written out, but errors are lost
+        if !self.chunk.is_empty() {
+            let chunk = std::mem::take(&mut self.chunk);
+            let _ = self.send(Message::Data(chunk));
+        }
+        self.sender = None;
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::io::{self, Read, Seek, Write};
+    use std::sync::{Arc, Mutex};
+    use super::{Reader, Writer};
+
+    // Lets the test look at what a writer on another thread wrote
+    #[derive(Clone)]
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn reading_and_writing_on_threads_works() {
+        let data: Vec<u8> = (0..200_000).map(|i| (i * 31 % 251) as u8).collect();
+        let mut reader = Reader::new(io::Cursor::new(data.clone()));
+        let mut read = Vec::new();
+        reader.read_to_end(&mut read).unwrap();
+        assert_eq!(read, data);
+        assert_eq!(reader.seek(io::SeekFrom::Start(100_000)).unwrap(), 100_000);
+        read.clear();
+        reader.read_to_end(&mut read).unwrap();
+        assert_eq!(read, &data[100_000..]);
+
+        let shared = Shared(Arc::new(Mutex::new(Vec::new())));
+        let mut writer = Writer::new(shared.clone());
+        writer.write_all(&data).unwrap();
+        writer.flush().unwrap();
+        assert_eq!(*shared.0.lock().unwrap(), data);
+    }
+}
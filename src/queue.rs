@@ -17,7 +17,7 @@ impl <K : std::cmp::Ord, V> PriorityQueue<K, V> {
 
     /// Remove the lowest priority item from the queue, if it exists
     pub fn remove(&mut self) -> Option<(K, V)> {
-        self.data.pop().map(|x| x)
+        self.data.pop()
     }
 
     /// Insert a value with a given priority key into the queue
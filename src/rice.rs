@@ -0,0 +1,203 @@
+//! This module contains Rice coding, which codes bytes in fewer bits the smaller they are,
+//! for data where each value is some fraction as likely as the one before it, like the
+//! residuals of a predictor. Rice codes are the Golomb codes dividing by a power of two.
+//!
+//! The input is split into blocks, each with its own parameter `k`, picked to give the
+//! fewest bits. A byte `v` is coded as `v >> k` in unary, with that many 1 bits followed
+//! by a 0 bit, and then the lowest `k` bits of `v`. Blocks of signed values, like differences,
+//! are zigzag mapped first, so that -1 becomes 1, 1 becomes 2, -2 becomes 3, and so on.
+//!
+//! Every block is its number of bytes, as a big endian u32, a byte holding `k` in its lowest
+//! 3 bits, with 0x08 set when the block is zigzag mapped, the number of bytes its codes take,
+//! as a big endian u32, and then those codes. A block with no bytes ends the data.
+use std::io;
+use crate::coding::BitWriter;
+
+
+/// How many bytes a block holds, except for the last one
+pub const BLOCK_SIZE: usize = 4096;
+
+// The bit of the parameter byte set when the block is zigzag mapped
+const ZIGZAG: u8 = 0x08;
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+
+// Map a byte holding a signed value to one that's small when that value is close to 0
+fn zigzag(byte: u8) -> u8 {
+    (byte << 1) ^ ((byte as i8 >> 7) as u8)
+}
+
+fn unzigzag(byte: u8) -> u8 {
+    (byte >> 1) ^ 0u8.wrapping_sub(byte & 1)
+}
+
+/// Pick the parameter byte coding a block in the fewest bits, returning it along with that number of bits
+pub fn parameter(block: &[u8]) -> (u8, u64) {
+    let mut counts = [0u64; 256];
+    for &byte in block {
+        counts[byte as usize] += 1;
+    }
+    let mut best = (0, u64::MAX);
+    for mapping in [0, ZIGZAG] {
+        for k in 0..8 {
+            let bits = (0..=255u8).map(|byte| {
+                let value = if mapping == ZIGZAG { zigzag(byte) } else { byte };
+                counts[byte as usize] * ((value >> k) as u64 + 1 + k as u64)
+            }).sum::<u64>();
+            if bits < best.1 {
+                best = (mapping | k, bits);
+            }
+        }
+    }
+    best
+}
+
+
+/// A writer Rice coding the bytes written to it into another writer, a block at a time
+pub struct Encoder<W> {
+    inner: W,
+    block: Vec<u8>,
+    codes: Vec<u8>
+}
+
+impl <W: io::Write> Encoder<W> {
+    pub fn new(inner: W) -> Self {
+        Encoder { inner, block: Vec::with_capacity(BLOCK_SIZE), codes: Vec::new() }
+    }
+
+    fn write_block(&mut self) -> io::Result<()> {
+        let (parameter, _) = parameter(&self.block);
+        let k = (parameter & 0x07) as usize;
+        let mut bits = BitWriter::<u128>::new();
+        self.codes.clear();
+        for &byte in &self.block {
+            let value = if parameter & ZIGZAG != 0 { zigzag(byte) } else { byte };
+            let mut ones = (value >> k) as usize;
+            // A quotient can take up to 256 bits, more than fit in one go
+            while ones >= 64 {
+                bits.write_bits(u64::MAX as u128, 64, &mut self.codes)?;
+                ones -= 64;
+            }
+            bits.write_bits((1 << ones) - 1, ones + 1, &mut self.codes)?;
+            bits.write_bits(value as u128 & ((1 << k) - 1), k, &mut self.codes)?;
+        }
+        bits.flush_bits(&mut self.codes)?;
+        self.inner.write_all(&(self.block.len() as u32).to_be_bytes())?;
+        self.inner.write_all(&[parameter])?;
+        self.inner.write_all(&(self.codes.len() as u32).to_be_bytes())?;
+        self.inner.write_all(&self.codes)?;
+        self.block.clear();
+        Ok(())
+    }
+
+    /// Write out the last block, and the empty block ending the data, returning the underlying writer
+    pub fn finish(mut self) -> io::Result<W> {
+        if !self.block.is_empty() {
+            self.write_block()?;
+        }
+        self.inner.write_all(&[0; 4])?;
+        Ok(self.inner)
+    }
+}
+
+impl <W: io::Write> io::Write for Encoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let taken = buf.len().min(BLOCK_SIZE - self.block.len());
+        self.block.extend_from_slice(&buf[..taken]);
+        if self.block.len() == BLOCK_SIZE {
+            self.write_block()?;
+        }
+        Ok(taken)
+    }
+
+    /// This doesn't write out the current block, since more bytes could go in it
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+
+/// Decode the blocks written by an Encoder, until the empty one ending them,
+/// returning how many bytes were decoded
+pub fn decode<R: io::Read, W: io::Write>(input: &mut R, writer: &mut W) -> io::Result<u64> {
+    let mut decoded = 0;
+    let mut block = Vec::with_capacity(BLOCK_SIZE);
+    let mut codes = Vec::new();
+    loop {
+        let mut len = [0; 4];
+        input.read_exact(&mut len)?;
+        let len = u32::from_be_bytes(len) as usize;
+        if len == 0 {
+            return Ok(decoded)
+        }
+        if len > BLOCK_SIZE {
+            return Err(invalid_data(&format!("blocks can't hold more than {} bytes", BLOCK_SIZE)))
+        }
+        let mut header = [0; 5];
+        input.read_exact(&mut header)?;
+        let parameter = header[0];
+        if parameter & !(ZIGZAG | 0x07) != 0 {
+            return Err(invalid_data(&format!("invalid Rice parameter {:#04x}", parameter)))
+        }
+        let codes_len = u32::from_be_bytes([header[1], header[2], header[3], header[4]]) as usize;
+        // No byte takes more than 256 bits, with a parameter of 0
+        if codes_len > len * 32 {
+            return Err(invalid_data("a block's codes are longer than its bytes could take"))
+        }
+        codes.resize(codes_len, 0);
+        input.read_exact(&mut codes)?;
+
+        let k = (parameter & 0x07) as usize;
+        let mut bits = (0..codes.len() * 8).map(|offset| (codes[offset / 8] >> (offset % 8)) & 1);
+        let ended = || invalid_data("a block's codes ended before its bytes did");
+        block.clear();
+        for _ in 0..len {
+            let mut quotient = 0usize;
+            while bits.next().ok_or_else(ended)? == 1 {
+                quotient += 1;
+            }
+            let mut value = quotient << k;
+            for i in 0..k {
+                value |= (bits.next().ok_or_else(ended)? as usize) << i;
+            }
+            if value > 255 {
+                return Err(invalid_data("a Rice code holds a value past 255"))
+            }
+            let value = value as u8;
+            block.push(if parameter & ZIGZAG != 0 { unzigzag(value) } else { value });
+        }
+        writer.write_all(&block)?;
+        decoded += len as u64;
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::io::Write;
+    use super::{decode, parameter, unzigzag, zigzag, Encoder, BLOCK_SIZE};
+
+    #[test]
+    fn encoding_then_decoding_works() {
+        assert!((0..=255).all(|byte| unzigzag(zigzag(byte)) == byte));
+        assert_eq!([zigzag(0), zigzag(0xFF), zigzag(1), zigzag(0xFE)], [0, 1, 2, 3]);
+        // Small unsigned values, then small signed ones, then every byte
+        let mut input: Vec<u8> = (0..BLOCK_SIZE).map(|i| (i % 7 == 0) as u8 * 3).collect();
+        input.extend((0..BLOCK_SIZE).map(|i| [0, 1, 0xFF, 2, 0xFE][i % 5]));
+        input.extend((0..=255).cycle().take(1000));
+        assert_eq!(parameter(&input[..BLOCK_SIZE]).0, 0);
+        assert_eq!(parameter(&input[BLOCK_SIZE..2 * BLOCK_SIZE]).0, 0x08 | 1);
+
+        let mut encoder = Encoder::new(Vec::new());
+        encoder.write_all(&input).unwrap();
+        let encoded = encoder.finish().unwrap();
+        assert!(encoded.len() < 2 * BLOCK_SIZE);
+        let mut decoded = Vec::new();
+        assert_eq!(decode(&mut &encoded[..], &mut decoded).unwrap(), input.len() as u64);
+        assert_eq!(decoded, input);
+        assert!(decode(&mut &encoded[..encoded.len() - 5], &mut Vec::new()).is_err());
+    }
+}
@@ -0,0 +1,118 @@
+//! This module contains a simple run length encoding, which can be applied
+//! to the input before Huffman coding it, when it contains long runs of bytes.
+//!
+//! Bytes are written out as is, except that after two equal bytes,
+//! the next byte counts how many more times that byte is repeated.
+use std::io;
+
+
+// The most additional repetitions of a byte a single count can represent
+const MAX_EXTRA: usize = 255;
+
+
+/// A writer run length encoding the bytes written to it into another writer
+pub struct Encoder<W> {
+    inner: W,
+    last: u8,
+    run: usize
+}
+
+impl <W: io::Write> Encoder<W> {
+    pub fn new(inner: W) -> Self {
+        Encoder { inner, last: 0, run: 0 }
+    }
+
+    fn write_run(&mut self) -> io::Result<()> {
+        match self.run {
+            0 => {}
+            1 => self.inner.write_all(&[self.last])?,
+            n => self.inner.write_all(&[self.last, self.last, (n - 2) as u8])?
+        }
+        self.run = 0;
+        Ok(())
+    }
+
+    /// Write out the run we're still in the middle of, returning the
+    /// underlying writer
+    pub fn finish(mut self) -> io::Result<W> {
+        self.write_run()?;
+        Ok(self.inner)
+    }
+}
+
+impl <W: io::Write> io::Write for Encoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        for &byte in buf {
+            if self.run > 0 && byte == self.last && self.run < MAX_EXTRA + 2 {
+                self.run += 1;
+            } else {
+                self.write_run()?;
+                self.last = byte;
+                self.run = 1;
+            }
+        }
+        Ok(buf.len())
+    }
+
+    /// This doesn't write out the current run, since more bytes could extend it
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+
+/// A writer undoing the run length encoding of the bytes written to it
+pub struct Decoder<W> {
+    inner: W,
+    last: Option<u8>,
+    counting: bool
+}
+
+impl <W: io::Write> Decoder<W> {
+    pub fn new(inner: W) -> Self {
+        Decoder { inner, last: None, counting: false }
+    }
+}
+
+impl <W: io::Write> io::Write for Decoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        for &byte in buf {
+            if self.counting {
+                // We only start counting after seeing a byte
+                let last = self.last.take().unwrap();
+                self.inner.write_all(&[last; MAX_EXTRA][..byte as usize])?;
+                self.counting = false;
+            } else {
+                self.inner.write_all(&[byte])?;
+                self.counting = self.last == Some(byte);
+                self.last = Some(byte);
+            }
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::io::Write;
+    use super::{Encoder, Decoder};
+
+    #[test]
+    fn encoding_then_decoding_works() {
+        let mut input = b"abbcccdddd".to_vec();
+        input.extend_from_slice(&[7; 600]);
+        input.push(8);
+        let mut encoder = Encoder::new(Vec::new());
+        encoder.write_all(&input).unwrap();
+        let encoded = encoder.finish().unwrap();
+        assert_eq!(encoded.len(), 20);
+        let mut decoded = Vec::new();
+        Decoder::new(&mut decoded).write_all(&encoded).unwrap();
+        assert_eq!(decoded, input);
+    }
+}
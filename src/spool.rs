@@ -0,0 +1,125 @@
+//! This module holds data that might get thrown away, like output that might need
+//! to be written again, keeping it in memory until it grows too large, and in a
+//! temporary file past that.
+use std::env;
+use std::fs::{self, File};
+use std::io::{self, Seek};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+
+/// How many bytes a spool keeps in memory, before moving them to a file
+pub const MEMORY_LIMIT: usize = 8 * 1024 * 1024;
+
+// Tells apart the files of the spools of a process
+static NEXT_FILE: AtomicU64 = AtomicU64::new(0);
+
+
+/// Data written once, and then either copied somewhere else, or dropped
+pub struct Spool {
+    memory: Vec<u8>,
+    file: Option<(File, PathBuf)>,
+    limit: usize,
+    len: u64
+}
+
+impl Spool {
+    pub fn new() -> Self {
+        Spool::with_limit(MEMORY_LIMIT)
+    }
+
+    /// Keep at most this many bytes in memory
+    pub fn with_limit(limit: usize) -> Self {
+        Spool { memory: Vec::new(), file: None, limit, len: 0 }
+    }
+
+    /// How many bytes have been written
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Whether the data went to a file, rather than staying in memory
+    pub fn spilled(&self) -> bool {
+        self.file.is_some()
+    }
+
+    /// Write everything in the spool to a writer, removing its file
+    pub fn copy_to<W: io::Write>(mut self, writer: &mut W) -> io::Result<()> {
+        writer.write_all(&self.memory)?;
+        if let Some((file, _)) = &mut self.file {
+            file.seek(io::SeekFrom::Start(0))?;
+            io::copy(file, writer)?;
+        }
+        Ok(())
+    }
+
+    fn spill(&mut self) -> io::Result<&mut File> {
+        if self.file.is_none() {
+            let n = NEXT_FILE.fetch_add(1, Ordering::Relaxed);
+            let path = env::temp_dir().join(format!("huffman-spool-{}-{}", std::process::id(), n));
+            let file = fs::OpenOptions::new().read(true).write(true).create_new(true).open(&path)?;
+            self.file = Some((file, path));
+        }
+        // The file was just created if it wasn't there
+        Ok(&mut self.file.as_mut().unwrap().0)
+    }
+}
+
+impl Default for Spool {
+    fn default() -> Self {
+        Spool::new()
+    }
+}
+
+impl io::Write for Spool {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = if self.file.is_none() && self.memory.len() + buf.len() <= self.limit {
+            self.memory.extend_from_slice(buf);
+            buf.len()
+        } else {
+            self.spill()?.write(buf)?
+        };
+        self.len += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match &mut self.file {
+            Some((file, _)) => file.flush(),
+            None => Ok(())
+        }
+    }
+}
+
+impl Drop for Spool {
+    fn drop(&mut self) {
+        if let Some((_, path)) = &self.file {
+            // There's nothing to do about a temporary file that can't be removed
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::io::Write;
+    use super::Spool;
+
+    #[test]
+    fn spools_spill_into_files() {
+        let mut spool = Spool::with_limit(4);
+        spool.write_all(b"abc").unwrap();
+        assert!(!spool.spilled());
+        spool.write_all(b"defg").unwrap();
+        assert!(spool.spilled());
+        assert_eq!(spool.len(), 7);
+        let mut copied = Vec::new();
+        spool.copy_to(&mut copied).unwrap();
+        assert_eq!(copied, b"abcdefg");
+    }
+}
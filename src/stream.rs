@@ -0,0 +1,291 @@
+//! This module contains an encoder and a decoder for raw Huffman codes, without a header
+//! or table, which can sit directly on non-blocking transports, like sockets.
+//!
+//! The encoder holds on to the codes its writer doesn't take, and the decoder to the codes
+//! it has read but not decoded yet, so that a write or read that's cut short, interrupted,
+//! or would block, loses nothing, and calling the same method again picks up from there.
+use std::io;
+use crate::coding::{Codebook, HuffWriter, OwnedHuffReader};
+
+
+/// How many bytes of codes an encoder holds on to, before refusing to take more bytes,
+/// and how many a decoder reads at once
+pub const BUFFER_SIZE: usize = 8192;
+
+
+// Write as much of a buffer as a writer takes, retrying when interrupted, and removing what
+// was written, failing with WouldBlock when the writer stops taking bytes before the end
+fn drain<W: io::Write>(writer: &mut W, pending: &mut Vec<u8>) -> io::Result<()> {
+    let mut written = 0;
+    let result = loop {
+        if written == pending.len() {
+            break Ok(())
+        }
+        match writer.write(&pending[written..]) {
+            Ok(0) => break Err(io::Error::new(io::ErrorKind::WriteZero, "the writer stopped taking bytes")),
+            Ok(n) => written += n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => break Err(e)
+        }
+    };
+    pending.drain(..written);
+    result
+}
+
+
+/// A writer Huffman coding the bytes written to it into another writer.
+///
+/// The codes are held on to until the writer takes them, and writing fails with
+/// WouldBlock, without taking any bytes, only once BUFFER_SIZE bytes of codes are waiting.
+/// `flush` and `finish` fail with WouldBlock until everything has been written, and
+/// can be called again once the writer is ready.
+pub struct Encoder<W> {
+    inner: W,
+    codes: Option<HuffWriter>,
+    pending: Vec<u8>
+}
+
+impl <W: io::Write> Encoder<W> {
+    pub fn new(inner: W, codebook: &Codebook) -> Self {
+        let codes = Some(HuffWriter::from_codebook(codebook));
+        Encoder { inner, codes, pending: Vec::with_capacity(BUFFER_SIZE) }
+    }
+
+    /// How many bytes of codes are waiting for the writer to take them
+    pub fn pending(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// Write the end of the transmission, and every code that's waiting, including the
+    /// last bits, which `flush` holds on to, since more codes could share their byte
+    pub fn finish(&mut self) -> io::Result<()> {
+        if let Some(codes) = self.codes.take() {
+            codes.finish(&mut self.pending)?;
+        }
+        drain(&mut self.inner, &mut self.pending)?;
+        self.inner.flush()
+    }
+
+    pub fn get_ref(&self) -> &W {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.inner
+    }
+
+    /// Take back the underlying writer, dropping any codes it hasn't taken yet
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+}
+
+impl <W: io::Write> io::Write for Encoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.pending.len() >= BUFFER_SIZE {
+            match drain(&mut self.inner, &mut self.pending) {
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock && self.pending.len() < BUFFER_SIZE => {}
+                result => result?
+            }
+        }
+        let codes = match &mut self.codes {
+            Some(codes) => codes,
+            None => return Err(io::Error::new(io::ErrorKind::InvalidInput, "the transmission has already ended"))
+        };
+        let mut taken = 0;
+        for &byte in buf {
+            if self.pending.len() >= BUFFER_SIZE {
+                break;
+            }
+            // The bytes before one that can't be coded were still taken
+            match codes.write_byte(byte, &mut self.pending) {
+                Ok(()) => taken += 1,
+                Err(e) if taken == 0 => return Err(e),
+                Err(_) => break
+            }
+        }
+        Ok(taken)
+    }
+
+    /// Write every whole byte of the codes so far, keeping the bits of the last one
+    fn flush(&mut self) -> io::Result<()> {
+        if let Some(codes) = &mut self.codes {
+            codes.flush_bytes(&mut self.pending)?;
+        }
+        drain(&mut self.inner, &mut self.pending)?;
+        self.inner.flush()
+    }
+}
+
+
+/// A reader decoding the Huffman codes read from another reader.
+///
+/// Reads are retried when interrupted, and fail with WouldBlock when the reader has nothing yet,
+/// in which case reading again once it's ready continues where decoding left off.
+/// Reading gives nothing more once the end of the transmission is reached.
+/// The decoder reads ahead of the codes into its own buffer, so whatever follows them starts
+/// with the bytes of `remaining`, and goes on with the rest of the underlying reader.
+pub struct Decoder<R> {
+    inner: R,
+    codes: OwnedHuffReader,
+    input: Box<[u8]>,
+    start: usize,
+    end: usize,
+    finished: bool
+}
+
+impl <R: io::Read> Decoder<R> {
+    pub fn new(inner: R, codebook: &Codebook) -> Self {
+        let codes = OwnedHuffReader::from_codebook(codebook);
+        Decoder { inner, codes, input: vec![0; BUFFER_SIZE].into_boxed_slice(), start: 0, end: 0, finished: false }
+    }
+
+    /// Whether the end of the transmission was reached
+    pub fn finished(&self) -> bool {
+        self.finished
+    }
+
+    /// The bytes read past the end of the transmission, which belong to whatever comes after it
+    pub fn remaining(&self) -> &[u8] {
+        &self.input[self.start..self.end]
+    }
+
+    /// Once the end of the transmission was reached, how many bits of the byte it ended in
+    /// were used, where the rest are padding, and the remaining bytes come after that byte
+    pub fn last_byte_bits(&self) -> Option<u32> {
+        self.codes.last_byte_bits()
+    }
+
+    pub fn get_ref(&self) -> &R {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+}
+
+impl <R: io::Read> io::Read for Decoder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while !self.finished && !buf.is_empty() {
+            if self.start < self.end {
+                let decoded = self.codes.decode_into(&self.input[self.start..self.end], buf);
+                self.start += decoded.read;
+                self.finished = decoded.finished;
+                if decoded.written > 0 {
+                    return Ok(decoded.written)
+                }
+                continue;
+            }
+            match self.inner.read(&mut self.input) {
+                Ok(0) => return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "the codes ended before the end of the transmission")),
+                Ok(read) => {
+                    self.start = 0;
+                    self.end = read;
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e)
+            }
+        }
+        Ok(0)
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::io::{self, Read, Write};
+    use crate::coding::{Codebook, Frequencies, HuffTree};
+    use super::{Decoder, Encoder, BUFFER_SIZE};
+
+    // A transport taking or giving at most 3 bytes at a time, and failing every other call
+    struct Flaky {
+        data: Vec<u8>,
+        pos: usize,
+        calls: usize
+    }
+
+    impl Flaky {
+        fn fail(&mut self) -> Option<io::Error> {
+            self.calls += 1;
+            match self.calls % 4 {
+                1 => Some(io::ErrorKind::Interrupted.into()),
+                3 => Some(io::ErrorKind::WouldBlock.into()),
+                _ => None
+            }
+        }
+    }
+
+    impl Write for Flaky {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if let Some(e) = self.fail() {
+                return Err(e)
+            }
+            let n = buf.len().min(3);
+            self.data.extend_from_slice(&buf[..n]);
+            Ok(n)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Read for Flaky {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if let Some(e) = self.fail() {
+                return Err(e)
+            }
+            let n = buf.len().min(3).min(self.data.len() - self.pos);
+            buf[..n].copy_from_slice(&self.data[self.pos..self.pos + n]);
+            self.pos += n;
+            Ok(n)
+        }
+    }
+
+    // Retry an operation until it doesn't block
+    fn retry<T>(mut f: impl FnMut() -> io::Result<T>) -> T {
+        loop {
+            match f() {
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                result => return result.unwrap()
+            }
+        }
+    }
+
+    #[test]
+    fn coding_survives_short_and_blocked_io() {
+        let input: Vec<u8> = b"abracadabra ".iter().cycle().take(BUFFER_SIZE * 5).copied().collect();
+        let freqs = Frequencies::count_reader(&mut &input[..]).unwrap();
+        let codebook = Codebook::from_tree(&HuffTree::from_freqs(&freqs));
+
+        let mut encoder = Encoder::new(Flaky { data: Vec::new(), pos: 0, calls: 0 }, &codebook);
+        let mut written = 0;
+        while written < input.len() {
+            written += retry(|| encoder.write(&input[written..]));
+        }
+        assert!(encoder.write(b"z").is_err());
+        retry(|| encoder.finish());
+        assert_eq!(encoder.pending(), 0);
+        let mut transport = encoder.into_inner();
+        transport.data.extend_from_slice(b"next");
+        transport.calls = 0;
+
+        let mut decoder = Decoder::new(transport, &codebook);
+        let mut decoded = Vec::new();
+        let mut buf = [0; 100];
+        loop {
+            match retry(|| decoder.read(&mut buf)) {
+                0 => break,
+                read => decoded.extend_from_slice(&buf[..read])
+            }
+        }
+        assert!(decoder.finished());
+        assert!(decoder.last_byte_bits().is_some_and(|bits| (1..=8).contains(&bits)));
+        assert_eq!(decoded, input);
+        let mut rest = decoder.remaining().to_vec();
+        let transport = decoder.into_inner();
+        rest.extend_from_slice(&transport.data[transport.pos..]);
+        assert_eq!(rest, b"next");
+    }
+}
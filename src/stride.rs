@@ -0,0 +1,174 @@
+//! This module reorders data made of fixed size records, like arrays of structs or
+//! the samples of a sensor, into planes, one for each byte of a record, before coding it.
+//! The bytes of a plane, like the high bytes of 16 bit samples, tend to look alike,
+//! while the bytes of a record don't, so coding them next to each other works better.
+//!
+//! The data is reordered in blocks, which are all BLOCK_SIZE bytes, rounded down to a
+//! whole number of records, but the last, which is however many bytes were left.
+use std::io;
+
+
+/// How many bytes get reordered at once, at most
+pub const BLOCK_SIZE: usize = 1024 * 1024;
+
+
+// The size of blocks holding whole records
+fn block_size(stride: u8) -> usize {
+    BLOCK_SIZE - BLOCK_SIZE % stride as usize
+}
+
+/// Move the bytes of every record in a block to the planes they belong to,
+/// where the last record can be cut short
+pub fn split_planes(block: &[u8], stride: u8, out: &mut Vec<u8>) {
+    out.clear();
+    for plane in 0..stride as usize {
+        out.extend(block.iter().skip(plane).step_by(stride as usize));
+    }
+}
+
+/// Put the bytes of the planes of a block back into their records, undoing split_planes
+pub fn join_planes(planes: &[u8], stride: u8, out: &mut Vec<u8>) {
+    out.clear();
+    out.resize(planes.len(), 0);
+    let mut bytes = planes.iter();
+    for plane in 0..stride as usize {
+        for (byte, &planed) in out.iter_mut().skip(plane).step_by(stride as usize).zip(&mut bytes) {
+            *byte = planed;
+        }
+    }
+}
+
+
+/// A reader giving back the blocks of another reader split into planes
+pub struct Planes<R> {
+    inner: R,
+    stride: u8,
+    block: Vec<u8>,
+    planes: Vec<u8>,
+    pos: usize
+}
+
+impl <R: io::Read> Planes<R> {
+    pub fn new(inner: R, stride: u8) -> Self {
+        Planes { inner, stride, block: Vec::new(), planes: Vec::new(), pos: 0 }
+    }
+
+    fn fill(&mut self) -> io::Result<()> {
+        self.block.clear();
+        self.block.resize(block_size(self.stride), 0);
+        let mut filled = 0;
+        while filled < self.block.len() {
+            match self.inner.read(&mut self.block[filled..]) {
+                Ok(0) => break,
+                Ok(read) => filled += read,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e)
+            }
+        }
+        self.block.truncate(filled);
+        split_planes(&self.block, self.stride, &mut self.planes);
+        self.pos = 0;
+        Ok(())
+    }
+}
+
+impl <R: io::Read> io::Read for Planes<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos == self.planes.len() {
+            self.fill()?;
+        }
+        let read = buf.len().min(self.planes.len() - self.pos);
+        buf[..read].copy_from_slice(&self.planes[self.pos..self.pos + read]);
+        self.pos += read;
+        Ok(read)
+    }
+}
+
+impl <R: io::Read + io::Seek> io::Seek for Planes<R> {
+    // The planes only line up with the input at its start, which is all encoding seeks back to
+    fn seek(&mut self, pos: io::SeekFrom) -> io::Result<u64> {
+        match pos {
+            io::SeekFrom::Start(0) => {
+                self.planes.clear();
+                self.pos = 0;
+                self.inner.seek(pos)
+            }
+            _ => Err(io::Error::new(io::ErrorKind::Unsupported, "planes can only be read again from the start"))
+        }
+    }
+}
+
+
+/// A writer putting the planes written to it back into records, block by block
+pub struct Records<W> {
+    inner: W,
+    stride: u8,
+    planes: Vec<u8>,
+    block: Vec<u8>
+}
+
+impl <W: io::Write> Records<W> {
+    pub fn new(inner: W, stride: u8) -> Self {
+        Records { inner, stride, planes: Vec::new(), block: Vec::new() }
+    }
+
+    fn write_block(&mut self) -> io::Result<()> {
+        join_planes(&self.planes, self.stride, &mut self.block);
+        self.planes.clear();
+        self.inner.write_all(&self.block)
+    }
+
+    /// Write out the last block, which can be shorter than the others, returning the underlying writer
+    pub fn finish(mut self) -> io::Result<W> {
+        if !self.planes.is_empty() {
+            self.write_block()?;
+        }
+        Ok(self.inner)
+    }
+}
+
+impl <W: io::Write> io::Write for Records<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let taken = buf.len().min(block_size(self.stride) - self.planes.len());
+        self.planes.extend_from_slice(&buf[..taken]);
+        if self.planes.len() == block_size(self.stride) {
+            self.write_block()?;
+        }
+        Ok(taken)
+    }
+
+    /// This doesn't write out the current block, since its planes aren't all there yet
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::io::{self, Read, Seek, Write};
+    use super::{block_size, split_planes, Planes, Records};
+
+    #[test]
+    fn planes_get_joined_back_into_records() {
+        let mut planes = Vec::new();
+        split_planes(b"aAbBcCd", 2, &mut planes);
+        assert_eq!(planes, b"abcdABC");
+
+        // Several blocks, with a record cut short at the end
+        let records: Vec<u8> = (0..block_size(3) * 2 + 100).map(|i| (i % 3 * 50 + i / 3 % 7) as u8).collect();
+        let mut reader = Planes::new(io::Cursor::new(&records), 3);
+        let mut planes = Vec::new();
+        reader.read_to_end(&mut planes).unwrap();
+        assert_eq!(planes.len(), records.len());
+        assert_eq!(&planes[..3], &[0, 1, 2]);
+        reader.seek(io::SeekFrom::Start(0)).unwrap();
+        let mut again = Vec::new();
+        reader.read_to_end(&mut again).unwrap();
+        assert_eq!(again, planes);
+
+        let mut writer = Records::new(Vec::new(), 3);
+        writer.write_all(&planes).unwrap();
+        assert_eq!(writer.finish().unwrap(), records);
+    }
+}
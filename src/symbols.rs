@@ -0,0 +1,491 @@
+//! This module contains Huffman coding over symbols made up of several bytes,
+//! like the words of a text, rather than over single bytes.
+//!
+//! The input is split into symbols, and the distinct symbols are gathered
+//! into a dictionary, written out before the encoded data. Decoding doesn't
+//! need to know how the input was split, since it only needs to write
+//! out the bytes of each symbol it decodes.
+use std::collections::HashMap;
+use std::io;
+use crate::coding::{BitWriter, WriterStats, MAX_CODE_LEN};
+use crate::queue::PriorityQueue;
+
+
+/// The longest a symbol can be, so that its length fits in a byte
+pub const MAX_SYMBOL_LEN: usize = 255;
+
+
+/// Decides where to split the input into symbols
+pub trait Splitter {
+    /// Whether or not `next` should start a new symbol, instead of
+    /// being added to the end of `symbol`, which is never empty
+    fn splits(&self, symbol: &[u8], next: u8) -> bool;
+}
+
+/// Splits text into words, and the spaces and punctuation between them
+pub struct Words;
+
+impl Words {
+    // Bytes above 0x7F are part of multi-byte characters, which we
+    // treat as letters so that accented words are kept whole
+    fn is_word_byte(byte: u8) -> bool {
+        byte.is_ascii_alphanumeric() || byte >= 0x80
+    }
+}
+
+impl Splitter for Words {
+    fn splits(&self, symbol: &[u8], next: u8) -> bool {
+        Words::is_word_byte(symbol[0]) != Words::is_word_byte(next)
+    }
+}
+
+/// Splits text into UTF-8 encoded characters. Bytes that aren't part of
+/// a valid character are left on their own as symbols.
+pub struct Utf8;
+
+impl Utf8 {
+    // How many bytes the character starting with this byte should take up
+    fn char_len(lead: u8) -> usize {
+        match lead {
+            0xC2..=0xDF => 2,
+            0xE0..=0xEF => 3,
+            0xF0..=0xF4 => 4,
+            _ => 1
+        }
+    }
+}
+
+impl Splitter for Utf8 {
+    fn splits(&self, symbol: &[u8], next: u8) -> bool {
+        let is_continuation = next & 0xC0 == 0x80;
+        symbol.len() >= Utf8::char_len(symbol[0]) || !is_continuation
+    }
+}
+
+/// Splits a stream of LEB128 varints, like the token IDs a tokenizer outputs, into one symbol
+/// per varint. A u32 takes at most 5 bytes, so bytes past that, and a varint the input ends
+/// in the middle of, are left as symbols of their own.
+pub struct Varints;
+
+// How many bytes a varint holding a u32 takes at most
+const MAX_VARINT_LEN: usize = 5;
+
+impl Splitter for Varints {
+    fn splits(&self, symbol: &[u8], _next: u8) -> bool {
+        // Every byte but the last has its high bit set
+        symbol[symbol.len() - 1] & 0x80 == 0 || symbol.len() >= MAX_VARINT_LEN
+    }
+}
+
+/// Splits the input into n-grams, runs of a fixed number of bytes, which picks up on
+/// the bytes that go together in structured binary data, like the fields of records.
+/// The input can end in the middle of an n-gram, leaving a shorter one at the end.
+pub struct Ngrams(pub usize);
+
+/// How many bytes the n-grams of the ngrams method have, unless told otherwise
+pub const NGRAM_SIZE: usize = 2;
+
+impl Splitter for Ngrams {
+    fn splits(&self, symbol: &[u8], _next: u8) -> bool {
+        symbol.len() >= self.0
+    }
+}
+
+/// Read the entire source, calling `f` with each symbol it gets split into
+pub fn split<R, S, F>(reader: &mut R, splitter: &S, mut f: F) -> io::Result<()>
+where
+    R: io::Read,
+    S: Splitter,
+    F: FnMut(&[u8]) -> io::Result<()>
+{
+    let mut symbol = Vec::with_capacity(MAX_SYMBOL_LEN);
+    let mut buf = [0; 8192];
+    loop {
+        let read = match reader.read(&mut buf) {
+            Ok(0) => break,
+            Ok(read) => read,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e)
+        };
+        for &byte in &buf[..read] {
+            let full = symbol.len() == MAX_SYMBOL_LEN;
+            if !symbol.is_empty() && (full || splitter.splits(&symbol, byte)) {
+                f(&symbol)?;
+                symbol.clear();
+            }
+            symbol.push(byte);
+        }
+    }
+    if !symbol.is_empty() {
+        f(&symbol)?;
+    }
+    Ok(())
+}
+
+
+/// The distinct symbols some input is made of, along with how often they occur
+#[derive(Clone, Debug, PartialEq)]
+pub struct Dictionary {
+    symbols: Vec<Vec<u8>>,
+    // Scaled down to fit in 32 bits if necessary
+    weights: Vec<u32>
+}
+
+impl Dictionary {
+    /// Split the entire source into symbols, counting how often each occurs
+    pub fn count<R: io::Read, S: Splitter>(reader: &mut R, splitter: &S) -> io::Result<Self> {
+        let mut counts: HashMap<Vec<u8>, u64> = HashMap::new();
+        split(reader, splitter, |symbol| {
+            *counts.entry(symbol.to_vec()).or_insert(0) += 1;
+            Ok(())
+        })?;
+        let mut pairs: Vec<_> = counts.into_iter().collect();
+        // Sort by count, and then by bytes, to get a consistent ordering
+        pairs.sort_by(|(symbol1, count1), (symbol2, count2)| {
+            count2.cmp(count1).then_with(|| symbol1.cmp(symbol2))
+        });
+        let max = pairs.first().map_or(0, |&(_, count)| count);
+        let mut scale = 0;
+        while max >> scale > u64::from(u32::MAX) {
+            scale += 1;
+        }
+        let weights = pairs.iter().map(|&(_, count)| ((count >> scale) as u32).max(1)).collect();
+        let symbols = pairs.into_iter().map(|(symbol, _)| symbol).collect();
+        Ok(Dictionary { symbols, weights })
+    }
+
+    /// The number of distinct symbols
+    pub fn len(&self) -> usize {
+        self.symbols.len()
+    }
+
+    /// Whether or not there are no symbols at all
+    pub fn is_empty(&self) -> bool {
+        self.symbols.is_empty()
+    }
+
+    /// The bytes making up the symbol at some index
+    pub fn symbol(&self, index: usize) -> &[u8] {
+        &self.symbols[index]
+    }
+
+    /// The weight the symbol at some index has when building the tree
+    pub fn weight(&self, index: usize) -> u32 {
+        self.weights[index]
+    }
+
+    /// Write the number of symbols, followed by the length, bytes, and weight
+    /// of each symbol
+    pub fn write<W: io::Write>(&self, writer: &mut W) -> io::Result<()> {
+        writer.write_all(&(self.symbols.len() as u32).to_be_bytes())?;
+        for (symbol, weight) in self.symbols.iter().zip(&self.weights) {
+            writer.write_all(&[symbol.len() as u8])?;
+            writer.write_all(symbol)?;
+            writer.write_all(&weight.to_be_bytes())?;
+        }
+        Ok(())
+    }
+
+    /// Attempt to read a dictionary from some source
+    pub fn read<R: io::Read>(reader: &mut R) -> io::Result<Self> {
+        Dictionary::read_at_most(reader, usize::MAX)
+    }
+
+    /// Attempt to read a dictionary from some source, failing before reading any symbols
+    /// if it has more than some number of them
+    pub fn read_at_most<R: io::Read>(reader: &mut R, max_symbols: usize) -> io::Result<Self> {
+        let mut num_buf = [0; 4];
+        reader.read_exact(&mut num_buf)?;
+        let num = u32::from_be_bytes(num_buf) as usize;
+        if num > max_symbols {
+            let msg = format!("the dictionary has {} symbols, more than the {} allowed", num, max_symbols);
+            return Err(io::Error::new(io::ErrorKind::InvalidData, msg))
+        }
+        let mut symbols = Vec::new();
+        let mut weights = Vec::new();
+        for _ in 0..num {
+            let mut len = [0; 1];
+            reader.read_exact(&mut len)?;
+            if len[0] == 0 {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "empty symbol in dictionary"))
+            }
+            let mut symbol = vec![0; len[0] as usize];
+            reader.read_exact(&mut symbol)?;
+            let mut weight = [0; 4];
+            reader.read_exact(&mut weight)?;
+            symbols.push(symbol);
+            weights.push(u32::from_be_bytes(weight));
+        }
+        Ok(Dictionary { symbols, weights })
+    }
+
+    /// The length of the longest code of the symbols, or of the end of the transmission
+    pub fn max_code_len(&self) -> usize {
+        depth(&self.build_nodes())
+    }
+
+    // Build the Huffman tree for these symbols, as a list of nodes,
+    // with the root coming last. The symbol after the last one is EOF.
+    fn build_nodes(&self) -> Vec<Node> {
+        let mut nodes = self.build_nodes_with(0);
+        // Like with HuffTree, we even out the weights until the codes are short enough
+        let mut floor = 1;
+        while depth(&nodes) > MAX_CODE_LEN {
+            nodes = self.build_nodes_with(floor);
+            floor *= 2;
+        }
+        nodes
+    }
+
+    // Build the nodes of the tree, after adding floor to every weight
+    fn build_nodes_with(&self, floor: u64) -> Vec<Node> {
+        let mut nodes: Vec<Node> = (0..=self.len()).map(Node::Leaf).collect();
+        let pairs = self.weights.iter().enumerate().map(|(i, &w)| (u64::from(w) + floor, i)).collect();
+        // The weights are already sorted in reverse order
+        let mut q = PriorityQueue::from_data(pairs);
+        q.insert(floor, self.len());
+        while let Some(((weight1, node1), (weight2, node2))) = q.remove_two() {
+            nodes.push(Node::Branch(node1, node2));
+            q.insert(weight1 + weight2, nodes.len() - 1);
+        }
+        nodes
+    }
+}
+
+
+#[derive(Clone, Copy, Debug)]
+enum Node {
+    Branch(usize, usize),
+    Leaf(usize)
+}
+
+// The depth of a tree built by build_nodes, where children always come before their parent
+fn depth(nodes: &[Node]) -> usize {
+    let mut depths: Vec<usize> = Vec::with_capacity(nodes.len());
+    for node in nodes {
+        let depth = match *node {
+            Node::Branch(left, right) => 1 + depths[left].max(depths[right]),
+            Node::Leaf(_) => 0
+        };
+        depths.push(depth);
+    }
+    depths.last().cloned().unwrap_or(0)
+}
+
+
+/// The analog of a HuffWriter, for the symbols in a dictionary
+pub struct SymbolWriter {
+    index: HashMap<Vec<u8>, usize>,
+    codes: Vec<(u128, usize)>,
+    bits: BitWriter,
+    count: u64
+}
+
+impl SymbolWriter {
+    pub fn new(dict: &Dictionary) -> Self {
+        let nodes = dict.build_nodes();
+        let mut codes = vec![(0, 0); dict.len() + 1];
+        let mut pending = vec![(nodes.len() - 1, 0, 0)];
+        while let Some((node, bits, shift)) = pending.pop() {
+            match nodes[node] {
+                Node::Branch(left, right) => {
+                    pending.push((left, bits, shift + 1));
+                    pending.push((right, (1 << shift) | bits, shift + 1));
+                }
+                Node::Leaf(symbol) => codes[symbol] = (bits, shift)
+            }
+        }
+        let index = dict.symbols.iter().enumerate().map(|(i, s)| (s.clone(), i)).collect();
+        SymbolWriter { index, codes, bits: BitWriter::new(), count: 0 }
+    }
+
+    /// The code for the symbol at some index in the dictionary, and its length in bits
+    pub fn code(&self, index: usize) -> (u128, usize) {
+        self.codes[index]
+    }
+
+    /// The code for the end of the transmission, and its length in bits
+    pub fn eof_code(&self) -> (u128, usize) {
+        self.codes[self.codes.len() - 1]
+    }
+
+    /// Write a symbol, which must be part of the dictionary
+    pub fn write_symbol<W: io::Write>(&mut self, symbol: &[u8], writer: &mut W) -> io::Result<()> {
+        match self.index.get(symbol) {
+            Some(&i) => {
+                let (bits, bit_size) = self.codes[i];
+                self.count += 1;
+                self.bits.write_bits(bits, bit_size, writer)
+            }
+            None => Err(io::Error::new(io::ErrorKind::InvalidInput, "symbol not in dictionary"))
+        }
+    }
+
+    /// How many symbols have been written, not counting the end of the transmission
+    pub fn symbol_count(&self) -> u64 {
+        self.count
+    }
+
+    /// Write the EOF symbol, and then flush out the remaining bits
+    #[deprecated(note = "use finish, which takes the writer, so that nothing can be written after the end")]
+    pub fn end_transmission<W: io::Write>(&mut self, writer: &mut W) -> io::Result<()> {
+        let (bits, bit_size) = self.codes[self.codes.len() - 1];
+        self.bits.write_bits(bits, bit_size, writer)?;
+        self.bits.flush_bits(writer)
+    }
+
+    /// Write the EOF symbol, and then flush out the remaining bits, returning how much was written
+    pub fn finish<W: io::Write>(mut self, writer: &mut W) -> io::Result<WriterStats> {
+        let (bits, bit_size) = self.codes[self.codes.len() - 1];
+        self.bits.write_bits(bits, bit_size, writer)?;
+        let stats = WriterStats { symbols: self.count, bits: self.bits.bit_position() };
+        self.bits.flush_bits(writer)?;
+        Ok(stats)
+    }
+}
+
+
+/// The analog of a HuffReader, for the symbols in a dictionary
+pub struct SymbolReader<'a> {
+    dict: &'a Dictionary,
+    nodes: Vec<Node>,
+    node: usize,
+    count: u64,
+    bits: u64
+}
+
+impl <'a> SymbolReader<'a> {
+    pub fn new(dict: &'a Dictionary) -> Self {
+        let nodes = dict.build_nodes();
+        let node = nodes.len() - 1;
+        SymbolReader { dict, nodes, node, count: 0, bits: 0 }
+    }
+
+    /// Feed a byte to this reader
+    /// Return true if the reader can continue to accept input
+    pub fn feed<W: io::Write>(&mut self, mut byte: u8, writer: &mut W) -> io::Result<bool> {
+        for _ in 0..8 {
+            if let Node::Branch(left, right) = self.nodes[self.node] {
+                self.node = if byte & 1 == 0 { left } else { right };
+                byte >>= 1;
+                self.bits += 1;
+            }
+            if let Node::Leaf(symbol) = self.nodes[self.node] {
+                if symbol == self.dict.len() {
+                    return Ok(false)
+                }
+                writer.write_all(&self.dict.symbols[symbol])?;
+                self.count += 1;
+                self.node = self.nodes.len() - 1;
+            }
+        }
+        Ok(true)
+    }
+
+    /// How many symbols have been read, not counting the end of the transmission
+    pub fn symbol_count(&self) -> u64 {
+        self.count
+    }
+
+    /// How many bits have been followed down the tree, up to the end of the transmission
+    pub fn bit_position(&self) -> u64 {
+        self.bits
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::{Dictionary, Ngrams, SymbolReader, SymbolWriter, Utf8, Varints, Words};
+    use crate::coding::MAX_CODE_LEN;
+
+    #[test]
+    fn counting_words_works() {
+        let text = b"the cat and the hat, and the bat";
+        let dict = Dictionary::count(&mut &text[..], &Words).unwrap();
+        assert_eq!(dict.symbols[0], b" ");
+        assert_eq!(dict.symbols[1], b"the");
+        assert_eq!(dict.symbols[2], b"and");
+        assert_eq!(dict.len(), 7);
+    }
+
+    #[test]
+    fn splitting_utf8_works() {
+        let text = "añb€\u{1F600}".as_bytes();
+        let mut input = text.to_vec();
+        // A stray continuation byte, and a truncated character
+        input.extend_from_slice(&[0x80, 0xE2, 0x82, b'c']);
+        let mut symbols = Vec::new();
+        super::split(&mut &input[..], &Utf8, |symbol| {
+            symbols.push(symbol.to_vec());
+            Ok(())
+        }).unwrap();
+        let expected: Vec<&[u8]> = vec![
+            b"a", "ñ".as_bytes(), b"b", "€".as_bytes(), "\u{1F600}".as_bytes(),
+            &[0x80], &[0xE2, 0x82], b"c"
+        ];
+        assert_eq!(symbols, expected);
+    }
+
+    #[test]
+    fn splitting_varints_works() {
+        // 1, 300, and 2^32 - 1, followed by too many continuation bytes, and an unfinished varint
+        let input = [0x01, 0xAC, 0x02, 0xFF, 0xFF, 0xFF, 0xFF, 0x0F, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x81];
+        let mut symbols = Vec::new();
+        super::split(&mut &input[..], &Varints, |symbol| {
+            symbols.push(symbol.to_vec());
+            Ok(())
+        }).unwrap();
+        let expected: Vec<&[u8]> = vec![
+            &[0x01], &[0xAC, 0x02], &[0xFF, 0xFF, 0xFF, 0xFF, 0x0F],
+            &[0x80, 0x80, 0x80, 0x80, 0x80], &[0x80, 0x81]
+        ];
+        assert_eq!(symbols, expected);
+    }
+
+    #[test]
+    fn splitting_ngrams_works() {
+        let input = b"abcdefg";
+        let mut symbols = Vec::new();
+        super::split(&mut &input[..], &Ngrams(3), |symbol| {
+            symbols.push(symbol.to_vec());
+            Ok(())
+        }).unwrap();
+        let expected: Vec<&[u8]> = vec![b"abc", b"def", b"g"];
+        assert_eq!(symbols, expected);
+    }
+
+    #[test]
+    fn encoding_then_decoding_works() {
+        let text = b"one fish, two fish, red fish, blue fish";
+        let dict = Dictionary::count(&mut &text[..], &Words).unwrap();
+        let mut encoded = Vec::new();
+        dict.write(&mut encoded).unwrap();
+        let mut writer = SymbolWriter::new(&dict);
+        super::split(&mut &text[..], &Words, |symbol| writer.write_symbol(symbol, &mut encoded)).unwrap();
+        writer.finish(&mut encoded).unwrap();
+
+        let mut input = &encoded[..];
+        let dict = Dictionary::read(&mut input).unwrap();
+        let mut reader = SymbolReader::new(&dict);
+        let mut decoded = Vec::new();
+        for &byte in input {
+            if !reader.feed(byte, &mut decoded).unwrap() {
+                break;
+            }
+        }
+        assert_eq!(&decoded[..], &text[..]);
+    }
+
+    #[test]
+    fn zero_weights_dont_make_codes_too_long() {
+        // Weights of 0 can only come from a file, and would make a chain 300 branches deep
+        let symbols: Vec<Vec<u8>> = (0..300u32).map(|i| i.to_string().into_bytes()).collect();
+        let mut weights = vec![0; 300];
+        weights[0] = u32::MAX;
+        let dict = Dictionary { symbols, weights };
+        assert!(super::depth(&dict.build_nodes()) <= MAX_CODE_LEN);
+        let writer = SymbolWriter::new(&dict);
+        assert!((0..dict.len()).all(|i| writer.code(i).1 <= MAX_CODE_LEN));
+    }
+}
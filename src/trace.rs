@@ -0,0 +1,19 @@
+//! This module contains macros instrumenting the library with `tracing`,
+//! which expand to nothing unless the `tracing` feature is enabled.
+
+
+// Enter a span, lasting until the end of the enclosing block
+macro_rules! trace_span {
+    ($($args:tt)*) => {
+        #[cfg(feature = "tracing")]
+        let _span = tracing::debug_span!($($args)*).entered();
+    };
+}
+
+// Record an event in the current span
+macro_rules! trace_event {
+    ($($args:tt)*) => {
+        #[cfg(feature = "tracing")]
+        tracing::debug!($($args)*);
+    };
+}
@@ -0,0 +1,149 @@
+//! This module watches a directory for files to compress, by scanning it every so often,
+//! and handing out the files that stopped changing since the last scan, so that files
+//! are only compressed once whatever is writing them is done.
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::time::SystemTime;
+
+
+/// Check whether a file name matches a pattern, where `*` matches anything, and `?` any one character
+pub fn matches(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    // Where to go back to when what a star matched needs to grow by one character
+    let mut star = None;
+    let (mut p, mut n) = (0, 0);
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p, n));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match star {
+                Some((star_p, star_n)) => {
+                    star = Some((star_p, star_n + 1));
+                    p = star_p + 1;
+                    n = star_n + 1;
+                }
+                None => return false
+            }
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+
+// The size and modification time of a file, which change whenever it's written to
+type Stamp = (u64, Option<SystemTime>);
+
+// What we know about a file from the last scan
+struct Seen {
+    stamp: Stamp,
+    // Whether the file has been handed out since it last changed
+    handed_out: bool
+}
+
+/// Watches a directory for files matching a pattern, skipping those with the suffix of encoded files
+pub struct Watcher {
+    dir: PathBuf,
+    pattern: String,
+    suffix: String,
+    seen: HashMap<PathBuf, Seen>
+}
+
+impl Watcher {
+    /// Start watching a directory, where the files already in it are left alone until they change
+    pub fn new(dir: PathBuf, pattern: String, suffix: String) -> io::Result<Self> {
+        let mut watcher = Watcher { dir, pattern, suffix, seen: HashMap::new() };
+        for (path, stamp) in watcher.scan()? {
+            watcher.seen.insert(path, Seen { stamp, handed_out: true });
+        }
+        Ok(watcher)
+    }
+
+    fn scan(&self) -> io::Result<Vec<(PathBuf, Stamp)>> {
+        let mut files = Vec::new();
+        for entry in fs::read_dir(&self.dir)? {
+            let entry = entry?;
+            let name = entry.file_name();
+            let name = name.to_string_lossy();
+            if name.ends_with(&self.suffix) || !matches(&self.pattern, &name) {
+                continue;
+            }
+            // Files can disappear between listing them and looking at them
+            let metadata = match entry.metadata() {
+                Ok(metadata) => metadata,
+                Err(_) => continue
+            };
+            if metadata.is_file() {
+                files.push((entry.path(), (metadata.len(), metadata.modified().ok())));
+            }
+        }
+        Ok(files)
+    }
+
+    /// Scan the directory again, returning the files that are new or were modified,
+    /// but haven't changed since the previous scan
+    pub fn poll(&mut self) -> io::Result<Vec<PathBuf>> {
+        let files = self.scan()?;
+        let mut settled = Vec::new();
+        let mut seen = HashMap::with_capacity(files.len());
+        for (path, stamp) in files {
+            let handed_out = match self.seen.get(&path) {
+                Some(previous) if previous.stamp == stamp => {
+                    if !previous.handed_out {
+                        settled.push(path.clone());
+                    }
+                    true
+                }
+                _ => false
+            };
+            seen.insert(path, Seen { stamp, handed_out });
+        }
+        self.seen = seen;
+        settled.sort();
+        Ok(settled)
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::env;
+    use std::fs;
+    use super::{matches, Watcher};
+
+    #[test]
+    fn matching_works() {
+        assert!(matches("*", "app.log"));
+        assert!(matches("*.log", "app.log"));
+        assert!(matches("app-?.l*g", "app-1.log"));
+        assert!(matches("*a*b", "xaab"));
+        assert!(!matches("*.log", "app.log.1"));
+        assert!(!matches("app-?.log", "app-10.log"));
+    }
+
+    #[test]
+    fn files_are_handed_out_once_settled() {
+        let dir = env::temp_dir().join(format!("huffman-watch-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("old.log"), b"old").unwrap();
+        let mut watcher = Watcher::new(dir.clone(), "*.log".to_string(), ".huf".to_string()).unwrap();
+        fs::write(dir.join("new.log"), b"new").unwrap();
+        fs::write(dir.join("new.log.huf"), b"encoded").unwrap();
+        fs::write(dir.join("new.txt"), b"other").unwrap();
+        assert!(watcher.poll().unwrap().is_empty());
+        assert_eq!(watcher.poll().unwrap(), vec![dir.join("new.log")]);
+        assert!(watcher.poll().unwrap().is_empty());
+        fs::write(dir.join("old.log"), b"changed").unwrap();
+        assert!(watcher.poll().unwrap().is_empty());
+        assert_eq!(watcher.poll().unwrap(), vec![dir.join("old.log")]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}